@@ -0,0 +1,35 @@
+//! Cost of reading a `Copy` task-local value, compared to a `thread_local!`.
+//!
+//! Run with `cargo bench --bench get`.
+
+use std::cell::Cell;
+
+use divan::black_box;
+use task_local::task_local;
+
+task_local! {
+    static NUMBER: u32;
+}
+
+thread_local! {
+    static THREAD_NUMBER: Cell<u32> = const { Cell::new(1) };
+}
+
+fn main() {
+    NUMBER.sync_scope(1u32, divan::main);
+}
+
+#[divan::bench]
+fn get() -> u32 {
+    black_box(&NUMBER).get()
+}
+
+#[divan::bench]
+fn get_copied() -> u32 {
+    black_box(&NUMBER).get_copied()
+}
+
+#[divan::bench]
+fn thread_local() -> u32 {
+    black_box(&THREAD_NUMBER).get()
+}
@@ -0,0 +1,120 @@
+//! Cost of entering a scope, for small and large task-local values.
+//!
+//! Entering a scope only installs a pointer to the value and the state of the
+//! scope, so all sizes should take about the same time per poll. Zero-sized
+//! marker keys pay for nothing else either: `poll_unscoped` polls the same
+//! future outside of any scope, and `poll_zst_watched` shows that watching a
+//! key adds nothing to entering its scope.
+//!
+//! Run with `cargo bench --bench poll`.
+
+use std::future::Future;
+use std::pin::{pin, Pin};
+use std::task::{Context, Poll};
+
+use divan::counter::ItemsCount;
+use divan::Bencher;
+use task_local::{task_local, LocalKey};
+
+const POLLS: usize = 1000;
+
+task_local! {
+    static MARKER: ();
+    static SMALL: [u8; 8];
+    static LARGE: [u8; 4096];
+}
+
+fn main() {
+    divan::main();
+}
+
+/// A future that is pending for a number of polls.
+struct Yield(usize);
+
+impl Future for Yield {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<()> {
+        if self.0 == 0 {
+            Poll::Ready(())
+        } else {
+            self.0 -= 1;
+            Poll::Pending
+        }
+    }
+}
+
+fn poll_scope<T: Copy>(bencher: Bencher, key: &'static LocalKey<T>, value: T) {
+    let waker = futures::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+
+    bencher.counter(ItemsCount::new(POLLS)).bench_local(|| {
+        let mut fut = pin!(key.scope(value, Yield(POLLS)));
+        while fut.as_mut().poll(&mut cx).is_pending() {}
+    });
+}
+
+fn poll_watched<T: Copy>(bencher: Bencher, key: &'static LocalKey<T>, value: T) {
+    let waker = futures::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+
+    bencher.counter(ItemsCount::new(POLLS)).bench_local(|| {
+        let mut fut = pin!(key.scope(value, async {
+            let _watch = key.watch();
+            Yield(POLLS).await;
+        }));
+        while fut.as_mut().poll(&mut cx).is_pending() {}
+    });
+}
+
+fn sync_scope<T: Copy>(bencher: Bencher, key: &'static LocalKey<T>, value: T) {
+    bencher.bench_local(|| key.sync_scope(value, || key.with(|value| *value)));
+}
+
+#[divan::bench]
+fn poll_unscoped(bencher: Bencher) {
+    let waker = futures::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+
+    bencher.counter(ItemsCount::new(POLLS)).bench_local(|| {
+        // Keeps the loop from being optimized away, as the task-local
+        // storage does for the scoped futures.
+        let mut fut = pin!(Yield(POLLS));
+        while divan::black_box(fut.as_mut()).poll(&mut cx).is_pending() {}
+    });
+}
+
+#[divan::bench]
+fn poll_zst(bencher: Bencher) {
+    poll_scope(bencher, &MARKER, ());
+}
+
+#[divan::bench]
+fn poll_zst_watched(bencher: Bencher) {
+    poll_watched(bencher, &MARKER, ());
+}
+
+#[divan::bench]
+fn poll_small(bencher: Bencher) {
+    poll_scope(bencher, &SMALL, [1; 8]);
+}
+
+#[divan::bench]
+fn poll_large(bencher: Bencher) {
+    poll_scope(bencher, &LARGE, [1; 4096]);
+}
+
+#[divan::bench]
+fn sync_scope_zst(bencher: Bencher) {
+    sync_scope(bencher, &MARKER, ());
+}
+
+#[divan::bench]
+fn sync_scope_small(bencher: Bencher) {
+    sync_scope(bencher, &SMALL, [1; 8]);
+}
+
+#[divan::bench]
+fn sync_scope_large(bencher: Bencher) {
+    sync_scope(bencher, &LARGE, [1; 4096]);
+}
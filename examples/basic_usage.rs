@@ -14,7 +14,7 @@ async fn nested_function() {
 async fn example() {
     // Set task-local values for the duration of this async block
     NUMBER
-        .scope(42, async {
+        .scope(42u32, async {
             MESSAGE
                 .scope("Hello, task-local!".to_string(), async {
                     println!("NUMBER = {}", NUMBER.get());
@@ -25,7 +25,7 @@ async fn example() {
 
                     // Nested scopes
                     NUMBER
-                        .scope(100, async {
+                        .scope(100u32, async {
                             println!("Inside nested scope: NUMBER = {}", NUMBER.get());
                             println!("Inside nested scope: MESSAGE = {}", MESSAGE.get());
                         })
@@ -45,11 +45,11 @@ async fn example() {
 
 // Example of using sync_scope for synchronous code
 fn sync_example() {
-    NUMBER.sync_scope(99, || {
+    NUMBER.sync_scope(99u32, || {
         println!("In sync_scope: NUMBER = {}", NUMBER.get());
 
         // Nested sync_scope
-        NUMBER.sync_scope(999, || {
+        NUMBER.sync_scope(999u32, || {
             println!("In nested sync_scope: NUMBER = {}", NUMBER.get());
         });
 
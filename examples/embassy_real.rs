@@ -3,6 +3,9 @@
 //! Tests task-local storage with actual Embassy executor.
 //!
 //! Run with: cargo run --example embassy_real
+//!
+//! To exercise the no_std backend with per-task slots instead, run:
+//! cargo run --example embassy_real --no-default-features --features embassy
 
 use task_local::task_local;
 use embassy_executor::{Spawner, SendSpawner};
@@ -50,7 +53,7 @@ async fn sensor_task(id: u8, value: u32) {
 
 #[embassy_executor::task]
 async fn coordinator_task(send_spawner: SendSpawner) {
-    TASK_VALUE.scope(999, async {
+    TASK_VALUE.scope(999u32, async {
         SHARED_STATE.scope("Coordinator", async {
             
             println!("Coordinator: TASK_VALUE = {}", TASK_VALUE.get());
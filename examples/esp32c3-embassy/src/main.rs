@@ -0,0 +1,74 @@
+//! Task-locals on the ESP32-C3 with `esp-hal`'s Embassy executors.
+//!
+//! Two instances of the same sensor task run with their own `SENSOR_ID`: one
+//! on the thread-mode executor and one on an interrupt executor that preempts
+//! it. The `embassy` feature keeps their values apart.
+//!
+//! The ESP32-C3 has no atomic compare-and-swap, so the crate is built with the
+//! `portable-atomic` feature. `esp-hal` already configures `portable-atomic`
+//! for the chip, so `critical-section` is not enabled.
+//!
+//! Build with `cargo build` from this directory, or flash and monitor a board
+//! with `cargo run`, which requires `espflash`.
+
+#![no_std]
+#![no_main]
+
+use embassy_executor::Spawner;
+use embassy_time::{Duration, Timer};
+use esp_backtrace as _;
+use esp_hal::clock::ClockControl;
+use esp_hal::interrupt::Priority;
+use esp_hal::peripherals::Peripherals;
+use esp_hal::prelude::*;
+use esp_hal::system::SystemControl;
+use esp_hal::timer::timg::TimerGroup;
+use esp_hal::timer::{ErasedTimer, OneShotTimer};
+use esp_hal_embassy::{Executor, InterruptExecutor};
+use esp_println::println;
+use static_cell::StaticCell;
+use task_local::task_local;
+
+task_local! {
+    static SENSOR_ID: u32;
+}
+
+#[embassy_executor::task(pool_size = 2)]
+async fn sensor(id: u32, period: Duration) {
+    SENSOR_ID
+        .scope(id, async {
+            loop {
+                println!("sensor {}", SENSOR_ID.get());
+                Timer::after(period).await;
+            }
+        })
+        .await
+}
+
+#[entry]
+fn main() -> ! {
+    let peripherals = Peripherals::take();
+    let system = SystemControl::new(peripherals.SYSTEM);
+    let clocks = ClockControl::boot_defaults(system.clock_control).freeze();
+
+    let timg0 = TimerGroup::new(peripherals.TIMG0, &clocks, None);
+    static TIMERS: StaticCell<[OneShotTimer<ErasedTimer>; 1]> = StaticCell::new();
+    let timers = TIMERS.init([OneShotTimer::new(timg0.timer0.into())]);
+    esp_hal_embassy::init(&clocks, timers);
+
+    // The sensor on the interrupt executor preempts the one on the
+    // thread-mode executor.
+    let interrupts = system.software_interrupt_control;
+    static HIGH: StaticCell<InterruptExecutor<2>> = StaticCell::new();
+    let high = HIGH.init(InterruptExecutor::new(interrupts.software_interrupt2));
+    high.start(Priority::Priority3)
+        .spawn(sensor(2, Duration::from_millis(300)))
+        .unwrap();
+
+    static LOW: StaticCell<Executor> = StaticCell::new();
+    LOW.init(Executor::new()).run(|spawner: Spawner| {
+        spawner
+            .spawn(sensor(1, Duration::from_millis(1000)))
+            .unwrap();
+    })
+}
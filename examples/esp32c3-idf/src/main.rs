@@ -0,0 +1,65 @@
+//! Task-locals on the ESP32-C3 with ESP-IDF and the `freertos` feature.
+//!
+//! A `std::thread`, which ESP-IDF runs as a FreeRTOS task through its pthread
+//! layer, and a task created directly with `xTaskCreatePinnedToCore` each
+//! run with their own `DEVICE_ID`. The `freertos` feature keeps the values in
+//! the thread-local storage pointers of the FreeRTOS tasks, so both kinds of
+//! tasks get their own storage.
+//!
+//! `sdkconfig.defaults` provides the second storage pointer per task that the
+//! crate uses. Build with `cargo build` from this directory, which requires
+//! the `ldproxy` linker and downloads ESP-IDF, or flash and monitor a board
+//! with `cargo run`, which requires `espflash`.
+
+use std::ffi::c_void;
+use std::thread;
+use std::time::Duration;
+
+use esp_idf_svc::sys;
+use task_local::task_local;
+
+task_local! {
+    static DEVICE_ID: u32;
+}
+
+fn report(source: &str) {
+    log::info!("{source}: device {}", DEVICE_ID.get());
+}
+
+/// The entry point of the raw FreeRTOS task.
+extern "C" fn sensor_task(_: *mut c_void) {
+    DEVICE_ID.sync_scope(2u32, || loop {
+        report("sensor task");
+        thread::sleep(Duration::from_millis(300));
+    })
+}
+
+fn main() {
+    sys::link_patches();
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    // Safety: The task never returns, and `sensor_task` ignores its argument.
+    let created = unsafe {
+        sys::xTaskCreatePinnedToCore(
+            Some(sensor_task),
+            c"sensor".as_ptr(),
+            4096,
+            std::ptr::null_mut(),
+            5,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    assert_eq!(created, 1, "the sensor task could not be created");
+
+    let worker = thread::spawn(|| {
+        DEVICE_ID.sync_scope(1u32, || loop {
+            report("worker thread");
+            thread::sleep(Duration::from_millis(1000));
+        })
+    });
+
+    // The main task is a FreeRTOS task too, outside of any scope.
+    assert!(DEVICE_ID.try_get().is_err());
+    worker.join().unwrap();
+}
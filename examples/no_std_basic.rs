@@ -15,7 +15,7 @@ fn main() {
     println!("Task-local example (works in both std and no_std)");
 
     // Basic usage with sync_scope
-    COUNTER.sync_scope(42, || {
+    COUNTER.sync_scope(42u32, || {
         println!("Counter value: {}", COUNTER.get());
         assert_eq!(COUNTER.get(), 42);
     });
@@ -26,10 +26,10 @@ fn main() {
     });
 
     // Nested scopes
-    COUNTER.sync_scope(1, || {
+    COUNTER.sync_scope(1u32, || {
         println!("Outer counter: {}", COUNTER.get());
         
-        COUNTER.sync_scope(2, || {
+        COUNTER.sync_scope(2u32, || {
             println!("Inner counter: {}", COUNTER.get());
             assert_eq!(COUNTER.get(), 2);
         });
@@ -54,7 +54,7 @@ fn main() {
 // 
 // #[embassy_executor::task]
 // async fn my_task() {
-//     COUNTER.scope(100, async {
+//     COUNTER.scope(100u32, async {
 //         // Your async task-local code here
 //         let value = COUNTER.get();
 //         // ... do something with value
@@ -0,0 +1,71 @@
+//! Debug checks of the thread that values which are not `Send` are used on.
+//!
+//! A `TaskLocalFuture` holding a value that is not `Send` is not `Send`
+//! either, so safe code can only poll it on the thread it was created on.
+//! Unsafe code can still move it to another thread, and
+//! [`enter_raw`](crate::raw::enter_raw) can attach its scope on another
+//! thread than the one it was detached from. In debug builds, the scopes of
+//! keys whose value type is not `Send` remember the thread they were first
+//! entered on, and entering them on another thread panics. Release builds
+//! check nothing.
+//!
+//! Whether the value type is `Send` is decided where the key is declared, see
+//! `__task_local_affine`.
+
+use crate::sync::const_fn;
+use crate::value_cell::ScopeState;
+use crate::LocalKey;
+
+/// Returns `true` if the value type of a key is not `Send`.
+pub(crate) type IsAffine = fn() -> bool;
+
+/// The `IsAffine` function of keys whose values are `Send`.
+#[cfg(all(debug_assertions, not(loom)))]
+pub(crate) fn not_affine() -> bool {
+    false
+}
+
+impl<T: 'static> LocalKey<T> {
+    const_fn! {
+        /// Sets the function telling whether the value type of the key is not
+        /// `Send`, see `__task_local_affine`.
+        #[doc(hidden)]
+        #[cfg_attr(
+            not(all(debug_assertions, not(loom))),
+            allow(unused_mut, unused_variables)
+        )]
+        pub fn __affine(mut self, affine: IsAffine) -> Self {
+            #[cfg(all(debug_assertions, not(loom)))]
+            {
+                self.affine = affine;
+            }
+            self
+        }
+    }
+
+    /// Records the current thread in the state of a scope that is entered,
+    /// and panics if the value of the key is not `Send` and the scope was
+    /// entered on another thread before, unless the thread is panicking.
+    #[cfg(all(debug_assertions, not(loom)))]
+    pub(crate) fn check_thread(&'static self, state: &mut ScopeState) {
+        // A `TaskLocalFuture` enters its scope again to drop the future it
+        // wraps, which must not panic while the check already does.
+        if !(self.affine)() || std::thread::panicking() {
+            return;
+        }
+        let current = std::thread::current().id();
+        match state.thread {
+            None => state.thread = Some(current),
+            Some(thread) => assert!(
+                thread == current,
+                "the scope of task-local `{}` was entered on another thread than \
+                 the one it was first entered on, but its value is not `Send`",
+                self.name
+            ),
+        }
+    }
+
+    #[cfg(not(all(debug_assertions, not(loom))))]
+    #[inline(always)]
+    pub(crate) fn check_thread(&'static self, _: &mut ScopeState) {}
+}
@@ -0,0 +1,187 @@
+//! Type-erased views of task-local keys.
+//!
+//! [`AnyLocalKey`] is implemented for every [`LocalKey`], whatever its value
+//! type, so that keys of different types can be handled together as
+//! `&'static dyn AnyLocalKey`: their names and value types, whether they are
+//! set, and, with the `alloc` feature, their values captured as an
+//! [`AnyValue`] and set again later. With the `registry` feature,
+//! [`registered_keys`](crate::registered_keys) lists the keys in use.
+//!
+//! Whether a key can be captured is decided where it is declared: the keys
+//! declared with [`task_local!`](crate::task_local) whose value type
+//! implements `Clone` and `Send` can, like `Debug` decides how their values
+//! are formatted.
+
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+#[cfg(feature = "alloc")]
+use core::any::Any;
+use core::any::TypeId;
+#[cfg(feature = "alloc")]
+use core::future::Future;
+#[cfg(feature = "alloc")]
+use core::pin::Pin;
+
+use crate::LocalKey;
+
+/// A value captured with [`AnyLocalKey::capture`].
+#[cfg(feature = "alloc")]
+pub type AnyValue = Box<dyn Any + Send>;
+
+/// A type-erased future scoped with [`AnyLocalKey::scope_any`].
+#[cfg(feature = "alloc")]
+pub type AnyScope<'a> = Pin<Box<dyn Future<Output = ()> + 'a>>;
+
+/// Captures a value of a key, see `__task_local_capture`.
+#[cfg(feature = "alloc")]
+pub(crate) type CaptureValue<T> = fn(&T) -> Option<AnyValue>;
+
+/// The capture function of keys whose values cannot be captured.
+#[cfg(feature = "alloc")]
+pub(crate) fn no_capture<T>(_: &T) -> Option<AnyValue> {
+    None
+}
+
+/// A task-local key with its value type erased.
+///
+/// Implemented for every [`LocalKey`]. See the [module documentation](self).
+///
+/// # Examples
+///
+/// ```
+/// use task_local::AnyLocalKey;
+///
+/// task_local::task_local! {
+///     static USER: String;
+///     static ATTEMPT: u32;
+/// }
+///
+/// let keys: [&'static dyn AnyLocalKey; 2] = [&USER, &ATTEMPT];
+///
+/// let captured: Vec<_> = USER.sync_scope("alice".to_string(), || {
+///     keys.iter()
+///         .filter_map(|key| Some((*key, key.capture()?)))
+///         .collect()
+/// });
+/// assert_eq!(captured.len(), 1);
+/// assert_eq!(captured[0].0.name(), "USER");
+///
+/// let (key, value) = captured.into_iter().next().unwrap();
+/// key.sync_restore(value, &mut || assert_eq!(USER.get(), "alice"))
+///     .unwrap();
+/// ```
+pub trait AnyLocalKey {
+    /// Returns the name of the key.
+    fn name(&self) -> &'static str;
+
+    /// Returns the path of the module the key was declared in.
+    fn module_path(&self) -> &'static str;
+
+    /// Returns the `TypeId` of the value type of the key.
+    fn value_type_id(&self) -> TypeId;
+
+    /// Returns the name of the value type of the key, as given by
+    /// [`core::any::type_name`].
+    fn value_type_name(&self) -> &'static str;
+
+    /// Returns the identifier of the key, see [`LocalKey::id`].
+    #[cfg(feature = "registry")]
+    fn id(&'static self) -> crate::KeyId;
+
+    /// Returns `true` if the key has a value in the current task.
+    fn is_set(&'static self) -> bool;
+
+    /// Returns a clone of the current value of the key, or `None` if it has
+    /// no value or its value type does not implement `Clone` and `Send`.
+    #[cfg(feature = "alloc")]
+    fn capture(&'static self) -> Option<AnyValue>;
+
+    /// Sets `value`, captured from this key, as the task-local value for the
+    /// closure `f`.
+    ///
+    /// Returns `value` back if it is not a value of this key.
+    #[cfg(all(feature = "alloc", not(feature = "panic-free")))]
+    fn sync_restore(&'static self, value: AnyValue, f: &mut dyn FnMut()) -> Result<(), AnyValue>;
+
+    /// Sets `value`, captured from this key, as the task-local value for the
+    /// future `f`.
+    ///
+    /// Returns `value` back, and drops `f`, if it is not a value of this key.
+    #[cfg(feature = "alloc")]
+    fn scope_any<'a>(
+        &'static self,
+        value: AnyValue,
+        f: AnyScope<'a>,
+    ) -> Result<AnyScope<'a>, AnyValue>;
+}
+
+impl<T: 'static> AnyLocalKey for LocalKey<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn module_path(&self) -> &'static str {
+        self.module_path
+    }
+
+    fn value_type_id(&self) -> TypeId {
+        TypeId::of::<T>()
+    }
+
+    fn value_type_name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
+    #[cfg(feature = "registry")]
+    fn id(&'static self) -> crate::KeyId {
+        LocalKey::id(self)
+    }
+
+    fn is_set(&'static self) -> bool {
+        self.try_with(|_| ()).is_ok()
+    }
+
+    #[cfg(feature = "alloc")]
+    fn capture(&'static self) -> Option<AnyValue> {
+        let capture = self.capture;
+        self.try_with(capture).ok().flatten()
+    }
+
+    #[cfg(all(feature = "alloc", not(feature = "panic-free")))]
+    fn sync_restore(&'static self, value: AnyValue, f: &mut dyn FnMut()) -> Result<(), AnyValue> {
+        let value = value.downcast::<T>()?;
+        self.sync_scope(*value, f);
+        Ok(())
+    }
+
+    #[cfg(feature = "alloc")]
+    fn scope_any<'a>(
+        &'static self,
+        value: AnyValue,
+        f: AnyScope<'a>,
+    ) -> Result<AnyScope<'a>, AnyValue> {
+        let value = value.downcast::<T>()?;
+        Ok(Box::pin(self.scope(*value, f)))
+    }
+}
+
+impl<T: 'static> LocalKey<T> {
+    crate::sync::const_fn! {
+        /// Sets the function capturing the values of the key, see
+        /// `__task_local_capture`.
+        #[doc(hidden)]
+        #[cfg(feature = "alloc")]
+        pub fn __capture(mut self, capture: CaptureValue<T>) -> Self {
+            self.capture = capture;
+            self
+        }
+    }
+}
+
+/// Returns `key` as a type-erased key, for the registry.
+#[cfg(feature = "registry")]
+pub(crate) fn erase<T: 'static>(key: *const ()) -> &'static dyn AnyLocalKey {
+    // Safety: `key` was stored by `registry::register::<T>` from a
+    // `&'static LocalKey<T>`.
+    unsafe { &*(key as *const LocalKey<T>) }
+}
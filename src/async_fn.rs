@@ -0,0 +1,78 @@
+//! Scopes around async closures.
+
+use core::future::Future;
+
+use crate::{LocalKey, TaskLocalFuture};
+
+impl<T: 'static> LocalKey<T> {
+    /// Sets a value `T` as the task-local value for the future returned by the
+    /// async closure `f`.
+    ///
+    /// This is the same as `self.scope(value, f())`, without building the
+    /// future separately. The closure is called right away, but none of its
+    /// body runs until the returned future is polled.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// task_local::task_local! {
+    ///     static USER: String;
+    /// }
+    ///
+    /// let greeting = USER
+    ///     .scope_async_fn("ferris", async || {
+    ///         USER.with(|user| format!("hello, {user}"))
+    ///     })
+    ///     .await;
+    ///
+    /// assert_eq!(greeting, "hello, ferris");
+    /// # }
+    /// ```
+    pub fn scope_async_fn<F, R>(
+        &'static self,
+        value: impl Into<T>,
+        f: F,
+    ) -> TaskLocalFuture<T, impl Future<Output = R>>
+    where
+        F: AsyncFnOnce() -> R,
+    {
+        self.scope(value, f())
+    }
+
+    /// Sets a value `T` as the task-local value for one call of the async
+    /// closure `f`.
+    ///
+    /// Unlike [`scope_async_fn`](Self::scope_async_fn), the closure is only
+    /// borrowed, so the same closure can run in a new scope on every iteration
+    /// of a service loop.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// task_local::task_local! {
+    ///     static REQUEST_ID: u32;
+    /// }
+    ///
+    /// let mut handled = Vec::new();
+    /// let mut handler = async || handled.push(REQUEST_ID.get());
+    ///
+    /// for id in 1..=3u32 {
+    ///     REQUEST_ID.scope_async_fn_mut(id, &mut handler).await;
+    /// }
+    ///
+    /// assert_eq!(handled, [1, 2, 3]);
+    /// # }
+    /// ```
+    pub fn scope_async_fn_mut<'a, F, R>(
+        &'static self,
+        value: impl Into<T>,
+        f: &'a mut F,
+    ) -> TaskLocalFuture<T, impl Future<Output = R> + 'a>
+    where
+        F: AsyncFnMut() -> R,
+    {
+        self.scope(value, f())
+    }
+}
@@ -0,0 +1,196 @@
+//! Task-local scopes for `async-graphql` requests and resolvers.
+//!
+//! With the `async-graphql` feature, two schema extensions establish
+//! task-locals while a GraphQL operation runs, so that resolvers and the code
+//! they call can read context such as the authenticated user or the tenant
+//! without looking it up in every `Context<'_>`:
+//!
+//! - [`ScopeExtension`] computes a value once per request, typically from the
+//!   request data, and scopes it around the execution of the operation.
+//! - [`ResolverScopeExtension`] computes a value for every field and scopes it
+//!   around the resolver of that field, including the fields selected below
+//!   it.
+//!
+//! Returning `None` from the value function runs the request or resolver
+//! without entering a scope. Work that the resolvers hand off to spawned
+//! tasks, such as batched data loader calls, runs outside of the scope unless
+//! the value is passed along explicitly, for example with
+//! [`LocalKey::scope_shared`](crate::LocalKey::scope_shared).
+//!
+//! # Examples
+//!
+//! ```
+//! # async fn dox() {
+//! use async_graphql::{EmptyMutation, EmptySubscription, Object, Request, Schema};
+//! use task_local::async_graphql::ScopeExtension;
+//!
+//! task_local::task_local! {
+//!     static TENANT: String;
+//! }
+//!
+//! struct Tenant(String);
+//!
+//! struct Query;
+//!
+//! #[Object]
+//! impl Query {
+//!     async fn tenant(&self) -> String {
+//!         TENANT.get()
+//!     }
+//! }
+//!
+//! let schema = Schema::build(Query, EmptyMutation, EmptySubscription)
+//!     .extension(ScopeExtension::new(&TENANT, |ctx| {
+//!         ctx.data_opt::<Tenant>().map(|tenant| tenant.0.clone())
+//!     }))
+//!     .finish();
+//!
+//! let request = Request::new("{ tenant }").data(Tenant("acme".into()));
+//! let response = schema.execute(request).await;
+//! assert_eq!(response.data.to_string(), r#"{tenant: "acme"}"#);
+//! # }
+//! ```
+
+use std::fmt;
+use std::sync::Arc;
+
+use ::async_graphql::extensions::{
+    Extension, ExtensionContext, ExtensionFactory, NextExecute, NextResolve, ResolveInfo,
+};
+use ::async_graphql::{Response, ServerResult, Value};
+
+use crate::LocalKey;
+
+/// A schema extension scoping a value computed once per request around the
+/// execution of the operation.
+///
+/// The value function is called with the context of the `execute` hook, in
+/// which the data of the request is available.
+///
+/// See the [module documentation](self).
+pub struct ScopeExtension<T: 'static, V> {
+    inner: Arc<RequestScope<T, V>>,
+}
+
+struct RequestScope<T: 'static, V> {
+    key: &'static LocalKey<T>,
+    make_value: V,
+}
+
+impl<T, V> ScopeExtension<T, V>
+where
+    T: Send + 'static,
+    V: Fn(&ExtensionContext<'_>) -> Option<T> + Send + Sync + 'static,
+{
+    /// Creates an extension scoping `key` with the value returned by
+    /// `make_value` for every request.
+    pub fn new(key: &'static LocalKey<T>, make_value: V) -> Self {
+        Self {
+            inner: Arc::new(RequestScope { key, make_value }),
+        }
+    }
+}
+
+impl<T: 'static, V> fmt::Debug for ScopeExtension<T, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScopeExtension")
+            .field("key", &self.inner.key.name())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T, V> ExtensionFactory for ScopeExtension<T, V>
+where
+    T: Send + 'static,
+    V: Fn(&ExtensionContext<'_>) -> Option<T> + Send + Sync + 'static,
+{
+    fn create(&self) -> Arc<dyn Extension> {
+        self.inner.clone()
+    }
+}
+
+#[::async_graphql::async_trait::async_trait]
+impl<T, V> Extension for RequestScope<T, V>
+where
+    T: Send + 'static,
+    V: Fn(&ExtensionContext<'_>) -> Option<T> + Send + Sync + 'static,
+{
+    async fn execute(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        operation_name: Option<&str>,
+        next: NextExecute<'_>,
+    ) -> Response {
+        match (self.make_value)(ctx) {
+            Some(value) => self.key.scope(value, next.run(ctx, operation_name)).await,
+            None => next.run(ctx, operation_name).await,
+        }
+    }
+}
+
+/// A schema extension scoping a value computed for every field around the
+/// resolver of the field.
+///
+/// The scope also covers the fields selected below the field, so the value
+/// of the innermost field with a value is seen.
+///
+/// See the [module documentation](self).
+pub struct ResolverScopeExtension<T: 'static, V> {
+    inner: Arc<ResolverScope<T, V>>,
+}
+
+struct ResolverScope<T: 'static, V> {
+    key: &'static LocalKey<T>,
+    make_value: V,
+}
+
+impl<T, V> ResolverScopeExtension<T, V>
+where
+    T: Send + 'static,
+    V: Fn(&ExtensionContext<'_>, &ResolveInfo<'_>) -> Option<T> + Send + Sync + 'static,
+{
+    /// Creates an extension scoping `key` with the value returned by
+    /// `make_value` for every field.
+    pub fn new(key: &'static LocalKey<T>, make_value: V) -> Self {
+        Self {
+            inner: Arc::new(ResolverScope { key, make_value }),
+        }
+    }
+}
+
+impl<T: 'static, V> fmt::Debug for ResolverScopeExtension<T, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResolverScopeExtension")
+            .field("key", &self.inner.key.name())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T, V> ExtensionFactory for ResolverScopeExtension<T, V>
+where
+    T: Send + 'static,
+    V: Fn(&ExtensionContext<'_>, &ResolveInfo<'_>) -> Option<T> + Send + Sync + 'static,
+{
+    fn create(&self) -> Arc<dyn Extension> {
+        self.inner.clone()
+    }
+}
+
+#[::async_graphql::async_trait::async_trait]
+impl<T, V> Extension for ResolverScope<T, V>
+where
+    T: Send + 'static,
+    V: Fn(&ExtensionContext<'_>, &ResolveInfo<'_>) -> Option<T> + Send + Sync + 'static,
+{
+    async fn resolve(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        info: ResolveInfo<'_>,
+        next: NextResolve<'_>,
+    ) -> ServerResult<Option<Value>> {
+        match (self.make_value)(ctx, &info) {
+            Some(value) => self.key.scope(value, next.run(ctx, info)).await,
+            None => next.run(ctx, info).await,
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! An access trail for keys declared with `#[task_local(audited)]`.
+//!
+//! Keys carrying credentials or other sensitive context may need a record of
+//! who read them. With the `audit` feature, every read of an audited key, by
+//! `with`, `try_with`, `get`, `get_copied` and the functions built on them, is
+//! passed to the function registered with
+//! [`set_audit_hook!`](crate::set_audit_hook) as an [`Access`], with the name
+//! of the key, the location of the read and whether it succeeded. Keys that
+//! are not audited only pay for checking a flag.
+//!
+//! Reads that bypass the checks of `with`, `with_unchecked` and
+//! `peek_from_isr`, are not audited, just as they are not counted by the
+//! `stats` feature.
+
+use core::fmt;
+use core::panic::Location;
+
+use crate::sync::const_fn;
+use crate::{AccessError, LocalKey};
+
+extern "Rust" {
+    fn _task_local_audit_hook(access: &Access);
+}
+
+/// A read of an audited key, passed to the hook registered with
+/// [`set_audit_hook!`](crate::set_audit_hook).
+///
+/// Requires the `audit` feature.
+#[derive(Clone, Copy, Debug)]
+pub struct Access {
+    key: &'static str,
+    module_path: &'static str,
+    location: &'static Location<'static>,
+    result: Result<(), AccessError>,
+}
+
+impl Access {
+    /// Returns the name of the key, as declared with `task_local!`.
+    pub fn key(&self) -> &'static str {
+        self.key
+    }
+
+    /// Returns the path of the module the key was declared in.
+    pub fn module_path(&self) -> &'static str {
+        self.module_path
+    }
+
+    /// Returns where the key was read.
+    pub fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+
+    /// Returns `Ok(())` if the value was read, and why it could not be
+    /// otherwise.
+    pub fn result(&self) -> Result<(), AccessError> {
+        self.result
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "task-local `{}::{}` read at {}",
+            self.module_path, self.key, self.location
+        )?;
+        match self.result {
+            Ok(()) => Ok(()),
+            Err(err) => write!(f, " failed: {}", err),
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Access {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(
+            f,
+            "task-local `{=str}::{=str}` read at {=str}:{=u32}",
+            self.module_path,
+            self.key,
+            self.location.file(),
+            self.location.line()
+        );
+        if let Err(err) = self.result {
+            defmt::write!(f, " failed: {}", err);
+        }
+    }
+}
+
+impl<T: 'static> LocalKey<T> {
+    const_fn! {
+        /// Makes the key report its reads, see `#[task_local(audited)]`.
+        #[doc(hidden)]
+        pub fn __audited(mut self) -> Self {
+            self.audited = true;
+            self
+        }
+    }
+
+    /// Reports a read of the key to the audit hook if the key is audited.
+    #[inline(always)]
+    #[track_caller]
+    pub(crate) fn audit(&self, result: Result<(), AccessError>) {
+        if self.audited {
+            report(Access {
+                key: self.name,
+                module_path: self.module_path,
+                location: Location::caller(),
+                result,
+            });
+        }
+    }
+}
+
+#[inline(never)]
+fn report(access: Access) {
+    // Safety: The symbol is defined by `set_audit_hook!` with this exact
+    // signature.
+    unsafe { _task_local_audit_hook(&access) }
+}
+
+/// Registers the function receiving the reads of audited keys.
+///
+/// Required by the `audit` feature. The function is called with the
+/// [`Access`] after every read of an audited key, outside of any critical
+/// section, and must not read an audited key itself. It must be defined
+/// exactly once in the final binary.
+///
+/// # Examples
+///
+/// ```ignore
+/// fn audit(access: &task_local::Access) {
+///     log::info!(target: "audit", "{}", access);
+/// }
+///
+/// task_local::set_audit_hook!(audit);
+///
+/// task_local::task_local! {
+///     #[task_local(audited)]
+///     pub static API_TOKEN: String;
+/// }
+/// ```
+#[macro_export]
+macro_rules! set_audit_hook {
+    ($f:path) => {
+        #[unsafe(no_mangle)]
+        fn _task_local_audit_hook(access: &$crate::Access) {
+            $f(access)
+        }
+    };
+}
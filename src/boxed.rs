@@ -0,0 +1,106 @@
+//! Conveniences for task-locals holding a boxed trait object.
+//!
+//! Keys such as `LocalKey<Box<dyn Logger>>` inject a dependency into the code
+//! running in a scope. The value passed to [`LocalKey::scope`] only converts
+//! into the type of the key with `Into`, which does not turn a `Box<Console>`
+//! into a `Box<dyn Logger>`, so [`scope_box`](LocalKey::scope_box) takes the
+//! box itself and lets it coerce. For values shared between tasks, see the
+//! conveniences for `LocalKey<Arc<T>>`, which also accept `Arc<dyn Trait>`.
+
+use alloc::boxed::Box;
+use core::future::Future;
+
+use crate::{AccessError, LocalKey, TaskLocalFuture};
+
+impl<T: ?Sized + 'static> LocalKey<Box<T>> {
+    /// Accesses the boxed task-local value and runs the provided closure on
+    /// it.
+    ///
+    /// This is the same as [`with`](Self::with), but the closure gets the
+    /// value itself, such as a `&dyn Logger`, instead of a reference to the
+    /// box.
+    ///
+    /// Requires the `alloc` feature.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the task local doesn't have a value set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// trait Logger {
+    ///     fn prefix(&self) -> &str;
+    /// }
+    ///
+    /// struct Console;
+    ///
+    /// impl Logger for Console {
+    ///     fn prefix(&self) -> &str {
+    ///         "console"
+    ///     }
+    /// }
+    ///
+    /// task_local::task_local! {
+    ///     static LOGGER: Box<dyn Logger>;
+    /// }
+    ///
+    /// fn line(logger: &dyn Logger) -> String {
+    ///     format!("[{}] started", logger.prefix())
+    /// }
+    ///
+    /// let line = LOGGER.sync_scope_box(Box::new(Console), || {
+    ///     LOGGER.with_dyn(|logger| line(logger))
+    /// });
+    /// assert_eq!(line, "[console] started");
+    /// ```
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn with_dyn<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        self.with(|value| f(&**value))
+    }
+
+    /// Accesses the boxed task-local value and runs the provided closure on
+    /// it, returning an [`AccessError`] if the value cannot be read.
+    ///
+    /// Requires the `alloc` feature.
+    pub fn try_with_dyn<F, R>(&'static self, f: F) -> Result<R, AccessError>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        self.try_with(|value| f(&**value))
+    }
+
+    /// Sets `value` as the task-local value for the future `f`.
+    ///
+    /// This is the same as [`scope`](Self::scope), but `value` is a box that
+    /// coerces to the type of the key, so `Box::new(Console)` can be passed
+    /// for a `Box<dyn Logger>` key.
+    ///
+    /// Requires the `alloc` feature.
+    #[track_caller]
+    pub fn scope_box<F>(&'static self, value: Box<T>, f: F) -> TaskLocalFuture<Box<T>, F>
+    where
+        F: Future,
+    {
+        self.scope(value, f)
+    }
+
+    /// Sets `value` as the task-local value for the closure `f`.
+    ///
+    /// This is the same as [`sync_scope`](Self::sync_scope), but `value` is a
+    /// box that coerces to the type of the key.
+    ///
+    /// Requires the `alloc` feature.
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn sync_scope_box<F, R>(&'static self, value: Box<T>, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        self.sync_scope(value, f)
+    }
+}
@@ -0,0 +1,191 @@
+//! Selections of task-local keys whose values are captured together.
+//!
+//! A [`Capture`] takes a snapshot of the values of some keys in one place and
+//! sets them again somewhere else, such as another task or another thread.
+//! It is what the channel wrappers and the rayon adapter carry across the
+//! boundaries they cross.
+
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use pin_project_lite::pin_project;
+
+use crate::TaskLocalStorage;
+
+/// A selection of task-local keys whose values are captured together.
+///
+/// This is implemented for `&'static K` for every [`TaskLocalStorage`] `K`
+/// with a [`Clone`] value, and for tuples of up to four selections. The
+/// snapshot of a tuple holds the snapshots of its elements, and its scopes
+/// are entered from the first element to the last.
+pub trait Capture: Copy + 'static {
+    /// The values of the selected keys at the time of the capture.
+    type Snapshot;
+
+    /// The future returned by [`scope`](Self::scope).
+    type Scope<F: Future>: Future<Output = F::Output>;
+
+    /// Takes a snapshot of the current values of the selected keys.
+    fn capture(self) -> Self::Snapshot;
+
+    /// Sets the values of `snapshot` as the task-local values for the future
+    /// `f`.
+    fn scope<F>(self, snapshot: Self::Snapshot, f: F) -> Self::Scope<F>
+    where
+        F: Future;
+
+    /// Sets the values of `snapshot` as the task-local values for the
+    /// closure `f`.
+    #[cfg(not(feature = "panic-free"))]
+    fn sync_scope<F, R>(self, snapshot: Self::Snapshot, f: F) -> R
+    where
+        F: FnOnce() -> R;
+}
+
+impl<K> Capture for &'static K
+where
+    K: TaskLocalStorage,
+    K::Value: Clone,
+{
+    type Snapshot = Option<K::Value>;
+    type Scope<F: Future> = MaybeScope<K::Scope<F>, F>;
+
+    fn capture(self) -> Option<K::Value> {
+        self.try_with(Clone::clone).ok()
+    }
+
+    fn scope<F>(self, snapshot: Option<K::Value>, f: F) -> Self::Scope<F>
+    where
+        F: Future,
+    {
+        match snapshot {
+            Some(value) => MaybeScope::Scoped {
+                future: TaskLocalStorage::scope(self, value, f),
+            },
+            None => MaybeScope::Unscoped { future: f },
+        }
+    }
+
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    fn sync_scope<F, R>(self, snapshot: Option<K::Value>, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        match snapshot {
+            Some(value) => TaskLocalStorage::sync_scope(self, value, f),
+            None => f(),
+        }
+    }
+}
+
+impl<A: Capture> Capture for (A,) {
+    type Snapshot = (A::Snapshot,);
+    type Scope<F: Future> = A::Scope<F>;
+
+    fn capture(self) -> Self::Snapshot {
+        (self.0.capture(),)
+    }
+
+    fn scope<F>(self, snapshot: Self::Snapshot, f: F) -> Self::Scope<F>
+    where
+        F: Future,
+    {
+        self.0.scope(snapshot.0, f)
+    }
+
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    fn sync_scope<F, R>(self, snapshot: Self::Snapshot, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        self.0.sync_scope(snapshot.0, f)
+    }
+}
+
+/// Implements `Capture` for a tuple by entering the scopes of its head around
+/// the scopes of its tail. Every element is given as `Type key snapshot`.
+macro_rules! impl_capture_for_tuple {
+    ($H:ident $h:ident $hs:ident, $($T:ident $t:ident $ts:ident),+) => {
+        impl<$H: Capture, $($T: Capture),+> Capture for ($H, $($T),+) {
+            type Snapshot = ($H::Snapshot, $($T::Snapshot),+);
+            type Scope<F: Future> = $H::Scope<<($($T,)+) as Capture>::Scope<F>>;
+
+            fn capture(self) -> Self::Snapshot {
+                let ($h, $($t),+) = self;
+                ($h.capture(), $($t.capture()),+)
+            }
+
+            fn scope<F>(self, snapshot: Self::Snapshot, f: F) -> Self::Scope<F>
+            where
+                F: Future,
+            {
+                let ($h, $($t),+) = self;
+                let ($hs, $($ts),+) = snapshot;
+                $h.scope($hs, ($($t,)+).scope(($($ts,)+), f))
+            }
+
+            #[cfg(not(feature = "panic-free"))]
+            #[track_caller]
+            fn sync_scope<F, R>(self, snapshot: Self::Snapshot, f: F) -> R
+            where
+                F: FnOnce() -> R,
+            {
+                let ($h, $($t),+) = self;
+                let ($hs, $($ts),+) = snapshot;
+                $h.sync_scope($hs, move || ($($t,)+).sync_scope(($($ts,)+), f))
+            }
+        }
+    };
+}
+
+impl_capture_for_tuple!(A a sa, B b sb);
+impl_capture_for_tuple!(A a sa, B b sb, C c sc);
+impl_capture_for_tuple!(A a sa, B b sb, C c sc, D d sd);
+
+pin_project! {
+    /// A future that runs `F` inside the scope of a key if a value was
+    /// captured for it, and outside of any scope of the key otherwise.
+    ///
+    /// Returned by [`Capture::scope`] for a single key.
+    #[project = MaybeScopeProj]
+    pub enum MaybeScope<S, F> {
+        /// A value was captured, and the future runs in its scope.
+        Scoped {
+            #[pin]
+            future: S,
+        },
+        /// No value was captured.
+        Unscoped {
+            #[pin]
+            future: F,
+        },
+    }
+}
+
+impl<S, F> Future for MaybeScope<S, F>
+where
+    S: Future,
+    F: Future<Output = S::Output>,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        match self.project() {
+            MaybeScopeProj::Scoped { future } => future.poll(cx),
+            MaybeScopeProj::Unscoped { future } => future.poll(cx),
+        }
+    }
+}
+
+impl<S, F> fmt::Debug for MaybeScope<S, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Scoped { .. } => f.write_str("MaybeScope::Scoped"),
+            Self::Unscoped { .. } => f.write_str("MaybeScope::Unscoped"),
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Channels that carry task-local context along with their messages.
+//!
+//! A task that hands work to another task over a channel loses its
+//! task-locals at the channel boundary: the receiving task runs in its own
+//! scopes, or in none. The wrappers in this module take a snapshot of a
+//! selection of task-locals when a message is sent, and re-enter it on the
+//! receiving side while the message is handled, so that context such as a
+//! request or trace id follows the work from one task to the next.
+//!
+//! The keys to carry are selected with a [`Capture`], which is a reference to
+//! a single key or a tuple of references to keys. Any
+//! [`TaskLocalStorage`](crate::TaskLocalStorage) with a [`Clone`] value can be
+//! selected, including `tokio::task_local!` keys with the `tokio-interop`
+//! feature. A key without a value at the time of sending is left unset while
+//! the message is handled.
+//!
+//! Received messages are wrapped in an [`Envelope`], which runs a handler
+//! inside the captured scopes with [`Envelope::scope`] or
+//! [`Envelope::sync_scope`].
+//!
+//! Two channel implementations are wrapped:
+//!
+//! - [`tokio`] with the `tokio-channel` feature, around
+//!   `tokio::sync::mpsc` channels.
+//! - [`embassy_sync`] with the `embassy-sync` feature, around
+//!   `embassy_sync::channel::Channel`, for `no_std` targets.
+
+use core::fmt;
+use core::future::Future;
+
+use crate::Capture;
+
+#[cfg(feature = "tokio-channel")]
+pub mod tokio;
+
+#[cfg(feature = "embassy-sync")]
+pub mod embassy_sync;
+
+/// A received message together with the task-local values captured when it
+/// was sent.
+///
+/// Returned by the receivers of this module. The values are only set while
+/// the message is handled through [`scope`](Self::scope) or
+/// [`sync_scope`](Self::sync_scope).
+///
+/// The snapshot type `S` is a separate parameter, rather than spelled out as
+/// `C::Snapshot`, so that futures holding channels and envelopes stay `Send`
+/// when spawned; it is always left at its default.
+pub struct Envelope<M, C, S = <C as Capture>::Snapshot> {
+    message: M,
+    keys: C,
+    snapshot: S,
+}
+
+impl<M, C, S> Envelope<M, C, S>
+where
+    C: Capture<Snapshot = S>,
+{
+    pub(crate) fn new(message: M, keys: C, snapshot: S) -> Self {
+        Self {
+            message,
+            keys,
+            snapshot,
+        }
+    }
+
+    /// Returns a reference to the message.
+    pub fn message(&self) -> &M {
+        &self.message
+    }
+
+    /// Returns the captured values.
+    pub fn snapshot(&self) -> &S {
+        &self.snapshot
+    }
+
+    /// Consumes the envelope, returning the message and discarding the
+    /// captured values.
+    pub fn into_message(self) -> M {
+        self.message
+    }
+
+    /// Consumes the envelope, returning the message and the captured values.
+    pub fn into_parts(self) -> (M, S) {
+        (self.message, self.snapshot)
+    }
+
+    /// Calls `f` with the message, and runs the returned future with the
+    /// captured values as the task-local values.
+    ///
+    /// `f` itself is called outside of the scopes; only the future it
+    /// returns runs inside them.
+    pub fn scope<F, Fut>(self, f: F) -> C::Scope<Fut>
+    where
+        F: FnOnce(M) -> Fut,
+        Fut: Future,
+    {
+        self.keys.scope(self.snapshot, f(self.message))
+    }
+
+    /// Calls `f` with the message and the captured values as the task-local
+    /// values.
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn sync_scope<F, R>(self, f: F) -> R
+    where
+        F: FnOnce(M) -> R,
+    {
+        let message = self.message;
+        self.keys.sync_scope(self.snapshot, move || f(message))
+    }
+}
+
+impl<M: fmt::Debug, C, S> fmt::Debug for Envelope<M, C, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Envelope")
+            .field("message", &self.message)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,222 @@
+//! Context-carrying wrappers around `embassy_sync` channels.
+//!
+//! Available with the `embassy-sync` feature. See the
+//! [module documentation](super) of `channel`.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+//! use task_local::channel::embassy_sync::ContextChannel;
+//! use task_local::LocalKey;
+//!
+//! task_local::task_local! {
+//!     static REQUEST_ID: u32;
+//! }
+//!
+//! static JOBS: ContextChannel<CriticalSectionRawMutex, u8, &'static LocalKey<u32>, 4> =
+//!     ContextChannel::new(&REQUEST_ID);
+//!
+//! async fn producer() {
+//!     REQUEST_ID.scope(7u32, JOBS.send(1)).await;
+//! }
+//!
+//! async fn consumer() {
+//!     loop {
+//!         JOBS.receive()
+//!             .await
+//!             .scope(|job| async move { handle(job, REQUEST_ID.get()).await })
+//!             .await;
+//!     }
+//! }
+//! ```
+
+use core::fmt;
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::channel::{Channel, Receiver, Sender, TryReceiveError, TrySendError};
+
+use super::{Capture, Envelope};
+
+/// A bounded channel carrying the values of a selection of keys along with
+/// every message.
+///
+/// Like [`Channel`], it is usually placed in a `static` and shared between
+/// tasks by reference. The last type parameter is the snapshot type of the
+/// selection and is left at its default.
+pub struct ContextChannel<RM: RawMutex, M, C, const N: usize, S = <C as Capture>::Snapshot> {
+    inner: Channel<RM, (M, S), N>,
+    keys: C,
+}
+
+impl<RM: RawMutex, M, C, const N: usize, S> ContextChannel<RM, M, C, N, S>
+where
+    C: Capture<Snapshot = S>,
+{
+    /// Creates an empty channel carrying the values of `keys`.
+    pub const fn new(keys: C) -> Self {
+        Self {
+            inner: Channel::new(),
+            keys,
+        }
+    }
+
+    /// Returns a sender for the channel.
+    pub fn sender(&self) -> ContextSender<'_, RM, M, C, N, S> {
+        ContextSender {
+            inner: self.inner.sender(),
+            keys: self.keys,
+        }
+    }
+
+    /// Returns a receiver for the channel.
+    pub fn receiver(&self) -> ContextReceiver<'_, RM, M, C, N, S> {
+        ContextReceiver {
+            inner: self.inner.receiver(),
+            keys: self.keys,
+        }
+    }
+
+    /// Sends `message` along with the current values of the selected keys,
+    /// waiting for capacity.
+    ///
+    /// The values are captured before waiting.
+    pub async fn send(&self, message: M) {
+        self.inner.send((message, self.keys.capture())).await
+    }
+
+    /// Attempts to send `message` along with the current values of the
+    /// selected keys, without waiting for capacity.
+    pub fn try_send(&self, message: M) -> Result<(), TrySendError<M>> {
+        try_send(self.inner.sender(), self.keys, message)
+    }
+
+    /// Receives the next message, waiting until one is available.
+    pub async fn receive(&self) -> Envelope<M, C, S> {
+        let (message, snapshot) = self.inner.receive().await;
+        Envelope::new(message, self.keys, snapshot)
+    }
+
+    /// Attempts to receive the next message without waiting.
+    pub fn try_receive(&self) -> Result<Envelope<M, C, S>, TryReceiveError> {
+        let (message, snapshot) = self.inner.try_receive()?;
+        Ok(Envelope::new(message, self.keys, snapshot))
+    }
+
+    /// Returns the number of messages in the channel.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns `true` if the channel is empty.
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Returns a reference to the wrapped channel.
+    pub fn get_ref(&self) -> &Channel<RM, (M, S), N> {
+        &self.inner
+    }
+}
+
+impl<RM: RawMutex, M, C, const N: usize, S> fmt::Debug for ContextChannel<RM, M, C, N, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContextChannel")
+            .field("len", &self.inner.len())
+            .field("capacity", &N)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A sender for a [`ContextChannel`].
+///
+/// Created by the function [`ContextChannel::sender`].
+pub struct ContextSender<'ch, RM: RawMutex, M, C, const N: usize, S = <C as Capture>::Snapshot> {
+    inner: Sender<'ch, RM, (M, S), N>,
+    keys: C,
+}
+
+impl<RM: RawMutex, M, C, const N: usize, S> ContextSender<'_, RM, M, C, N, S>
+where
+    C: Capture<Snapshot = S>,
+{
+    /// Sends `message` along with the current values of the selected keys,
+    /// waiting for capacity.
+    ///
+    /// See [`ContextChannel::send`].
+    pub async fn send(&self, message: M) {
+        self.inner.send((message, self.keys.capture())).await
+    }
+
+    /// Attempts to send `message` along with the current values of the
+    /// selected keys, without waiting for capacity.
+    pub fn try_send(&self, message: M) -> Result<(), TrySendError<M>> {
+        try_send(self.inner, self.keys, message)
+    }
+}
+
+impl<RM: RawMutex, M, C: Copy, const N: usize, S> Clone for ContextSender<'_, RM, M, C, N, S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<RM: RawMutex, M, C: Copy, const N: usize, S> Copy for ContextSender<'_, RM, M, C, N, S> {}
+
+impl<RM: RawMutex, M, C, const N: usize, S> fmt::Debug for ContextSender<'_, RM, M, C, N, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContextSender").finish_non_exhaustive()
+    }
+}
+
+/// A receiver for a [`ContextChannel`].
+///
+/// Created by the function [`ContextChannel::receiver`].
+pub struct ContextReceiver<'ch, RM: RawMutex, M, C, const N: usize, S = <C as Capture>::Snapshot> {
+    inner: Receiver<'ch, RM, (M, S), N>,
+    keys: C,
+}
+
+impl<RM: RawMutex, M, C, const N: usize, S> ContextReceiver<'_, RM, M, C, N, S>
+where
+    C: Capture<Snapshot = S>,
+{
+    /// Receives the next message, waiting until one is available.
+    pub async fn receive(&self) -> Envelope<M, C, S> {
+        let (message, snapshot) = self.inner.receive().await;
+        Envelope::new(message, self.keys, snapshot)
+    }
+
+    /// Attempts to receive the next message without waiting.
+    pub fn try_receive(&self) -> Result<Envelope<M, C, S>, TryReceiveError> {
+        let (message, snapshot) = self.inner.try_receive()?;
+        Ok(Envelope::new(message, self.keys, snapshot))
+    }
+}
+
+impl<RM: RawMutex, M, C: Copy, const N: usize, S> Clone for ContextReceiver<'_, RM, M, C, N, S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<RM: RawMutex, M, C: Copy, const N: usize, S> Copy for ContextReceiver<'_, RM, M, C, N, S> {}
+
+impl<RM: RawMutex, M, C, const N: usize, S> fmt::Debug for ContextReceiver<'_, RM, M, C, N, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContextReceiver").finish_non_exhaustive()
+    }
+}
+
+fn try_send<RM: RawMutex, M, C, const N: usize, S>(
+    sender: Sender<'_, RM, (M, S), N>,
+    keys: C,
+    message: M,
+) -> Result<(), TrySendError<M>>
+where
+    C: Capture<Snapshot = S>,
+{
+    sender
+        .try_send((message, keys.capture()))
+        .map_err(|TrySendError::Full((message, _))| TrySendError::Full(message))
+}
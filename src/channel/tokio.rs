@@ -0,0 +1,198 @@
+//! Context-carrying wrappers around `tokio::sync::mpsc` channels.
+//!
+//! Available with the `tokio-channel` feature. See the
+//! [module documentation](super) of `channel`.
+//!
+//! # Examples
+//!
+//! ```
+//! # async fn dox() {
+//! use task_local::channel::tokio::channel;
+//!
+//! task_local::task_local! {
+//!     static REQUEST_ID: u64;
+//! }
+//!
+//! let (tx, mut rx) = channel::<&str, _>(&REQUEST_ID, 8);
+//!
+//! REQUEST_ID
+//!     .scope(7u64, async move {
+//!         tx.send("hello").await.unwrap();
+//!     })
+//!     .await;
+//!
+//! let envelope = rx.recv().await.unwrap();
+//! let line = envelope
+//!     .scope(|message| async move { format!("[{}] {message}", REQUEST_ID.get()) })
+//!     .await;
+//! assert_eq!(line, "[7] hello");
+//! # }
+//! ```
+
+use std::fmt;
+
+use tokio::sync::mpsc::error::{SendError, TryRecvError, TrySendError};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use super::{Capture, Envelope};
+
+/// Creates a bounded channel carrying the values of `keys` along with every
+/// message.
+///
+/// See [`tokio::sync::mpsc::channel`].
+///
+/// # Panics
+///
+/// Panics if `buffer` is zero.
+pub fn channel<M, C: Capture>(
+    keys: C,
+    buffer: usize,
+) -> (ContextSender<M, C>, ContextReceiver<M, C>) {
+    let (tx, rx) = mpsc::channel(buffer);
+    (ContextSender::new(tx, keys), ContextReceiver::new(rx, keys))
+}
+
+/// The sending half of a channel created by [`channel`].
+///
+/// Every message is sent together with a snapshot of the values of the
+/// selected keys, taken when [`send`](Self::send) or
+/// [`try_send`](Self::try_send) is called.
+pub struct ContextSender<M, C, S = <C as Capture>::Snapshot> {
+    inner: Sender<(M, S)>,
+    keys: C,
+}
+
+impl<M, C, S> ContextSender<M, C, S>
+where
+    C: Capture<Snapshot = S>,
+{
+    /// Wraps a sender of messages paired with snapshots of `keys`.
+    pub fn new(inner: Sender<(M, S)>, keys: C) -> Self {
+        Self { inner, keys }
+    }
+
+    /// Sends `message` along with the current values of the selected keys,
+    /// waiting for capacity.
+    ///
+    /// The values are captured before waiting. If the receiver has been
+    /// closed, the message is returned in the error.
+    pub async fn send(&self, message: M) -> Result<(), SendError<M>> {
+        let snapshot = self.keys.capture();
+        self.inner
+            .send((message, snapshot))
+            .await
+            .map_err(|SendError((message, _))| SendError(message))
+    }
+
+    /// Attempts to send `message` along with the current values of the
+    /// selected keys, without waiting for capacity.
+    pub fn try_send(&self, message: M) -> Result<(), TrySendError<M>> {
+        let snapshot = self.keys.capture();
+        self.inner
+            .try_send((message, snapshot))
+            .map_err(|err| match err {
+                TrySendError::Full((message, _)) => TrySendError::Full(message),
+                TrySendError::Closed((message, _)) => TrySendError::Closed(message),
+            })
+    }
+
+    /// Returns `true` if the receiver has been dropped or closed.
+    pub fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    /// Returns the selected keys.
+    pub fn keys(&self) -> C {
+        self.keys
+    }
+
+    /// Returns a reference to the wrapped sender.
+    pub fn get_ref(&self) -> &Sender<(M, S)> {
+        &self.inner
+    }
+
+    /// Consumes the wrapper, returning the wrapped sender.
+    pub fn into_inner(self) -> Sender<(M, S)> {
+        self.inner
+    }
+}
+
+impl<M, C: Copy, S> Clone for ContextSender<M, C, S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            keys: self.keys,
+        }
+    }
+}
+
+impl<M, C, S> fmt::Debug for ContextSender<M, C, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContextSender")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The receiving half of a channel created by [`channel`].
+///
+/// Messages are received in an [`Envelope`] holding the values captured when
+/// they were sent.
+pub struct ContextReceiver<M, C, S = <C as Capture>::Snapshot> {
+    inner: Receiver<(M, S)>,
+    keys: C,
+}
+
+impl<M, C, S> ContextReceiver<M, C, S>
+where
+    C: Capture<Snapshot = S>,
+{
+    /// Wraps a receiver of messages paired with snapshots of `keys`.
+    pub fn new(inner: Receiver<(M, S)>, keys: C) -> Self {
+        Self { inner, keys }
+    }
+
+    /// Receives the next message, waiting until one is available.
+    ///
+    /// Returns `None` once the channel is closed and empty.
+    pub async fn recv(&mut self) -> Option<Envelope<M, C, S>> {
+        let (message, snapshot) = self.inner.recv().await?;
+        Some(Envelope::new(message, self.keys, snapshot))
+    }
+
+    /// Attempts to receive the next message without waiting.
+    pub fn try_recv(&mut self) -> Result<Envelope<M, C, S>, TryRecvError> {
+        let (message, snapshot) = self.inner.try_recv()?;
+        Ok(Envelope::new(message, self.keys, snapshot))
+    }
+
+    /// Closes the receiving half, without dropping it.
+    ///
+    /// See [`Receiver::close`].
+    pub fn close(&mut self) {
+        self.inner.close()
+    }
+
+    /// Returns the selected keys.
+    pub fn keys(&self) -> C {
+        self.keys
+    }
+
+    /// Returns a reference to the wrapped receiver.
+    pub fn get_ref(&self) -> &Receiver<(M, S)> {
+        &self.inner
+    }
+
+    /// Consumes the wrapper, returning the wrapped receiver.
+    pub fn into_inner(self) -> Receiver<(M, S)> {
+        self.inner
+    }
+}
+
+impl<M, C, S> fmt::Debug for ContextReceiver<M, C, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ContextReceiver")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,274 @@
+//! Hierarchical contexts of task-local values.
+//!
+//! A [`Context`] holds values for any number of keys and is entered as a
+//! whole, at the cost of a single scope however many keys it holds. A child
+//! context starts as a view of its parent and only stores the keys it
+//! overrides: the values form a persistent linked list, shared through `Arc`s,
+//! in which every override is a node pointing to the context it was derived
+//! from. Deriving a context, entering it and capturing the current one are
+//! O(1), so a stack of middleware adding a key each stays cheap with dozens of
+//! keys. Reading a key walks the list from the innermost override, which is
+//! O(number of overrides).
+//!
+//! A context is also a snapshot that is cheap to propagate: cloning it only
+//! increments a reference count, so handing it to thousands of spawned tasks
+//! shares its values instead of cloning them, and [`Context::make_mut`]
+//! copies a value only when it is written.
+//!
+//! Inside [`Context::scope`] or [`Context::sync_scope`], a key that is not set
+//! by a scope of its own reads its value from the current context, through
+//! `with`, `try_with`, `get` and the functions built on them. A scope of the
+//! key itself takes precedence over the context, wherever it was entered, and
+//! `set` only replaces the value of such a scope: values in a context are
+//! shared and never modified.
+//!
+//! # Examples
+//!
+//! ```
+//! # async fn dox() {
+//! use task_local::context::Context;
+//!
+//! task_local::task_local! {
+//!     static TENANT: &'static str;
+//!     static REQUEST_ID: u64;
+//! }
+//!
+//! let base = Context::new().with_value(&TENANT, "acme");
+//! base.scope(async {
+//!     // The child shares the value of `TENANT` with its parent.
+//!     let child = Context::current().with_value(&REQUEST_ID, 7);
+//!     child
+//!         .scope(async {
+//!             assert_eq!(TENANT.get(), "acme");
+//!             assert_eq!(REQUEST_ID.get(), 7);
+//!         })
+//!         .await;
+//!     assert!(REQUEST_ID.try_with(|_| ()).is_err());
+//! })
+//! .await;
+//! # }
+//! ```
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt;
+use core::future::Future;
+
+use crate::fallback::Fallback;
+use crate::{AccessError, LocalKey, TaskLocalFuture};
+
+crate::task_local! {
+    /// The context entered by the innermost `Context::scope`.
+    static CURRENT: Context;
+}
+
+/// A set of task-local values that are entered together, see the
+/// [module documentation](self).
+///
+/// Cloning a context is cheap: it only increments a reference count.
+#[derive(Clone, Default)]
+pub struct Context {
+    head: Option<Arc<Node>>,
+}
+
+/// The value of one key, and the context it overrides.
+struct Node {
+    key: usize,
+    name: &'static str,
+    value: Box<dyn Any + Send + Sync>,
+    parent: Option<Arc<Node>>,
+}
+
+impl Context {
+    /// Creates an empty context.
+    pub const fn new() -> Self {
+        Self { head: None }
+    }
+
+    /// Returns the context entered by the innermost [`scope`](Self::scope) or
+    /// [`sync_scope`](Self::sync_scope), or an empty one outside of any.
+    ///
+    /// Values set by scopes of the keys themselves are not part of it.
+    pub fn current() -> Self {
+        CURRENT.try_with(Clone::clone).unwrap_or_default()
+    }
+
+    /// Returns a child of this context where `key` has the value `value`, and
+    /// every other key the value it has in this context.
+    ///
+    /// Nothing is copied from this context, which is shared with the child.
+    pub fn with_value<T>(&self, key: &'static LocalKey<T>, value: T) -> Self
+    where
+        T: Send + Sync + 'static,
+    {
+        let node = Node {
+            key: key_id(key),
+            name: key.name,
+            value: Box::new(value),
+            parent: self.head.clone(),
+        };
+        Self {
+            head: Some(Arc::new(node)),
+        }
+    }
+
+    /// Returns a child of this context where `key` has its current value,
+    /// if it has one, and this context otherwise.
+    ///
+    /// This captures the value of `key`, typically set by a scope of its own,
+    /// so that it can be shared through the context. See
+    /// [`make_mut`](Self::make_mut) for an example.
+    pub fn with_current<T>(&self, key: &'static LocalKey<T>) -> Self
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        match key.try_with(Clone::clone) {
+            Ok(value) => self.with_value(key, value),
+            Err(_) => self.clone(),
+        }
+    }
+
+    /// Returns the value of `key` in this context, if it has one.
+    pub fn get<T: 'static>(&self, key: &'static LocalKey<T>) -> Option<&T> {
+        let key = key_id(key);
+        self.nodes()
+            .find(|node| node.key == key)
+            .and_then(|node| node.value.downcast_ref())
+    }
+
+    /// Returns a mutable reference to the value of `key` in this context, if
+    /// it has one.
+    ///
+    /// Like [`Arc::make_mut`], the value is copied on write: if it is shared
+    /// with other contexts, such as the context this one was cloned from or
+    /// derived from, it is cloned into an override of this context first, and
+    /// the other contexts keep seeing the previous value. Further calls modify
+    /// the override in place.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// use task_local::context::Context;
+    ///
+    /// task_local::task_local! {
+    ///     static TAGS: Vec<&'static str>;
+    /// }
+    ///
+    /// let base = TAGS.sync_scope(vec!["api"], || Context::new().with_current(&TAGS));
+    ///
+    /// // Every task shares the captured values instead of cloning them.
+    /// let tasks: Vec<_> = (0..1000)
+    ///     .map(|_| tokio::spawn(base.clone().scope(async { TAGS.with(Vec::len) })))
+    ///     .collect();
+    /// for task in tasks {
+    ///     assert_eq!(task.await.unwrap(), 1);
+    /// }
+    ///
+    /// // Writing copies the value for this context only.
+    /// let mut admin = base.clone();
+    /// admin.make_mut(&TAGS).unwrap().push("admin");
+    /// assert_eq!(admin.get(&TAGS).unwrap(), &["api", "admin"]);
+    /// assert_eq!(base.get(&TAGS).unwrap(), &["api"]);
+    /// # }
+    /// ```
+    pub fn make_mut<T>(&mut self, key: &'static LocalKey<T>) -> Option<&mut T>
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        let id = key_id(key);
+        let owned = self
+            .head
+            .as_mut()
+            .is_some_and(|head| head.key == id && Arc::get_mut(head).is_some());
+        if !owned {
+            let value = self.get(key)?.clone();
+            *self = self.with_value(key, value);
+        }
+        let head = self.head.as_mut().and_then(Arc::get_mut)?;
+        head.value.downcast_mut()
+    }
+
+    /// Returns `true` if this context holds no value.
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    /// Enters this context for the future `f`.
+    ///
+    /// Keys that are not set by a scope of their own inside `f` read their
+    /// value from this context.
+    pub fn scope<F>(self, f: F) -> ContextFuture<F>
+    where
+        F: Future,
+    {
+        CURRENT.scope(self, f)
+    }
+
+    /// Enters this context for the closure `f`.
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn sync_scope<F, R>(self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        CURRENT.sync_scope(self, f)
+    }
+
+    fn nodes(&self) -> impl Iterator<Item = &Node> {
+        let mut next = self.head.as_deref();
+        core::iter::from_fn(move || {
+            let node = next?;
+            next = node.parent.as_deref();
+            Some(node)
+        })
+    }
+}
+
+impl fmt::Debug for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Only the innermost value of every key is visible.
+        let mut seen = Vec::new();
+        let mut list = f.debug_list();
+        for node in self.nodes() {
+            if !seen.contains(&node.key) {
+                seen.push(node.key);
+                list.entry(&node.name);
+            }
+        }
+        list.finish()
+    }
+}
+
+/// A future that enters a [`Context`] during its execution.
+///
+/// Created by the function [`Context::scope`].
+pub type ContextFuture<F> = TaskLocalFuture<Context, F>;
+
+fn key_id<T: 'static>(key: &'static LocalKey<T>) -> usize {
+    key as *const LocalKey<T> as usize
+}
+
+impl<F> Fallback<F> {
+    /// Runs the closure on the value of `key` in the current context if
+    /// reading it from a scope returned `res`, which did not call it.
+    pub(crate) fn or_context<T, R>(
+        &mut self,
+        key: &'static LocalKey<T>,
+        res: Result<R, AccessError>,
+    ) -> Result<R, AccessError>
+    where
+        T: 'static,
+        F: FnOnce(&T) -> R,
+    {
+        // The current context is not looked up in itself. Going through the
+        // non-generic `current` also keeps `try_with` from instantiating
+        // itself for ever more closure types.
+        if !matches!(res, Err(AccessError::NotSet)) || key_id(key) == key_id(&CURRENT) {
+            return res;
+        }
+        self.or_value(res, Context::current().get(key))
+    }
+}
@@ -0,0 +1,137 @@
+//! Formatting adapters rendering the current value of a key.
+
+use core::fmt;
+
+use crate::LocalKey;
+
+/// Written in place of the value when the key is not set.
+const UNSET: &str = "<unset>";
+
+impl<T: 'static> LocalKey<T> {
+    /// Returns an adapter that formats the current task-local value with
+    /// `Display`, or `<unset>` if the key has no value.
+    ///
+    /// The value is read every time the adapter is formatted, not when it is
+    /// created, so the adapter can be embedded directly in `format!` or
+    /// logging macros without a [`with`](Self::with) closure at every call
+    /// site. With the `defmt` feature, it also implements `defmt::Format` for
+    /// values that do.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// task_local::task_local! {
+    ///     static REQUEST_ID: u64;
+    /// }
+    ///
+    /// let line = REQUEST_ID.sync_scope(7u64, || format!("[{}] started", REQUEST_ID.display()));
+    /// assert_eq!(line, "[7] started");
+    /// assert_eq!(format!("[{}]", REQUEST_ID.display()), "[<unset>]");
+    /// ```
+    pub fn display(&'static self) -> DisplayValue<T>
+    where
+        T: fmt::Display,
+    {
+        DisplayValue { local: self }
+    }
+
+    /// Returns an adapter that formats the current task-local value with
+    /// `Debug`, or `<unset>` if the key has no value.
+    ///
+    /// Like [`display`](Self::display), the value is read when the adapter is
+    /// formatted. Values whose type does not implement `Debug` are formatted
+    /// as `<opaque>`, as in the `Debug` output of a [`Handle`](crate::Handle).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// task_local::task_local! {
+    ///     static TENANT: &'static str;
+    /// }
+    ///
+    /// let line = TENANT.sync_scope("acme", || format!("tenant={:?}", TENANT.debug()));
+    /// assert_eq!(line, "tenant=\"acme\"");
+    /// ```
+    pub fn debug(&'static self) -> DebugValue<T> {
+        DebugValue { local: self }
+    }
+}
+
+/// Formats the current value of a key with `Display`.
+///
+/// Created by the method [`LocalKey::display`].
+pub struct DisplayValue<T: 'static> {
+    local: &'static LocalKey<T>,
+}
+
+/// Formats the current value of a key with `Debug`.
+///
+/// Created by the method [`LocalKey::debug`].
+pub struct DebugValue<T: 'static> {
+    local: &'static LocalKey<T>,
+}
+
+impl<T: 'static> Clone for DisplayValue<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: 'static> Copy for DisplayValue<T> {}
+
+impl<T: 'static> Clone for DebugValue<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: 'static> Copy for DebugValue<T> {}
+
+impl<T: fmt::Display + 'static> fmt::Display for DisplayValue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.local
+            .try_with(|value| fmt::Display::fmt(value, f))
+            .unwrap_or_else(|_| f.pad(UNSET))
+    }
+}
+
+impl<T: 'static> fmt::Debug for DisplayValue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DisplayValue")
+            .field("key", &self.local.name)
+            .finish()
+    }
+}
+
+impl<T: 'static> fmt::Debug for DebugValue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fmt_value = self.local.fmt_value;
+        self.local
+            .try_with(|value| fmt_value(value, f))
+            .unwrap_or_else(|_| f.pad(UNSET))
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<T: defmt::Format + 'static> defmt::Format for DisplayValue<T> {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        format_value(self.local, f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<T: defmt::Format + 'static> defmt::Format for DebugValue<T> {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        format_value(self.local, f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+fn format_value<T: defmt::Format + 'static>(local: &'static LocalKey<T>, f: defmt::Formatter<'_>) {
+    if local
+        .try_with(|value| defmt::write!(f, "{}", value))
+        .is_err()
+    {
+        defmt::write!(f, "{=str}", UNSET)
+    }
+}
@@ -0,0 +1,313 @@
+//! Double-buffered values for interrupt handlers.
+//!
+//! Values that tasks update and interrupt handlers read, such as calibration
+//! coefficients, need a read path that never waits for the task. A
+//! [`DoubleBuffered`] key keeps two buffers per core: the front buffer holds
+//! the published value, and every update writes the back buffer and then
+//! publishes it with a single atomic store of a sequence number, whose parity
+//! tells writers and readers apart and whose count selects the front buffer.
+//! Readers load the sequence number, copy the front buffer and load the
+//! sequence number again. The buffer they copied is only written again by the
+//! second update after the first load, so the copy is whole unless the second
+//! load shows that this update started, in which case they copy again.
+//!
+//! Unlike a [`LocalKey`](crate::LocalKey), the key has a single published
+//! value per core rather than one per task. Entering a scope publishes the
+//! value of the scope and leaving it publishes the previous value again, so,
+//! as with the plain no_std backend, the value seen is the one of the task
+//! that is running, or that an interrupt handler preempted.
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::future::Future;
+use core::mem::MaybeUninit;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use pin_project_lite::pin_project;
+
+use crate::atomic::{fence, AtomicUsize, Ordering};
+use crate::exclusive;
+use crate::per_core::{self, MAX_CORES};
+
+/// The two buffers of a key on one core.
+struct Buffers<T> {
+    values: [UnsafeCell<Option<T>>; 2],
+    /// Twice the number of updates, plus one while an update writes the back
+    /// buffer. The front buffer is `values[(seq / 2) % 2]`.
+    seq: AtomicUsize,
+}
+
+impl<T: Copy> Buffers<T> {
+    const fn new() -> Self {
+        Self {
+            values: [UnsafeCell::new(None), UnsafeCell::new(None)],
+            seq: AtomicUsize::new(0),
+        }
+    }
+
+    fn load(&self) -> Option<T> {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            let value = self.copy(seq);
+            if self.unchanged(seq) {
+                // Safety: No update wrote the buffer during the copy.
+                return unsafe { value.assume_init() };
+            }
+        }
+    }
+
+    /// Copies the front buffer as of `seq`, which may be torn.
+    fn copy(&self, seq: usize) -> MaybeUninit<Option<T>> {
+        let front = &self.values[(seq / 2) % 2];
+        // Safety: The copy is not turned into a `T` before it is known to be
+        // whole. A volatile read keeps the compiler from assuming that the
+        // buffer does not change while it is copied.
+        let value = unsafe { (front.get() as *const MaybeUninit<Option<T>>).read_volatile() };
+        fence(Ordering::Acquire);
+        value
+    }
+
+    /// Returns whether the front buffer as of `seq` was not written since.
+    fn unchanged(&self, seq: usize) -> bool {
+        // The buffer is written again by the second update after `seq`, from
+        // `seq` rounded down to even plus 3 on.
+        self.seq.load(Ordering::Relaxed).wrapping_sub(seq & !1) < 3
+    }
+
+    /// Publishes `value`, returning the value that was published before.
+    ///
+    /// Must be called with exclusive access to the writer side, see
+    /// `exclusive`.
+    fn publish(&self, value: Option<T>) -> Option<T> {
+        let seq = self.seq.load(Ordering::Relaxed);
+        let front = (seq / 2) % 2;
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        // Safety: Writers are serialized by `exclusive`, and readers detect
+        // that the back buffer was written while they copied it, see `load`.
+        let prev = unsafe {
+            *self.values[1 - front].get() = value;
+            *self.values[front].get()
+        };
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+        prev
+    }
+}
+
+/// A key for a `Copy` value that tasks publish and interrupt handlers read
+/// without locking.
+///
+/// [`load`](Self::load) never blocks, so it can be called from interrupt
+/// handlers of any priority. Updates, through [`scope`](Self::scope),
+/// [`sync_scope`](Self::sync_scope) and [`set`](Self::set), write the back
+/// buffer inside a critical section with the `critical-section`, `embassy` or
+/// `rtic` feature, and then publish it with a single atomic store.
+///
+/// A reader retries when the buffer it copies was overwritten in the
+/// meantime, which takes two updates during the copy: a writer preempting the
+/// reader, such as a task on an interrupt executor of higher priority than
+/// the reading handler, or writers on another core sharing the buffers.
+///
+/// With the `per-core` feature, every core has its own buffers, so that
+/// readers and writers on different cores never share them.
+///
+/// Only available in no_std builds without the `forbid-unsafe` feature.
+///
+/// # Examples
+///
+/// ```ignore
+/// use task_local::DoubleBuffered;
+///
+/// #[derive(Clone, Copy)]
+/// struct Calibration {
+///     offset: i16,
+///     gain: f32,
+/// }
+///
+/// static CALIBRATION: DoubleBuffered<Calibration> = DoubleBuffered::new();
+///
+/// #[interrupt]
+/// fn ADC() {
+///     if let Some(cal) = CALIBRATION.load() {
+///         let sample = (read_adc() + cal.offset) as f32 * cal.gain;
+///         // ...
+///     }
+/// }
+///
+/// #[embassy_executor::task]
+/// async fn measure() {
+///     let cal = Calibration { offset: -3, gain: 1.02 };
+///     CALIBRATION.scope(cal, run_measurements()).await;
+/// }
+/// ```
+pub struct DoubleBuffered<T: 'static> {
+    cores: [Buffers<T>; MAX_CORES],
+}
+
+// Safety: Writers are serialized by `exclusive` and readers only keep copies
+// that no writer touched, see `Buffers`. Values are written
+// by one context and copied out by another, so they must be `Send`.
+unsafe impl<T: Copy + Send + 'static> Sync for DoubleBuffered<T> {}
+
+impl<T: Copy + 'static> DoubleBuffered<T> {
+    /// Creates a key without a published value.
+    pub const fn new() -> Self {
+        Self {
+            cores: [const { Buffers::new() }; MAX_CORES],
+        }
+    }
+
+    /// Returns the buffers of the core the caller is running on, `None` if
+    /// the core is out of range.
+    fn buffers(&self) -> Option<&Buffers<T>> {
+        per_core::current().map(|core| &self.cores[core])
+    }
+
+    fn publish(&self, value: Option<T>) -> Option<T> {
+        exclusive(|| self.buffers()?.publish(value))
+    }
+
+    /// Returns a copy of the published value, or `None` if there is none.
+    ///
+    /// This never blocks, so it is safe to call from interrupt handlers. It
+    /// only copies the value again if an update overwrote it meanwhile.
+    pub fn load(&self) -> Option<T> {
+        self.buffers()?.load()
+    }
+
+    /// Publishes `value` until the current scope ends, or for good outside
+    /// of any scope, returning the value it replaces.
+    pub fn set(&self, value: T) -> Option<T> {
+        self.publish(Some(value))
+    }
+
+    /// Publishes `value` while the future `f` is polled.
+    ///
+    /// The previous value is published again after every poll, so other
+    /// tasks see their own value. A value published with [`set`](Self::set)
+    /// inside the scope is kept for the rest of the scope.
+    pub fn scope<F>(&'static self, value: impl Into<T>, f: F) -> DoubleBufferedFuture<T, F>
+    where
+        F: Future,
+    {
+        DoubleBufferedFuture {
+            key: self,
+            value: Some(value.into()),
+            future: f,
+        }
+    }
+
+    /// Publishes `value` while the closure `f` runs.
+    ///
+    /// The previous value is published again when `f` returns or panics.
+    pub fn sync_scope<F, R>(&'static self, value: impl Into<T>, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let mut value = Some(value.into());
+        let _guard = Published::enter(self, &mut value);
+        f()
+    }
+}
+
+impl<T: Copy + 'static> Default for DoubleBuffered<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: 'static> fmt::Debug for DoubleBuffered<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DoubleBuffered").finish_non_exhaustive()
+    }
+}
+
+/// Publishes the value of a scope until dropped, and then the previous value
+/// again, handing the value of the scope back to `slot`.
+struct Published<'a, T: Copy + 'static> {
+    key: &'static DoubleBuffered<T>,
+    slot: &'a mut Option<T>,
+    prev: Option<T>,
+}
+
+impl<'a, T: Copy + 'static> Published<'a, T> {
+    fn enter(key: &'static DoubleBuffered<T>, slot: &'a mut Option<T>) -> Self {
+        let prev = key.publish(*slot);
+        Self { key, slot, prev }
+    }
+}
+
+impl<T: Copy + 'static> Drop for Published<'_, T> {
+    fn drop(&mut self) {
+        *self.slot = self.key.publish(self.prev);
+    }
+}
+
+pin_project! {
+    /// A future that publishes the value of a [`DoubleBuffered`] key while it
+    /// is polled.
+    ///
+    /// Created by the function [`DoubleBuffered::scope`].
+    pub struct DoubleBufferedFuture<T: 'static, F> {
+        key: &'static DoubleBuffered<T>,
+        value: Option<T>,
+        #[pin]
+        future: F,
+    }
+}
+
+impl<T: Copy + 'static, F: Future> Future for DoubleBufferedFuture<T, F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = self.project();
+        let _guard = Published::enter(this.key, this.value);
+        this.future.poll(cx)
+    }
+}
+
+impl<T: 'static, F> fmt::Debug for DoubleBufferedFuture<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DoubleBufferedFuture")
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A reader preempted by writers keeps its copy after one update, which
+    // writes the other buffer, and copies again after two.
+    #[test]
+    fn preempted_reader_copies_again() {
+        let buffers = Buffers::new();
+        buffers.publish(Some(1u32));
+
+        let seq = buffers.seq.load(Ordering::Acquire);
+        buffers.publish(Some(2));
+        assert!(buffers.unchanged(seq));
+        // Safety: Unchanged since `seq`.
+        assert_eq!(unsafe { buffers.copy(seq).assume_init() }, Some(1));
+
+        buffers.publish(Some(3));
+        assert!(!buffers.unchanged(seq));
+        assert_eq!(buffers.load(), Some(3));
+    }
+
+    // The same while a writer is between its two stores.
+    #[test]
+    fn reader_during_update() {
+        let buffers = Buffers::new();
+        buffers.publish(Some(1u32));
+        buffers.seq.fetch_add(1, Ordering::Relaxed);
+
+        let seq = buffers.seq.load(Ordering::Acquire);
+        assert_eq!(buffers.load(), Some(1));
+        buffers.seq.fetch_add(1, Ordering::Relaxed);
+        assert!(buffers.unchanged(seq));
+        buffers.publish(Some(2));
+        assert!(!buffers.unchanged(seq));
+    }
+}
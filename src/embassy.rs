@@ -0,0 +1,93 @@
+//! Per-task storage for the no_std backend on top of the Embassy executor.
+//!
+//! The plain no_std backend keeps a single slot per key and swaps the value of
+//! the task being polled into it. That is fine as long as only one task is
+//! inside a poll at any time, but breaks down with Embassy's interrupt
+//! executors: a higher-priority task can preempt a lower-priority one in the
+//! middle of a poll and would then see (or trip over) the other task's value.
+//!
+//! With the `embassy` feature, every key instead keeps a small table of slots
+//! (see [`slots`](crate::slots)), one per task that is currently inside a
+//! scope of the key. The task is identified by the raw Embassy task pointer
+//! carried in the `Waker` that is passed to
+//! [`TaskLocalFuture::poll`](crate::TaskLocalFuture).
+//!
+//! Futures polled with a waker that was not created by the Embassy executor,
+//! such as `Waker::noop()` in [`block_on_scoped`](crate::embedded::block_on_scoped) or
+//! the waker of a combinator that wakes its futures one by one, still work:
+//! the data pointer of the waker identifies their task instead. Such a future
+//! does not see the scopes of the Embassy task around it, and a waker without
+//! a data pointer stands for the pseudo-task described below.
+//!
+//! # Multi-priority executors
+//!
+//! Keys can be used from tasks on any number of interrupt executors at the
+//! same time:
+//!
+//! - A preempting task never touches the slot of the task it preempted, so
+//!   preemption in the middle of `with` cannot cause a borrow panic, and the
+//!   preempted task finds its own value unchanged when it resumes.
+//! - The current task is saved and restored around every poll. Interrupts
+//!   nest strictly, so the restore order always matches the save order.
+//! - Claiming and releasing slots is done with atomic operations, and all
+//!   other shared state is only accessed inside a critical section. The
+//!   `embassy` feature therefore depends on the `critical-section` crate, for
+//!   which Embassy applications always provide an implementation.
+//!
+//! Code that runs outside of any `TaskLocalFuture` poll, such as a plain
+//! interrupt handler or `main` before the executor starts, shares a single
+//! pseudo-task per core.
+
+use core::task::Waker;
+
+use crate::atomic::{AtomicUsize, Ordering};
+use crate::per_core::{self, MAX_CORES};
+
+/// Identity used for code that runs outside of any `TaskLocalFuture` poll,
+/// such as `sync_scope` before the executor is started. Task pointers are
+/// always aligned, so this never collides with a real task.
+const NO_TASK: usize = 1;
+
+/// The task whose `TaskLocalFuture` is currently being polled, per core.
+static CURRENT_TASK: [AtomicUsize; MAX_CORES] = [const { AtomicUsize::new(NO_TASK) }; MAX_CORES];
+
+/// Returns the task being polled on the current core, or `None` if the core
+/// is out of range, see `per_core::current`.
+pub(crate) fn current_task() -> Option<usize> {
+    per_core::current().map(|core| CURRENT_TASK[core].load(Ordering::Acquire))
+}
+
+/// Restores the previously polled task when dropped.
+pub(crate) struct TaskGuard {
+    // The core and its previous task, `None` if the core is out of range.
+    prev: Option<(usize, usize)>,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if let Some((core, prev)) = self.prev {
+            CURRENT_TASK[core].store(prev, Ordering::Release);
+        }
+    }
+}
+
+/// Records the task owning `waker` as the current task until the returned
+/// guard is dropped.
+///
+/// Preempting executors always finish their poll before returning to the
+/// preempted one, so saving and restoring the previous task is enough to keep
+/// the current task correct across priority levels.
+///
+/// The data pointer of an Embassy waker is the raw task pointer, which is
+/// used as is. Other wakers are not rejected, see the module documentation.
+pub(crate) fn enter_task(waker: &Waker) -> TaskGuard {
+    let task = match waker.data() as usize {
+        0 => NO_TASK,
+        task => task,
+    };
+
+    TaskGuard {
+        prev: per_core::current()
+            .map(|core| (core, CURRENT_TASK[core].swap(task, Ordering::AcqRel))),
+    }
+}
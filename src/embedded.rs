@@ -0,0 +1,119 @@
+//! A minimal executor for bare-metal tests and boot code.
+//!
+//! Before the real executor of an embedded application starts, and in tests
+//! running on the target, futures are usually driven by a hand-written loop
+//! polling them with a no-op waker. [`block_on_scoped`] is that loop, entering
+//! the scopes of a list of keys around the future, so that code reading
+//! task-locals can run unchanged.
+//!
+//! With the `embassy` feature, the future runs as the pseudo-task of code
+//! outside of any Embassy task, since the no-op waker carries no task
+//! pointer, see the `embassy` module.
+
+use core::future::Future;
+use core::hint;
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+
+use crate::{LocalKey, TaskLocalFuture};
+
+/// A list of keys with the values to set for them.
+///
+/// This is implemented for a `(&KEY, value)` pair binding one key, for `()`
+/// binding none, and for tuples of up to four bindings, whose scopes are
+/// entered from the first binding to the last.
+pub trait Bindings {
+    /// The future returned by [`scope`](Self::scope).
+    type Scope<F: Future>: Future<Output = F::Output>;
+
+    /// Sets the values of the bindings as the task-local values for the
+    /// future `f`.
+    fn scope<F>(self, f: F) -> Self::Scope<F>
+    where
+        F: Future;
+}
+
+impl<T: 'static> Bindings for (&'static LocalKey<T>, T) {
+    type Scope<F: Future> = TaskLocalFuture<T, F>;
+
+    fn scope<F>(self, f: F) -> Self::Scope<F>
+    where
+        F: Future,
+    {
+        self.0.scope(self.1, f)
+    }
+}
+
+impl Bindings for () {
+    type Scope<F: Future> = F;
+
+    fn scope<F>(self, f: F) -> F
+    where
+        F: Future,
+    {
+        f
+    }
+}
+
+/// Implements `Bindings` for a tuple by entering the scope of its head around
+/// the scopes of its tail.
+macro_rules! impl_bindings_for_tuple {
+    ($H:ident $h:ident $(, $T:ident $t:ident)*) => {
+        impl<$H: Bindings, $($T: Bindings),*> Bindings for ($H, $($T,)*) {
+            type Scope<F: Future> = $H::Scope<<($($T,)*) as Bindings>::Scope<F>>;
+
+            fn scope<F>(self, f: F) -> Self::Scope<F>
+            where
+                F: Future,
+            {
+                let ($h, $($t,)*) = self;
+                $h.scope(($($t,)*).scope(f))
+            }
+        }
+    };
+}
+
+impl_bindings_for_tuple!(A a);
+impl_bindings_for_tuple!(A a, B b);
+impl_bindings_for_tuple!(A a, B b, C c);
+impl_bindings_for_tuple!(A a, B b, C c, D d);
+
+/// Runs `future` to completion on the current core inside the scopes of
+/// `bindings`, and returns its output.
+///
+/// The future is polled in a loop until it completes, with a waker that does
+/// nothing, so it makes progress even if whatever it waits for never wakes
+/// it, at the cost of keeping the core busy. This is meant for tests and for
+/// boot code that runs before the real executor starts, not as an executor
+/// for the application.
+///
+/// # Examples
+///
+/// ```
+/// use task_local::embedded::block_on_scoped;
+///
+/// task_local::task_local! {
+///     static DEVICE_ID: u32;
+///     static BOOT_STAGE: &'static str;
+/// }
+///
+/// let id = block_on_scoped(((&DEVICE_ID, 7), (&BOOT_STAGE, "init")), async {
+///     assert_eq!(BOOT_STAGE.get(), "init");
+///     DEVICE_ID.get()
+/// });
+/// assert_eq!(id, 7);
+/// ```
+pub fn block_on_scoped<B, F>(bindings: B, future: F) -> F::Output
+where
+    B: Bindings,
+    F: Future,
+{
+    let mut future = pin!(bindings.scope(future));
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        hint::spin_loop();
+    }
+}
@@ -0,0 +1,44 @@
+//! Default values read from the environment.
+//!
+//! A key declared with `#[task_local(env = "VARIABLE")]` has a default value,
+//! parsed from the environment variable the first time the key is read
+//! outside of any scope, and read by `with`, `try_with` and the functions
+//! built on them wherever no scope of the key is entered. Scopes, with the
+//! `context` feature the current context, and a default set with
+//! `set_global_default` take precedence over it.
+
+use crate::fallback::Fallback;
+use crate::sync::const_fn;
+use crate::{AccessError, LocalKey};
+
+impl<T: 'static> LocalKey<T> {
+    const_fn! {
+        /// Gives the key a default value, see `#[task_local(env = "...")]`.
+        #[doc(hidden)]
+        pub fn __default(mut self, default: fn() -> Option<&'static T>) -> Self {
+            self.default = Some(default);
+            self
+        }
+    }
+}
+
+impl<F> Fallback<F> {
+    /// Runs the closure on the default value of `key`, if it has one, when
+    /// reading it from a scope returned `res` because it is not set.
+    pub(crate) fn or_default<T, R>(
+        &mut self,
+        key: &'static LocalKey<T>,
+        res: Result<R, AccessError>,
+    ) -> Result<R, AccessError>
+    where
+        T: 'static,
+        F: FnOnce(&T) -> R,
+    {
+        match key.default {
+            Some(default) if matches!(res, Err(AccessError::NotSet)) => {
+                self.or_value(res, default())
+            }
+            _ => res,
+        }
+    }
+}
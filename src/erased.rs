@@ -0,0 +1,92 @@
+//! Type-erased scopes.
+//!
+//! Every distinct future passed to [`LocalKey::scope`] instantiates its own
+//! `TaskLocalFuture`. Scoping a `dyn Future` instead shares one instantiation
+//! between all futures with the same value and output types, trading a
+//! virtual call per poll for less code.
+
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+
+use crate::{LocalKey, TaskLocalFuture};
+
+/// A [`TaskLocalFuture`] scoping a pinned reference to a `dyn Future`.
+///
+/// Created by the function [`LocalKey::scope_dyn`].
+pub type DynTaskLocalFuture<'a, T, R> =
+    TaskLocalFuture<T, Pin<&'a mut (dyn Future<Output = R> + 'a)>>;
+
+/// A [`TaskLocalFuture`] scoping a boxed `dyn Future`.
+///
+/// Created by the function [`LocalKey::scope_boxed`].
+#[cfg(feature = "alloc")]
+pub type BoxedTaskLocalFuture<'a, T, R> =
+    TaskLocalFuture<T, Pin<Box<dyn Future<Output = R> + Send + 'a>>>;
+
+impl<T: 'static> LocalKey<T> {
+    /// Sets a value `T` as the task-local value for the type-erased future
+    /// `f`.
+    ///
+    /// This is the same as [`scope`](Self::scope), but all futures with the
+    /// same output type share a single instantiation of [`TaskLocalFuture`],
+    /// which keeps code size down when many different futures are scoped. It
+    /// needs no allocation: the future is pinned by the caller, for example
+    /// on the stack with [`pin!`](core::pin::pin).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// use core::pin::pin;
+    ///
+    /// task_local::task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// let fut = pin!(async { NUMBER.get() });
+    /// assert_eq!(NUMBER.scope_dyn(1u32, fut).await, 1);
+    /// # }
+    /// ```
+    pub fn scope_dyn<'a, R>(
+        &'static self,
+        value: impl Into<T>,
+        f: Pin<&'a mut (dyn Future<Output = R> + 'a)>,
+    ) -> DynTaskLocalFuture<'a, T, R> {
+        self.scope(value, f)
+    }
+
+    /// Sets a value `T` as the task-local value for the future `f`, boxing
+    /// the future.
+    ///
+    /// Like [`scope_dyn`](Self::scope_dyn), all futures with the same output
+    /// type share a single instantiation of [`TaskLocalFuture`]. The future
+    /// must be `Send`, so the returned future can be spawned on multi-threaded
+    /// executors if `T` is `Send`.
+    ///
+    /// Requires the `alloc` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// task_local::task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// assert_eq!(NUMBER.scope_boxed(1u32, async { NUMBER.get() }).await, 1);
+    /// # }
+    /// ```
+    #[cfg(feature = "alloc")]
+    pub fn scope_boxed<'a, F>(
+        &'static self,
+        value: impl Into<T>,
+        f: F,
+    ) -> BoxedTaskLocalFuture<'a, T, F::Output>
+    where
+        F: Future + Send + 'a,
+    {
+        self.scope(value, Box::pin(f))
+    }
+}
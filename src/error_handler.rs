@@ -0,0 +1,152 @@
+//! A handler for failed task-local accesses, replacing the panics.
+//!
+//! Reading a key that is not set, or entering a scope while the storage is
+//! borrowed, panics. In no_std firmware the panic handler then has little to
+//! go on, and with `panic = "abort"` the message is often not even printed.
+//! With the `error-handler` feature, these failures are passed to the function
+//! registered with [`set_error_handler!`](crate::set_error_handler) instead,
+//! together with the name of the key and the location of the access, so that
+//! the firmware can log them, with `defmt` for example, and reset in an
+//! orderly way.
+
+use core::fmt;
+use core::panic::Location;
+
+use crate::{AccessError, ScopeError};
+
+extern "Rust" {
+    fn _task_local_error_handler(failure: &Failure) -> !;
+}
+
+/// What went wrong in a [`Failure`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum FailureKind {
+    /// The value could not be read, by `with`, `get` and the like.
+    Access(AccessError),
+    /// The value could not be replaced with `set`, because the key is not set
+    /// or its storage is borrowed.
+    Set,
+    /// A scope could not be entered.
+    Scope(ScopeError),
+    /// A `TaskLocalFuture` was polled after it completed.
+    PolledAfterCompletion,
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Access(err) => fmt::Display::fmt(err, f),
+            Self::Set => {
+                f.write_str("cannot set a task-local outside of a scope or while it is borrowed")
+            }
+            Self::Scope(err) => fmt::Display::fmt(err, f),
+            Self::PolledAfterCompletion => f.write_str("`TaskLocalFuture` polled after completion"),
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for FailureKind {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        match self {
+            Self::Access(err) => defmt::write!(f, "{}", err),
+            Self::Set => defmt::write!(f, "set failed"),
+            Self::Scope(err) => defmt::write!(f, "{}", err),
+            Self::PolledAfterCompletion => defmt::write!(f, "polled after completion"),
+        }
+    }
+}
+
+/// A failed access to a task-local, passed to the handler registered with
+/// [`set_error_handler!`](crate::set_error_handler).
+///
+/// Requires the `error-handler` feature.
+#[derive(Debug)]
+pub struct Failure {
+    key: &'static str,
+    kind: FailureKind,
+    location: &'static Location<'static>,
+}
+
+impl Failure {
+    /// Returns the name of the key, as declared with `task_local!`.
+    pub fn key(&self) -> &'static str {
+        self.key
+    }
+
+    /// Returns what went wrong.
+    pub fn kind(&self) -> FailureKind {
+        self.kind
+    }
+
+    /// Returns where the key was accessed.
+    pub fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (task-local `{}`, at {})",
+            self.kind, self.key, self.location
+        )
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Failure {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(
+            f,
+            "{} (task-local `{=str}`, at {=str}:{=u32})",
+            self.kind,
+            self.key,
+            self.location.file(),
+            self.location.line()
+        )
+    }
+}
+
+/// Passes a failed access to the key named `key` to the registered handler.
+#[cfg_attr(feature = "panic-free", allow(dead_code))]
+#[track_caller]
+pub(crate) fn fail(key: &'static str, kind: FailureKind) -> ! {
+    let failure = Failure {
+        key,
+        kind,
+        location: Location::caller(),
+    };
+    // Safety: The symbol is defined by `set_error_handler!` with this exact
+    // signature.
+    unsafe { _task_local_error_handler(&failure) }
+}
+
+/// Registers the function handling failed task-local accesses.
+///
+/// Required by the `error-handler` feature. The function is called with the
+/// [`Failure`] instead of panicking wherever an access to a key, or entering
+/// a scope of it, would panic, and must not return. It must be defined
+/// exactly once in the final binary.
+///
+/// # Examples
+///
+/// ```ignore
+/// fn task_local_failed(failure: &task_local::Failure) -> ! {
+///     defmt::error!("{}", failure);
+///     cortex_m::peripheral::SCB::sys_reset()
+/// }
+///
+/// task_local::set_error_handler!(task_local_failed);
+/// ```
+#[macro_export]
+macro_rules! set_error_handler {
+    ($f:path) => {
+        #[unsafe(no_mangle)]
+        fn _task_local_error_handler(failure: &$crate::Failure) -> ! {
+            $f(failure)
+        }
+    };
+}
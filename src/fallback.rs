@@ -0,0 +1,50 @@
+//! Values read by `try_with` when a key is not set by a scope.
+//!
+//! The closure passed to `try_with` is kept in a [`Fallback`] while the value
+//! of the current scope is read, so that it can be run on a fallback value if
+//! there is no scope: the value of the key in the current context, with the
+//! `context` feature, or its default value, for keys declared with
+//! `#[task_local(env = "...")]`.
+
+use crate::AccessError;
+
+/// The closure passed to `LocalKey::try_with`, kept to be run on a fallback
+/// value if the key is not set by a scope.
+pub(crate) struct Fallback<F>(Option<F>);
+
+impl<F> Fallback<F> {
+    pub(crate) fn new(f: F) -> Self {
+        Self(Some(f))
+    }
+
+    /// Runs the closure on the value of a scope.
+    #[inline(always)]
+    pub(crate) fn call<T, R>(&mut self, value: &T) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        match self.0.take() {
+            Some(f) => f(value),
+            None => unreachable!("closure of `try_with` called twice"),
+        }
+    }
+
+    /// Runs the closure on `value`, if there is one, when reading the key
+    /// from a scope returned `res` because it is not set.
+    pub(crate) fn or_value<T, R>(
+        &mut self,
+        res: Result<R, AccessError>,
+        value: Option<&T>,
+    ) -> Result<R, AccessError>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        match (res, value, self.0.take()) {
+            (Err(AccessError::NotSet), Some(value), Some(f)) => Ok(f(value)),
+            (res, _, f) => {
+                self.0 = f;
+                res
+            }
+        }
+    }
+}
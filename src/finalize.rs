@@ -0,0 +1,165 @@
+//! Scoped futures followed by an async finalizer.
+//!
+//! Flushing a per-request buffer or closing a per-scope connection needs the
+//! value of the scope once the future is done with it, and usually awaits
+//! something itself. [`LocalKey::scope_with_finalizer`] runs such a
+//! finalizer in the same task, right after the future, without a wrapper
+//! future written by hand for every key.
+
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use pin_project_lite::pin_project;
+
+use crate::{FinishTaskLocalFuture, LocalKey};
+
+impl<T: 'static> LocalKey<T> {
+    /// Sets a value `T` as the task-local value for the future `f`, then
+    /// passes the value to `finalizer` and awaits the future it returns.
+    ///
+    /// `finalizer` receives the value as it was left by `f`, for example
+    /// after calls to [`set`](Self::set), together with the output of `f`, or
+    /// `None` if `f` was cancelled with [`FinalizeFuture::cancel`]. The
+    /// returned future resolves to the output of the finalizer. The finalizer
+    /// runs after the scope is left, so the key has the value of the
+    /// enclosing scope again, if any.
+    ///
+    /// A `FinalizeFuture` dropped before completion cannot run the finalizer,
+    /// which would have to be awaited: the value is dropped as usual instead.
+    /// Call [`cancel`](FinalizeFuture::cancel) and keep polling the future to
+    /// finalize a scope that is given up on.
+    ///
+    /// # Panics
+    ///
+    /// Polling the returned future panics if it is polled inside a call to
+    /// [`with`](Self::with) or [`try_with`](Self::try_with) on the same key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// task_local::task_local! {
+    ///     static LOG_BUFFER: Vec<String>;
+    /// }
+    ///
+    /// let flushed = LOG_BUFFER
+    ///     .scope_with_finalizer(
+    ///         Vec::new(),
+    ///         async {
+    ///             LOG_BUFFER.set(vec!["request handled".to_string()]);
+    ///             200
+    ///         },
+    ///         |buffer, status| async move {
+    ///             // Write `buffer` to the log sink, asynchronously.
+    ///             (status, buffer.len())
+    ///         },
+    ///     )
+    ///     .await;
+    /// assert_eq!(flushed, (Some(200), 1));
+    /// # }
+    /// ```
+    pub fn scope_with_finalizer<F, Fin, Fut>(
+        &'static self,
+        value: impl Into<T>,
+        f: F,
+        finalizer: Fin,
+    ) -> FinalizeFuture<T, F, Fin, Fut>
+    where
+        F: Future,
+        Fin: FnOnce(T, Option<F::Output>) -> Fut,
+        Fut: Future,
+    {
+        FinalizeFuture {
+            inner: self.scope(value, f).finish(),
+            finalizer: Some(finalizer),
+            finalizing: None,
+        }
+    }
+}
+
+pin_project! {
+    /// A future that sets a value `T` of a task local for the future `F`
+    /// during its execution, and then awaits the finalizer `Fin` given the
+    /// value.
+    ///
+    /// Created by the function [`LocalKey::scope_with_finalizer`].
+    pub struct FinalizeFuture<T, F, Fin, Fut>
+    where
+        T: 'static,
+    {
+        #[pin]
+        inner: FinishTaskLocalFuture<T, F>,
+        finalizer: Option<Fin>,
+        #[pin]
+        finalizing: Option<Fut>,
+    }
+}
+
+impl<T, F, Fin, Fut> FinalizeFuture<T, F, Fin, Fut>
+where
+    T: 'static,
+    F: Future,
+    Fin: FnOnce(T, Option<F::Output>) -> Fut,
+{
+    /// Cancels the wrapped future, so that the next poll runs the finalizer
+    /// with no output.
+    ///
+    /// The wrapped future is dropped as by
+    /// [`TaskLocalFuture::cancel`](crate::TaskLocalFuture::cancel). Does
+    /// nothing if the finalizer already started.
+    pub fn cancel(self: Pin<&mut Self>) {
+        let mut this = self.project();
+        if this.finalizing.is_some() {
+            return;
+        }
+        let value = this.inner.cancel();
+        if let (Some(value), Some(finalizer)) = (value, this.finalizer.take()) {
+            this.finalizing.set(Some(finalizer(value, None)));
+        }
+    }
+}
+
+impl<T, F, Fin, Fut> Future for FinalizeFuture<T, F, Fin, Fut>
+where
+    T: 'static,
+    F: Future,
+    Fin: FnOnce(T, Option<F::Output>) -> Fut,
+    Fut: Future,
+{
+    type Output = Fut::Output;
+
+    #[track_caller]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Fut::Output> {
+        let mut this = self.project();
+        if this.finalizing.is_none() {
+            let (output, value) = match this.inner.poll(cx) {
+                Poll::Ready(done) => done,
+                Poll::Pending => return Poll::Pending,
+            };
+            // The finalizer is only taken here and by `cancel`, which both
+            // start finalizing.
+            #[cfg(not(feature = "panic-free"))]
+            let finalizer = this.finalizer.take().expect("finalizer taken");
+            #[cfg(feature = "panic-free")]
+            let Some(finalizer) = this.finalizer.take() else {
+                return Poll::Pending;
+            };
+            this.finalizing.set(Some(finalizer(value, Some(output))));
+        }
+        match this.finalizing.as_pin_mut() {
+            Some(finalizing) => finalizing.poll(cx),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T: 'static, F, Fin, Fut> fmt::Debug for FinalizeFuture<T, F, Fin, Fut> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FinalizeFuture")
+            .field("inner", &self.inner)
+            .field("finalizing", &self.finalizing.is_some())
+            .finish_non_exhaustive()
+    }
+}
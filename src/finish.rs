@@ -0,0 +1,121 @@
+//! Scoped futures that give the value back on completion.
+
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use pin_project_lite::pin_project;
+
+use crate::{DropPolicy, TaskLocalFuture};
+
+impl<T: 'static, F> TaskLocalFuture<T, F> {
+    /// Returns a future that resolves to the output of the wrapped future
+    /// together with the task local value.
+    ///
+    /// This saves a separate call to [`take_value`](Self::take_value) after
+    /// completion, and returns the value as it was left by the future, for
+    /// example after calls to [`set`](crate::LocalKey::set). The value is
+    /// always returned, whatever the [`drop_policy`](Self::drop_policy) of the
+    /// future.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// task_local::task_local! {
+    ///     static BYTES_READ: usize;
+    /// }
+    ///
+    /// let (output, read) = BYTES_READ
+    ///     .scope(0usize, async {
+    ///         BYTES_READ.set(42);
+    ///         "done"
+    ///     })
+    ///     .finish()
+    ///     .await;
+    ///
+    /// assert_eq!((output, read), ("done", 42));
+    /// # }
+    /// ```
+    pub fn finish(self) -> FinishTaskLocalFuture<T, F> {
+        FinishTaskLocalFuture {
+            inner: self.drop_policy(DropPolicy::OutsideScope),
+        }
+    }
+}
+
+pin_project! {
+    /// A future that sets a value `T` of a task local for the future `F` during
+    /// its execution, and resolves to the output of `F` together with the
+    /// value.
+    ///
+    /// Created by the function [`TaskLocalFuture::finish`].
+    pub struct FinishTaskLocalFuture<T, F>
+    where
+        T: 'static,
+    {
+        #[pin]
+        inner: TaskLocalFuture<T, F>,
+    }
+}
+
+impl<T, F> FinishTaskLocalFuture<T, F>
+where
+    T: 'static,
+{
+    /// Cancels the wrapped future, returning the task local value.
+    ///
+    /// See [`TaskLocalFuture::cancel`].
+    pub fn cancel(self: Pin<&mut Self>) -> Option<T> {
+        self.project().inner.cancel()
+    }
+
+    /// Returns a reference to the wrapped future.
+    ///
+    /// See [`TaskLocalFuture::get_ref`].
+    pub fn get_ref(&self) -> Option<&F> {
+        self.inner.get_ref()
+    }
+}
+
+impl<T: 'static, F: Future> Future for FinishTaskLocalFuture<T, F> {
+    type Output = (F::Output, T);
+
+    #[track_caller]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.project().inner;
+        let local = inner.local;
+        match inner.as_mut().poll_scope(cx) {
+            Ok(Poll::Ready(output)) => {
+                // The value can only be taken by `cancel`, after which the
+                // future is not polled again.
+                #[cfg(not(feature = "panic-free"))]
+                let value = inner.take_value().expect("task-local value taken");
+                #[cfg(feature = "panic-free")]
+                let Some(value) = inner.take_value() else {
+                    return Poll::Pending;
+                };
+                Poll::Ready((output, value))
+            }
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(err) => err.retry_or_panic(local.name, cx),
+        }
+    }
+}
+
+impl<T: 'static, F> fmt::Debug for FinishTaskLocalFuture<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.inner, f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<T: 'static, F> defmt::Format for FinishTaskLocalFuture<T, F>
+where
+    T: defmt::Format,
+{
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::Format::format(&self.inner, f)
+    }
+}
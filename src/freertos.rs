@@ -0,0 +1,169 @@
+//! FreeRTOS task storage for the std backend.
+//!
+//! On std targets running on FreeRTOS, such as ESP-IDF, `thread_local!` is
+//! only tied to FreeRTOS tasks through the pthread layer of the platform,
+//! which tasks created with `xTaskCreate` or by C components do not always go
+//! through. With the `freertos` feature, the storage of every key is instead
+//! kept by the [`FreeRtos`] storage provider in a block owned by the current
+//! FreeRTOS task, found through its thread-local storage pointer
+//! [`TLS_INDEX`], so task-locals follow the RTOS task whichever way it was
+//! created.
+//!
+//! The block of a task is created on the first access to a key in the task,
+//! and holds one slot per key used in the task since then, indexed by the
+//! number of the key. On ESP-IDF, it is freed when the task is deleted, by
+//! the deletion callback of the storage pointer. Plain FreeRTOS has no such
+//! callback, so there the block, a small table of pointers and the empty
+//! storage of each key, is not freed: tasks that use task-locals should live
+//! for the whole program, or be few, as with threads on these platforms.
+//! Scopes are always exited before a task returns, so no value is leaked
+//! with it.
+//!
+//! Keys must not be accessed from interrupt handlers, which would see the
+//! block of the task they interrupted.
+
+use core::ffi::{c_int, c_void};
+use core::ptr;
+use std::boxed::Box;
+
+use crate::provider::{Slots, StorageKey, StorageProvider};
+
+/// The index of the thread-local storage pointer of every task that holds
+/// its block of task-locals.
+///
+/// ESP-IDF keeps index 0 for its pthread layer, so the FreeRTOS configuration
+/// must provide at least two pointers per task, with
+/// `CONFIG_FREERTOS_THREAD_LOCAL_STORAGE_POINTERS=2` on ESP-IDF or
+/// `configNUM_THREAD_LOCAL_STORAGE_POINTERS` elsewhere.
+///
+/// Requires the `freertos` feature.
+pub const TLS_INDEX: c_int = 1;
+
+extern "C" {
+    fn xTaskGetCurrentTaskHandle() -> *mut c_void;
+    fn pvTaskGetThreadLocalStoragePointer(task: *mut c_void, index: c_int) -> *mut c_void;
+    #[cfg(not(any(target_os = "espidf", test)))]
+    fn vTaskSetThreadLocalStoragePointer(task: *mut c_void, index: c_int, value: *mut c_void);
+    #[cfg(any(target_os = "espidf", test))]
+    fn vTaskSetThreadLocalStoragePointerAndDelCallback(
+        task: *mut c_void,
+        index: c_int,
+        value: *mut c_void,
+        callback: unsafe extern "C" fn(c_int, *mut c_void),
+    );
+}
+
+/// The slots of every key used in a task.
+type Block = Slots;
+
+/// Frees the block of a deleted task.
+#[cfg(any(target_os = "espidf", test))]
+unsafe extern "C" fn delete_block(_: c_int, block: *mut c_void) {
+    // Safety: The block was created by `FreeRtos`, and its task is deleted.
+    drop(unsafe { Box::from_raw(block.cast::<Block>()) });
+}
+
+/// A [`StorageProvider`] keeping the slots of every FreeRTOS task in its
+/// thread-local storage pointer [`TLS_INDEX`].
+///
+/// It is the default provider with the `freertos` feature.
+///
+/// Requires the `freertos` feature.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FreeRtos;
+
+// Safety: Every task has its own block, which is only freed once the task is
+// deleted, and is only accessed by the task itself, outside of interrupt
+// handlers.
+unsafe impl StorageProvider for FreeRtos {
+    fn slot_for_current_task(&self, key: StorageKey) -> *mut *mut () {
+        // Safety: These functions may be called from any task, and return
+        // null before the scheduler is started. The pointer at `TLS_INDEX`
+        // belongs to this crate.
+        unsafe {
+            let task = xTaskGetCurrentTaskHandle();
+            if task.is_null() {
+                return ptr::null_mut();
+            }
+            let mut block = pvTaskGetThreadLocalStoragePointer(task, TLS_INDEX) as *mut Block;
+            if block.is_null() {
+                block = Box::into_raw(Box::new(Block::new()));
+                #[cfg(not(any(target_os = "espidf", test)))]
+                vTaskSetThreadLocalStoragePointer(task, TLS_INDEX, block.cast());
+                #[cfg(any(target_os = "espidf", test))]
+                vTaskSetThreadLocalStoragePointerAndDelCallback(
+                    task,
+                    TLS_INDEX,
+                    block.cast(),
+                    delete_block,
+                );
+            }
+            (*block).get(key)
+        }
+    }
+}
+
+// The lib tests run every thread as a task, whose storage pointers are kept
+// in a `thread_local!`, and deleted when the thread exits.
+#[cfg(test)]
+mod mock {
+    use core::cell::Cell;
+    use core::ffi::{c_int, c_void};
+    use core::ptr;
+
+    use super::TLS_INDEX;
+
+    type Callback = unsafe extern "C" fn(c_int, *mut c_void);
+
+    /// The storage pointers of a task, with their deletion callbacks.
+    struct Task([(Cell<*mut c_void>, Cell<Option<Callback>>); 2]);
+
+    impl Drop for Task {
+        fn drop(&mut self) {
+            for (index, (value, callback)) in self.0.iter().enumerate() {
+                if let Some(callback) = callback.get() {
+                    // Safety: The task is deleted.
+                    unsafe { callback(index as c_int, value.get()) };
+                }
+            }
+        }
+    }
+
+    std::thread_local! {
+        static TASK: Task = const {
+            Task([
+                (Cell::new(ptr::null_mut()), Cell::new(None)),
+                (Cell::new(ptr::null_mut()), Cell::new(None)),
+            ])
+        };
+    }
+
+    #[unsafe(no_mangle)]
+    extern "C" fn xTaskGetCurrentTaskHandle() -> *mut c_void {
+        TASK.try_with(|task| task as *const Task as *mut c_void)
+            .unwrap_or(ptr::null_mut())
+    }
+
+    #[unsafe(no_mangle)]
+    extern "C" fn pvTaskGetThreadLocalStoragePointer(
+        task: *mut c_void,
+        index: c_int,
+    ) -> *mut c_void {
+        assert_eq!((task, index), (xTaskGetCurrentTaskHandle(), TLS_INDEX));
+        TASK.with(|task| task.0[index as usize].0.get())
+    }
+
+    #[unsafe(no_mangle)]
+    extern "C" fn vTaskSetThreadLocalStoragePointerAndDelCallback(
+        task: *mut c_void,
+        index: c_int,
+        value: *mut c_void,
+        callback: Callback,
+    ) {
+        assert_eq!((task, index), (xTaskGetCurrentTaskHandle(), TLS_INDEX));
+        TASK.with(|task| {
+            task.0[index as usize].0.set(value);
+            task.0[index as usize].1.set(Some(callback));
+        });
+    }
+}
@@ -0,0 +1,179 @@
+//! Process-wide default values.
+//!
+//! [`LocalKey::set_global_default`] gives a key a value that is read
+//! wherever no scope of the key is entered, on every thread, like the global
+//! default subscriber of `tracing`. Binaries that only override a value in a
+//! few places then do not need to enter a scope at the start of every task.
+//!
+//! Keys accept values that are not `Send` or `Sync`, which cannot be shared
+//! between threads, so the default is stored type-erased in the key and only
+//! keys whose value type is both can set one.
+
+use std::any::Any;
+use std::boxed::Box;
+use std::sync::OnceLock;
+
+use crate::fallback::Fallback;
+use crate::{AccessError, LocalKey};
+
+/// Where the value read by [`LocalKey::try_with_source`] and
+/// [`LocalKey::get_or_global`] came from.
+///
+/// Requires the `std` feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValueSource {
+    /// The value is set by an enclosing scope, or with the `context` feature
+    /// by the current context.
+    Scope,
+    /// The value is the default set with [`LocalKey::set_global_default`].
+    Global,
+    /// The value is the default read from the environment with
+    /// `#[task_local(env = "...")]`.
+    Env,
+}
+
+/// The global default value of a key, if one was set.
+pub(crate) struct Global(OnceLock<Box<dyn Any + Send + Sync>>);
+
+impl Global {
+    pub(crate) const fn new() -> Self {
+        Self(OnceLock::new())
+    }
+
+    fn get<T: 'static>(&self) -> Option<&T> {
+        self.0.get().and_then(|value| value.downcast_ref())
+    }
+}
+
+impl<T: Send + Sync + 'static> LocalKey<T> {
+    /// Sets the process-wide default value of this key, read by
+    /// [`with`](Self::with), [`get`](Self::get) and the other accessors
+    /// wherever no scope of the key is entered, on every thread.
+    ///
+    /// The default can only be set once. If it is already set, `value` is
+    /// returned as the error. Scopes take precedence over the default, and so
+    /// does the current context with the `context` feature, while the default
+    /// takes precedence over a default read from the environment with
+    /// `#[task_local(env = "...")]`.
+    ///
+    /// Requires the `std` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// task_local::task_local! {
+    ///     static REGION: &'static str;
+    /// }
+    ///
+    /// REGION.set_global_default("eu-west-1").unwrap();
+    /// assert_eq!(REGION.get(), "eu-west-1");
+    ///
+    /// // A scope overrides the default where needed.
+    /// REGION.sync_scope("us-east-1", || assert_eq!(REGION.get(), "us-east-1"));
+    ///
+    /// assert_eq!(REGION.set_global_default("ap-south-1"), Err("ap-south-1"));
+    /// ```
+    pub fn set_global_default(&'static self, value: T) -> Result<(), T> {
+        // `OnceLock::set` would hand the value back boxed and type-erased.
+        let mut value = Some(value);
+        self.global.0.get_or_init(|| match value.take() {
+            Some(value) => Box::new(value),
+            None => unreachable!(),
+        });
+        match value {
+            Some(value) => Err(value),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T: 'static> LocalKey<T> {
+    /// Accesses the current task-local and runs the provided closure, also
+    /// returning where the value came from.
+    ///
+    /// This reads the same value as [`try_with`](Self::try_with), which lets
+    /// diagnostics or layers that apply their own defaults tell a value set
+    /// for the current task from a default.
+    ///
+    /// Requires the `std` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use task_local::ValueSource;
+    ///
+    /// task_local::task_local! {
+    ///     static REGION: &'static str;
+    /// }
+    ///
+    /// REGION.set_global_default("eu-west-1").unwrap();
+    /// assert_eq!(REGION.try_with_source(|region| region.len()), Ok((9, ValueSource::Global)));
+    ///
+    /// REGION.sync_scope("us-east-1", || {
+    ///     assert_eq!(REGION.try_with_source(|region| region.len()), Ok((9, ValueSource::Scope)));
+    /// });
+    /// ```
+    pub fn try_with_source<F, R>(&'static self, f: F) -> Result<(R, ValueSource), AccessError>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let mut fallback = Fallback::new(f);
+        let f = |value: &T| fallback.call(value);
+
+        let try_with_res = self.inner.try_with(|cell| self.access(cell, f));
+        let res = try_with_res.unwrap_or(Err(AccessError::NotSet));
+
+        #[cfg(feature = "context")]
+        let res = fallback.or_context(self, res);
+        let res = match res {
+            Err(AccessError::NotSet) => match fallback.or_global(self, res) {
+                Err(AccessError::NotSet) => fallback
+                    .or_default(self, Err(AccessError::NotSet))
+                    .map(|res| (res, ValueSource::Env)),
+                res => res.map(|res| (res, ValueSource::Global)),
+            },
+            res => res.map(|res| (res, ValueSource::Scope)),
+        };
+        self.record_access(res)
+    }
+}
+
+impl<T: Clone + 'static> LocalKey<T> {
+    /// Returns a copy of the task-local value and whether it came from a
+    /// scope or from a default.
+    ///
+    /// This is the same as [`get`](Self::get), see
+    /// [`try_with_source`](Self::try_with_source).
+    ///
+    /// Requires the `std` feature.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the task local doesn't have a value set.
+    #[track_caller]
+    pub fn get_or_global(&'static self) -> (T, ValueSource) {
+        match self.try_with_source(T::clone) {
+            Ok(res) => res,
+            Err(err) => self.access_panic(err),
+        }
+    }
+}
+
+impl<F> Fallback<F> {
+    /// Runs the closure on the global default value of `key`, if it has one,
+    /// when reading it from a scope returned `res` because it is not set.
+    pub(crate) fn or_global<T, R>(
+        &mut self,
+        key: &'static LocalKey<T>,
+        res: Result<R, AccessError>,
+    ) -> Result<R, AccessError>
+    where
+        T: 'static,
+        F: FnOnce(&T) -> R,
+    {
+        if !matches!(res, Err(AccessError::NotSet)) {
+            return res;
+        }
+        self.or_value(res, key.global.get())
+    }
+}
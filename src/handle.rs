@@ -0,0 +1,189 @@
+//! Owned snapshots of task-local values.
+
+use core::fmt;
+use core::future::Future;
+use core::ops::Deref;
+
+use crate::{LocalKey, TaskLocalFuture};
+
+impl<T: Clone + 'static> LocalKey<T> {
+    /// Returns an owned handle to a copy of the task-local value.
+    ///
+    /// Unlike the reference passed to [`with`](Self::with), the handle is
+    /// detached from the current scope: it can be moved into another task or
+    /// a callback that outlives the scope, and can re-enter a scope of the
+    /// same key with the copied value through [`Handle::scope`] and
+    /// [`Handle::sync_scope`].
+    ///
+    /// The handle is a snapshot. Values set with [`set`](Self::set) after the
+    /// handle was created, in the scope it was taken from or in a scope
+    /// entered through the handle, are not seen by the other side, and the two
+    /// copies can diverge. Wrap the value in an `Arc` with interior mutability
+    /// to share state instead of copying it.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the task local doesn't have a value set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// task_local::task_local! {
+    ///     static REQUEST_ID: u32;
+    /// }
+    ///
+    /// let handle = REQUEST_ID.sync_scope(7u32, || REQUEST_ID.get_handle());
+    ///
+    /// // The scope has ended, but the handle can carry the value elsewhere.
+    /// let id = handle.scope(async { REQUEST_ID.get() }).await;
+    /// assert_eq!(id, 7);
+    /// # }
+    /// ```
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn get_handle(&'static self) -> Handle<T> {
+        Handle {
+            local: self,
+            value: self.get(),
+        }
+    }
+
+    /// Sets a value `T` as the task-local value for the future returned by
+    /// `f`, passing `f` a [`Handle`] to a copy of the value.
+    ///
+    /// The future reads the value through the handle, which derefs to it,
+    /// without looking up the key or checking that it is set on every
+    /// access. The value is cloned once, when the scope is created, so it
+    /// should be cheap to clone, such as a `Copy` type or an `Arc`.
+    ///
+    /// As with [`get_handle`](Self::get_handle), the handle is a snapshot:
+    /// values set with [`set`](Self::set) inside the scope are only seen
+    /// through the key.
+    ///
+    /// # Panics
+    ///
+    /// Polling the returned future panics if it is polled inside a call to
+    /// [`with`](Self::with) or [`try_with`](Self::try_with) on the same key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// task_local::task_local! {
+    ///     static REQUEST_ID: u64;
+    /// }
+    ///
+    /// let lines = REQUEST_ID
+    ///     .scope_with(7u64, |id| async move {
+    ///         (0..3).map(|i| format!("[{}] step {i}", id.get())).collect::<Vec<_>>()
+    ///     })
+    ///     .await;
+    /// assert_eq!(lines[2], "[7] step 2");
+    /// # }
+    /// ```
+    #[track_caller]
+    pub fn scope_with<F, Fut>(&'static self, value: impl Into<T>, f: F) -> TaskLocalFuture<T, Fut>
+    where
+        F: FnOnce(Handle<T>) -> Fut,
+        Fut: Future,
+    {
+        let value = value.into();
+        let handle = Handle {
+            local: self,
+            value: value.clone(),
+        };
+        self.scope(value, f(handle))
+    }
+}
+
+/// An owned copy of a task-local value, detached from the scope it was
+/// taken from.
+///
+/// Created by the function [`LocalKey::get_handle`], or passed to the
+/// closure of [`LocalKey::scope_with`].
+#[derive(Clone)]
+pub struct Handle<T: 'static> {
+    local: &'static LocalKey<T>,
+    value: T,
+}
+
+impl<T: 'static> Handle<T> {
+    /// Returns the key the value was taken from.
+    pub fn key(&self) -> &'static LocalKey<T> {
+        self.local
+    }
+
+    /// Returns a reference to the value.
+    ///
+    /// This is the same as dereferencing the handle.
+    pub fn get(&self) -> &T {
+        &self.value
+    }
+
+    /// Consumes the handle, returning the value.
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+
+    /// Sets the value of the handle as the task-local value for the future
+    /// `f`, like [`LocalKey::scope`].
+    pub fn scope<F>(self, f: F) -> TaskLocalFuture<T, F>
+    where
+        F: Future,
+    {
+        self.local.scope(self.value, f)
+    }
+
+    /// Sets the value of the handle as the task-local value for the closure
+    /// `f`, like [`LocalKey::sync_scope`].
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if called inside a call to
+    /// [`with`](LocalKey::with) or [`try_with`](LocalKey::try_with) on the
+    /// same key.
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn sync_scope<F, R>(self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        self.local.sync_scope(self.value, f)
+    }
+}
+
+impl<T: 'static> Deref for Handle<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: 'static> fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct Value<'a, T: 'static>(&'a Handle<T>);
+
+        impl<T: 'static> fmt::Debug for Value<'_, T> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                (self.0.local.fmt_value)(&self.0.value, f)
+            }
+        }
+
+        f.debug_struct("Handle")
+            .field("key", &self.local.name)
+            .field("value", &Value(self))
+            .finish()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<T: 'static> defmt::Format for Handle<T>
+where
+    T: defmt::Format,
+{
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "Handle {{ value: {} }}", self.value)
+    }
+}
@@ -0,0 +1,329 @@
+//! Inheritance of task-locals by spawned tasks.
+//!
+//! With the `inherit` feature, keys declared with `#[task_local(inherit)]` are
+//! copied into the tasks spawned with [`spawn`](crate::spawn): the values they
+//! have in the spawning task are captured in an [`Inherited`] and scoped
+//! around the child task. Other keys are not copied, so large or
+//! request-specific values only travel where they are passed explicitly.
+//!
+//! Like the registry, the keys add themselves to a global intrusive list the
+//! first time a scope of them is entered, which is all that is needed to find
+//! the keys that are set in the current task.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt;
+use core::future::Future;
+use core::marker::PhantomData;
+use core::pin::Pin;
+use core::ptr;
+use core::task::{Context, Poll};
+
+use crate::atomic::{AtomicBool, AtomicPtr, Ordering};
+use crate::keys::{TaskId, TASK_ID};
+use crate::LocalKey;
+
+/// Head of the list of inheritable keys that were entered at least once.
+static HEAD: AtomicPtr<Node> = AtomicPtr::new(ptr::null_mut());
+
+/// Copies the value of a key, if it is set.
+type CaptureFn = fn(*const ()) -> Option<Box<dyn Value>>;
+
+/// Inheritance entry embedded in every key.
+pub(crate) struct Node {
+    capture: Option<CaptureFn>,
+    registered: AtomicBool,
+    next: AtomicPtr<Node>,
+    key: AtomicPtr<()>,
+}
+
+impl Node {
+    pub(crate) const fn new() -> Self {
+        Self {
+            capture: None,
+            registered: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+            key: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+}
+
+impl<T: 'static> LocalKey<T> {
+    crate::sync::const_fn! {
+        #[doc(hidden)]
+        pub fn __inherit(mut self) -> Self
+        where
+            T: Clone + Send,
+        {
+            self.inherit.capture = Some(capture_key::<T>);
+            self
+        }
+    }
+}
+
+/// Adds `key` to the list of inheritable keys if it was declared with
+/// `#[task_local(inherit)]` and is not already in it.
+pub(crate) fn register<T: 'static>(key: &'static LocalKey<T>) {
+    let node = &key.inherit;
+    if node.capture.is_none()
+        || node.registered.load(Ordering::Relaxed)
+        || node.registered.swap(true, Ordering::Relaxed)
+    {
+        return;
+    }
+
+    node.key
+        .store(key as *const LocalKey<T> as *mut (), Ordering::Relaxed);
+    let node_ptr = node as *const Node as *mut Node;
+    let mut head = HEAD.load(Ordering::Relaxed);
+    loop {
+        node.next.store(head, Ordering::Relaxed);
+        match HEAD.compare_exchange_weak(head, node_ptr, Ordering::Release, Ordering::Relaxed) {
+            Ok(_) => break,
+            Err(current) => head = current,
+        }
+    }
+}
+
+fn capture_key<T: Clone + Send + 'static>(key: *const ()) -> Option<Box<dyn Value>> {
+    // Safety: `key` was stored by `register::<T>` from a `&'static LocalKey<T>`.
+    let key = unsafe { &*(key as *const LocalKey<T>) };
+    let value = key.try_with(Clone::clone).ok()?;
+    Some(Box::new(Captured { key, value }))
+}
+
+/// The output of the child future, boxed so that every key can wrap the same
+/// future type.
+type ErasedFuture = Pin<Box<dyn Future<Output = Box<dyn Any + Send>> + Send>>;
+
+/// A captured value of some key.
+trait Value: Send {
+    fn name(&self) -> &'static str;
+
+    fn scope(self: Box<Self>, f: ErasedFuture) -> ErasedFuture;
+}
+
+struct Captured<T: 'static> {
+    key: &'static LocalKey<T>,
+    value: T,
+}
+
+impl<T: Send + 'static> Value for Captured<T> {
+    fn name(&self) -> &'static str {
+        self.key.name
+    }
+
+    fn scope(self: Box<Self>, f: ErasedFuture) -> ErasedFuture {
+        Box::pin(self.key.scope(self.value, f))
+    }
+}
+
+/// The values of the inheritable keys set in a task, to be scoped around
+/// another task.
+///
+/// Keys declared with `#[task_local(inherit)]` are captured, all others are
+/// left out. Their values must be `Clone` and `Send`. Every capture also
+/// takes a new [`TaskId`], which is set as the value of
+/// [`TASK_ID`](crate::keys::TASK_ID) for the task scoped with it.
+///
+/// [`spawn`](crate::spawn) captures and scopes the values automatically;
+/// `Inherited` is for executors and spawning functions it does not cover.
+///
+/// # Examples
+///
+/// ```
+/// # async fn dox() {
+/// use task_local::Inherited;
+///
+/// task_local::task_local! {
+///     #[task_local(inherit)]
+///     static TRACE_ID: u64;
+///
+///     static SCRATCH: Vec<u8>;
+/// }
+///
+/// let inherited = TRACE_ID
+///     .scope(7u64, SCRATCH.scope(Vec::new(), async { Inherited::capture() }))
+///     .await;
+///
+/// let seen = inherited
+///     .scope(async { (TRACE_ID.get(), SCRATCH.try_with(|_| ()).is_ok()) })
+///     .await;
+/// assert_eq!(seen, (7, false));
+/// # }
+/// ```
+pub struct Inherited {
+    values: Vec<Box<dyn Value>>,
+    task_id: TaskId,
+}
+
+impl Inherited {
+    /// Captures the values of the inheritable keys that are set in the
+    /// current task.
+    pub fn capture() -> Self {
+        let mut values = Vec::new();
+        let mut node = HEAD.load(Ordering::Acquire);
+        // Safety: Nodes live in `static` keys and are never removed from the
+        // list, so every pointer in it stays valid.
+        while let Some(current) = unsafe { node.as_ref() } {
+            if let Some(capture) = current.capture {
+                values.extend(capture(current.key.load(Ordering::Relaxed)));
+            }
+            node = current.next.load(Ordering::Relaxed);
+        }
+        Self {
+            values,
+            task_id: TaskId::next(),
+        }
+    }
+
+    /// Returns `true` if no inheritable key was set.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Returns the identifier of the task scoped with the captured values.
+    pub fn task_id(&self) -> TaskId {
+        self.task_id
+    }
+
+    /// Sets the captured values as the task-local values for the future `f`,
+    /// and the identifier of the task as the value of
+    /// [`TASK_ID`](crate::keys::TASK_ID).
+    pub fn scope<F>(self, f: F) -> InheritedFuture<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let mut future: ErasedFuture = Box::pin(async move {
+            let output: Box<dyn Any + Send> = Box::new(f.await);
+            output
+        });
+        for value in self.values {
+            future = value.scope(future);
+        }
+        future = Box::pin(TASK_ID.scope(self.task_id, future));
+        InheritedFuture {
+            future,
+            _output: PhantomData,
+        }
+    }
+}
+
+impl fmt::Debug for Inherited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.values.iter().map(|value| value.name()))
+            .finish()
+    }
+}
+
+/// A future that sets the values of an [`Inherited`] during its execution.
+///
+/// Created by the function [`Inherited::scope`].
+pub struct InheritedFuture<R> {
+    future: ErasedFuture,
+    _output: PhantomData<fn() -> R>,
+}
+
+impl<R: 'static> Future for InheritedFuture<R> {
+    type Output = R;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<R> {
+        self.future.as_mut().poll(cx).map(|output| {
+            *output
+                .downcast::<R>()
+                .unwrap_or_else(|_| unreachable!("output of the inherited future"))
+        })
+    }
+}
+
+impl<R> fmt::Debug for InheritedFuture<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InheritedFuture").finish_non_exhaustive()
+    }
+}
+
+/// Spawns `future` on the current Tokio runtime, with the inheritable
+/// task-locals of the current task.
+///
+/// The values of the keys declared with `#[task_local(inherit)]` are captured
+/// when `spawn` is called and set for the whole lifetime of the spawned task,
+/// together with a new [`TaskId`] as the value of
+/// [`TASK_ID`](crate::keys::TASK_ID). Requires the `inherit` and
+/// `tokio-interop` features.
+///
+/// # Panics
+///
+/// Panics if called outside of a Tokio runtime, like [`tokio::spawn`].
+///
+/// # Examples
+///
+/// ```
+/// # async fn dox() {
+/// task_local::task_local! {
+///     #[task_local(inherit)]
+///     static TRACE_ID: u64;
+/// }
+///
+/// let handle = TRACE_ID
+///     .scope(7u64, async { task_local::spawn(async { TRACE_ID.get() }) })
+///     .await;
+/// assert_eq!(handle.await.unwrap(), 7);
+/// # }
+/// ```
+#[cfg(feature = "tokio-interop")]
+#[track_caller]
+pub fn spawn<F>(future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(Inherited::capture().scope(future))
+}
+
+/// Spawns the task returned by `task` on `spawner`, passing it the
+/// inheritable task-locals of the current task.
+///
+/// Embassy tasks are declared with `#[embassy_executor::task]`, so their
+/// futures cannot be wrapped from the outside. Instead, the task takes the
+/// [`Inherited`] values as an argument and scopes its body with
+/// [`Inherited::scope`], which also sets the [`TaskId`] of the task. Requires
+/// the `inherit` and `embassy` features.
+///
+/// # Examples
+///
+/// ```ignore
+/// use embassy_executor::Spawner;
+/// use task_local::Inherited;
+///
+/// task_local::task_local! {
+///     #[task_local(inherit)]
+///     static TRACE_ID: u32;
+/// }
+///
+/// #[embassy_executor::task]
+/// async fn worker(inherited: Inherited) {
+///     inherited
+///         .scope(async {
+///             defmt::info!("trace {}", TRACE_ID.get());
+///         })
+///         .await;
+/// }
+///
+/// async fn handle(spawner: Spawner) {
+///     TRACE_ID
+///         .scope(7u32, async {
+///             task_local::spawn(spawner, worker).unwrap();
+///         })
+///         .await;
+/// }
+/// ```
+#[cfg(all(not(feature = "std"), feature = "embassy"))]
+pub fn spawn<S>(
+    spawner: embassy_executor::Spawner,
+    task: impl FnOnce(Inherited) -> embassy_executor::SpawnToken<S>,
+) -> Result<(), embassy_executor::SpawnError> {
+    spawner.spawn(task(Inherited::capture()))
+}
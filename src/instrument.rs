@@ -0,0 +1,334 @@
+//! Instrumentation hooks for runtime tooling.
+//!
+//! With the `instrument` feature, the [`Instrument`] installed with
+//! [`set_instrument`] is told about every scope of every key being entered and
+//! exited, with the key, the Tokio task the scope runs in and the value, so
+//! that tools like `tokio-console` or an in-house TUI can show which
+//! task-locals are set on which task. Scopes are reported at the same points
+//! as with `trace-scopes`: a future entered in a scope once, on its first
+//! poll, and exited once, when it completes, is cancelled or is dropped.
+//!
+//! [`TaskRegistry`] is an instrument keeping the scopes entered in every task
+//! until they are exited, which can be queried by task id.
+//!
+//! # Examples
+//!
+//! ```
+//! use task_local::instrument::{self, TaskRegistry};
+//!
+//! static TASKS: TaskRegistry = TaskRegistry::new();
+//!
+//! task_local::task_local! {
+//!     static REQUEST_ID: u64;
+//! }
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! instrument::set_instrument(&TASKS).unwrap();
+//!
+//! let task = tokio::spawn(REQUEST_ID.scope(7u64, async {
+//!     let scopes = TASKS.scopes(tokio::task::id());
+//!     assert_eq!((scopes[0].key(), scopes[0].value()), ("REQUEST_ID", Some("7")));
+//!     tokio::task::yield_now().await;
+//! }));
+//! let id = task.id();
+//! task.await.unwrap();
+//! assert!(TASKS.scopes(id).is_empty());
+//! # }
+//! ```
+
+use std::boxed::Box;
+use std::collections::HashMap;
+use std::fmt;
+use std::format;
+use std::string::String;
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+use std::vec::Vec;
+
+use tokio::task::Id;
+
+use crate::value_cell::ScopeState;
+#[cfg(feature = "scope-ids")]
+use crate::ScopeId;
+use crate::LocalKey;
+
+/// The instrument installed with [`set_instrument`].
+static INSTRUMENT: OnceLock<Box<dyn Instrument>> = OnceLock::new();
+
+/// Receives the scopes of every key being entered and exited.
+///
+/// Both methods are called on the thread running the scope, from inside the
+/// scope, so they should return quickly. They may read task-locals, but must
+/// not enter scopes of their own.
+///
+/// Requires the `instrument` feature.
+pub trait Instrument: Send + Sync + 'static {
+    /// Called when a scope is entered, with its value.
+    fn scope_entered(&self, event: &ScopeEvent<'_>);
+
+    /// Called when a scope reported to
+    /// [`scope_entered`](Self::scope_entered) is exited. The event has no
+    /// value.
+    fn scope_exited(&self, event: &ScopeEvent<'_>);
+}
+
+impl<I: Instrument + ?Sized> Instrument for &'static I {
+    fn scope_entered(&self, event: &ScopeEvent<'_>) {
+        (**self).scope_entered(event)
+    }
+
+    fn scope_exited(&self, event: &ScopeEvent<'_>) {
+        (**self).scope_exited(event)
+    }
+}
+
+/// A scope being entered or exited, passed to an [`Instrument`].
+///
+/// Requires the `instrument` feature.
+#[derive(Clone, Copy, Debug)]
+pub struct ScopeEvent<'a> {
+    id: usize,
+    key: &'static str,
+    module_path: &'static str,
+    task: Option<Id>,
+    #[cfg(feature = "scope-ids")]
+    scope: Option<ScopeId>,
+    value: Option<&'a dyn fmt::Debug>,
+}
+
+impl<'a> ScopeEvent<'a> {
+    #[cfg_attr(not(feature = "scope-ids"), allow(unused_variables))]
+    fn new<T: 'static>(key: &'static LocalKey<T>, state: ScopeState) -> Self {
+        Self {
+            id: key as *const LocalKey<T> as usize,
+            key: key.name,
+            module_path: key.module_path,
+            task: tokio::task::try_id(),
+            #[cfg(feature = "scope-ids")]
+            scope: state.id,
+            value: None,
+        }
+    }
+
+    /// Returns the name of the key.
+    pub fn key(&self) -> &'static str {
+        self.key
+    }
+
+    /// Returns the module path of the key.
+    pub fn module_path(&self) -> &'static str {
+        self.module_path
+    }
+
+    /// Returns the id of the Tokio task the scope runs in, or `None` outside
+    /// of a Tokio task, for example in `block_on` or on another executor.
+    pub fn task(&self) -> Option<Id> {
+        self.task
+    }
+
+    /// Returns the id of the scope, the same when it is entered and exited.
+    ///
+    /// Requires the `scope-ids` feature.
+    #[cfg(feature = "scope-ids")]
+    pub fn scope_id(&self) -> Option<ScopeId> {
+        self.scope
+    }
+
+    /// Returns the value of the scope when it is entered, formatted with
+    /// `Debug` if its type implements it and as `<opaque>` otherwise.
+    pub fn value(&self) -> Option<&'a dyn fmt::Debug> {
+        self.value
+    }
+}
+
+/// Installs the instrument receiving the scopes of every key.
+///
+/// The instrument can only be installed once. If one is already installed,
+/// `instrument` is returned as the error.
+///
+/// Requires the `instrument` feature.
+pub fn set_instrument<I: Instrument>(instrument: I) -> Result<(), I> {
+    // `OnceLock::set` would hand the instrument back boxed and type-erased.
+    let mut instrument = Some(instrument);
+    INSTRUMENT.get_or_init(|| match instrument.take() {
+        Some(instrument) => Box::new(instrument),
+        None => unreachable!(),
+    });
+    match instrument {
+        Some(instrument) => Err(instrument),
+        None => Ok(()),
+    }
+}
+
+/// Reports that a scope of `key` was entered, with its value unless it was
+/// taken.
+pub(crate) fn enter<T: 'static>(key: &'static LocalKey<T>, state: ScopeState) {
+    let Some(instrument) = INSTRUMENT.get() else {
+        return;
+    };
+    let event = ScopeEvent::new(key, state);
+    // Only the value of the scope is reported, without counting an access in
+    // the statistics of the key.
+    let reported = key.inner.try_with(|cell| {
+        key.access(cell, |value| {
+            let value = Value(value, key.fmt_value);
+            instrument.scope_entered(&ScopeEvent {
+                value: Some(&value),
+                ..event
+            })
+        })
+    });
+    if !matches!(reported, Ok(Ok(()))) {
+        instrument.scope_entered(&event);
+    }
+}
+
+/// Reports that a scope of `key` was exited.
+pub(crate) fn exit<T: 'static>(key: &'static LocalKey<T>, state: ScopeState) {
+    if let Some(instrument) = INSTRUMENT.get() {
+        instrument.scope_exited(&ScopeEvent::new(key, state));
+    }
+}
+
+struct Value<'a, T>(&'a T, crate::FmtValue<T>);
+
+impl<T> fmt::Debug for Value<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (self.1)(self.0, f)
+    }
+}
+
+/// An [`Instrument`] keeping the scopes entered in every Tokio task until
+/// they are exited, queryable by task id.
+///
+/// Scopes entered outside of a Tokio task are not kept. Values are formatted
+/// when the scope is entered, so a value replaced with `LocalKey::set` is
+/// shown as it was when the scope was entered.
+///
+/// Requires the `instrument` feature.
+#[derive(Debug, Default)]
+pub struct TaskRegistry {
+    tasks: Mutex<Option<Tasks>>,
+}
+
+/// The scopes entered in every task, with the address of their key.
+type Tasks = HashMap<Id, Vec<(usize, ScopeRecord)>>;
+
+impl TaskRegistry {
+    /// Creates an empty registry, to be installed with [`set_instrument`].
+    pub const fn new() -> Self {
+        Self {
+            tasks: Mutex::new(None),
+        }
+    }
+
+    /// Returns the scopes entered in the task `id`, outermost first.
+    pub fn scopes(&self, id: Id) -> Vec<ScopeRecord> {
+        match self.lock().as_ref().and_then(|tasks| tasks.get(&id)) {
+            Some(scopes) => scopes.iter().map(|(_, scope)| scope.clone()).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns every task with at least one scope entered, with its scopes.
+    pub fn tasks(&self) -> Vec<(Id, Vec<ScopeRecord>)> {
+        let tasks = self.lock();
+        let Some(tasks) = tasks.as_ref() else {
+            return Vec::new();
+        };
+        tasks
+            .iter()
+            .map(|(id, scopes)| (*id, scopes.iter().map(|(_, scope)| scope.clone()).collect()))
+            .collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<Tasks>> {
+        self.tasks.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Instrument for TaskRegistry {
+    fn scope_entered(&self, event: &ScopeEvent<'_>) {
+        let Some(task) = event.task else {
+            return;
+        };
+        let scope = ScopeRecord {
+            key: event.key,
+            module_path: event.module_path,
+            #[cfg(feature = "scope-ids")]
+            scope: event.scope,
+            value: event.value.map(|value| format!("{value:?}")),
+        };
+        let mut tasks = self.lock();
+        let scopes = tasks.get_or_insert_with(HashMap::new).entry(task);
+        scopes.or_default().push((event.id, scope));
+    }
+
+    fn scope_exited(&self, event: &ScopeEvent<'_>) {
+        let Some(task) = event.task else {
+            return;
+        };
+        let mut tasks = self.lock();
+        let Some(tasks) = tasks.as_mut() else {
+            return;
+        };
+        let Some(scopes) = tasks.get_mut(&task) else {
+            return;
+        };
+        // Scopes of the same key in one task are nested, so the innermost
+        // one is exited first.
+        if let Some(index) = scopes.iter().rposition(|(id, _)| *id == event.id) {
+            scopes.remove(index);
+        }
+        if scopes.is_empty() {
+            tasks.remove(&task);
+        }
+    }
+}
+
+/// A scope entered in a task, returned by [`TaskRegistry::scopes`].
+///
+/// Requires the `instrument` feature.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScopeRecord {
+    key: &'static str,
+    module_path: &'static str,
+    #[cfg(feature = "scope-ids")]
+    scope: Option<ScopeId>,
+    value: Option<String>,
+}
+
+impl ScopeRecord {
+    /// Returns the name of the key.
+    pub fn key(&self) -> &'static str {
+        self.key
+    }
+
+    /// Returns the module path of the key.
+    pub fn module_path(&self) -> &'static str {
+        self.module_path
+    }
+
+    /// Returns the id of the scope.
+    ///
+    /// Requires the `scope-ids` feature.
+    #[cfg(feature = "scope-ids")]
+    pub fn scope_id(&self) -> Option<ScopeId> {
+        self.scope
+    }
+
+    /// Returns the value of the scope as it was formatted when the scope was
+    /// entered, or `None` if it had already been taken.
+    pub fn value(&self) -> Option<&str> {
+        self.value.as_deref()
+    }
+}
+
+impl fmt::Display for ScopeRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{} = {}", self.key, value),
+            None => write!(f, "{}", self.key),
+        }
+    }
+}
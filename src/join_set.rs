@@ -0,0 +1,186 @@
+//! A Tokio `JoinSet` carrying task-locals into the tasks it spawns.
+//!
+//! Tasks spawned on a `tokio::task::JoinSet` start without any task-local
+//! set. A [`ScopedJoinSet`] takes a snapshot of a selection of keys, see
+//! [`Capture`], every time it spawns a task, and scopes the task with it, so
+//! that every child sees the values its parent had when spawning it. Joining
+//! works as with a plain `JoinSet`.
+
+use core::fmt;
+use core::future::Future;
+
+use tokio::task::{AbortHandle, JoinError, JoinSet};
+
+use crate::keys::{TaskId, TASK_ID};
+use crate::Capture;
+
+/// A `tokio::task::JoinSet` whose tasks are scoped with the task-locals of
+/// the task spawning them.
+///
+/// The keys to carry are selected with a [`Capture`], a reference to a key or
+/// a tuple of references to keys, as for the channel wrappers. A key without
+/// a value when a task is spawned is left unset in that task. Every task
+/// also gets a new [`TaskId`] as the value of
+/// [`TASK_ID`](crate::keys::TASK_ID). Like a
+/// `JoinSet`, dropping a `ScopedJoinSet` aborts the tasks still running.
+///
+/// Requires the `tokio-interop` feature.
+///
+/// # Examples
+///
+/// ```
+/// # async fn dox() {
+/// use task_local::ScopedJoinSet;
+///
+/// task_local::task_local! {
+///     static REQUEST_ID: u64;
+/// }
+///
+/// let mut set = ScopedJoinSet::new(&REQUEST_ID);
+/// REQUEST_ID.sync_scope(7u64, || {
+///     set.spawn(async { REQUEST_ID.get() });
+/// });
+/// assert_eq!(set.join_next().await.unwrap().unwrap(), 7);
+/// # }
+/// ```
+pub struct ScopedJoinSet<T, C> {
+    inner: JoinSet<T>,
+    keys: C,
+}
+
+impl<T: 'static, C: Capture> ScopedJoinSet<T, C> {
+    /// Creates an empty set carrying the values of `keys` into its tasks.
+    pub fn new(keys: C) -> Self {
+        Self::from_inner(JoinSet::new(), keys)
+    }
+
+    /// Wraps `inner`. The tasks already in it are left as they are.
+    pub fn from_inner(inner: JoinSet<T>, keys: C) -> Self {
+        Self { inner, keys }
+    }
+
+    /// Spawns `future` on the current Tokio runtime, scoped with the current
+    /// values of the keys.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a Tokio runtime, like
+    /// `JoinSet::spawn`.
+    #[track_caller]
+    pub fn spawn<F>(&mut self, future: F) -> AbortHandle
+    where
+        F: Future<Output = T> + Send + 'static,
+        C::Scope<F>: Send + 'static,
+        T: Send,
+    {
+        let scoped = self.keys.scope(self.keys.capture(), future);
+        self.inner.spawn(TASK_ID.scope(TaskId::next(), scoped))
+    }
+
+    /// Spawns `future` on the current `LocalSet`, scoped with the current
+    /// values of the keys.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a `LocalSet`, like
+    /// `JoinSet::spawn_local`.
+    #[track_caller]
+    pub fn spawn_local<F>(&mut self, future: F) -> AbortHandle
+    where
+        F: Future<Output = T> + 'static,
+        C::Scope<F>: 'static,
+    {
+        let scoped = self.keys.scope(self.keys.capture(), future);
+        self.inner
+            .spawn_local(TASK_ID.scope(TaskId::next(), scoped))
+    }
+
+    /// Runs `f` on the blocking thread pool, with the current values of the
+    /// keys set.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a Tokio runtime, like
+    /// `JoinSet::spawn_blocking`.
+    #[track_caller]
+    pub fn spawn_blocking<F>(&mut self, f: F) -> AbortHandle
+    where
+        F: FnOnce() -> T + Send + 'static,
+        C: Send,
+        C::Snapshot: Send,
+        T: Send,
+    {
+        let keys = self.keys;
+        let snapshot = keys.capture();
+        let task_id = TaskId::next();
+        self.inner.spawn_blocking(move || {
+            TASK_ID.sync_scope(task_id, || keys.sync_scope(snapshot, f))
+        })
+    }
+
+    /// Waits for the next task to complete and returns its output, or `None`
+    /// if the set is empty.
+    pub async fn join_next(&mut self) -> Option<Result<T, JoinError>> {
+        self.inner.join_next().await
+    }
+
+    /// Returns the output of a task that already completed, if any, without
+    /// waiting.
+    pub fn try_join_next(&mut self) -> Option<Result<T, JoinError>> {
+        self.inner.try_join_next()
+    }
+
+    /// Aborts every task and waits for them to finish.
+    pub async fn shutdown(&mut self) {
+        self.inner.shutdown().await
+    }
+
+    /// Aborts every task. They are still returned by
+    /// [`join_next`](Self::join_next), as cancelled.
+    pub fn abort_all(&mut self) {
+        self.inner.abort_all()
+    }
+
+    /// Removes every task from the set without aborting them.
+    pub fn detach_all(&mut self) {
+        self.inner.detach_all()
+    }
+
+    /// Returns the number of tasks in the set.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns `true` if the set has no tasks.
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Returns the selection of keys carried into the tasks.
+    pub fn keys(&self) -> C {
+        self.keys
+    }
+
+    /// Returns the wrapped `JoinSet`.
+    pub fn get_ref(&self) -> &JoinSet<T> {
+        &self.inner
+    }
+
+    /// Returns the wrapped `JoinSet`, to spawn a task without task-locals.
+    pub fn get_mut(&mut self) -> &mut JoinSet<T> {
+        &mut self.inner
+    }
+
+    /// Consumes the wrapper, returning the wrapped `JoinSet`.
+    pub fn into_inner(self) -> JoinSet<T> {
+        self.inner
+    }
+}
+
+impl<T, C> fmt::Debug for ScopedJoinSet<T, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScopedJoinSet")
+            .field("len", &self.inner.len())
+            .finish_non_exhaustive()
+    }
+}
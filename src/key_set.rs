@@ -0,0 +1,203 @@
+//! A static pool of keys claimed at runtime.
+//!
+//! Keys declared with `task_local!` are fixed at compile time. Plugin-style
+//! firmware sometimes only learns at initialization how many contexts it
+//! needs, one per loaded plugin for example. A [`LocalKeySet`], declared with
+//! [`local_key_set!`](crate::local_key_set), reserves the storage of a fixed
+//! number of keys in a `static`, and hands them out one at a time with
+//! [`LocalKeySet::claim`]. Every claimed key is a plain [`LocalKey`], and stays
+//! claimed for the rest of the program.
+
+use core::fmt;
+
+use crate::atomic::{AtomicUsize, Ordering};
+use crate::LocalKey;
+
+#[cfg(all(
+    not(feature = "forbid-unsafe"),
+    any(feature = "embassy", feature = "rtic")
+))]
+use crate::{slots::TaskSlot, FmtValue};
+
+/// Declares a new [`LocalKeySet`] with room for a fixed number of keys.
+///
+/// The keys of the set are all named after the set, and use the default
+/// number of slots with the `embassy` and `rtic` features. Only available in
+/// no_std builds, and not with `forbid-unsafe` together with `embassy`.
+///
+/// # Examples
+///
+/// ```ignore
+/// task_local::local_key_set! {
+///     static PLUGIN_STATE: [u32; 4];
+/// }
+///
+/// let first = PLUGIN_STATE.claim().unwrap();
+/// let second = PLUGIN_STATE.claim().unwrap();
+/// first.sync_scope(1u32, || {
+///     second.sync_scope(2u32, || {
+///         assert_eq!((first.get(), second.get()), (1, 2));
+///     });
+/// });
+/// ```
+#[macro_export]
+macro_rules! local_key_set {
+    () => {};
+
+    ($(#[$attr:meta])* $vis:vis static $name:ident: [$t:ty; $n:expr]; $($rest:tt)*) => {
+        $crate::__local_key_set_inner!($(#[$attr])* $vis $name, $t, $n);
+        $crate::local_key_set!($($rest)*);
+    };
+
+    ($(#[$attr:meta])* $vis:vis static $name:ident: [$t:ty; $n:expr]) => {
+        $crate::__local_key_set_inner!($(#[$attr])* $vis $name, $t, $n);
+    };
+}
+
+#[cfg(not(any(feature = "embassy", feature = "rtic")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __local_key_set_inner {
+    ($(#[$attr:meta])* $vis:vis $name:ident, $t:ty, $n:expr) => {
+        $(#[$attr])*
+        $vis static $name: $crate::LocalKeySet<$t, { $n }> = $crate::LocalKeySet::__new(
+            [const {
+                $crate::LocalKey::__new(
+                    ::core::stringify!($name),
+                    ::core::module_path!(),
+                    $crate::__task_local_fmt_value!($t),
+                )
+            }; $n],
+        );
+    };
+}
+
+// With `embassy` and `rtic` every key of the set gets a slot table of its own,
+// declared next to the set.
+#[cfg(any(feature = "embassy", feature = "rtic"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __local_key_set_inner {
+    ($(#[$attr:meta])* $vis:vis $name:ident, $t:ty, $n:expr) => {
+        $(#[$attr])*
+        $vis static $name: $crate::LocalKeySet<$t, { $n }> = {
+            static __SLOTS: [
+                [$crate::__private::TaskSlot<$t>; $crate::__private::TASK_SLOTS * $crate::__private::MAX_CORES];
+                $n
+            ] = [const {
+                [const { $crate::__private::TaskSlot::new() };
+                    $crate::__private::TASK_SLOTS * $crate::__private::MAX_CORES]
+            }; $n];
+
+            $crate::LocalKeySet::__with_slots(
+                ::core::stringify!($name),
+                ::core::module_path!(),
+                $crate::__task_local_fmt_value!($t),
+                &__SLOTS,
+            )
+        };
+    };
+}
+
+/// A fixed number of keys for values of type `T`, claimed at runtime.
+///
+/// This type is generated by the [`local_key_set!`](crate::local_key_set)
+/// macro. [`claim`](Self::claim) returns a key that no one else has claimed,
+/// until all `N` keys are taken.
+pub struct LocalKeySet<T: 'static, const N: usize> {
+    keys: [LocalKey<T>; N],
+    claimed: AtomicUsize,
+}
+
+impl<T: 'static, const N: usize> LocalKeySet<T, N> {
+    #[doc(hidden)]
+    pub const fn __new(keys: [LocalKey<T>; N]) -> Self {
+        Self {
+            keys,
+            claimed: AtomicUsize::new(0),
+        }
+    }
+
+    #[doc(hidden)]
+    #[cfg(all(
+        not(feature = "forbid-unsafe"),
+        any(feature = "embassy", feature = "rtic")
+    ))]
+    pub const fn __with_slots<const S: usize>(
+        name: &'static str,
+        module_path: &'static str,
+        fmt_value: FmtValue<T>,
+        slots: &'static [[TaskSlot<T>; S]; N],
+    ) -> Self {
+        use core::mem::MaybeUninit;
+
+        let mut keys = [const { MaybeUninit::<LocalKey<T>>::uninit() }; N];
+        let mut i = 0;
+        while i < N {
+            keys[i] = MaybeUninit::new(LocalKey::__new(name, module_path, fmt_value, &slots[i]));
+            i += 1;
+        }
+        // Safety: Every element was initialized by the loop, and
+        // `MaybeUninit<LocalKey<T>>` has the layout of `LocalKey<T>`.
+        Self::__new(unsafe { keys.as_ptr().cast::<[LocalKey<T>; N]>().read() })
+    }
+
+    /// Claims a key of the set, or returns [`KeySetExhausted`] if all of them
+    /// are claimed already.
+    ///
+    /// The key stays claimed for the rest of the program.
+    pub fn claim(&'static self) -> Result<&'static LocalKey<T>, KeySetExhausted> {
+        self.claimed
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |claimed| {
+                (claimed < N).then_some(claimed + 1)
+            })
+            .map(|index| &self.keys[index])
+            .map_err(|_| KeySetExhausted { capacity: N })
+    }
+
+    /// Returns the number of keys claimed so far.
+    pub fn claimed(&self) -> usize {
+        self.claimed.load(Ordering::Acquire)
+    }
+
+    /// Returns the number of keys in the set.
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T: 'static, const N: usize> fmt::Debug for LocalKeySet<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalKeySet")
+            .field("claimed", &self.claimed())
+            .field("capacity", &N)
+            .finish_non_exhaustive()
+    }
+}
+
+/// An error returned by [`LocalKeySet::claim`] when every key of the set is
+/// claimed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct KeySetExhausted {
+    capacity: usize,
+}
+
+impl KeySetExhausted {
+    /// Returns the number of keys in the set.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+impl fmt::Display for KeySetExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "all {} keys of the set are claimed", self.capacity)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for KeySetExhausted {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "all {=usize} keys of the set are claimed", self.capacity)
+    }
+}
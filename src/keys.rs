@@ -0,0 +1,155 @@
+//! Well-known keys for context that most tasks have.
+//!
+//! Libraries that log, trace or measure what a task does need its name or
+//! identifier, and would otherwise each declare their own key for it, which
+//! the application has to set once per library. The keys of this module are
+//! a common place to set and find such context: an executor or a server sets
+//! them once, and every library reads them with the accessors below.
+//!
+//! The crate sets [`TASK_ID`] in the tasks spawned through its own wrappers:
+//! `task_local::spawn`, `TaskScope::spawn`, `ScopedJoinSet`, the futures of
+//! `Inherited::scope`, which cover Embassy tasks, and `wasm::spawn_local`.
+//! Every such task gets a new identifier from [`TaskId::next`]. The other
+//! keys are left to the application.
+//!
+//! # Examples
+//!
+//! ```
+//! use task_local::keys::{self, Priority, TaskId};
+//!
+//! fn log(message: &str) -> String {
+//!     match (keys::component(), keys::task_id()) {
+//!         (Some(component), Some(id)) => format!("[{component} {id}] {message}"),
+//!         _ => message.to_string(),
+//!     }
+//! }
+//!
+//! keys::COMPONENT.sync_scope("billing", || {
+//!     keys::TASK_ID.sync_scope(TaskId::new(7), || {
+//!         assert_eq!(log("charged"), "[billing #7] charged");
+//!     });
+//!     assert_eq!(keys::priority(), None);
+//! });
+//! ```
+
+use core::fmt;
+
+use crate::atomic::{AtomicUsize, Ordering};
+
+/// The number of the next identifier returned by [`TaskId::next`].
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+crate::task_local! {
+    /// The name of the current task, such as `"http-worker"`.
+    pub static TASK_NAME: &'static str;
+
+    /// The identifier of the current task, unique within the process.
+    pub static TASK_ID: TaskId;
+
+    /// The scheduling priority of the current task.
+    pub static PRIORITY: Priority;
+
+    /// The component, such as a library or a subsystem, that the current
+    /// task works for.
+    pub static COMPONENT: &'static str;
+}
+
+/// Returns the name of the current task, if [`TASK_NAME`] is set.
+pub fn task_name() -> Option<&'static str> {
+    TASK_NAME.try_get_copied().ok()
+}
+
+/// Returns the identifier of the current task, if [`TASK_ID`] is set.
+pub fn task_id() -> Option<TaskId> {
+    TASK_ID.try_get_copied().ok()
+}
+
+/// Returns the priority of the current task, if [`PRIORITY`] is set.
+pub fn priority() -> Option<Priority> {
+    PRIORITY.try_get_copied().ok()
+}
+
+/// Returns the component of the current task, if [`COMPONENT`] is set.
+pub fn component() -> Option<&'static str> {
+    COMPONENT.try_get_copied().ok()
+}
+
+/// The identifier of a task, the value of [`TASK_ID`].
+///
+/// Formatted as `#` followed by the number.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    /// Creates an identifier from its number.
+    pub const fn new(id: u64) -> Self {
+        Self(id)
+    }
+
+    /// Returns a new identifier, distinct from every identifier returned
+    /// before.
+    ///
+    /// Numbers start at 1 and only wrap around after `usize::MAX`
+    /// identifiers. They are never reused otherwise, but do not prevent
+    /// [`new`](Self::new) from creating the same identifier.
+    pub fn next() -> Self {
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed) as u64)
+    }
+
+    /// Returns the number of the identifier.
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for TaskId {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "#{=u64}", self.0)
+    }
+}
+
+/// The scheduling priority of a task, the value of [`PRIORITY`].
+///
+/// Higher priorities are more urgent. The meaning of a level is up to the
+/// executor, such as the priority of an interrupt executor or of an RTIC
+/// task.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Priority(u8);
+
+impl Priority {
+    /// The lowest priority, that of background work.
+    pub const LOWEST: Self = Self(0);
+
+    /// The highest priority.
+    pub const HIGHEST: Self = Self(u8::MAX);
+
+    /// Creates a priority from its level.
+    pub const fn new(level: u8) -> Self {
+        Self(level)
+    }
+
+    /// Returns the level of the priority.
+    pub const fn get(self) -> u8 {
+        self.0
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Priority {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "{=u8}", self.0)
+    }
+}
@@ -0,0 +1,185 @@
+//! Detection of leaked scopes, for tests.
+//!
+//! With the `leak-check` feature, every `TaskLocalFuture` is recorded in a
+//! global table from its creation until it is dropped, together with the
+//! location of the call to `scope` that created it and the thread it was
+//! created on. A future that is leaked, for example with `mem::forget` or in
+//! a reference cycle, is never removed from the table, and its value is never
+//! dropped. A [`LeakCheck`] reports the futures created on its thread since
+//! it was started that are still alive, as well as the keys that are still
+//! set on the thread, found through the registry of keys.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::panic::Location;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::thread::{self, ThreadId};
+
+use crate::LocalKey;
+
+/// Identifier of the next tracked scope. Identifiers only grow, so a check
+/// can tell which scopes were created after it started.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The scopes that were created and not dropped yet.
+static SCOPES: Mutex<BTreeMap<u64, Scope>> = Mutex::new(BTreeMap::new());
+
+struct Scope {
+    key: &'static str,
+    module_path: &'static str,
+    location: &'static Location<'static>,
+    thread: ThreadId,
+}
+
+/// Records a `TaskLocalFuture` in the table of live scopes until dropped.
+pub(crate) struct Tracked {
+    id: u64,
+}
+
+impl Tracked {
+    #[track_caller]
+    pub(crate) fn new<T: 'static>(key: &'static LocalKey<T>) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let scope = Scope {
+            key: key.name,
+            module_path: key.module_path,
+            location: Location::caller(),
+            thread: thread::current().id(),
+        };
+        scopes().insert(id, scope);
+        Self { id }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        scopes().remove(&self.id);
+    }
+}
+
+fn scopes() -> MutexGuard<'static, BTreeMap<u64, Scope>> {
+    SCOPES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Checks that no scope was leaked on the current thread, for use at the end
+/// of a test.
+///
+/// Requires the `leak-check` feature.
+///
+/// # Examples
+///
+/// ```
+/// task_local::task_local! {
+///     static REQUEST_ID: u64;
+/// }
+///
+/// let check = task_local::LeakCheck::new();
+///
+/// let future = REQUEST_ID.scope(7u64, async {});
+/// std::mem::forget(future);
+///
+/// let leaks = check.leaks();
+/// assert_eq!(leaks.len(), 1);
+/// assert_eq!(leaks[0].key(), "REQUEST_ID");
+/// ```
+#[derive(Debug)]
+pub struct LeakCheck {
+    thread: ThreadId,
+    start: u64,
+}
+
+impl LeakCheck {
+    /// Starts a check on the current thread.
+    ///
+    /// Only the futures created on this thread after this call are checked,
+    /// so that tests running in parallel on other threads are not reported.
+    pub fn new() -> Self {
+        Self {
+            thread: thread::current().id(),
+            start: NEXT_ID.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the leaks found so far.
+    ///
+    /// These are the `TaskLocalFuture`s created on the thread of the check
+    /// since it was started and not dropped yet, and the keys that are set on
+    /// the current thread. Keys are only known once a scope of them has been
+    /// entered.
+    pub fn leaks(&self) -> Vec<Leak> {
+        let mut leaks: Vec<Leak> = scopes()
+            .range(self.start..)
+            .filter(|(_, scope)| scope.thread == self.thread)
+            .map(|(_, scope)| Leak {
+                key: scope.key,
+                module_path: scope.module_path,
+                location: Some(scope.location),
+            })
+            .collect();
+        if thread::current().id() == self.thread {
+            leaks.extend(crate::registry::set_keys().map(|(key, module_path)| Leak {
+                key,
+                module_path,
+                location: None,
+            }));
+        }
+        leaks
+    }
+
+    /// Panics with the list of leaks if any were found.
+    #[track_caller]
+    pub fn assert_no_leaks(self) {
+        let leaks = self.leaks();
+        if !leaks.is_empty() {
+            let list: Vec<_> = leaks.iter().map(|leak| format!("  {leak}")).collect();
+            panic!("task-local scopes leaked:\n{}", list.join("\n"));
+        }
+    }
+}
+
+impl Default for LeakCheck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A leaked scope or a key left set, found by a [`LeakCheck`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Leak {
+    key: &'static str,
+    module_path: &'static str,
+    location: Option<&'static Location<'static>>,
+}
+
+impl Leak {
+    /// Returns the name of the key.
+    pub fn key(&self) -> &'static str {
+        self.key
+    }
+
+    /// Returns the module path of the key.
+    pub fn module_path(&self) -> &'static str {
+        self.module_path
+    }
+
+    /// Returns where the leaked future was created, or `None` if the key is
+    /// still set but the scope setting it is not known, such as the scope of
+    /// a `sync_scope` that is still running.
+    pub fn location(&self) -> Option<&'static Location<'static>> {
+        self.location
+    }
+}
+
+impl fmt::Display for Leak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.location {
+            Some(location) => write!(
+                f,
+                "scope of `{}::{}` created at {} was never dropped",
+                self.module_path, self.key, location
+            ),
+            None => write!(f, "`{}::{}` is still set", self.module_path, self.key),
+        }
+    }
+}
@@ -7,7 +7,162 @@
 //! # Features
 //!
 //! - `std` (default): Use the standard library thread-local implementation
+//! - `alloc`: Use a global allocator in no_std builds. Enabled by `std`. This lets a
+//!   [`Watch`] wake any number of watchers instead of only the most recent one.
 //! - `error-trait`: Enable `std::error::Error` implementation for error types
+//! - `critical-section`: In no_std builds, guard every access to task-local storage
+//!   with `critical_section::with`, making the crate sound on multi-core targets
+//!   such as the RP2040. Values are still shared between cores.
+//! - `portable-atomic`: Use the `portable-atomic` crate for internal atomics, so the crate
+//!   builds on targets without native atomic read-modify-write operations such as
+//!   Cortex-M0/M0+. Combine with `critical-section` to use it as the fallback, unless the
+//!   HAL already configures `portable-atomic`, like `esp-hal` does. See "ESP32" below.
+//! - `per-core`: In no_std builds, keep independent task-local state per core for
+//!   targets running one executor per core. The application registers the function
+//!   returning the current core index with `set_core_id_fn!`.
+//! - `tokio-interop`: Implement [`TaskLocalStorage`] for `tokio::task::LocalKey`, so
+//!   keys declared with `tokio::task_local!` work with generic code over task-locals, and
+//!   add [`ScopedJoinSet`], a `JoinSet` carrying task-locals into the tasks it spawns
+//! - `tower`: Add layers scoping task-locals around the futures of `tower` services, per
+//!   request or per connection. See the `tower` module. Implies `std`.
+//! - `async-graphql`: Add `async-graphql` schema extensions scoping task-locals per request or
+//!   per resolver. See the `async_graphql` module. Implies `std`.
+//! - `rayon`: Add an adapter setting task-locals on the worker threads of `rayon` parallel
+//!   iterators. See the `rayon` module. Implies `std`.
+//! - `wasm-bindgen-futures`: Add `spawn_local` spawning a `wasm_bindgen_futures` task with a
+//!   selection of the current task-locals. See the `wasm` module. Implies `std`.
+//! - `pyo3`: Add a bridge mirroring task-locals into Python `contextvars` while calling
+//!   into Python with PyO3. See the `python` module. Implies `std`.
+//! - `tokio-channel`: Add wrappers around `tokio::sync::mpsc` channels carrying task-locals
+//!   along with every message. See the `channel` module. Implies `std`.
+//! - `embassy-sync`: Add the same wrappers around `embassy_sync` channels, for no_std
+//!   targets. See the `channel` module.
+//! - `stream`: Add [`LocalKey::scope_each`], scoping every future of a stream with its
+//!   own value
+//! - `registry`: Keep a registry of the keys in use, so that [`dump()`] can show which
+//!   keys are set in the current task and their values, and [`registered_keys`] can list
+//!   them as [`AnyLocalKey`]s. Every registered key gets a [`KeyId`], returned by
+//!   `LocalKey::id`, for tools keeping per-key data in arrays.
+//! - `raw-hooks`: Add [`exit_raw`] and [`enter_raw`], which detach the scopes of every key
+//!   from the current thread and attach them again, for executors that switch between
+//!   tasks at their own context-switch points. Implies `registry` and `alloc`; cannot be
+//!   combined with `embassy` or `rtic` in no_std builds.
+//! - `leak-check`: Track the futures returned by `scope` until they are dropped, so that
+//!   [`LeakCheck`] can report the scopes leaked by a test, with the location they were
+//!   created at, and the keys left set. Implies `std` and `registry`.
+//! - `testing`: Add the `testing` module, with [`assert_set!`], [`assert_unset!`] and
+//!   [`with_locals!`] for tests, and [`testing::run`], which fails a test leaving any key
+//!   set. Implies `std` and `registry`.
+//! - `context`: Add the `context` module, whose `Context` holds the values of any number of
+//!   keys and is entered as a single scope. Child contexts share the values of their
+//!   parent and only store the keys they override. Also adds [`KeyNamespace`], grouping
+//!   the keys of one component, such as a plugin, under names of their own to snapshot and
+//!   dump them apart from the keys of other components, unless `forbid-unsafe` is enabled.
+//!   Implies `alloc`.
+//! - `read-mostly`: Add [`ReadMostlyKey`], declared with `read_mostly!`, for values read
+//!   very often and rarely changed. Its global value is kept in an `ArcSwap` and read
+//!   without touching task-local storage while no override scope exists. Implies `std`.
+//! - `inherit`: Copy the keys declared with `#[task_local(inherit)]` into spawned tasks,
+//!   with [`Inherited`] and, with `tokio-interop` or in no_std builds with `embassy`,
+//!   `spawn`. With `tokio-interop`, `TaskScope` also awaits or cancels the spawned tasks
+//!   as a group. Implies `alloc`.
+//! - `trace-scopes`: Log every scope of every key being entered and exited, with `tracing`
+//!   events in std builds and `defmt` logs in no_std builds, which then require `defmt`
+//! - `trace-scope-values`: Also log the value of a scope when it is entered. Implies
+//!   `trace-scopes`.
+//! - `scope-ids`: Give every scope a `ScopeId`, returned by [`current_scope_id`] inside the
+//!   scope and included in the logs of `trace-scopes` and the events of `instrument`, so
+//!   that the entry and exit of a scope can be matched even when values repeat
+//! - `instrument`: Add the `instrument` module, reporting every scope being entered and
+//!   exited with its Tokio task id and value to an installed `Instrument`, for runtime
+//!   tooling. `TaskRegistry` keeps the scopes of every task, queryable by task id.
+//!   Implies `std`.
+//! - `tracing`: Add `tracing::record`, recording the values of a selection of keys as
+//!   fields of the current `tracing` span. See the `tracing` module. Implies `std`.
+//! - `sentry`: Add a bridge adding task-locals to Sentry events as tags, the user or
+//!   other parts of the event, and running futures with a Sentry hub of their own. See
+//!   the `sentry` module. Implies `std`.
+//! - `stats`: Count the scopes, accesses and borrow conflicts of every key, returned by
+//!   `LocalKey::stats`
+//! - `metrics`: Add `LocalKey::record_metrics`, reporting the counters of `stats` to the
+//!   `metrics` recorder. Implies `stats` and `std`.
+//! - `zeroize`: Wipe the values of keys declared with `#[task_local(zeroize)]` with
+//!   `zeroize` before they are dropped, when their scope exits or their `TaskLocalFuture`
+//!   is dropped
+//! - `secrecy`: Format the values of keys holding a `secrecy::SecretBox`, such as a
+//!   `SecretString`, as `[REDACTED]` in `dump`, `LocalKey::debug`, scope tracing and
+//!   every other output of the crate. Implies `alloc`.
+//! - `audit`: Report every read of the keys declared with `#[task_local(audited)]`, with
+//!   the name of the key and the location of the read, to a function registered with
+//!   `set_audit_hook!`, which must be defined in the final binary. Cannot be combined with
+//!   `forbid-unsafe`.
+//! - `defmt`: Implement `defmt::Format` for the public types, for logging over RTT
+//!   without pulling in `core::fmt`
+//! - `embassy`: In no_std builds, keep a separate slot per Embassy task so that
+//!   tasks on preempting (interrupt) executors never see each other's values.
+//!   Required when keys are used from more than one executor priority level.
+//!   Storage is guarded by critical sections as with `critical-section`, but
+//!   `portable-atomic` is left to the application to configure. The number of slots of
+//!   a key is set with `#[task_local(tasks = N)]`.
+//! - `rtic`: In no_std builds, keep a separate slot per RTIC priority level so that
+//!   keys can be used from `idle`, hardware tasks and async software tasks alike. See
+//!   the `rtic` module. Guards storage with critical sections like `embassy`; cannot be
+//!   combined with `embassy`.
+//! - `custom-storage`: In std builds, keep the storage of every key where the
+//!   `StorageProvider` installed with `provider::set_provider` finds the current task,
+//!   instead of a `thread_local!`, for kernels, simulators and executors with their own
+//!   notion of a task. See the `provider` module. Implies `std`; cannot be combined with
+//!   `forbid-unsafe`.
+//! - `freertos`: Add the `FreeRtos` storage provider, keeping the storage of every key in
+//!   the current FreeRTOS task through one of its thread-local storage pointers, for std
+//!   builds on FreeRTOS such as ESP-IDF, and use it unless another provider is installed.
+//!   See the `freertos` module. Implies `custom-storage`.
+//! - `backend-thread-local`, `backend-critical-section`, `backend-single-core` and
+//!   `backend-custom`: State the storage backend the application relies on, so that a
+//!   combination of features providing another one fails to build. See "Backends" below.
+//! - `forbid-unsafe`: Build the crate without any `unsafe` code, under
+//!   `#![forbid(unsafe_code)]`. Values are moved into the key on every poll instead of
+//!   being referenced in place, and `LocalKey::with_unchecked` is not available. In
+//!   no_std builds this requires `critical-section`, and cannot be combined with
+//!   `per-core`, `rtic`, `registry` or `inherit`.
+//! - `panic-free`: In no_std builds, remove the methods that panic when a key cannot be
+//!   accessed or a scope cannot be entered, such as `get`, `with`, `set` and
+//!   `sync_scope`, leaving their `try_` variants. A `TaskLocalFuture` whose scope cannot
+//!   be entered wakes its task and tries again on the next poll instead of panicking, and
+//!   stays pending when polled after completion; `try_scope` reports the error instead.
+//!   Meant for `panic = "abort"` firmware, where any panic is fatal. Like any feature that
+//!   removes API, it should be enabled by the final binary. Cannot be combined with `std`
+//!   or `raw-hooks`. An out-of-range index returned by the `set_core_id_fn!` function
+//!   remains a configuration error that panics.
+//! - `error-handler`: Pass failed accesses and scopes that cannot be entered to a function
+//!   registered with `set_error_handler!`, with the name of the key and the location of
+//!   the access, instead of panicking, so that firmware can log them and reset. The
+//!   function must be defined in the final binary. Cannot be combined with
+//!   `forbid-unsafe`.
+//!
+//! # Backends
+//!
+//! Which storage the keys use follows from the other features, and an application can
+//! name the one it relies on with a `backend-*` feature. Only one of them can be enabled,
+//! and enabling one along with features selecting another backend is a compile error:
+//!
+//! - `backend-thread-local`: the std backend, one `thread_local!` per key. Implies `std`;
+//!   cannot be combined with `custom-storage` or `freertos`.
+//! - `backend-custom`: the std backend with the storage found by a `StorageProvider`.
+//!   Implies `custom-storage`.
+//! - `backend-critical-section`: the no_std backend with every access guarded by a
+//!   critical section, sound with several cores and interrupt executors. Implies
+//!   `critical-section`, and can be combined with `embassy`, `rtic` and `per-core`, which
+//!   also use critical sections. Requires `default-features = false`.
+//! - `backend-single-core`: the no_std backend without any critical section, only sound
+//!   on a single core where keys are never accessed from interrupt handlers or preempting
+//!   executors. Requires `default-features = false`; cannot be combined with
+//!   `critical-section`, `embassy`, `rtic` or `per-core`.
+//!
+//! ```toml
+//! [dependencies]
+//! task-local = { version = "0.1", default-features = false, features = ["backend-critical-section"] }
+//! ```
 //!
 //! # Standard Library Usage
 //!
@@ -19,6 +174,15 @@
 //! task-local = "0.1"
 //! ```
 //!
+//! # WebAssembly
+//!
+//! On WebAssembly targets without the `atomics` target feature, such as
+//! `wasm32-unknown-unknown`, the module cannot share its memory with another thread. The std
+//! backend then keeps the storage of a key in the key itself instead of a `thread_local!`, so
+//! accessing a key costs no more than a `RefCell` borrow. Scopes work as usual in futures
+//! passed to `wasm_bindgen_futures::spawn_local`; the `wasm-bindgen-futures` feature adds a
+//! `spawn_local` that carries task-locals into the spawned future.
+//!
 //! # No-std Usage
 //!
 //! When using this crate in no_std environments (like embedded systems with Embassy),
@@ -33,13 +197,33 @@
 //! single-threaded embedded environments. It provides the same API but with some
 //! limitations:
 //!
-//! - No thread-local storage (uses unsafe cell instead)
+//! - No thread-local storage (uses a single global cell per key instead)
 //! - Designed for single-threaded environments
 //! - Perfect for Embassy and other embedded async runtimes
 //! - Same API as the std version
 //! - **Important**: In no_std mode, all task-locals share global state, so concurrent
 //!   access (like in tests) may interfere with each other. This is expected behavior
 //!   for single-threaded embedded systems where tasks run cooperatively.
+//! - Without the `embassy` feature, a task on a higher-priority interrupt executor that
+//!   preempts another task in the middle of `with` on the same key will panic when
+//!   entering a scope of that key, and sees the preempted task's value otherwise.
+//!
+//! Interrupt handlers that need the context of the code they preempted, such as the
+//! current mode, can read `Copy` values with `LocalKey::peek_from_isr`, which never
+//! changes the state of the key.
+//!
+//! Values that tasks update and interrupt handlers read, such as calibration
+//! coefficients, can be kept in a `DoubleBuffered` key instead, whose updates are
+//! published with a single atomic store so that handlers read them without locking,
+//! retrying only when an update overwrites the value they copy.
+//!
+//! Firmware that only learns at initialization how many keys it needs, one per loaded
+//! plugin for example, can declare a pool of them with `local_key_set!` and claim them
+//! at runtime with `LocalKeySet::claim`.
+//!
+//! For tests on the target and boot code that runs before the executor starts,
+//! `embedded::block_on_scoped` drives a future to completion inside the scopes of a
+//! list of `(&KEY, value)` bindings.
 //!
 //! ## No-std Example
 //!
@@ -52,26 +236,78 @@
 //! }
 //!
 //! // Synchronous usage
-//! SENSOR_ID.sync_scope(42, || {
+//! SENSOR_ID.sync_scope(42u32, || {
 //!     let id = SENSOR_ID.get();
 //!     // ... use sensor id
 //! });
 //!
 //! // Async usage with Embassy or similar
 //! async fn sensor_task() {
-//!     SENSOR_ID.scope(42, async move {
+//!     SENSOR_ID.scope(42u32, async move {
 //!         let id = SENSOR_ID.get();
 //!         // ... async sensor operations
 //!     }).await;
 //! }
 //! ```
+//!
+//! ## ESP32
+//!
+//! The `esp-hal` Embassy executors, including the interrupt executors, work with
+//! the `embassy` feature. Chips without atomic compare-and-swap (the ESP32-C2,
+//! ESP32-C3 and ESP32-S2) also need `portable-atomic`, which `esp-hal` configures
+//! for them, so `critical-section` must not be enabled on top:
+//!
+//! ```toml
+//! [dependencies]
+//! task-local = { version = "0.1", default-features = false, features = ["embassy", "portable-atomic"] }
+//! ```
+//!
+//! On the dual-core ESP32 and ESP32-S3 with an executor per core, the `per-core`
+//! feature keeps the cores apart, with `esp_hal::get_core()` as the core index:
+//!
+//! ```ignore
+//! fn current_core() -> usize {
+//!     esp_hal::get_core() as usize
+//! }
+//!
+//! task_local::set_core_id_fn!(current_core);
+//! ```
+//!
+//! `examples/esp32c3-embassy` is a complete application for the ESP32-C3.
+//!
+//! ## AVR and MSP430
+//!
+//! AVR and MSP430 have no atomic read-modify-write operations at all. With the
+//! `portable-atomic` feature, the atomics of the crate are implemented by
+//! briefly disabling interrupts, which is sound because these chips are always
+//! single-core. If keys are accessed from interrupt handlers as well as from the
+//! main loop, also enable `critical-section`, so that every access to the storage
+//! runs in `interrupt::free`, with the `critical-section` implementation of the HAL
+//! such as `avr-device`'s `critical-section-impl` feature:
+//!
+//! ```toml
+//! [dependencies]
+//! task-local = { version = "0.1", default-features = false, features = ["portable-atomic", "critical-section"] }
+//! avr-device = { version = "0.5", features = ["atmega328p", "critical-section-impl"] }
+//! ```
+//!
+//! `Arc` is not available on these targets, so neither is the `context` feature, nor
+//! the methods for keys holding an `Arc` or a `Weak`.
+//!
+//! # Loom
+//!
+//! When built with `RUSTFLAGS="--cfg loom"`, keys use the atomics, mutexes and
+//! thread-locals of [`loom`](https://docs.rs/loom), so they can be used inside
+//! `loom::model`. Keys are then created lazily in every model execution and can
+//! only be accessed from within a model.
 
 #![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(feature = "forbid-unsafe", forbid(unsafe_code))]
 
-use pin_project_lite::pin_project;
+#[cfg(feature = "alloc")]
+extern crate alloc;
 
-#[cfg(feature = "std")]
-use std::cell::RefCell;
+use pin_project_lite::pin_project;
 
 #[cfg(feature = "error-trait")]
 use std::error::Error;
@@ -86,6 +322,15 @@ use std::marker::PhantomPinned;
 #[cfg(not(feature = "std"))]
 use core::marker::PhantomPinned;
 
+#[cfg(all(feature = "std", not(feature = "error-handler")))]
+use std::panic::Location;
+#[cfg(all(
+    not(feature = "std"),
+    not(feature = "panic-free"),
+    not(feature = "error-handler")
+))]
+use core::panic::Location;
+
 #[cfg(feature = "std")]
 use std::pin::Pin;
 #[cfg(not(feature = "std"))]
@@ -97,16 +342,522 @@ use std::task::{Context, Poll};
 use core::task::{Context, Poll};
 
 #[cfg(feature = "std")]
-use std::{fmt, mem, thread};
+use std::{fmt, mem};
 #[cfg(not(feature = "std"))]
 use core::{fmt, mem};
 
-// No-std specific imports
+// Atomics used internally. Targets without native atomic read-modify-write
+// operations (such as thumbv6m) can route them through `portable-atomic`.
+// Under `cfg(loom)` most of them come from `sync` instead.
+#[cfg(not(feature = "portable-atomic"))]
+#[cfg_attr(loom, allow(unused_imports))]
+use core::sync::atomic;
+#[cfg(feature = "portable-atomic")]
+#[cfg_attr(loom, allow(unused_imports))]
+use portable_atomic as atomic;
+
+mod sync;
+use sync::const_fn;
+
+#[cfg(all(feature = "custom-storage", not(loom)))]
+pub mod provider;
+
+#[cfg(all(feature = "freertos", not(loom)))]
+pub mod freertos;
+
+mod watch;
+use watch::WatchState;
+pub use watch::{Changed, Watch};
+
+mod storage;
+pub use storage::TaskLocalStorage;
+
+mod capture;
+pub use capture::{Capture, MaybeScope};
+
+pub mod embedded;
+
+pub mod keys;
+
+mod snapshot;
+#[cfg(feature = "std")]
+pub use snapshot::block_on_with_context;
+pub use snapshot::{current, Snapshot};
+
+#[cfg(feature = "tokio-interop")]
+pub mod tokio_interop;
+
+#[cfg(feature = "tokio-interop")]
+mod join_set;
+#[cfg(feature = "tokio-interop")]
+pub use join_set::ScopedJoinSet;
+
+#[cfg(feature = "tower")]
+pub mod tower;
+
+#[cfg(feature = "async-graphql")]
+pub mod async_graphql;
+
+#[cfg(feature = "rayon")]
+pub mod rayon;
+
+#[cfg(feature = "wasm-bindgen-futures")]
+pub mod wasm;
+
+#[cfg(feature = "pyo3")]
+pub mod python;
+
+#[cfg(any(feature = "tokio-channel", feature = "embassy-sync"))]
+pub mod channel;
+
 #[cfg(not(feature = "std"))]
-use core::cell::UnsafeCell;
+mod per_core;
+#[cfg(all(
+    not(feature = "std"),
+    not(feature = "per-core"),
+    not(any(feature = "embassy", feature = "rtic"))
+))]
+use per_core::MAX_CORES;
+#[cfg(all(not(feature = "std"), feature = "per-core"))]
+pub use per_core::MAX_CORES;
+
+#[cfg(all(not(feature = "std"), any(feature = "embassy", feature = "rtic")))]
+mod slots;
+#[cfg(all(not(feature = "std"), any(feature = "embassy", feature = "rtic")))]
+use slots::TaskSlots;
+
+#[cfg(all(not(feature = "std"), not(feature = "forbid-unsafe")))]
+mod double_buffered;
+#[cfg(all(not(feature = "std"), not(feature = "forbid-unsafe")))]
+pub use double_buffered::{DoubleBuffered, DoubleBufferedFuture};
+
+#[cfg(all(not(feature = "std"), not(loom)))]
+mod key_set;
+#[cfg(all(not(feature = "std"), not(loom)))]
+pub use key_set::{KeySetExhausted, LocalKeySet};
+
+#[cfg(all(not(feature = "std"), feature = "embassy"))]
+mod embassy;
+
+#[cfg(all(not(feature = "std"), feature = "rtic"))]
+pub mod rtic;
+
+#[cfg(all(feature = "trace-scopes", not(feature = "std"), not(feature = "defmt")))]
+compile_error!("the `trace-scopes` feature requires `defmt` in no_std builds");
+
+#[cfg(all(feature = "context", not(target_has_atomic = "ptr")))]
+compile_error!("the `context` feature requires a target with atomic compare-and-swap for `Arc`");
+
+#[cfg(all(feature = "embassy", feature = "rtic"))]
+compile_error!("the `embassy` and `rtic` features cannot be enabled at the same time");
+
+#[cfg(all(
+    feature = "custom-storage",
+    any(feature = "forbid-unsafe", target_arch = "wasm32")
+))]
+compile_error!(
+    "the `custom-storage` and `freertos` features cannot be combined with `forbid-unsafe` or WebAssembly"
+);
+
+#[cfg(any(
+    all(feature = "backend-thread-local", feature = "backend-critical-section"),
+    all(feature = "backend-thread-local", feature = "backend-single-core"),
+    all(feature = "backend-thread-local", feature = "backend-custom"),
+    all(feature = "backend-critical-section", feature = "backend-single-core"),
+    all(feature = "backend-critical-section", feature = "backend-custom"),
+    all(feature = "backend-single-core", feature = "backend-custom")
+))]
+compile_error!("only one of the `backend-*` features can be enabled");
+
+#[cfg(all(feature = "backend-thread-local", feature = "custom-storage"))]
+compile_error!(
+    "the `backend-thread-local` feature cannot be combined with `custom-storage` or `freertos`, use `backend-custom`"
+);
+
+#[cfg(all(
+    any(feature = "backend-critical-section", feature = "backend-single-core"),
+    feature = "std"
+))]
+compile_error!(
+    "the `backend-critical-section` and `backend-single-core` features are no_std backends, disable the default features"
+);
+
+#[cfg(all(
+    feature = "backend-single-core",
+    any(
+        feature = "critical-section",
+        feature = "embassy",
+        feature = "rtic",
+        feature = "per-core"
+    )
+))]
+compile_error!(
+    "the `backend-single-core` feature cannot be combined with `critical-section`, `embassy`, `rtic` or `per-core`"
+);
+
+#[cfg(all(not(feature = "portable-atomic"), not(target_has_atomic = "ptr")))]
+compile_error!(
+    "this target has no atomic compare-and-swap, enable the `portable-atomic` feature, with `critical-section` unless the HAL configures `portable-atomic`"
+);
+
+#[cfg(all(
+    feature = "raw-hooks",
+    not(feature = "std"),
+    any(feature = "embassy", feature = "rtic")
+))]
+compile_error!("the `raw-hooks` feature cannot be combined with `embassy` or `rtic`");
+
+#[cfg(all(
+    feature = "forbid-unsafe",
+    any(
+        feature = "per-core",
+        feature = "rtic",
+        feature = "registry",
+        feature = "inherit"
+    )
+))]
+compile_error!(
+    "the `forbid-unsafe` feature cannot be combined with `per-core`, `rtic`, `registry` or `inherit`"
+);
+
+#[cfg(all(
+    feature = "forbid-unsafe",
+    not(feature = "std"),
+    not(any(feature = "critical-section", feature = "embassy", feature = "rtic"))
+))]
+compile_error!("the `forbid-unsafe` feature requires `critical-section` in no_std builds");
+
+#[cfg(all(feature = "panic-free", any(feature = "std", feature = "raw-hooks")))]
+compile_error!("the `panic-free` feature cannot be combined with `std` or `raw-hooks`");
+
+#[cfg(all(any(feature = "error-handler", feature = "audit"), feature = "forbid-unsafe"))]
+compile_error!("the `error-handler` and `audit` features cannot be combined with `forbid-unsafe`");
+
+mod value_cell;
+use value_cell::{Entered, ScopeState, ValueCell};
+
+mod handle;
+pub use handle::Handle;
+
+mod display;
+pub use display::{DebugValue, DisplayValue};
+
+mod string;
+
+mod map;
+pub use map::MappedKey;
+
+mod multi;
+#[cfg(not(feature = "panic-free"))]
+pub use multi::with;
+pub use multi::{try_with, KeyAccessError, WithKeys};
+
+mod poison;
+
+#[cfg(feature = "zeroize")]
+mod zeroize;
+
+#[cfg(feature = "secrecy")]
+mod secrecy;
+
+#[cfg(feature = "audit")]
+mod audit;
+#[cfg(feature = "audit")]
+pub use audit::Access;
+
+#[cfg(feature = "error-handler")]
+mod error_handler;
+#[cfg(feature = "error-handler")]
+pub use error_handler::{Failure, FailureKind};
+
+#[cfg(any(feature = "std", feature = "context"))]
+mod fallback;
+#[cfg(any(feature = "std", feature = "context"))]
+use fallback::Fallback;
+
+#[cfg(feature = "std")]
+mod env;
+
+#[cfg(feature = "std")]
+mod global;
+
+#[cfg(feature = "std")]
+mod affinity;
+#[cfg(feature = "std")]
+pub use global::ValueSource;
+
+// `Arc` is only available on targets with atomic compare-and-swap, unlike
+// Cortex-M0, AVR or MSP430.
+#[cfg(all(feature = "alloc", target_has_atomic = "ptr"))]
+mod shared;
+
+#[cfg(feature = "alloc")]
+mod boxed;
+
+#[cfg(all(feature = "alloc", target_has_atomic = "ptr"))]
+mod weak;
+
+#[cfg(feature = "context")]
+pub mod context;
+#[cfg(all(feature = "context", not(feature = "forbid-unsafe")))]
+mod namespace;
+#[cfg(all(feature = "context", not(feature = "forbid-unsafe")))]
+pub use namespace::{KeyNamespace, NamespaceDump, NamespaceError};
+
+#[cfg(feature = "read-mostly")]
+mod read_mostly;
+#[cfg(feature = "read-mostly")]
+pub use read_mostly::{ReadMostlyFuture, ReadMostlyKey};
+
+#[cfg(feature = "std")]
+mod unwind;
+#[cfg(feature = "std")]
+pub use unwind::CatchUnwindTaskLocalFuture;
+
+mod async_fn;
+
+mod scope_fn;
+
+mod finish;
+pub use finish::FinishTaskLocalFuture;
+
+mod finalize;
+pub use finalize::FinalizeFuture;
+
+mod transaction;
+pub use transaction::TransactionFuture;
+
+#[cfg(not(feature = "panic-free"))]
+mod token;
+#[cfg(not(feature = "panic-free"))]
+pub use token::InScope;
+
+mod erased;
+#[cfg(feature = "alloc")]
+pub use erased::BoxedTaskLocalFuture;
+pub use erased::DynTaskLocalFuture;
+
+#[cfg(feature = "stream")]
+mod stream;
+#[cfg(feature = "stream")]
+pub use stream::ScopeEach;
+
+#[cfg(feature = "scope-ids")]
+mod scope_id;
+#[cfg(feature = "scope-ids")]
+pub use scope_id::{current_scope_id, ScopeId};
+
+#[cfg(feature = "trace-scopes")]
+mod trace;
+
+#[cfg(feature = "instrument")]
+pub mod instrument;
+
+#[cfg(feature = "tracing")]
+pub mod tracing;
+
+#[cfg(feature = "sentry")]
+pub mod sentry;
+
+#[cfg(feature = "stats")]
+mod stats;
+#[cfg(feature = "stats")]
+pub use stats::Stats;
+
+mod any_key;
+#[cfg(feature = "alloc")]
+pub use any_key::{AnyScope, AnyValue};
+pub use any_key::AnyLocalKey;
+
+#[cfg(feature = "registry")]
+mod registry;
+#[cfg(feature = "registry")]
+pub use registry::{dump, registered_keys, Dump, KeyId};
+
+#[cfg(feature = "raw-hooks")]
+mod raw;
+#[cfg(feature = "raw-hooks")]
+pub use raw::{enter_raw, exit_raw, RawContext};
+
+#[cfg(feature = "leak-check")]
+mod leak;
+#[cfg(feature = "leak-check")]
+pub use leak::{Leak, LeakCheck};
+
+#[cfg(feature = "testing")]
+pub mod testing;
+
+// `pin_project!` does not accept `cfg` attributes on fields, so the tracker of
+// a `TaskLocalFuture` is a unit when leaks are not checked.
+#[cfg(feature = "leak-check")]
+type LeakTracker = leak::Tracked;
+#[cfg(not(feature = "leak-check"))]
+type LeakTracker = ();
+
+#[cfg(feature = "leak-check")]
+#[track_caller]
+fn track_scope<T: 'static>(key: &'static LocalKey<T>) -> LeakTracker {
+    leak::Tracked::new(key)
+}
+
+#[cfg(not(feature = "leak-check"))]
+#[inline(always)]
+fn track_scope<T: 'static>(_: &'static LocalKey<T>) -> LeakTracker {}
+
+#[cfg(feature = "inherit")]
+mod inherit;
+#[cfg(all(feature = "inherit", feature = "tokio-interop"))]
+pub use inherit::spawn;
+#[cfg(all(feature = "inherit", not(feature = "std"), feature = "embassy"))]
+pub use inherit::spawn;
+#[cfg(feature = "inherit")]
+pub use inherit::{Inherited, InheritedFuture};
+
+#[cfg(all(feature = "inherit", feature = "tokio-interop"))]
+mod task_scope;
+#[cfg(all(feature = "inherit", feature = "tokio-interop"))]
+pub use task_scope::TaskScope;
+
+// Not public API. Used by the `task_local!` macro so that its expansion does
+// not depend on what is in scope at the call site.
+#[doc(hidden)]
+pub mod __private {
+    use core::fmt;
+    use core::marker::PhantomData;
+
+    #[cfg(all(
+        feature = "std",
+        not(loom),
+        target_arch = "wasm32",
+        not(target_feature = "atomics"),
+        not(feature = "forbid-unsafe")
+    ))]
+    pub use crate::sync::single_thread::SingleThread;
+    #[cfg(all(feature = "custom-storage", not(loom)))]
+    pub use crate::provider::ProvidedStorage;
+    #[cfg(all(not(feature = "std"), any(feature = "embassy", feature = "rtic")))]
+    pub use crate::slots::{TaskSlot, TASK_SLOTS};
+    #[cfg(all(not(feature = "std"), any(feature = "embassy", feature = "rtic")))]
+    pub use crate::per_core::MAX_CORES;
+    pub use crate::value_cell::ValueCell;
+    #[cfg(loom)]
+    pub use loom;
+    #[cfg(feature = "read-mostly")]
+    pub use std::sync::Arc;
+    #[cfg(feature = "std")]
+    pub use std::sync::OnceLock;
+    #[cfg(feature = "std")]
+    pub use std::thread_local;
+
+    /// Parses the value of the environment variable `var`, for
+    /// `#[task_local(env = "...")]`.
+    #[cfg(feature = "std")]
+    pub fn parse_env<T>(var: &str, parse: impl FnOnce(&str) -> Option<T>) -> Option<T> {
+        std::env::var(var).ok().and_then(|value| parse(&value))
+    }
+
+    /// Picks `Debug` to format a value of type `T` if it is implemented, and a
+    /// placeholder otherwise. Called as `(&&&DebugProbe::<T>(PhantomData)).fmt_value(..)`
+    /// with all three traits in scope; method resolution prefers [`ViaRedacted`], then
+    /// [`ViaDebug`].
+    pub struct DebugProbe<T>(pub PhantomData<T>);
+
+    /// Formats secrets as `[REDACTED]`, see `secrecy.rs`.
+    pub trait ViaRedacted<T> {
+        fn fmt_value(&self, value: &T, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+    }
+
+    pub trait ViaDebug<T> {
+        fn fmt_value(&self, value: &T, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+    }
+
+    impl<T: fmt::Debug> ViaDebug<T> for &DebugProbe<T> {
+        fn fmt_value(&self, value: &T, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            fmt::Debug::fmt(value, f)
+        }
+    }
+
+    pub trait ViaOpaque<T> {
+        fn fmt_value(&self, value: &T, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+    }
+
+    impl<T> ViaOpaque<T> for DebugProbe<T> {
+        fn fmt_value(&self, _: &T, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("<opaque>")
+        }
+    }
+
+    /// Picks `Clone` to capture a value of type `T` for [`AnyLocalKey::capture`] if
+    /// `T` is `Clone` and `Send`. Called as `(&&CloneProbe::<T>(PhantomData)).capture_value(..)`
+    /// like [`DebugProbe`].
+    #[cfg(feature = "alloc")]
+    pub struct CloneProbe<T>(pub PhantomData<T>);
+
+    #[cfg(feature = "alloc")]
+    pub trait ViaClone<T> {
+        fn capture_value(&self, value: &T) -> Option<crate::AnyValue>;
+    }
+
+    #[cfg(feature = "alloc")]
+    impl<T: Clone + Send + 'static> ViaClone<T> for &CloneProbe<T> {
+        fn capture_value(&self, value: &T) -> Option<crate::AnyValue> {
+            Some(alloc::boxed::Box::new(value.clone()))
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    pub trait ViaNoClone<T> {
+        fn capture_value(&self, value: &T) -> Option<crate::AnyValue>;
+    }
+
+    #[cfg(feature = "alloc")]
+    impl<T> ViaNoClone<T> for CloneProbe<T> {
+        fn capture_value(&self, _: &T) -> Option<crate::AnyValue> {
+            None
+        }
+    }
+
+    /// Tells whether `T` is not `Send`, for the thread checks of debug builds.
+    /// Called as `(&&SendProbe::<T>(PhantomData)).affine()` like [`DebugProbe`].
+    #[cfg(feature = "std")]
+    pub struct SendProbe<T>(pub PhantomData<T>);
+
+    #[cfg(feature = "std")]
+    pub trait ViaSend<T> {
+        fn affine(&self) -> bool;
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: Send> ViaSend<T> for &SendProbe<T> {
+        fn affine(&self) -> bool {
+            false
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub trait ViaNotSend<T> {
+        fn affine(&self) -> bool;
+    }
+
+    #[cfg(feature = "std")]
+    impl<T> ViaNotSend<T> for SendProbe<T> {
+        fn affine(&self) -> bool {
+            true
+        }
+    }
+}
+
+/// Formats a task-local value, see [`__private::DebugProbe`].
+type FmtValue<T> = fn(&T, &mut fmt::Formatter<'_>) -> fmt::Result;
 
 /// Declares a new task-local key of type [`LocalKey`].
 ///
+/// The same macro is used with every backend: depending on the features
+/// enabled on this crate, it expands to a key backed by thread-local storage
+/// (`std`) or by the no_std storage. The expansion does not depend on `std`
+/// being available in the calling crate, so `#![no_std]` libraries can declare
+/// keys without any `cfg` blocks.
+///
 /// # Syntax
 ///
 /// The macro wraps any number of static declarations and makes them local to the current task.
@@ -125,50 +876,740 @@ use core::cell::UnsafeCell;
 /// # fn main() {}
 /// ```
 ///
+/// # Values
+///
+/// [`scope`](crate::LocalKey::scope) and
+/// [`sync_scope`](crate::LocalKey::sync_scope) take any value converting into
+/// the type of the key, so a `String` key can be scoped with a string literal.
+/// Integer literals therefore need a suffix for keys of other integer types
+/// than `i32`.
+///
+/// ```
+/// task_local::task_local! {
+///     static MESSAGE: String;
+///     static NUMBER: u32;
+/// }
+///
+/// MESSAGE.sync_scope("hello", || {
+///     NUMBER.sync_scope(1u32, || assert_eq!(MESSAGE.get(), "hello"));
+/// });
+/// ```
+///
+/// # Inheritance
+///
+/// With the `inherit` feature, a key annotated with `#[task_local(inherit)]`
+/// is copied into the tasks spawned with `spawn`, or scoped with an
+/// [`Inherited`](crate::Inherited), from a task where it is set. The value
+/// type must be `Clone` and `Send`. Keys are not inherited by default, so that
+/// large values are only copied where asked for.
+///
+/// ```ignore
+/// task_local::task_local! {
+///     /// The trace of the current request, also seen by spawned tasks.
+///     #[task_local(inherit)]
+///     pub static TRACE_ID: u64;
+/// }
+/// ```
+///
+/// # Poisoning
+///
+/// A key annotated with `#[task_local(poison)]` is poisoned, like a `Mutex`,
+/// when a closure passed to [`with`](crate::LocalKey::with) or
+/// [`try_with`](crate::LocalKey::try_with) panics. Once the panic is caught,
+/// `try_with` returns [`AccessError::Poisoned`](crate::AccessError::Poisoned)
+/// and `with` panics until the scope that was current exits, so that a value
+/// left half-updated is not read by mistake. Options can be combined, as in
+/// `#[task_local(inherit, poison)]`.
+///
+/// ```
+/// task_local::task_local! {
+///     /// The balance of the current transaction.
+///     #[task_local(poison)]
+///     pub static BALANCE: std::cell::Cell<i64>;
+/// }
+/// ```
+///
+/// # Zeroizing
+///
+/// With the `zeroize` feature, a key annotated with `#[task_local(zeroize)]`
+/// wipes the value of a scope with `Zeroize` right before
+/// dropping it: when a `sync_scope` returns or unwinds, and when a
+/// [`TaskLocalFuture`] is dropped, after completing or cancelled. The value
+/// type must implement `Zeroize`. Values handed back to the caller, by
+/// [`set`](crate::LocalKey::set) or [`TaskLocalFuture::take_value`], are not
+/// wiped, and neither are copies left behind when the value is moved into the
+/// scope.
+///
+/// ```ignore
+/// task_local::task_local! {
+///     /// The credentials of the current request.
+///     #[task_local(zeroize)]
+///     pub static API_TOKEN: zeroize::Zeroizing<String>;
+/// }
+/// ```
+///
+/// # Auditing
+///
+/// With the `audit` feature, every read of a key annotated with
+/// `#[task_local(audited)]`, through `with`, `get` and the functions built on
+/// them, is reported to the function registered with `set_audit_hook!`, with
+/// the name of the key and the location of the read.
+///
+/// ```ignore
+/// task_local::task_local! {
+///     /// The credentials of the current request, read under audit.
+///     #[task_local(audited, zeroize)]
+///     pub static API_TOKEN: zeroize::Zeroizing<String>;
+/// }
+/// ```
+///
+/// # Defaults from the environment
+///
+/// A key annotated with `#[task_local(env = "VARIABLE")]` falls back to a
+/// value parsed from the environment variable `VARIABLE` wherever no scope of
+/// the key is entered, which suits settings such as a log level, feature
+/// toggles or endpoints that a scope can override. The variable is read and
+/// parsed once, the first time the key is read outside of any scope, and the
+/// key is not set outside of scopes if the variable is missing or cannot be
+/// parsed. A default set with [`LocalKey::set_global_default`] takes
+/// precedence over the environment.
+///
+/// The value is parsed with `FromStr`, which can be spelled out as
+/// `parse = FromStr`, or with the function given as `parse = path::to::parse`,
+/// taking a `&str` and returning a `Result`. The value type must be `Send` and
+/// `Sync`, since the default is shared by all threads. Requires the `std`
+/// feature.
+///
+/// ```
+/// fn parse_flag(value: &str) -> Result<bool, String> {
+///     match value {
+///         "1" | "on" => Ok(true),
+///         "0" | "off" => Ok(false),
+///         _ => Err(format!("invalid flag `{value}`")),
+///     }
+/// }
+///
+/// task_local::task_local! {
+///     #[task_local(env = "LOG_LEVEL", parse = FromStr)]
+///     pub static LOG_LEVEL: u8;
+///
+///     #[task_local(env = "VERBOSE", parse = parse_flag)]
+///     pub static VERBOSE: bool;
+/// }
+/// ```
+///
+/// # C access
+///
+/// A key annotated with `#[task_local(c_export)]` gets an `extern "C"`
+/// getter, so that C code called from a scope, such as a driver, can read the
+/// value of the key. The value type must be `Copy` and have a C
+/// representation. The getter is exported as `task_local_get_` followed by the
+/// name of the key, copies the current value to `out` and returns `true`, or
+/// returns `false` if the key is not set or `out` is null:
+///
+/// ```c
+/// bool task_local_get_DEVICE_ID(uint32_t *out);
+/// ```
+///
+/// Symbol names are global and do not include the module of the key, so two
+/// keys with the same name in different modules or crates collide at link
+/// time. `#[task_local(c_export = "symbol")]` exports the getter under the
+/// given name instead:
+///
+/// ```
+/// task_local::task_local! {
+///     /// The device the current task talks to, read by the C drivers.
+///     #[task_local(c_export)]
+///     pub static DEVICE_ID: u32;
+///
+///     /// Exported as `net_task_local_get_DEVICE_ID`.
+///     #[task_local(c_export = "net_task_local_get_DEVICE_ID")]
+///     pub static NET_DEVICE_ID: u32;
+/// }
+/// ```
+///
+/// # Task slots
+///
+/// With the `embassy` and `rtic` features, every key has a table of slots,
+/// one for each task or priority level that is inside a scope of the key at
+/// the same time. The table is a `static` next to the key, with 8 slots per
+/// core by default. `#[task_local(tasks = N)]` sets the number of slots of a
+/// key, to save RAM on small targets or to allow more preempting contexts.
+/// It bounds how many tasks are inside a scope at once, not how deeply the
+/// scopes of one task nest, which needs no slots (see [`LocalKey`]).
+/// Entering a scope while all slots are claimed fails with a [`ScopeError`]
+/// from [`LocalKey::try_scope`] and [`LocalKey::try_sync_scope`], and panics
+/// with `scope` and `sync_scope`.
+///
+/// ```ignore
+/// task_local::task_local! {
+///     // Only used by the main task and one interrupt executor.
+///     #[task_local(tasks = 2)]
+///     static SENSOR_ID: u8;
+/// }
+/// ```
+///
 /// See [`LocalKey` documentation][`LocalKey`] for more information.
 #[macro_export]
 macro_rules! task_local {
      // empty (base case for the recursion)
     () => {};
 
-    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty; $($rest:tt)*) => {
-        $crate::__task_local_inner!($(#[$attr])* $vis $name, $t);
-        $crate::task_local!($($rest)*);
+    ($($tokens:tt)+) => {
+        $crate::__task_local_attrs!([] [] $($tokens)+);
     };
-
-    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty) => {
-        $crate::__task_local_inner!($(#[$attr])* $vis $name, $t);
-    }
 }
 
-// Conditional implementation based on std feature
-#[cfg(feature = "std")]
+// Collects the attributes of a declaration, taking out `#[task_local(...)]`
+// options as `[name]` or `[name = value]` groups, then declares the key and
+// continues with the next declaration.
 #[doc(hidden)]
 #[macro_export]
-macro_rules! __task_local_inner {
-    ($(#[$attr:meta])* $vis:vis $name:ident, $t:ty) => {
-        $(#[$attr])*
-        $vis static $name: $crate::LocalKey<$t> = {
-            std::thread_local! {
-                static __KEY: std::cell::RefCell<Option<$t>> = const { std::cell::RefCell::new(None) };
-            }
+macro_rules! __task_local_attrs {
+    (
+        [$($attrs:tt)*] [$($opts:tt)*]
+        #[task_local($($opt:ident $(= $val:tt $(:: $path:tt)*)?),+ $(,)?)] $($rest:tt)*
+    ) => {
+        $crate::__task_local_attrs!(
+            [$($attrs)*] [$($opts)* $([$opt $(= $val $(:: $path)*)?])+] $($rest)*
+        );
+    };
 
-            $crate::LocalKey { inner: __KEY }
-        };
+    ([$($attrs:tt)*] [$($opts:tt)*] #[$attr:meta] $($rest:tt)*) => {
+        $crate::__task_local_attrs!([$($attrs)* #[$attr]] [$($opts)*] $($rest)*);
+    };
+
+    ([$($attrs:tt)*] [$($opts:tt)*] $vis:vis static $name:ident: $t:ty; $($rest:tt)*) => {
+        $crate::__task_local_inner!($($attrs)* [$($opts)*] $vis $name, $t);
+        $crate::__task_local_c_export!([$($opts)*] $name, $t);
+        $crate::task_local!($($rest)*);
+    };
+
+    ([$($attrs:tt)*] [$($opts:tt)*] $vis:vis static $name:ident: $t:ty) => {
+        $crate::__task_local_inner!($($attrs)* [$($opts)*] $vis $name, $t);
+        $crate::__task_local_c_export!([$($opts)*] $name, $t);
     };
 }
 
-#[cfg(not(feature = "std"))]
+// Applies the `#[task_local(...)]` options of a declaration to the key built
+// by `$key`.
 #[doc(hidden)]
 #[macro_export]
-macro_rules! __task_local_inner {
-    ($(#[$attr:meta])* $vis:vis $name:ident, $t:ty) => {
-        $(#[$attr])*
-        $vis static $name: $crate::LocalKey<$t> = $crate::LocalKey::new();
+macro_rules! __task_local_options {
+    ([] $t:ty, $key:expr) => {
+        $crate::__task_local_affine!($t, $crate::__task_local_capture!($t, $key))
     };
-}
-
-/// A key for task-local data.
+    ([[inherit] $($rest:tt)*] $t:ty, $key:expr) => {
+        $crate::__task_local_options!(
+            [$($rest)*] $t, $crate::__task_local_inherit!([inherit] $key)
+        )
+    };
+    ([[poison] $($rest:tt)*] $t:ty, $key:expr) => {
+        $crate::__task_local_options!([$($rest)*] $t, $key.__poison())
+    };
+    ([[audited] $($rest:tt)*] $t:ty, $key:expr) => {
+        $crate::__task_local_options!([$($rest)*] $t, $crate::__task_local_audited!([audited] $key))
+    };
+    ([[zeroize] $($rest:tt)*] $t:ty, $key:expr) => {
+        $crate::__task_local_options!([$($rest)*] $t, $crate::__task_local_zeroize!([zeroize] $key))
+    };
+    // Handled by `__task_local_c_export`, which declares an item.
+    ([[c_export] $($rest:tt)*] $t:ty, $key:expr) => {
+        $crate::__task_local_options!([$($rest)*] $t, $key)
+    };
+    ([[c_export = $symbol:literal] $($rest:tt)*] $t:ty, $key:expr) => {
+        $crate::__task_local_options!([$($rest)*] $t, $key)
+    };
+    ([[env = $var:literal] [parse = FromStr] $($rest:tt)*] $t:ty, $key:expr) => {
+        $crate::__task_local_options!([$($rest)*] $t, $crate::__task_local_env!(
+            $key, $t, $var, |value: &str| value.parse::<$t>().ok()
+        ))
+    };
+    ([[env = $var:literal] [parse = $parse:path] $($rest:tt)*] $t:ty, $key:expr) => {
+        $crate::__task_local_options!([$($rest)*] $t, $crate::__task_local_env!(
+            $key, $t, $var, |value: &str| $parse(value).ok()
+        ))
+    };
+    ([[env = $var:literal] $($rest:tt)*] $t:ty, $key:expr) => {
+        $crate::__task_local_options!([$($rest)*] $t, $crate::__task_local_env!(
+            $key, $t, $var, |value: &str| value.parse::<$t>().ok()
+        ))
+    };
+    ([[tasks = $tasks:literal] $($rest:tt)*] $t:ty, $key:expr) => {
+        $crate::__task_local_options!([$($rest)*] $t, $crate::__task_local_tasks!([tasks] $key))
+    };
+    ([[parse $($val:tt)*] $($rest:tt)*] $t:ty, $key:expr) => {
+        ::core::compile_error!(
+            "`parse` in `#[task_local(...)]` must directly follow `env = \"VARIABLE\"`"
+        )
+    };
+    ([[$opt:ident $($val:tt)*] $($rest:tt)*] $t:ty, $key:expr) => {
+        ::core::compile_error!(::core::concat!(
+            "unknown option `",
+            ::core::stringify!($opt),
+            "` in `#[task_local(...)]`, expected `inherit`, `poison`, `zeroize`, `audited`, `c_export`, `env` or `tasks`"
+        ))
+    };
+}
+
+// Expands to the number of slots per core of the slot table of a key, given
+// with `#[task_local(tasks = N)]`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_slot_count {
+    ([]) => {
+        $crate::__private::TASK_SLOTS
+    };
+    ([[tasks = $tasks:literal] $($rest:tt)*]) => {
+        $tasks
+    };
+    ([[$($opt:tt)*] $($rest:tt)*]) => {
+        $crate::__task_local_slot_count!([$($rest)*])
+    };
+}
+
+// Accepts `#[task_local(tasks = N)]` in builds with slot tables, where the
+// table is sized by `__task_local_inner`.
+#[cfg(all(not(feature = "std"), any(feature = "embassy", feature = "rtic")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_tasks {
+    ([tasks] $key:expr) => {
+        $key
+    };
+}
+
+#[cfg(not(all(not(feature = "std"), any(feature = "embassy", feature = "rtic"))))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_tasks {
+    ([tasks] $key:expr) => {
+        ::core::compile_error!(
+            "`#[task_local(tasks = N)]` requires the `embassy` or `rtic` feature in a no_std build"
+        )
+    };
+}
+
+// Marks the key built by `$key` as inheritable, for a declaration annotated
+// with `#[task_local(inherit)]`.
+#[cfg(feature = "inherit")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_inherit {
+    ([inherit] $key:expr) => {
+        $key.__inherit()
+    };
+}
+
+#[cfg(not(feature = "inherit"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_inherit {
+    ([inherit] $key:expr) => {
+        ::core::compile_error!(
+            "`#[task_local(inherit)]` requires the `inherit` feature of `task-local`"
+        )
+    };
+}
+
+// Makes the key built by `$key` wipe its values, for keys declared with
+// `#[task_local(zeroize)]`.
+#[cfg(feature = "zeroize")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_zeroize {
+    ([zeroize] $key:expr) => {
+        $key.__zeroize()
+    };
+}
+
+#[cfg(not(feature = "zeroize"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_zeroize {
+    ([zeroize] $key:expr) => {
+        ::core::compile_error!(
+            "`#[task_local(zeroize)]` requires the `zeroize` feature of `task-local`"
+        )
+    };
+}
+
+// Makes the key built by `$key` report its reads, for keys declared with
+// `#[task_local(audited)]`.
+#[cfg(feature = "audit")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_audited {
+    ([audited] $key:expr) => {
+        $key.__audited()
+    };
+}
+
+#[cfg(not(feature = "audit"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_audited {
+    ([audited] $key:expr) => {
+        ::core::compile_error!(
+            "`#[task_local(audited)]` requires the `audit` feature of `task-local`"
+        )
+    };
+}
+
+// Declares the C getter of a key annotated with `#[task_local(c_export)]`, and
+// nothing for other keys.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_c_export {
+    ([] $name:ident, $t:ty) => {};
+    ([[c_export] $($rest:tt)*] $name:ident, $t:ty) => {
+        $crate::__task_local_c_getter!(
+            ::core::concat!("task_local_get_", ::core::stringify!($name)),
+            $name,
+            $t
+        );
+    };
+    ([[c_export = $symbol:literal] $($rest:tt)*] $name:ident, $t:ty) => {
+        $crate::__task_local_c_getter!($symbol, $name, $t);
+    };
+    ([$opt:tt $($rest:tt)*] $name:ident, $t:ty) => {
+        $crate::__task_local_c_export!([$($rest)*] $name, $t);
+    };
+}
+
+// Declares the C getter of the key `$name`, exported as `$symbol`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_c_getter {
+    ($symbol:expr, $name:ident, $t:ty) => {
+        const _: () = {
+            #[export_name = $symbol]
+            extern "C" fn get(out: *mut $t) -> bool {
+                match $name.try_with(|value: &$t| *value) {
+                    Ok(value) if !out.is_null() => {
+                        // Safety: The C caller passes a pointer that is valid
+                        // for writes, checked to be non-null.
+                        unsafe { out.write(value) };
+                        true
+                    }
+                    _ => false,
+                }
+            }
+        };
+    };
+}
+
+// Gives the key built by `$key` a default value parsed once from the
+// environment variable `$var` with `$parse`, for a declaration annotated with
+// `#[task_local(env = "...")]`.
+#[cfg(feature = "std")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_env {
+    ($key:expr, $t:ty, $var:literal, $parse:expr) => {
+        $key.__default({
+            fn default() -> ::core::option::Option<&'static $t> {
+                static VALUE: $crate::__private::OnceLock<::core::option::Option<$t>> =
+                    $crate::__private::OnceLock::new();
+                VALUE
+                    .get_or_init(|| $crate::__private::parse_env($var, $parse))
+                    .as_ref()
+            }
+            default
+        })
+    };
+}
+
+#[cfg(not(feature = "std"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_env {
+    ($key:expr, $t:ty, $var:literal, $parse:expr) => {
+        ::core::compile_error!(
+            "`#[task_local(env = \"...\")]` requires the `std` feature of `task-local`"
+        )
+    };
+}
+
+// Conditional implementation based on std feature
+#[cfg(all(
+    feature = "std",
+    not(loom),
+    not(feature = "custom-storage"),
+    not(all(
+        target_arch = "wasm32",
+        not(target_feature = "atomics"),
+        not(feature = "forbid-unsafe")
+    ))
+))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_inner {
+    ($(#[$attr:meta])* [$($opts:tt)*] $vis:vis $name:ident, $t:ty) => {
+        $(#[$attr])*
+        $vis static $name: $crate::LocalKey<$t> = {
+            $crate::__private::thread_local! {
+                static __KEY: $crate::__private::ValueCell<$t> =
+                    const { $crate::__private::ValueCell::new() };
+            }
+
+            $crate::__task_local_options!([$($opts)*] $t, $crate::LocalKey::__new(
+                ::core::stringify!($name),
+                ::core::module_path!(),
+                $crate::__task_local_fmt_value!($t),
+                __KEY,
+            ))
+        };
+    };
+}
+
+// With `custom-storage` the storage is found by the storage provider, see
+// `provider.rs`.
+#[cfg(all(feature = "custom-storage", not(loom)))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_inner {
+    ($(#[$attr:meta])* [$($opts:tt)*] $vis:vis $name:ident, $t:ty) => {
+        $(#[$attr])*
+        $vis static $name: $crate::LocalKey<$t> =
+            $crate::__task_local_options!([$($opts)*] $t, $crate::LocalKey::__new(
+                ::core::stringify!($name),
+                ::core::module_path!(),
+                $crate::__task_local_fmt_value!($t),
+                $crate::__private::ProvidedStorage::new($crate::__private::ValueCell::<$t>::new),
+            ));
+    };
+}
+
+// Single-threaded WebAssembly keeps the storage in the key itself, see
+// `sync::single_thread`.
+#[cfg(all(
+    feature = "std",
+    not(loom),
+    target_arch = "wasm32",
+    not(target_feature = "atomics"),
+    not(feature = "forbid-unsafe")
+))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_inner {
+    ($(#[$attr:meta])* [$($opts:tt)*] $vis:vis $name:ident, $t:ty) => {
+        $(#[$attr])*
+        $vis static $name: $crate::LocalKey<$t> =
+            $crate::__task_local_options!([$($opts)*] $t, $crate::LocalKey::__new(
+                ::core::stringify!($name),
+                ::core::module_path!(),
+                $crate::__task_local_fmt_value!($t),
+                $crate::__private::SingleThread::new($crate::__private::ValueCell::new()),
+            ));
+    };
+}
+
+#[cfg(all(not(feature = "std"), not(loom), not(any(feature = "embassy", feature = "rtic"))))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_inner {
+    ($(#[$attr:meta])* [$($opts:tt)*] $vis:vis $name:ident, $t:ty) => {
+        $(#[$attr])*
+        $vis static $name: $crate::LocalKey<$t> =
+            $crate::__task_local_options!([$($opts)*] $t, $crate::LocalKey::__new(
+                ::core::stringify!($name),
+                ::core::module_path!(),
+                $crate::__task_local_fmt_value!($t),
+            ));
+    };
+}
+
+// With `embassy` and `rtic` the slot table of the key is declared next to it,
+// see `slots.rs`.
+#[cfg(all(not(feature = "std"), not(loom), any(feature = "embassy", feature = "rtic")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_inner {
+    ($(#[$attr:meta])* [$($opts:tt)*] $vis:vis $name:ident, $t:ty) => {
+        $(#[$attr])*
+        $vis static $name: $crate::LocalKey<$t> = {
+            static __SLOTS: [
+                $crate::__private::TaskSlot<$t>;
+                $crate::__task_local_slot_count!([$($opts)*]) * $crate::__private::MAX_CORES
+            ] = [const { $crate::__private::TaskSlot::new() };
+                $crate::__task_local_slot_count!([$($opts)*]) * $crate::__private::MAX_CORES];
+
+            $crate::__task_local_options!([$($opts)*] $t, $crate::LocalKey::__new(
+                ::core::stringify!($name),
+                ::core::module_path!(),
+                $crate::__task_local_fmt_value!($t),
+                &__SLOTS,
+            ))
+        };
+    };
+}
+
+// Loom types cannot be created in constant contexts, so under `cfg(loom)` the
+// key is created on first use in every model execution, like a loom
+// `lazy_static!`, and the static only dereferences to it.
+#[cfg(loom)]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_inner {
+    ($(#[$attr:meta])* [$($opts:tt)*] $vis:vis $name:ident, $t:ty) => {
+        $(#[$attr])*
+        $vis static $name: $name = $name { __private: () };
+
+        #[doc(hidden)]
+        #[allow(non_camel_case_types)]
+        $vis struct $name {
+            __private: (),
+        }
+
+        impl ::core::ops::Deref for $name {
+            type Target = $crate::LocalKey<$t>;
+
+            fn deref(&self) -> &$crate::LocalKey<$t> {
+                static KEY: $crate::__private::loom::lazy_static::Lazy<$crate::LocalKey<$t>> =
+                    $crate::__private::loom::lazy_static::Lazy {
+                        init: || {
+                            $crate::__task_local_options!(
+                                [$($opts)*] $t, $crate::__task_local_loom_new!([$($opts)*] $name, $t)
+                            )
+                        },
+                        _p: ::core::marker::PhantomData,
+                    };
+                KEY.get()
+            }
+        }
+    };
+}
+
+#[cfg(all(feature = "std", loom))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_loom_new {
+    ([$($opts:tt)*] $name:ident, $t:ty) => {{
+        $crate::__private::loom::thread_local! {
+            static __KEY: $crate::__private::ValueCell<$t> = $crate::__private::ValueCell::new();
+        }
+
+        $crate::LocalKey::__new(
+            ::core::stringify!($name),
+            ::core::module_path!(),
+            $crate::__task_local_fmt_value!($t),
+            &__KEY,
+        )
+    }};
+}
+
+#[cfg(all(not(feature = "std"), loom, not(any(feature = "embassy", feature = "rtic"))))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_loom_new {
+    ([$($opts:tt)*] $name:ident, $t:ty) => {
+        $crate::LocalKey::__new(
+            ::core::stringify!($name),
+            ::core::module_path!(),
+            $crate::__task_local_fmt_value!($t),
+        )
+    };
+}
+
+#[cfg(all(not(feature = "std"), loom, any(feature = "embassy", feature = "rtic")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_loom_new {
+    ([$($opts:tt)*] $name:ident, $t:ty) => {{
+        static __SLOTS: $crate::__private::loom::lazy_static::Lazy<
+            [$crate::__private::TaskSlot<$t>; $crate::__task_local_slot_count!([$($opts)*]) * $crate::__private::MAX_CORES],
+        > = $crate::__private::loom::lazy_static::Lazy {
+            init: || ::core::array::from_fn(|_| $crate::__private::TaskSlot::new()),
+            _p: ::core::marker::PhantomData,
+        };
+
+        $crate::LocalKey::__new(
+            ::core::stringify!($name),
+            ::core::module_path!(),
+            $crate::__task_local_fmt_value!($t),
+            __SLOTS.get(),
+        )
+    }};
+}
+
+// Expands to a function formatting values of type `$t` with `Debug` if
+// implemented, and a placeholder otherwise.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_fmt_value {
+    ($t:ty) => {{
+        // The signature is fixed by `LocalKey`, whatever `$t` is.
+        #[allow(clippy::ptr_arg, clippy::borrowed_box)]
+        fn fmt_value(value: &$t, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
+            #[allow(unused_imports)]
+            use $crate::__private::{ViaDebug as _, ViaOpaque as _, ViaRedacted as _};
+            let probe = $crate::__private::DebugProbe::<$t>(::core::marker::PhantomData);
+            (&&&probe).fmt_value(value, f)
+        }
+        fmt_value
+    }};
+}
+
+// Expands to `$key` set up to capture its values with `Clone` if `$t`
+// implements `Clone` and `Send`, see `__private::CloneProbe`.
+#[cfg(feature = "alloc")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_capture {
+    ($t:ty, $key:expr) => {
+        $key.__capture({
+            // The signature is fixed by `LocalKey`, whatever `$t` is.
+            #[allow(clippy::ptr_arg, clippy::borrowed_box)]
+            fn capture(value: &$t) -> ::core::option::Option<$crate::AnyValue> {
+                #[allow(unused_imports)]
+                use $crate::__private::{ViaClone as _, ViaNoClone as _};
+                let probe = $crate::__private::CloneProbe::<$t>(::core::marker::PhantomData);
+                (&&probe).capture_value(value)
+            }
+            capture
+        })
+    };
+}
+
+#[cfg(not(feature = "alloc"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_capture {
+    ($t:ty, $key:expr) => {
+        $key
+    };
+}
+
+// Expands to `$key` set up to check the thread its scopes are entered on if
+// `$t` is not `Send`, see `__private::SendProbe`.
+#[cfg(feature = "std")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_affine {
+    ($t:ty, $key:expr) => {
+        $key.__affine({
+            fn affine() -> bool {
+                #[allow(unused_imports)]
+                use $crate::__private::{ViaNotSend as _, ViaSend as _};
+                let probe = $crate::__private::SendProbe::<$t>(::core::marker::PhantomData);
+                (&&probe).affine()
+            }
+            affine
+        })
+    };
+}
+
+#[cfg(not(feature = "std"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __task_local_affine {
+    ($t:ty, $key:expr) => {
+        $key
+    };
+}
+
+/// A key for task-local data.
 ///
 /// This type is generated by the [`task_local!`] macro.
 ///
@@ -185,55 +1626,251 @@ macro_rules! __task_local_inner {
 ///     static NUMBER: u32;
 /// }
 ///
-/// NUMBER.scope(1, async move {
+/// NUMBER.scope(1u32, async move {
 ///     assert_eq!(NUMBER.get(), 1);
 /// }).await;
 ///
-/// NUMBER.scope(2, async move {
+/// NUMBER.scope(2u32, async move {
 ///     assert_eq!(NUMBER.get(), 2);
 ///
-///     NUMBER.scope(3, async move {
+///     NUMBER.scope(3u32, async move {
 ///         assert_eq!(NUMBER.get(), 3);
 ///     }).await;
 /// }).await;
 /// # }
 /// ```
 ///
+/// # Values that are not `Send`
+///
+/// Values are stored per thread, so the value type does not need to be
+/// `Send`. A [`TaskLocalFuture`] holding such a value is not `Send` either,
+/// which makes the compiler check that every access happens on the thread the
+/// scope was created on: the future can only run on a single-threaded
+/// executor, such as a Tokio `LocalSet`.
+///
+/// ```
+/// # async fn dox() {
+/// use std::cell::RefCell;
+/// use std::rc::Rc;
+///
+/// task_local::task_local! {
+///     static CACHE: Rc<RefCell<Vec<u32>>>;
+/// }
+///
+/// let local = tokio::task::LocalSet::new();
+/// local
+///     .run_until(async {
+///         let cache = Rc::new(RefCell::new(Vec::new()));
+///         let task = CACHE.scope(cache.clone(), async {
+///             CACHE.with(|cache| cache.borrow_mut().push(1));
+///         });
+///         tokio::task::spawn_local(task).await.unwrap();
+///         assert_eq!(*cache.borrow(), [1]);
+///     })
+///     .await;
+/// # }
+/// ```
+///
+/// Unsafe code can still move such a future to another thread. In debug
+/// builds, a scope of a key whose value type is not `Send` remembers the
+/// thread it was first entered on, and entering it on another thread panics.
+///
+/// # Panics and unwinding
+///
+/// If the closure of a [`sync_scope`](Self::sync_scope) or a future polled
+/// in a [`scope`](Self::scope) panics, the value of the enclosing scope is
+/// restored while the panic unwinds, and the key can be used again once the
+/// panic is caught. A future that panics is dropped with its value still set,
+/// and is never polled again. [`sync_scope_catch_unwind`] and
+/// [`scope_catch_unwind`] catch the panic at the scope boundary.
+///
+/// A key declared with `#[task_local(poison)]` is poisoned by a panic in
+/// [`with`](Self::with) caught inside the scope, see [`task_local!`].
+///
 /// [`std::thread::LocalKey`]: struct@std::thread::LocalKey
+/// [`sync_scope_catch_unwind`]: Self::sync_scope_catch_unwind
+/// [`scope_catch_unwind`]: Self::scope_catch_unwind
 #[cfg(feature = "std")]
 pub struct LocalKey<T: 'static> {
-    #[doc(hidden)]
-    pub inner: thread::LocalKey<RefCell<Option<T>>>,
+    inner: sync::ThreadLocal<ValueCell<T>>,
+    watch: WatchState,
+    name: &'static str,
+    module_path: &'static str,
+    fmt_value: FmtValue<T>,
+    // Captures values for `AnyLocalKey::capture`, see `any_key.rs`.
+    #[cfg(feature = "alloc")]
+    capture: any_key::CaptureValue<T>,
+    poison: bool,
+    // Wipes the values of scopes, see `zeroize.rs`.
+    #[cfg(feature = "zeroize")]
+    zeroize: Option<fn(&mut T)>,
+    // Whether reads are reported to the audit hook, see `audit.rs`.
+    #[cfg(feature = "audit")]
+    audited: bool,
+    // The value read outside of any scope, see `env.rs`.
+    default: Option<fn() -> Option<&'static T>>,
+    // The value set with `set_global_default`, see `global.rs`.
+    global: global::Global,
+    // Whether the value type is not `Send`, see `affinity.rs`.
+    #[cfg(all(debug_assertions, not(loom)))]
+    affine: affinity::IsAffine,
+    #[cfg(feature = "registry")]
+    node: registry::Node,
+    #[cfg(feature = "inherit")]
+    inherit: inherit::Node,
+    #[cfg(feature = "stats")]
+    stats: stats::Counters,
 }
 
 /// A key for task-local data in no_std environments.
 ///
 /// This is a simplified version that works well with single-threaded
-/// embedded systems like those using Embassy.
+/// embedded systems like those using Embassy. Nested scopes of the same key
+/// behave exactly like in the std version: the outer value is hidden while
+/// the inner scope runs and visible again afterwards.
+///
+/// There is no nesting depth to configure. The value of every scope stays in
+/// its own future or stack frame, and the key only points to the innermost
+/// one, so a nested scope takes no RAM of the key and there is no table of
+/// scopes that could overflow. Like recursion, nesting is only bounded by the
+/// stack, or by the size of the futures holding the scopes.
+///
+/// With the `per-core` feature, each core has its own copy of the storage.
+///
+/// Since the key is a `static` shared by all tasks, the value type must be
+/// `Send`. Types like `Cell` and `RefCell` can be stored, but `Rc` cannot.
+/// Unlike the std version, which stores values per thread, the no_std
+/// backend has no thread to tie such values to.
+///
+/// As in the std version, the value of the enclosing scope is restored, and
+/// the slot of the task released, while a panic unwinds out of a scope.
 #[cfg(not(feature = "std"))]
 pub struct LocalKey<T: 'static> {
-    inner: UnsafeCell<Option<T>>,
+    #[cfg(not(any(feature = "embassy", feature = "rtic")))]
+    inner: [ValueCell<T>; MAX_CORES],
+    #[cfg(any(feature = "embassy", feature = "rtic"))]
+    inner: TaskSlots<T>,
+    watch: WatchState,
+    name: &'static str,
+    module_path: &'static str,
+    fmt_value: FmtValue<T>,
+    // Captures values for `AnyLocalKey::capture`, see `any_key.rs`.
+    #[cfg(feature = "alloc")]
+    capture: any_key::CaptureValue<T>,
+    poison: bool,
+    // Wipes the values of scopes, see `zeroize.rs`.
+    #[cfg(feature = "zeroize")]
+    zeroize: Option<fn(&mut T)>,
+    // Whether reads are reported to the audit hook, see `audit.rs`.
+    #[cfg(feature = "audit")]
+    audited: bool,
+    #[cfg(feature = "registry")]
+    node: registry::Node,
+    #[cfg(feature = "inherit")]
+    inherit: inherit::Node,
+    #[cfg(feature = "stats")]
+    stats: stats::Counters,
 }
 
-// Safety: LocalKey is safe to share between tasks in single-threaded embedded systems
-#[cfg(not(feature = "std"))]
-unsafe impl<T: 'static> Sync for LocalKey<T> {}
+// Safety: The key behaves like a mutex around the stored values. Values are
+// reached through the key by whichever task is running and may be replaced,
+// and the old value dropped, by another one, so they must be `Send`. Every
+// access is exclusive: in single-threaded embedded systems tasks run one at a
+// time, with the `critical-section` feature every access happens inside a
+// critical section, and with the `embassy` and `rtic` features every context
+// only ever touches its own slot.
+//
+// `peek_from_isr` reads the value of the context an interrupt handler
+// preempted, possibly while that context is inside `with`. The read happens
+// in a critical section like every other access, and goes through the borrow
+// flag of the cell, so it copies the value only when the preempted context
+// does not borrow it mutably, and that context does not run until the handler
+// returns. `T: Sync` is therefore not required, just like for `Mutex<T>`.
+// `DoubleBuffered` keys, which are read without a critical section, are a
+// separate type with their own bounds.
+//
+// With the `forbid-unsafe` feature the values are kept in a
+// `critical_section::Mutex` instead, which gives the same bounds.
+#[cfg(all(not(feature = "std"), not(feature = "forbid-unsafe")))]
+unsafe impl<T: Send + 'static> Sync for LocalKey<T> {}
+#[cfg(all(not(feature = "std"), not(feature = "forbid-unsafe")))]
+unsafe impl<T: Send + 'static> Send for LocalKey<T> {}
+
+/// Runs `f` with exclusive access to task-local storage.
+///
+/// With the `critical-section`, `embassy` or `rtic` feature this enters a
+/// critical section, so that other cores and interrupt handlers cannot touch
+/// the storage concurrently. Otherwise the environment is assumed to be
+/// single-threaded.
 #[cfg(not(feature = "std"))]
-unsafe impl<T: 'static> Send for LocalKey<T> {}
+#[inline]
+pub(crate) fn exclusive<R>(f: impl FnOnce() -> R) -> R {
+    #[cfg(any(feature = "critical-section", feature = "embassy", feature = "rtic"))]
+    return critical_section::with(|_| f());
+    #[cfg(not(any(feature = "critical-section", feature = "embassy", feature = "rtic")))]
+    return f();
+}
 
 // Implementation for no_std
 #[cfg(not(feature = "std"))]
 impl<T: 'static> LocalKey<T> {
-    /// Creates a new LocalKey for no_std environments.
-    pub const fn new() -> Self {
-        Self {
-            inner: UnsafeCell::new(None),
+    const_fn! {
+        #[doc(hidden)]
+        pub fn __new(
+            name: &'static str,
+            module_path: &'static str,
+            fmt_value: FmtValue<T>,
+            #[cfg(any(feature = "embassy", feature = "rtic"))] slots: &'static [slots::TaskSlot<T>],
+        ) -> Self {
+            Self {
+                #[cfg(not(any(feature = "embassy", feature = "rtic")))]
+                inner: [const { ValueCell::new() }; MAX_CORES],
+                #[cfg(any(feature = "embassy", feature = "rtic"))]
+                inner: TaskSlots::new(slots),
+                watch: WatchState::new(),
+                name,
+                module_path,
+                fmt_value,
+                #[cfg(feature = "alloc")]
+                capture: any_key::no_capture::<T>,
+                poison: false,
+                #[cfg(feature = "zeroize")]
+                zeroize: None,
+                #[cfg(feature = "audit")]
+                audited: false,
+                #[cfg(feature = "registry")]
+                node: registry::Node::new::<T>(),
+                #[cfg(feature = "inherit")]
+                inherit: inherit::Node::new(),
+                #[cfg(feature = "stats")]
+                stats: stats::Counters::new(),
+            }
         }
     }
 
+    /// Returns the cell holding the value of the current task, if any.
+    fn cell(&'static self) -> Option<&'static ValueCell<T>> {
+        #[cfg(not(any(feature = "embassy", feature = "rtic")))]
+        return per_core::current().and_then(|core| self.inner.get(core));
+        #[cfg(any(feature = "embassy", feature = "rtic"))]
+        return self.inner.current();
+    }
+
+    /// Takes the value of the current scope out of the storage, see
+    /// [`DropPolicy::InsideScope`].
+    fn take_current(&'static self) -> Option<T> {
+        exclusive(|| self.cell().and_then(ValueCell::take))
+    }
+
     /// Sets a value `T` as the task-local value for the future `F`.
     ///
-    /// On completion of `scope`, the task-local will be dropped.
+    /// The value can be given as any type converting into `T`, such as a
+    /// `&str` for a `String` key. An integer literal needs a suffix when `T`
+    /// is not `i32`, as in `1u32`, since its type cannot be inferred.
+    ///
+    /// The task-local value is dropped when the returned future is dropped,
+    /// outside of the scope. See [`TaskLocalFuture::drop_policy`] to drop it
+    /// when the future completes instead.
     ///
     /// ### Panics
     ///
@@ -249,7 +1886,7 @@ impl<T: 'static> LocalKey<T> {
     ///     static NUMBER: u32;
     /// }
     ///
-    /// NUMBER.scope(1, async move {
+    /// NUMBER.scope(1u32, async move {
     ///     // println! not available in no_std, but you get the idea
     ///     assert_eq!(NUMBER.get(), 1);
     /// }).await;
@@ -258,26 +1895,38 @@ impl<T: 'static> LocalKey<T> {
     ///
     /// [`with`]: fn@Self::with
     /// [`try_with`]: fn@Self::try_with
-    pub fn scope<F>(&'static self, value: T, f: F) -> TaskLocalFuture<T, F>
+    pub fn scope<F>(&'static self, value: impl Into<T>, f: F) -> TaskLocalFuture<T, F>
     where
         F: Future,
     {
         TaskLocalFuture {
             local: self,
-            slot: Some(value),
+            slot: Some(value.into()),
             future: Some(f),
+            entered: false,
+            drop_policy: DropPolicy::OutsideScope,
+            state: ScopeState::new(),
+            leak: track_scope(self),
             _pinned: PhantomPinned,
         }
     }
 
     /// Sets a value `T` as the task-local value for the closure `F`.
     ///
-    /// On completion of `sync_scope`, the task-local will be dropped.
+    /// The value can be given as any type converting into `T`, such as a
+    /// `&str` for a `String` key. An integer literal needs a suffix when `T`
+    /// is not `i32`, as in `1u32`, since its type cannot be inferred.
+    ///
+    /// On completion of `sync_scope`, the task-local will be dropped, after the
+    /// value of the enclosing scope is restored. See
+    /// [`sync_scope_with_drop_policy`](Self::sync_scope_with_drop_policy) to
+    /// drop it inside the scope instead.
     ///
     /// ### Panics
     ///
     /// This method panics if called inside a call to [`with`] or [`try_with`]
-    /// on the same `LocalKey`, or if interrupts occur during execution.
+    /// on the same `LocalKey`. For a non-panicking variant, see
+    /// [`try_sync_scope`](fn@Self::try_sync_scope).
     ///
     /// ### Examples
     ///
@@ -287,53 +1936,152 @@ impl<T: 'static> LocalKey<T> {
     ///     static NUMBER: u32;
     /// }
     ///
-    /// NUMBER.sync_scope(1, || {
+    /// NUMBER.sync_scope(1u32, || {
     ///     assert_eq!(NUMBER.get(), 1);
     /// });
     /// ```
     ///
     /// [`with`]: fn@Self::with
     /// [`try_with`]: fn@Self::try_with
+    #[cfg(not(feature = "panic-free"))]
     #[track_caller]
-    pub fn sync_scope<F, R>(&'static self, value: T, f: F) -> R
+    pub fn sync_scope<F, R>(&'static self, value: impl Into<T>, f: F) -> R
     where
         F: FnOnce() -> R,
     {
-        let mut value = Some(value);
-        match self.scope_inner(&mut value, f) {
+        match self.try_sync_scope(value, f) {
             Ok(res) => res,
-            Err(err) => err.panic(),
+            Err(err) => err.kind.panic(self.name),
         }
     }
 
-    fn scope_inner<F, R>(&'static self, slot: &mut Option<T>, f: F) -> Result<R, ScopeInnerErr>
+    /// Sets a value `T` as the task-local value for the future `F`, without
+    /// panicking if the scope cannot be entered.
+    ///
+    /// Behaves like [`scope`], except that the returned future resolves to a
+    /// [`ScopeError`] instead of panicking when the storage cannot be entered,
+    /// for example because it is borrowed by [`with`] or [`try_with`].
+    ///
+    /// The value can be given as any type converting into `T`, like in
+    /// [`scope`].
+    ///
+    /// ### Examples
+    ///
+    /// ```ignore
+    /// # use task_local::task_local;
+    /// # async fn dox() {
+    /// task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// let res = NUMBER.try_scope(1u32, async move { NUMBER.get() }).await;
+    /// assert_eq!(res, Ok(1));
+    /// # }
+    /// ```
+    ///
+    /// [`scope`]: fn@Self::scope
+    /// [`with`]: fn@Self::with
+    /// [`try_with`]: fn@Self::try_with
+    pub fn try_scope<F>(&'static self, value: impl Into<T>, f: F) -> TryTaskLocalFuture<T, F>
     where
-        F: FnOnce() -> R,
+        F: Future,
     {
-        struct Guard<'a, T: 'static> {
-            local: &'static LocalKey<T>,
-            slot: &'a mut Option<T>,
-        }
-
-        impl<T: 'static> Drop for Guard<'_, T> {
-            fn drop(&mut self) {
-                // Safety: We're in single-threaded no_std environment
-                // and we own the slot data
-                unsafe {
-                    let inner_ptr = self.local.inner.get();
-                    mem::swap(self.slot, &mut *inner_ptr);
-                }
-            }
+        TryTaskLocalFuture {
+            inner: self.scope(value, f),
         }
+    }
+
+    /// Sets a value `T` as the task-local value for the closure `F`, without
+    /// panicking if the scope cannot be entered.
+    ///
+    /// Behaves like [`sync_scope`], except that a [`ScopeError`] is returned
+    /// instead of panicking when the storage cannot be entered, for example
+    /// because it is borrowed by [`with`] or [`try_with`]. In that case `f` is
+    /// not called and `value` is dropped.
+    ///
+    /// The value can be given as any type converting into `T`, like in
+    /// [`sync_scope`].
+    ///
+    /// ### Examples
+    ///
+    /// ```ignore
+    /// # use task_local::task_local;
+    /// task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// let res = NUMBER.try_sync_scope(1u32, || {
+    ///     // The storage is borrowed inside `with`, so this scope is refused.
+    ///     NUMBER.with(|_| NUMBER.try_sync_scope(2u32, || ()).is_err())
+    /// });
+    /// assert_eq!(res, Ok(true));
+    /// ```
+    ///
+    /// [`sync_scope`]: fn@Self::sync_scope
+    /// [`with`]: fn@Self::with
+    /// [`try_with`]: fn@Self::try_with
+    pub fn try_sync_scope<F, R>(&'static self, value: impl Into<T>, f: F) -> Result<R, ScopeError>
+    where
+        F: FnOnce() -> R,
+    {
+        self.try_sync_scope_with(value.into(), DropPolicy::OutsideScope, f)
+    }
+
+    fn scope_inner<F, R>(
+        &'static self,
+        slot: &mut Option<T>,
+        state: &mut ScopeState,
+        f: F,
+    ) -> Result<R, ScopeInnerErr>
+    where
+        F: FnOnce() -> R,
+    {
+        struct Guard<'a, T: 'static> {
+            #[cfg(any(feature = "embassy", feature = "rtic"))]
+            slots: &'static TaskSlots<T>,
+            #[cfg(any(feature = "embassy", feature = "rtic"))]
+            claimed: Option<usize>,
+            cell: &'static ValueCell<T>,
+            entered: Entered<'a, T>,
+        }
+
+        impl<T: 'static> Drop for Guard<'_, T> {
+            fn drop(&mut self) {
+                exclusive(|| self.cell.exit(&mut self.entered));
+
+                #[cfg(any(feature = "embassy", feature = "rtic"))]
+                self.slots.release(self.claimed);
+            }
+        }
+
+        #[cfg(not(any(feature = "embassy", feature = "rtic")))]
+        let cell = self.cell().ok_or(ScopeInnerErr::NoTaskSlot)?;
+        #[cfg(any(feature = "embassy", feature = "rtic"))]
+        let slots = &self.inner;
+        #[cfg(any(feature = "embassy", feature = "rtic"))]
+        let (cell, claimed) = slots.enter()?;
+
+        // Safety: The guard below passes `entered` to `exit` when dropped, and
+        // it is not leaked.
+        #[cfg(not(feature = "forbid-unsafe"))]
+        let entered = self.record_enter(exclusive(|| unsafe { cell.enter(slot, state) }))?;
+        #[cfg(feature = "forbid-unsafe")]
+        let entered = self.record_enter(exclusive(|| cell.enter(slot, state)))?;
+
+        #[cfg(feature = "registry")]
+        registry::register(self);
+        #[cfg(feature = "inherit")]
+        inherit::register(self);
+
+        let guard = Guard {
+            #[cfg(any(feature = "embassy", feature = "rtic"))]
+            slots,
+            #[cfg(any(feature = "embassy", feature = "rtic"))]
+            claimed,
+            cell,
+            entered,
+        };
 
-        // Safety: We're in single-threaded no_std environment
-        unsafe {
-            let inner_ptr = self.inner.get();
-            mem::swap(slot, &mut *inner_ptr);
-        }
-
-        let guard = Guard { local: self, slot };
-
         let res = f();
 
         drop(guard);
@@ -341,11 +2089,49 @@ impl<T: 'static> LocalKey<T> {
         Ok(res)
     }
 
+    /// Replaces the task-local value of the current scope, returning the
+    /// previous value.
+    ///
+    /// The new value is visible until the scope ends or it is replaced again.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the task local doesn't have a value set,
+    /// or if it is called inside a call to [`with`] or [`try_with`] on the
+    /// same `LocalKey`.
+    ///
+    /// [`with`]: fn@Self::with
+    /// [`try_with`]: fn@Self::try_with
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn set(&'static self, value: T) -> T {
+        match self.try_set(value) {
+            Some(prev) => prev,
+            None => self.set_panic(),
+        }
+    }
+
+    /// Replaces the task-local value of the current scope, returning the
+    /// previous value.
+    ///
+    /// Returns `None`, dropping `value`, if the task local doesn't have a
+    /// value set or if it is called inside a call to [`with`] or [`try_with`]
+    /// on the same `LocalKey`. For a panicking variant, see `set`.
+    ///
+    /// [`with`]: fn@Self::with
+    /// [`try_with`]: fn@Self::try_with
+    pub fn try_set(&'static self, value: T) -> Option<T> {
+        let prev = exclusive(|| self.cell().and_then(|cell| cell.replace(value)))?;
+        self.value_changed();
+        Some(prev)
+    }
+
     /// Accesses the current task-local and runs the provided closure.
     ///
     /// # Panics
     ///
     /// This function will panic if the task local doesn't have a value set.
+    #[cfg(not(feature = "panic-free"))]
     #[track_caller]
     pub fn with<F, R>(&'static self, f: F) -> R
     where
@@ -353,36 +2139,210 @@ impl<T: 'static> LocalKey<T> {
     {
         match self.try_with(f) {
             Ok(res) => res,
-            Err(_) => panic!("cannot access a task-local storage value without setting it first"),
+            Err(err) => self.access_panic(err),
         }
     }
 
     /// Accesses the current task-local and runs the provided closure.
     ///
     /// If the task-local with the associated key is not present, this
-    /// method will return an `AccessError`. For a panicking variant,
+    /// method will return [`AccessError::NotSet`]. If it is accessed from a
+    /// context that preempted another one in the middle of entering or leaving
+    /// a scope, it returns [`AccessError::Borrowed`]. For a panicking variant,
     /// see `with`.
+    ///
+    /// With the `critical-section`, `embassy` or `rtic` feature, `f` runs
+    /// inside a critical section and should be kept short.
+    #[cfg_attr(feature = "audit", track_caller)]
     pub fn try_with<F, R>(&'static self, f: F) -> Result<R, AccessError>
     where
         F: FnOnce(&T) -> R,
     {
-        // Safety: In single-threaded no_std environments, this is safe.
-        unsafe {
-            let inner_ptr = self.inner.get();
-            match (*inner_ptr).as_ref() {
-                Some(value) => Ok(f(value)),
-                None => Err(AccessError { _private: () }),
-            }
-        }
+        #[cfg(feature = "context")]
+        let mut fallback = Fallback::new(f);
+        #[cfg(feature = "context")]
+        let f = |value: &T| fallback.call(value);
+
+        // No user-defined code runs while a `borrow_mut` call is active, so
+        // `try_borrow` can only fail if we preempted the code holding it.
+        let res = exclusive(|| {
+            let cell = self.cell().ok_or(AccessError::NotSet)?;
+            self.access(cell, f)
+        });
+
+        #[cfg(feature = "context")]
+        let res = fallback.or_context(self, res);
+        self.record_access(res)
+    }
+
+    /// Returns a copy of the task-local value, or an [`AccessError`] if it
+    /// cannot be read.
+    ///
+    /// This is the non-panicking variant of [`get_copied`](Self::get_copied).
+    #[inline(always)]
+    #[cfg_attr(feature = "audit", track_caller)]
+    pub fn try_get_copied(&'static self) -> Result<T, AccessError>
+    where
+        T: Copy,
+    {
+        let res = exclusive(|| {
+            let cell = self.cell().ok_or(AccessError::NotSet)?;
+            self.copy(cell)
+        });
+
+        #[cfg(feature = "context")]
+        let res = Fallback::new(|value: &T| *value).or_context(self, res);
+        self.record_access(res)
+    }
+
+    /// Accesses the current task-local and runs the provided closure, without
+    /// checking that the value is set.
+    ///
+    /// This skips the checks done by [`with`](Self::with), for hot loops that
+    /// are known to run inside a scope of this key.
+    ///
+    /// With the `critical-section`, `embassy` or `rtic` feature, `f` still runs
+    /// inside a critical section, since a preempting context could otherwise
+    /// replace the value.
+    ///
+    /// # Safety
+    ///
+    /// The caller must be inside a scope of this key whose value has not been
+    /// taken, and must not enter a scope of this key, replace its value with
+    /// [`set`](Self::set), or access it other than through shared references
+    /// while `f` runs.
+    ///
+    /// Not available with the `forbid-unsafe` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// task_local::task_local! {
+    ///     static GAIN: f32;
+    /// }
+    ///
+    /// GAIN.sync_scope(2.0, || {
+    ///     let samples = [1.0, 2.0, 3.0];
+    ///     // Safety: We are inside a scope of `GAIN`, and `set` is not called.
+    ///     let sum: f32 = samples
+    ///         .iter()
+    ///         .map(|sample| unsafe { GAIN.with_unchecked(|gain| sample * gain) })
+    ///         .sum();
+    ///     assert_eq!(sum, 12.0);
+    /// });
+    /// ```
+    #[cfg(not(feature = "forbid-unsafe"))]
+    #[inline(always)]
+    pub unsafe fn with_unchecked<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        exclusive(|| self.cell().unwrap_unchecked().with_unchecked(f))
+    }
+
+    /// Returns a copy of the task-local value, for interrupt handlers.
+    ///
+    /// Unlike [`get_copied`](Self::get_copied), this only reads the storage:
+    /// the borrow state of the key is checked but never changed, and no slot
+    /// is claimed. An interrupt handler can therefore read the value set by
+    /// the code it preempted, such as the current mode or device id, even if
+    /// that code is in the middle of [`with`](Self::with).
+    ///
+    /// The value is the one of the current context: the task being polled
+    /// with the `embassy` feature, the current priority level with the `rtic`
+    /// feature, whose tasks are themselves interrupt handlers, and whatever
+    /// runs on the core otherwise. The fallback of the `context` feature is
+    /// not consulted, and the read is not counted by the `stats` feature.
+    ///
+    /// Returns [`AccessError::NotSet`] if there is no value,
+    /// [`AccessError::Borrowed`] if the interrupt preempted code entering or
+    /// leaving a scope of this key, and [`AccessError::Poisoned`] if the
+    /// scope is poisoned.
+    ///
+    /// With the `critical-section`, `embassy` or `rtic` feature the read
+    /// happens in a critical section, like every other access, so that a
+    /// handler on one core never copies the value while a task on another
+    /// core replaces it. The critical section never waits for the code the
+    /// handler preempted, which cannot be inside one, only for another core
+    /// to finish an access. Without these features the environment is
+    /// assumed to be single-threaded and the read takes no lock at all.
+    ///
+    /// Not available with the `forbid-unsafe` feature, which keeps the values
+    /// behind a critical section.
+    ///
+    /// # Examples
+    ///
+    /// ```ignore
+    /// task_local::task_local! {
+    ///     static MODE: u8;
+    /// }
+    ///
+    /// #[interrupt]
+    /// fn TIMER0() {
+    ///     // Falls back to mode 0 if the preempted code is outside any scope.
+    ///     let mode = MODE.peek_from_isr().unwrap_or(0);
+    /// }
+    /// ```
+    #[cfg(not(feature = "forbid-unsafe"))]
+    #[inline]
+    pub fn peek_from_isr(&'static self) -> Result<T, AccessError>
+    where
+        T: Copy,
+    {
+        exclusive(|| {
+            let cell = self.cell().ok_or(AccessError::NotSet)?;
+            self.copy(cell)
+        })
     }
 }
 
 // Implementation for std
 #[cfg(feature = "std")]
 impl<T: 'static> LocalKey<T> {
+    const_fn! {
+        #[doc(hidden)]
+        pub fn __new(
+            name: &'static str,
+            module_path: &'static str,
+            fmt_value: FmtValue<T>,
+            inner: sync::ThreadLocal<ValueCell<T>>,
+        ) -> Self {
+            Self {
+                inner,
+                watch: WatchState::new(),
+                name,
+                module_path,
+                fmt_value,
+                #[cfg(feature = "alloc")]
+                capture: any_key::no_capture::<T>,
+                poison: false,
+                #[cfg(feature = "zeroize")]
+                zeroize: None,
+                #[cfg(feature = "audit")]
+                audited: false,
+                default: None,
+                global: global::Global::new(),
+                #[cfg(all(debug_assertions, not(loom)))]
+                affine: affinity::not_affine,
+                #[cfg(feature = "registry")]
+                node: registry::Node::new::<T>(),
+                #[cfg(feature = "inherit")]
+                inherit: inherit::Node::new(),
+                #[cfg(feature = "stats")]
+                stats: stats::Counters::new(),
+            }
+        }
+    }
+
     /// Sets a value `T` as the task-local value for the future `F`.
     ///
-    /// On completion of `scope`, the task-local will be dropped.
+    /// The value can be given as any type converting into `T`, such as a
+    /// `&str` for a `String` key. An integer literal needs a suffix when `T`
+    /// is not `i32`, as in `1u32`, since its type cannot be inferred.
+    ///
+    /// The task-local value is dropped when the returned future is dropped,
+    /// outside of the scope. See [`TaskLocalFuture::drop_policy`] to drop it
+    /// when the future completes instead.
     ///
     /// ### Panics
     ///
@@ -397,7 +2357,7 @@ impl<T: 'static> LocalKey<T> {
     ///     static NUMBER: u32;
     /// }
     ///
-    /// NUMBER.scope(1, async move {
+    /// NUMBER.scope(1u32, async move {
     ///     println!("task local value: {}", NUMBER.get());
     /// }).await;
     /// # }
@@ -405,26 +2365,39 @@ impl<T: 'static> LocalKey<T> {
     ///
     /// [`with`]: fn@Self::with
     /// [`try_with`]: fn@Self::try_with
-    pub fn scope<F>(&'static self, value: T, f: F) -> TaskLocalFuture<T, F>
+    #[track_caller]
+    pub fn scope<F>(&'static self, value: impl Into<T>, f: F) -> TaskLocalFuture<T, F>
     where
         F: Future,
     {
         TaskLocalFuture {
             local: self,
-            slot: Some(value),
+            slot: Some(value.into()),
             future: Some(f),
+            entered: false,
+            drop_policy: DropPolicy::OutsideScope,
+            state: ScopeState::new(),
+            leak: track_scope(self),
             _pinned: PhantomPinned,
         }
     }
 
     /// Sets a value `T` as the task-local value for the closure `F`.
     ///
-    /// On completion of `sync_scope`, the task-local will be dropped.
+    /// The value can be given as any type converting into `T`, such as a
+    /// `&str` for a `String` key. An integer literal needs a suffix when `T`
+    /// is not `i32`, as in `1u32`, since its type cannot be inferred.
+    ///
+    /// On completion of `sync_scope`, the task-local will be dropped, after the
+    /// value of the enclosing scope is restored. See
+    /// [`sync_scope_with_drop_policy`](Self::sync_scope_with_drop_policy) to
+    /// drop it inside the scope instead.
     ///
     /// ### Panics
     ///
     /// This method panics if called inside a call to [`with`] or [`try_with`]
-    /// on the same `LocalKey`.
+    /// on the same `LocalKey`. For a non-panicking variant, see
+    /// [`try_sync_scope`](fn@Self::try_sync_scope).
     ///
     /// ### Examples
     ///
@@ -434,7 +2407,7 @@ impl<T: 'static> LocalKey<T> {
     ///     static NUMBER: u32;
     /// }
     ///
-    /// NUMBER.sync_scope(1, || {
+    /// NUMBER.sync_scope(1u32, || {
     ///     println!("task local value: {}", NUMBER.get());
     /// });
     /// # }
@@ -443,54 +2416,138 @@ impl<T: 'static> LocalKey<T> {
     /// [`with`]: fn@Self::with
     /// [`try_with`]: fn@Self::try_with
     #[track_caller]
-    pub fn sync_scope<F, R>(&'static self, value: T, f: F) -> R
+    pub fn sync_scope<F, R>(&'static self, value: impl Into<T>, f: F) -> R
     where
         F: FnOnce() -> R,
     {
-        let mut value = Some(value);
-        match self.scope_inner(&mut value, f) {
+        match self.try_sync_scope(value, f) {
             Ok(res) => res,
-            Err(err) => err.panic(),
+            Err(err) => err.kind.panic(self.name),
+        }
+    }
+
+    /// Sets a value `T` as the task-local value for the future `F`, without
+    /// panicking if the scope cannot be entered.
+    ///
+    /// Behaves like [`scope`], except that the returned future resolves to a
+    /// [`ScopeError`] instead of panicking when the storage cannot be entered,
+    /// for example because it is borrowed by [`with`] or [`try_with`].
+    ///
+    /// The value can be given as any type converting into `T`, like in
+    /// [`scope`].
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// task_local::task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// let res = NUMBER.try_scope(1u32, async move { NUMBER.get() }).await;
+    /// assert_eq!(res, Ok(1));
+    /// # }
+    /// ```
+    ///
+    /// [`scope`]: fn@Self::scope
+    /// [`with`]: fn@Self::with
+    /// [`try_with`]: fn@Self::try_with
+    #[track_caller]
+    pub fn try_scope<F>(&'static self, value: impl Into<T>, f: F) -> TryTaskLocalFuture<T, F>
+    where
+        F: Future,
+    {
+        TryTaskLocalFuture {
+            inner: self.scope(value, f),
         }
     }
 
-    fn scope_inner<F, R>(&'static self, slot: &mut Option<T>, f: F) -> Result<R, ScopeInnerErr>
+    /// Sets a value `T` as the task-local value for the closure `F`, without
+    /// panicking if the scope cannot be entered.
+    ///
+    /// Behaves like [`sync_scope`], except that a [`ScopeError`] is returned
+    /// instead of panicking when the storage cannot be entered, for example
+    /// because it is borrowed by [`with`] or [`try_with`]. In that case `f` is
+    /// not called and `value` is dropped.
+    ///
+    /// The value can be given as any type converting into `T`, like in
+    /// [`sync_scope`].
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// task_local::task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// let res = NUMBER.try_sync_scope(1u32, || {
+    ///     // The storage is borrowed inside `with`, so this scope is refused.
+    ///     NUMBER.with(|_| NUMBER.try_sync_scope(2u32, || ()).is_err())
+    /// });
+    /// assert_eq!(res, Ok(true));
+    /// ```
+    ///
+    /// [`sync_scope`]: fn@Self::sync_scope
+    /// [`with`]: fn@Self::with
+    /// [`try_with`]: fn@Self::try_with
+    pub fn try_sync_scope<F, R>(&'static self, value: impl Into<T>, f: F) -> Result<R, ScopeError>
+    where
+        F: FnOnce() -> R,
+    {
+        self.try_sync_scope_with(value.into(), DropPolicy::OutsideScope, f)
+    }
+
+    fn scope_inner<F, R>(
+        &'static self,
+        slot: &mut Option<T>,
+        state: &mut ScopeState,
+        f: F,
+    ) -> Result<R, ScopeInnerErr>
     where
         F: FnOnce() -> R,
     {
         struct Guard<'a, T: 'static> {
             local: &'static LocalKey<T>,
-            slot: &'a mut Option<T>,
+            entered: Entered<'a, T>,
         }
 
         impl<T: 'static> Drop for Guard<'_, T> {
             fn drop(&mut self) {
                 // This should not panic.
                 //
-                // We know that the RefCell was not borrowed before the call to
-                // `scope_inner`, so the only way for this to panic is if the
-                // closure has created but not destroyed a RefCell guard.
-                // However, we never give user-code access to the guards, so
-                // there's no way for user-code to forget to destroy a guard.
-                //
-                // The call to `with` also should not panic, since the
-                // thread-local wasn't destroyed when we first called
-                // `scope_inner`, and it shouldn't have gotten destroyed since
-                // then.
-                self.local.inner.with(|inner| {
-                    let mut ref_mut = inner.borrow_mut();
-                    mem::swap(self.slot, &mut *ref_mut);
-                });
+                // The call to `with` should not panic, since the thread-local
+                // wasn't destroyed when we first called `scope_inner`, and it
+                // shouldn't have gotten destroyed since then. See
+                // `ValueCell::exit` for the borrow.
+                self.local.inner.with(|inner| inner.exit(&mut self.entered));
+                #[cfg(all(feature = "custom-storage", not(loom)))]
+                self.local.inner.exited();
             }
         }
 
-        self.inner.try_with(|inner| {
-            inner
-                .try_borrow_mut()
-                .map(|mut ref_mut| mem::swap(slot, &mut *ref_mut))
-        })??;
+        self.check_thread(state);
+
+        // Safety: The guard below passes `entered` to `exit` when dropped, and
+        // it is not leaked.
+        #[cfg(not(feature = "forbid-unsafe"))]
+        let entered = self
+            .inner
+            .try_with(|inner| unsafe { inner.enter(slot, state) })?;
+        #[cfg(feature = "forbid-unsafe")]
+        let entered = self.inner.try_with(|inner| inner.enter(slot, state))?;
+        let entered = self.record_enter(entered)?;
+        #[cfg(all(feature = "custom-storage", not(loom)))]
+        self.inner.entered();
+
+        #[cfg(feature = "registry")]
+        registry::register(self);
+        #[cfg(feature = "inherit")]
+        inherit::register(self);
 
-        let guard = Guard { local: self, slot };
+        let guard = Guard {
+            local: self,
+            entered,
+        };
 
         let res = f();
 
@@ -499,6 +2556,61 @@ impl<T: 'static> LocalKey<T> {
         Ok(res)
     }
 
+    /// Takes the value of the current scope out of the storage, see
+    /// [`DropPolicy::InsideScope`].
+    fn take_current(&'static self) -> Option<T> {
+        self.inner.try_with(ValueCell::take).ok().flatten()
+    }
+
+    /// Replaces the task-local value of the current scope, returning the
+    /// previous value.
+    ///
+    /// The new value is visible until the scope ends or it is replaced again.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the task local doesn't have a value set,
+    /// or if it is called inside a call to [`with`] or [`try_with`] on the
+    /// same `LocalKey`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// task_local::task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// NUMBER.sync_scope(1u32, || {
+    ///     assert_eq!(NUMBER.set(2), 1);
+    ///     assert_eq!(NUMBER.get(), 2);
+    /// });
+    /// ```
+    ///
+    /// [`with`]: fn@Self::with
+    /// [`try_with`]: fn@Self::try_with
+    #[track_caller]
+    pub fn set(&'static self, value: T) -> T {
+        match self.try_set(value) {
+            Some(prev) => prev,
+            None => self.set_panic(),
+        }
+    }
+
+    /// Replaces the task-local value of the current scope, returning the
+    /// previous value.
+    ///
+    /// Returns `None`, dropping `value`, if the task local doesn't have a
+    /// value set or if it is called inside a call to [`with`] or [`try_with`]
+    /// on the same `LocalKey`. For a panicking variant, see `set`.
+    ///
+    /// [`with`]: fn@Self::with
+    /// [`try_with`]: fn@Self::try_with
+    pub fn try_set(&'static self, value: T) -> Option<T> {
+        let prev = self.inner.try_with(|inner| inner.replace(value)).ok()??;
+        self.value_changed();
+        Some(prev)
+    }
+
     /// Accesses the current task-local and runs the provided closure.
     ///
     /// # Panics
@@ -511,15 +2623,16 @@ impl<T: 'static> LocalKey<T> {
     {
         match self.try_with(f) {
             Ok(res) => res,
-            Err(_) => panic!("cannot access a task-local storage value without setting it first"),
+            Err(err) => self.access_panic(err),
         }
     }
 
     /// Accesses the current task-local and runs the provided closure.
     ///
     /// If the task-local with the associated key is not present, this
-    /// method will return an `AccessError`. For a panicking variant,
+    /// method will return [`AccessError::NotSet`]. For a panicking variant,
     /// see `with`.
+    #[cfg_attr(feature = "audit", track_caller)]
     pub fn try_with<F, R>(&'static self, f: F) -> Result<R, AccessError>
     where
         F: FnOnce(&T) -> R,
@@ -527,94 +2640,518 @@ impl<T: 'static> LocalKey<T> {
         // If called after the thread-local storing the task-local is destroyed,
         // then we are outside of a closure where the task-local is set.
         //
-        // Therefore, it is correct to return an AccessError if `try_with`
-        // returns an error.
-        let try_with_res = self.inner.try_with(|v| {
-            // This call to `borrow` cannot panic because no user-defined code
-            // runs while a `borrow_mut` call is active.
-            v.borrow().as_ref().map(f)
-        });
+        // Therefore, it is correct to return `NotSet` if `try_with` returns an
+        // error.
+        //
+        // Borrowing the value cannot fail because no user-defined code runs
+        // while it is mutably borrowed.
+        let mut fallback = Fallback::new(f);
+        let f = |value: &T| fallback.call(value);
 
-        match try_with_res {
-            Ok(Some(res)) => Ok(res),
-            Ok(None) | Err(_) => Err(AccessError { _private: () }),
-        }
+        let try_with_res = self.inner.try_with(|cell| self.access(cell, f));
+        let res = try_with_res.unwrap_or(Err(AccessError::NotSet));
+
+        #[cfg(feature = "context")]
+        let res = fallback.or_context(self, res);
+        let res = fallback.or_global(self, res);
+        let res = fallback.or_default(self, res);
+        self.record_access(res)
     }
-}
 
-impl<T: Clone + 'static> LocalKey<T> {
-    /// Returns a copy of the task-local value
-    /// if the task-local value implements `Clone`.
+    /// Returns a copy of the task-local value, or an [`AccessError`] if it
+    /// cannot be read.
     ///
-    /// # Panics
-    ///
-    /// This function will panic if the task local doesn't have a value set.
-    #[track_caller]
-    pub fn get(&'static self) -> T {
-        self.with(|v| v.clone())
-    }
-}
+    /// This is the non-panicking variant of [`get_copied`](Self::get_copied).
+    #[inline(always)]
+    #[cfg_attr(feature = "audit", track_caller)]
+    pub fn try_get_copied(&'static self) -> Result<T, AccessError>
+    where
+        T: Copy,
+    {
+        let copy_res = self.inner.try_with(|cell| self.copy(cell));
+        let res = copy_res.unwrap_or(Err(AccessError::NotSet));
 
-impl<T: 'static> fmt::Debug for LocalKey<T> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.pad("LocalKey { .. }")
+        let mut fallback = Fallback::new(|value: &T| *value);
+        #[cfg(feature = "context")]
+        let res = fallback.or_context(self, res);
+        let res = fallback.or_global(self, res);
+        let res = fallback.or_default(self, res);
+        self.record_access(res)
     }
-}
 
-pin_project! {
-    /// A future that sets a value `T` of a task local for the future `F` during
-    /// its execution.
+    /// Accesses the current task-local and runs the provided closure, without
+    /// checking that the value is set.
     ///
-    /// The value of the task-local must be `'static` and will be dropped on the
-    /// completion of the future.
+    /// This skips the checks done by [`with`](Self::with), for hot loops that
+    /// are known to run inside a scope of this key.
     ///
-    /// Created by the function [`LocalKey::scope`](self::LocalKey::scope).
+    /// # Safety
     ///
-    /// ### Examples
+    /// The caller must be inside a scope of this key whose value has not been
+    /// taken, and must not enter a scope of this key, replace its value with
+    /// [`set`](Self::set), or access it other than through shared references
+    /// while `f` runs.
+    ///
+    /// Not available with the `forbid-unsafe` feature.
+    ///
+    /// # Examples
     ///
     /// ```
-    /// # async fn dox() {
     /// task_local::task_local! {
-    ///     static NUMBER: u32;
+    ///     static GAIN: f32;
     /// }
     ///
-    /// NUMBER.scope(1, async move {
-    ///     println!("task local value: {}", NUMBER.get());
-    /// }).await;
-    /// # }
+    /// GAIN.sync_scope(2.0, || {
+    ///     let samples = [1.0, 2.0, 3.0];
+    ///     // Safety: We are inside a scope of `GAIN`, and `set` is not called.
+    ///     let sum: f32 = samples
+    ///         .iter()
+    ///         .map(|sample| unsafe { GAIN.with_unchecked(|gain| sample * gain) })
+    ///         .sum();
+    ///     assert_eq!(sum, 12.0);
+    /// });
     /// ```
-    pub struct TaskLocalFuture<T, F>
+    #[cfg(not(feature = "forbid-unsafe"))]
+    #[inline(always)]
+    pub unsafe fn with_unchecked<F, R>(&'static self, f: F) -> R
     where
-        T: 'static,
+        F: FnOnce(&T) -> R,
     {
-        local: &'static LocalKey<T>,
-        slot: Option<T>,
-        #[pin]
-        future: Option<F>,
-        #[pin]
-        _pinned: PhantomPinned,
-    }
-
-    impl<T: 'static, F> PinnedDrop for TaskLocalFuture<T, F> {
-        fn drop(this: Pin<&mut Self>) {
-            let this = this.project();
-            if mem::needs_drop::<F>() && this.future.is_some() {
-                // Drop the future while the task-local is set, if possible. Otherwise
-                // the future is dropped normally when the `Option<F>` field drops.
-                let mut future = this.future;
-                let _ = this.local.scope_inner(this.slot, || {
-                    future.set(None);
-                });
-            }
-        }
+        self.inner.with(|cell| cell.with_unchecked(f))
     }
 }
 
-impl<T, F> TaskLocalFuture<T, F>
-where
-    T: 'static,
-{
-    /// Returns the value stored in the task local by this `TaskLocalFuture`.
+impl<T: 'static> LocalKey<T> {
+    /// Returns a [`Watch`] that is notified whenever the value of this
+    /// task-local changes.
+    ///
+    /// A change is recorded when the value the current task sees changes:
+    /// when the value of its scope is replaced with `set`, or when the watch
+    /// is used in another scope than before. See [`Watch`].
+    pub fn watch(&'static self) -> Watch<T> {
+        Watch::new(self)
+    }
+
+    /// Returns the name of the static this key was declared as by
+    /// [`task_local!`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// task_local::task_local! {
+    ///     static DEVICE_ID: u32;
+    /// }
+    ///
+    /// assert_eq!(DEVICE_ID.name(), "DEVICE_ID");
+    /// ```
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the path of the module this key was declared in, as given by
+    /// [`module_path!`].
+    pub const fn module_path(&self) -> &'static str {
+        self.module_path
+    }
+
+    /// Sets a value `T` as the task-local value for the closure `f`, dropping
+    /// the value as set by `policy`.
+    ///
+    /// [`sync_scope`](Self::sync_scope) drops the value with
+    /// [`DropPolicy::OutsideScope`].
+    ///
+    /// # Panics
+    ///
+    /// This method panics if called inside a call to [`with`](Self::with) or
+    /// [`try_with`](Self::try_with) on the same key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::Mutex;
+    /// use task_local::DropPolicy;
+    ///
+    /// task_local::task_local! {
+    ///     static SPAN: Span;
+    /// }
+    ///
+    /// static SEEN: Mutex<Vec<Option<&str>>> = Mutex::new(Vec::new());
+    ///
+    /// struct Span(&'static str);
+    ///
+    /// impl Drop for Span {
+    ///     fn drop(&mut self) {
+    ///         SEEN.lock().unwrap().push(SPAN.try_with(|span| span.0).ok());
+    ///     }
+    /// }
+    ///
+    /// SPAN.sync_scope(Span("request"), || {
+    ///     SPAN.sync_scope(Span("query"), || {});
+    ///     SPAN.sync_scope_with_drop_policy(Span("query"), DropPolicy::InsideScope, || {});
+    /// });
+    ///
+    /// // Dropped outside of its scope, the first query sees the enclosing span;
+    /// // dropped inside, the second sees none.
+    /// assert_eq!(SEEN.lock().unwrap()[..2], [Some("request"), None]);
+    /// ```
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn sync_scope_with_drop_policy<F, R>(
+        &'static self,
+        value: impl Into<T>,
+        policy: DropPolicy,
+        f: F,
+    ) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        match self.try_sync_scope_with(value.into(), policy, f) {
+            Ok(res) => res,
+            Err(err) => err.kind.panic(self.name),
+        }
+    }
+
+    fn try_sync_scope_with<F, R>(
+        &'static self,
+        value: T,
+        policy: DropPolicy,
+        f: F,
+    ) -> Result<R, ScopeError>
+    where
+        F: FnOnce() -> R,
+    {
+        /// Drops the value of the current scope, see [`DropPolicy::InsideScope`].
+        struct DropInScope<T: 'static>(&'static LocalKey<T>);
+
+        impl<T: 'static> Drop for DropInScope<T> {
+            fn drop(&mut self) {
+                self.0.drop_current();
+            }
+        }
+
+        /// Records that the scope was exited, see [`LocalKey::scope_exited`].
+        struct Exit<T: 'static>(&'static LocalKey<T>, ScopeState);
+
+        impl<T: 'static> Drop for Exit<T> {
+            fn drop(&mut self) {
+                self.0.scope_exited(self.1);
+            }
+        }
+
+        #[cfg(not(feature = "zeroize"))]
+        let mut value = Some(value);
+        #[cfg(feature = "zeroize")]
+        let mut value = zeroize::Wiped::new(self, Some(value));
+        let state = ScopeState::new();
+        // `scope_inner` hands the state back into `scope_state` on exit.
+        let mut scope_state = state;
+        self.scope_inner(&mut value, &mut scope_state, || {
+            self.scope_entered(state);
+            let _exit = Exit(self, state);
+            let _drop = (policy == DropPolicy::InsideScope).then(|| DropInScope(self));
+            f()
+        })
+        .map_err(|kind| ScopeError { kind })
+    }
+
+    /// Drops the value of the current scope, see [`DropPolicy::InsideScope`].
+    fn drop_current(&'static self) {
+        #[cfg(not(feature = "zeroize"))]
+        drop(self.take_current());
+        #[cfg(feature = "zeroize")]
+        drop(zeroize::Wiped::new(self, self.take_current()));
+    }
+
+    /// Runs `f` on the cell of the current thread, core or task, if there is
+    /// one.
+    pub(crate) fn with_current_cell<R>(
+        &'static self,
+        f: impl FnOnce(&ValueCell<T>) -> R,
+    ) -> Option<R> {
+        #[cfg(feature = "std")]
+        return self.inner.try_with(f).ok();
+        #[cfg(not(feature = "std"))]
+        return exclusive(|| self.cell().map(f));
+    }
+
+    /// Records that a scope of this key was entered: when the closure of a
+    /// `sync_scope` is called, and on the first poll of a `TaskLocalFuture`.
+    /// Called inside the scope, with the state the scope was created with.
+    #[cfg_attr(
+        not(any(feature = "trace-scopes", feature = "instrument")),
+        allow(unused_variables)
+    )]
+    fn scope_entered(&'static self, state: ScopeState) {
+        #[cfg(feature = "trace-scopes")]
+        trace::enter(self, state);
+        #[cfg(feature = "instrument")]
+        instrument::enter(self, state);
+        #[cfg(feature = "stats")]
+        self.stats.scope_entered();
+    }
+
+    /// Records that a scope recorded by [`scope_entered`](Self::scope_entered)
+    /// was exited.
+    #[cfg_attr(
+        not(any(feature = "trace-scopes", feature = "instrument")),
+        allow(unused_variables)
+    )]
+    fn scope_exited(&'static self, state: ScopeState) {
+        #[cfg(feature = "trace-scopes")]
+        trace::exit(self, state);
+        #[cfg(feature = "instrument")]
+        instrument::exit(self, state);
+        #[cfg(feature = "stats")]
+        self.stats.scope_exited();
+    }
+
+    /// Counts an access to the value of this key in its statistics, and
+    /// reports it to the audit hook.
+    #[inline(always)]
+    #[cfg_attr(feature = "audit", track_caller)]
+    fn record_access<R>(&'static self, res: Result<R, AccessError>) -> Result<R, AccessError> {
+        #[cfg(feature = "audit")]
+        self.audit(res.as_ref().map(drop).map_err(|err| *err));
+        #[cfg(feature = "stats")]
+        {
+            self.stats.access();
+            if let Err(AccessError::Borrowed) = res {
+                self.stats.borrow_conflict();
+            }
+        }
+        res
+    }
+
+    /// Counts a scope that could not be entered because the storage of this
+    /// key was borrowed in its statistics.
+    #[inline(always)]
+    fn record_enter<R, E>(&'static self, res: Result<R, E>) -> Result<R, E> {
+        #[cfg(feature = "stats")]
+        if res.is_err() {
+            self.stats.borrow_conflict();
+        }
+        res
+    }
+
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    fn access_panic(&self, err: AccessError) -> ! {
+        #[cfg(feature = "error-handler")]
+        error_handler::fail(self.name, FailureKind::Access(err));
+        #[cfg(not(feature = "error-handler"))]
+        match err {
+            AccessError::Borrowed => panic!(
+                "task-local `{}` is being replaced (accessed at {})",
+                self.name,
+                Location::caller()
+            ),
+            AccessError::NotSet => panic!(
+                "task-local `{}` not set (accessed at {})",
+                self.name,
+                Location::caller()
+            ),
+            AccessError::Poisoned => panic!(
+                "task-local `{}` poisoned by a panic in `with` (accessed at {})",
+                self.name,
+                Location::caller()
+            ),
+            AccessError::Gone => panic!(
+                "task-local `{}` refers to a value that was dropped (accessed at {})",
+                self.name,
+                Location::caller()
+            ),
+        }
+    }
+
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    fn set_panic(&self) -> ! {
+        #[cfg(feature = "error-handler")]
+        error_handler::fail(self.name, FailureKind::Set);
+        #[cfg(not(feature = "error-handler"))]
+        panic!(
+            "cannot set task-local `{}` outside of a scope or while it is borrowed (set at {})",
+            self.name,
+            Location::caller()
+        )
+    }
+}
+
+impl<T: Clone + 'static> LocalKey<T> {
+    /// Returns a copy of the task-local value
+    /// if the task-local value implements `Clone`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the task local doesn't have a value set.
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn get(&'static self) -> T {
+        self.with(|v| v.clone())
+    }
+
+    /// Returns a copy of the task-local value, or an [`AccessError`] if it
+    /// cannot be read.
+    ///
+    /// This is the non-panicking variant of [`get`](Self::get).
+    #[cfg_attr(feature = "audit", track_caller)]
+    pub fn try_get(&'static self) -> Result<T, AccessError> {
+        self.try_with(T::clone)
+    }
+}
+
+impl<T: Copy + 'static> LocalKey<T> {
+    /// Returns a copy of the task-local value.
+    ///
+    /// This is the same as [`get`](Self::get), but reads the value directly
+    /// instead of going through [`with`](Self::with) and a closure, which makes
+    /// it cheaper for small `Copy` values read in hot code.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the task local doesn't have a value set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// task_local::task_local! {
+    ///     static SAMPLE_RATE: u32;
+    /// }
+    ///
+    /// SAMPLE_RATE.sync_scope(100u32, || {
+    ///     assert_eq!(SAMPLE_RATE.get_copied(), 100);
+    /// });
+    /// ```
+    #[cfg(not(feature = "panic-free"))]
+    #[inline(always)]
+    #[track_caller]
+    pub fn get_copied(&'static self) -> T {
+        match self.try_get_copied() {
+            Ok(value) => value,
+            Err(err) => self.access_panic(err),
+        }
+    }
+}
+
+impl<T: 'static> fmt::Debug for LocalKey<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalKey")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<T: 'static> defmt::Format for LocalKey<T> {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "LocalKey {{ name: {=str}, .. }}", self.name)
+    }
+}
+
+/// When the value of a scope is dropped.
+///
+/// By default the value is dropped [outside of its scope](Self::OutsideScope),
+/// so a `Drop` implementation that accesses the same key sees the value of the
+/// enclosing scope, if any. The policy is chosen per scope, with
+/// [`TaskLocalFuture::drop_policy`] and [`LocalKey::sync_scope_with_drop_policy`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DropPolicy {
+    /// The value is dropped after its scope is left.
+    ///
+    /// `sync_scope` drops the value once the closure returned and the value of
+    /// the enclosing scope was restored. A [`TaskLocalFuture`] keeps the value
+    /// until it is dropped itself, so that it can still be taken with
+    /// [`take_value`](TaskLocalFuture::take_value) after completion, and drops
+    /// it outside of any poll.
+    #[default]
+    OutsideScope,
+    /// The value is dropped as the last step of its scope.
+    ///
+    /// The scope is still entered while the value is dropped, but the key has
+    /// no value: accessing it from the `Drop` implementation of the value
+    /// reports it as not set instead of showing the enclosing value. Other
+    /// task-locals keep the values they have around the scope. A
+    /// [`TaskLocalFuture`] drops the value in the poll that completes it, or
+    /// right after the wrapped future when it is dropped before completion.
+    ///
+    /// If the scope cannot be entered when a `TaskLocalFuture` is dropped, for
+    /// example because it is dropped inside a call to `with` on the same key,
+    /// the value is dropped outside of the scope.
+    InsideScope,
+}
+
+pin_project! {
+    /// A future that sets a value `T` of a task local for the future `F` during
+    /// its execution.
+    ///
+    /// The value of the task-local must be `'static` and will be dropped on the
+    /// completion of the future.
+    ///
+    /// Created by the function [`LocalKey::scope`](self::LocalKey::scope).
+    ///
+    /// ### Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// task_local::task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// NUMBER.scope(1u32, async move {
+    ///     println!("task local value: {}", NUMBER.get());
+    /// }).await;
+    /// # }
+    /// ```
+    pub struct TaskLocalFuture<T, F>
+    where
+        T: 'static,
+    {
+        local: &'static LocalKey<T>,
+        slot: Option<T>,
+        #[pin]
+        future: Option<F>,
+        entered: bool,
+        drop_policy: DropPolicy,
+        // The state of the scope, kept here between polls like the value,
+        // see `value_cell.rs`.
+        state: ScopeState,
+        leak: LeakTracker,
+        #[pin]
+        _pinned: PhantomPinned,
+    }
+
+    impl<T: 'static, F> PinnedDrop for TaskLocalFuture<T, F> {
+        fn drop(this: Pin<&mut Self>) {
+            let this = this.project();
+            let exiting = *this.entered && this.future.is_some();
+            let drop_inside = *this.drop_policy == DropPolicy::InsideScope;
+            if (mem::needs_drop::<F>() || drop_inside) && this.future.is_some() {
+                // Drop the future while the task-local is set, if possible. Otherwise
+                // the future is dropped normally when the `Option<F>` field drops.
+                let mut future = this.future;
+                let local = *this.local;
+                let _ = local.scope_inner(this.slot, this.state, || {
+                    future.set(None);
+                    if drop_inside {
+                        local.drop_current();
+                    }
+                });
+            }
+            if exiting {
+                this.local.scope_exited(*this.state);
+            }
+            #[cfg(feature = "zeroize")]
+            this.local.wipe(this.slot);
+        }
+    }
+}
+
+impl<T, F> TaskLocalFuture<T, F>
+where
+    T: 'static,
+{
+    /// Returns the value stored in the task local by this `TaskLocalFuture`.
     ///
     /// The function returns:
     ///
@@ -633,7 +3170,7 @@ where
     ///     static KEY: u32;
     /// }
     ///
-    /// let fut = KEY.scope(42, async {
+    /// let fut = KEY.scope(42u32, async {
     ///     // Do some async work
     /// });
     ///
@@ -652,50 +3189,361 @@ where
         let this = self.project();
         this.slot.take()
     }
-}
 
-impl<T: 'static, F: Future> Future for TaskLocalFuture<T, F> {
-    type Output = F::Output;
+    /// Cancels the wrapped future, returning the task local value.
+    ///
+    /// The future is dropped with the task local set, as when the
+    /// `TaskLocalFuture` itself is dropped, so that state it stashed in the
+    /// value is complete when the value is returned. If the scope cannot be
+    /// entered, for example because `cancel` is called inside a call to
+    /// [`with`](LocalKey::with) on the same key, the future is dropped without
+    /// the task local set.
+    ///
+    /// Afterwards the `TaskLocalFuture` behaves like a completed one. Returns
+    /// `None` if the value has already been taken.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// task_local::task_local! {
+    ///     static RECEIVED: Vec<u8>;
+    /// }
+    ///
+    /// let mut fut = Box::pin(RECEIVED.scope(Vec::new(), async {
+    ///     RECEIVED.set(vec![1, 2]);
+    ///     std::future::pending::<()>().await;
+    /// }));
+    ///
+    /// // Poll the connection handler once, then give up on it.
+    /// let _ = futures::poll!(fut.as_mut());
+    /// assert_eq!(fut.as_mut().cancel(), Some(vec![1, 2]));
+    /// # }
+    /// ```
+    pub fn cancel(self: Pin<&mut Self>) -> Option<T> {
+        let this = self.project();
+        if this.future.is_some() {
+            let local = *this.local;
+            let mut future = this.future;
+            if local
+                .scope_inner(this.slot, this.state, || future.set(None))
+                .is_err()
+            {
+                future.set(None);
+            }
+            if *this.entered {
+                local.scope_exited(*this.state);
+            }
+        }
+        this.slot.take()
+    }
 
+    /// Replaces the value stored in the task local by this `TaskLocalFuture`,
+    /// returning the previous value.
+    ///
+    /// The new value is seen by the wrapped future from its next poll on.
+    /// Returns `None` if the previous value has been taken with
+    /// [`take_value`](Self::take_value).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// task_local::task_local! {
+    ///     static TOKEN: &'static str;
+    /// }
+    ///
+    /// let mut fut = Box::pin(TOKEN.scope("old", async { TOKEN.get() }));
+    ///
+    /// assert_eq!(fut.as_mut().replace_value("new"), Some("old"));
+    /// assert_eq!(fut.await, "new");
+    /// # }
+    /// ```
+    pub fn replace_value(self: Pin<&mut Self>, value: T) -> Option<T> {
+        let this = self.project();
+        // Between polls the value lives in `slot`, not in the task-local
+        // storage, so replacing it here is all that is needed.
+        let prev = this.slot.replace(value);
+        if *this.entered && this.future.is_some() {
+            if let Some(seen) = this.local.watch.bump(this.state) {
+                this.local.watch.wake(seen);
+            }
+        }
+        prev
+    }
+
+    /// Returns a reference to the wrapped future.
+    ///
+    /// Returns `None` if the future has already completed.
+    pub fn get_ref(&self) -> Option<&F> {
+        self.future.as_ref()
+    }
+
+    /// Returns a mutable reference to the wrapped future.
+    ///
+    /// Returns `None` if the future has already completed. Note that the
+    /// task-local value is not set while the future is accessed this way.
+    pub fn get_mut(&mut self) -> Option<&mut F> {
+        // A `&mut Self` cannot be obtained once `self` is pinned, since it is
+        // `!Unpin`, so handing out `&mut F` does not break the pinning of `F`.
+        self.future.as_mut()
+    }
+
+    /// Returns a pinned mutable reference to the wrapped future.
+    ///
+    /// Returns `None` if the future has already completed. Note that the
+    /// task-local value is not set while the future is accessed this way.
+    pub fn get_pin_mut(self: Pin<&mut Self>) -> Option<Pin<&mut F>> {
+        self.project().future.as_pin_mut()
+    }
+
+    /// Consumes this `TaskLocalFuture`, returning the wrapped future.
+    ///
+    /// Returns `None` if the future has already completed. The task-local
+    /// value is dropped; use [`take_value`](Self::take_value) first to keep it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// task_local::task_local! {
+    ///     static KEY: u32;
+    /// }
+    ///
+    /// let fut = KEY.scope(42u32, async { 1 });
+    /// let inner = fut.into_inner().unwrap();
+    /// // `inner` can now be scoped again, or run without the task-local.
+    /// let fut = KEY.scope(7u32, inner);
+    /// # drop(fut);
+    /// ```
+    pub fn into_inner(mut self) -> Option<F> {
+        // `self` has never been pinned, since it is `!Unpin` and owned here,
+        // so the future may be moved out. Dropping `self` afterwards does not
+        // record an exit, because a future that was never polled was never
+        // entered.
+        self.future.take()
+    }
+
+    /// Sets when the task-local value is dropped, see [`DropPolicy`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// use task_local::DropPolicy;
+    ///
+    /// task_local::task_local! {
+    ///     static KEY: u32;
+    /// }
+    ///
+    /// let mut fut = Box::pin(KEY.scope(1u32, async {}).drop_policy(DropPolicy::InsideScope));
+    /// fut.as_mut().await;
+    ///
+    /// // The value was dropped by the poll that completed the future.
+    /// assert_eq!(fut.as_mut().take_value(), None);
+    /// # }
+    /// ```
+    pub fn drop_policy(mut self, policy: DropPolicy) -> Self {
+        self.drop_policy = policy;
+        self
+    }
+
+    /// Also sets `value` as the value of `key` for the wrapped future.
+    ///
+    /// This is the same as `key.scope(value, self)`, written in the order the
+    /// keys are listed in rather than inside-out: the returned future enters
+    /// the scope of `key`, then the scope of this future. If both scopes are
+    /// of the same key, the value of this future is seen by the wrapped one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// task_local::task_local! {
+    ///     static TENANT: &'static str;
+    ///     static REQUEST_ID: u64;
+    /// }
+    ///
+    /// let line = TENANT
+    ///     .scope("acme", async { format!("[{}/{}]", TENANT.get(), REQUEST_ID.get()) })
+    ///     .and_scope(&REQUEST_ID, 7u64)
+    ///     .await;
+    /// assert_eq!(line, "[acme/7]");
+    /// # }
+    /// ```
     #[track_caller]
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    pub fn and_scope<U>(
+        self,
+        key: &'static LocalKey<U>,
+        value: impl Into<U>,
+    ) -> TaskLocalFuture<U, Self>
+    where
+        U: 'static,
+        F: Future,
+    {
+        key.scope(value, self)
+    }
+}
+
+impl<T: 'static, F: Future> TaskLocalFuture<T, F> {
+    #[track_caller]
+    fn poll_scope(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Result<Poll<F::Output>, ScopeInnerErr> {
         let this = self.project();
         let mut future_opt = this.future;
+        let local = *this.local;
+        let entered = this.entered;
+        let drop_policy = *this.drop_policy;
 
-        let res = this
-            .local
-            .scope_inner(this.slot, || match future_opt.as_mut().as_pin_mut() {
-                Some(fut) => {
-                    let res = fut.poll(cx);
-                    if res.is_ready() {
-                        future_opt.set(None);
-                    }
-                    Some(res)
+        #[cfg(all(not(feature = "std"), feature = "embassy"))]
+        let _task = embassy::enter_task(cx.waker());
+
+        /// Drops the future and records that the scope was left, unless
+        /// forgotten.
+        struct Complete<'a, T: 'static, F> {
+            future: Pin<&'a mut Option<F>>,
+            local: &'static LocalKey<T>,
+            drop_policy: DropPolicy,
+            state: ScopeState,
+        }
+
+        impl<T: 'static, F> Drop for Complete<'_, T, F> {
+            fn drop(&mut self) {
+                self.future.set(None);
+                if self.drop_policy == DropPolicy::InsideScope {
+                    self.local.drop_current();
                 }
-                None => None,
-            });
+                self.local.scope_exited(self.state);
+            }
+        }
+
+        let state = *this.state;
+        let res = local.scope_inner(this.slot, this.state, || {
+            if future_opt.is_none() {
+                return None;
+            }
+            if !*entered {
+                *entered = true;
+                local.scope_entered(state);
+            }
+            // A future that panics is completed like a ready one: it is dropped
+            // while the task-local is still set, and never polled again.
+            let mut complete = Complete {
+                future: future_opt.as_mut(),
+                local,
+                drop_policy,
+                state,
+            };
+            let future = complete.future.as_mut().as_pin_mut();
+            let res = future.map(|fut| fut.poll(cx));
+            if let Some(Poll::Pending) = res {
+                mem::forget(complete);
+            }
+            res
+        });
 
         match res {
-            Ok(Some(res)) => res,
+            Ok(Some(res)) => Ok(res),
+            #[cfg(all(not(feature = "panic-free"), not(feature = "error-handler")))]
             Ok(None) => panic!("`TaskLocalFuture` polled after completion"),
-            Err(err) => err.panic(),
+            #[cfg(all(not(feature = "panic-free"), feature = "error-handler"))]
+            Ok(None) => error_handler::fail(local.name, FailureKind::PolledAfterCompletion),
+            // A completed future stays pending forever, like a fused future
+            // that never wakes its task.
+            #[cfg(feature = "panic-free")]
+            Ok(None) => Ok(Poll::Pending),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl<T: 'static, F: Future> Future for TaskLocalFuture<T, F> {
+    type Output = F::Output;
+
+    #[track_caller]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let local = self.local;
+        match self.poll_scope(cx) {
+            Ok(res) => res,
+            Err(err) => err.retry_or_panic(local.name, cx),
         }
     }
 }
 
-impl<T: 'static, F> fmt::Debug for TaskLocalFuture<T, F>
+/// Clones the wrapped future together with the value it stores, so that a
+/// scoped future can be duplicated, for example to retry a request.
+///
+/// The clone starts as a future that was never polled, with a clone of the
+/// value as it is currently stored: the value given to
+/// [`scope`](LocalKey::scope), unless it was replaced since, with
+/// [`replace_value`](Self::replace_value) or with [`LocalKey::set`] in a poll.
+/// Whether the value was poisoned and the [`DropPolicy`] are kept, and a value
+/// taken with [`take_value`](Self::take_value) is missing in the clone too.
+///
+/// # Examples
+///
+/// ```
+/// # async fn dox() {
+/// use std::future::Future;
+/// use std::pin::Pin;
+/// use std::task::{Context, Poll};
+///
+/// task_local::task_local! {
+///     static TENANT: &'static str;
+/// }
+///
+/// // A request that can be sent again, like most client request futures.
+/// #[derive(Clone)]
+/// struct Request;
+///
+/// impl Future for Request {
+///     type Output = String;
+///
+///     fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<String> {
+///         Poll::Ready(format!("GET /{}/items", TENANT.get()))
+///     }
+/// }
+///
+/// let request = TENANT.scope("acme", Request);
+/// let retry = request.clone();
+/// assert_eq!(request.await, "GET /acme/items");
+/// assert_eq!(retry.await, "GET /acme/items");
+/// # }
+/// ```
+impl<T, F> Clone for TaskLocalFuture<T, F>
 where
-    T: fmt::Debug,
+    T: Clone + 'static,
+    F: Clone,
 {
+    #[track_caller]
+    fn clone(&self) -> Self {
+        TaskLocalFuture {
+            local: self.local,
+            slot: self.slot.clone(),
+            future: self.future.clone(),
+            entered: false,
+            drop_policy: self.drop_policy,
+            state: self.state.fork(),
+            leak: track_scope(self.local),
+            _pinned: PhantomPinned,
+        }
+    }
+}
+
+// The value is printed with `Debug` if `T` implements it, and as `<opaque>`
+// otherwise, so the future is `Debug` for every `T`.
+impl<T: 'static, F> fmt::Debug for TaskLocalFuture<T, F> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         /// Format the Option without Some.
         struct TransparentOption<'a, T> {
             value: &'a Option<T>,
+            fmt_value: FmtValue<T>,
         }
-        impl<T: fmt::Debug> fmt::Debug for TransparentOption<'_, T> {
+        impl<T> fmt::Debug for TransparentOption<'_, T> {
             fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
                 match self.value.as_ref() {
-                    Some(value) => value.fmt(f),
+                    Some(value) => (self.fmt_value)(value, f),
                     // Hitting the None branch should not be possible.
                     None => f.pad("<missing>"),
                 }
@@ -703,49 +3551,284 @@ where
         }
 
         f.debug_struct("TaskLocalFuture")
-            .field("value", &TransparentOption { value: &self.slot })
+            .field("key", &self.local.name)
+            .field(
+                "value",
+                &TransparentOption {
+                    value: &self.slot,
+                    fmt_value: self.local.fmt_value,
+                },
+            )
             .finish()
     }
 }
 
-/// An error returned by [`LocalKey::try_with`](method@LocalKey::try_with).
-#[derive(Clone, Copy, Eq, PartialEq)]
-pub struct AccessError {
-    _private: (),
+#[cfg(feature = "defmt")]
+impl<T: 'static, F> defmt::Format for TaskLocalFuture<T, F>
+where
+    T: defmt::Format,
+{
+    fn format(&self, f: defmt::Formatter<'_>) {
+        match self.slot.as_ref() {
+            Some(value) => defmt::write!(f, "TaskLocalFuture {{ value: {} }}", value),
+            // Hitting the None branch should not be possible.
+            None => defmt::write!(f, "TaskLocalFuture {{ value: <missing> }}"),
+        }
+    }
 }
 
-impl fmt::Debug for AccessError {
+pin_project! {
+    /// A future that sets a value `T` of a task local for the future `F` during
+    /// its execution, resolving to an error instead of panicking if the scope
+    /// cannot be entered.
+    ///
+    /// Created by the function [`LocalKey::try_scope`](self::LocalKey::try_scope).
+    pub struct TryTaskLocalFuture<T, F>
+    where
+        T: 'static,
+    {
+        #[pin]
+        inner: TaskLocalFuture<T, F>,
+    }
+}
+
+impl<T, F> TryTaskLocalFuture<T, F>
+where
+    T: 'static,
+{
+    /// Returns the value stored in the task local by this future.
+    ///
+    /// See [`TaskLocalFuture::take_value`]. This can be used to get the value
+    /// back after the scope could not be entered.
+    pub fn take_value(self: Pin<&mut Self>) -> Option<T> {
+        self.project().inner.take_value()
+    }
+
+    /// Cancels the wrapped future, returning the task local value.
+    ///
+    /// See [`TaskLocalFuture::cancel`].
+    pub fn cancel(self: Pin<&mut Self>) -> Option<T> {
+        self.project().inner.cancel()
+    }
+
+    /// Replaces the value stored in the task local by this future, returning
+    /// the previous value.
+    ///
+    /// See [`TaskLocalFuture::replace_value`].
+    pub fn replace_value(self: Pin<&mut Self>, value: T) -> Option<T> {
+        self.project().inner.replace_value(value)
+    }
+
+    /// Returns a reference to the wrapped future.
+    ///
+    /// See [`TaskLocalFuture::get_ref`].
+    pub fn get_ref(&self) -> Option<&F> {
+        self.inner.get_ref()
+    }
+
+    /// Returns a mutable reference to the wrapped future.
+    ///
+    /// See [`TaskLocalFuture::get_mut`].
+    pub fn get_mut(&mut self) -> Option<&mut F> {
+        self.inner.get_mut()
+    }
+
+    /// Returns a pinned mutable reference to the wrapped future.
+    ///
+    /// See [`TaskLocalFuture::get_pin_mut`].
+    pub fn get_pin_mut(self: Pin<&mut Self>) -> Option<Pin<&mut F>> {
+        self.project().inner.get_pin_mut()
+    }
+
+    /// Consumes this future, returning the wrapped future.
+    ///
+    /// See [`TaskLocalFuture::into_inner`].
+    pub fn into_inner(self) -> Option<F> {
+        self.inner.into_inner()
+    }
+
+    /// Sets when the task-local value is dropped.
+    ///
+    /// See [`TaskLocalFuture::drop_policy`].
+    pub fn drop_policy(self, policy: DropPolicy) -> Self {
+        Self {
+            inner: self.inner.drop_policy(policy),
+        }
+    }
+}
+
+impl<T: 'static, F: Future> Future for TryTaskLocalFuture<T, F> {
+    type Output = Result<F::Output, ScopeError>;
+
+    #[track_caller]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project().inner.poll_scope(cx) {
+            Ok(res) => res.map(Ok),
+            Err(kind) => Poll::Ready(Err(ScopeError { kind })),
+        }
+    }
+}
+
+impl<T: 'static, F> fmt::Debug for TryTaskLocalFuture<T, F> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("AccessError").finish()
+        fmt::Debug::fmt(&self.inner, f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<T: 'static, F> defmt::Format for TryTaskLocalFuture<T, F>
+where
+    T: defmt::Format,
+{
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::Format::format(&self.inner, f)
     }
 }
 
+/// An error returned by [`LocalKey::try_with`](method@LocalKey::try_with).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum AccessError {
+    /// The task-local is not set, because the caller is not inside a scope of
+    /// the key.
+    NotSet,
+    /// The task-local is currently being replaced by the context that was
+    /// preempted by the caller, so its value cannot be read.
+    ///
+    /// This can only happen with the no_std backend when a key is accessed
+    /// from an interrupt without the `critical-section`, `embassy` or `rtic`
+    /// feature.
+    Borrowed,
+    /// The current scope of the task-local was poisoned by a panic in a
+    /// closure passed to `with` or `try_with`, so its value may be
+    /// inconsistent.
+    ///
+    /// This can only happen for keys declared with `#[task_local(poison)]`,
+    /// until the poisoned scope exits.
+    Poisoned,
+    /// The task-local holds a weak reference to a value that was dropped.
+    ///
+    /// This can only be returned by
+    /// [`LocalKey::try_with_upgraded`](method@LocalKey::try_with_upgraded).
+    Gone,
+}
+
 impl fmt::Display for AccessError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Display::fmt("task-local value not set", f)
+        let msg = match self {
+            Self::NotSet => "task-local value not set",
+            Self::Borrowed => "task-local value is being replaced",
+            Self::Poisoned => "task-local value poisoned by a panic",
+            Self::Gone => "task-local value was dropped",
+        };
+        fmt::Display::fmt(msg, f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for AccessError {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        match self {
+            Self::NotSet => defmt::write!(f, "AccessError::NotSet"),
+            Self::Borrowed => defmt::write!(f, "AccessError::Borrowed"),
+            Self::Poisoned => defmt::write!(f, "AccessError::Poisoned"),
+            Self::Gone => defmt::write!(f, "AccessError::Gone"),
+        }
     }
 }
 
 #[cfg(feature = "error-trait")]
 impl Error for AccessError {}
 
+/// An error returned by [`LocalKey::try_sync_scope`](method@LocalKey::try_sync_scope)
+/// and [`LocalKey::try_scope`](method@LocalKey::try_scope) when the scope
+/// cannot be entered.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct ScopeError {
+    kind: ScopeInnerErr,
+}
+
+impl fmt::Debug for ScopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScopeError").finish()
+    }
+}
+
+impl fmt::Display for ScopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.kind.message(), f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for ScopeError {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "ScopeError")
+    }
+}
+
+#[cfg(feature = "error-trait")]
+impl Error for ScopeError {}
+
 #[allow(dead_code)]
+#[derive(Clone, Copy, Eq, PartialEq)]
 enum ScopeInnerErr {
     BorrowError,
     AccessError,
+    #[cfg(not(feature = "std"))]
+    NoTaskSlot,
 }
 
 impl ScopeInnerErr {
-    #[track_caller]
-    fn panic(&self) -> ! {
+    fn message(&self) -> &'static str {
         match self {
             Self::BorrowError => {
-                panic!("cannot enter a task-local scope while the task-local storage is borrowed")
+                "cannot enter a task-local scope while the task-local storage is borrowed"
             }
-            Self::AccessError => panic!(
+            Self::AccessError => {
                 "cannot enter a task-local scope during or after destruction of the underlying thread-local"
-            ),
+            }
+            #[cfg(all(not(feature = "std"), any(feature = "embassy", feature = "rtic")))]
+            Self::NoTaskSlot => {
+                "cannot enter a task-local scope: too many tasks are inside a scope of this task-local, see `#[task_local(tasks = N)]`, or the current task cannot be identified"
+            }
+            #[cfg(all(not(feature = "std"), not(any(feature = "embassy", feature = "rtic"))))]
+            Self::NoTaskSlot => {
+                "cannot enter a task-local scope: the current core has no storage, see `set_core_id_fn!`"
+            }
+        }
+    }
+
+    /// Handles an error entering the scope of a `TaskLocalFuture` that is
+    /// polled: panics, or with the `panic-free` feature, wakes the task to
+    /// try again on its next poll.
+    #[track_caller]
+    fn retry_or_panic<R>(&self, name: &'static str, cx: &mut Context<'_>) -> Poll<R> {
+        #[cfg(not(feature = "panic-free"))]
+        {
+            let _ = cx;
+            self.panic(name)
         }
+        #[cfg(feature = "panic-free")]
+        {
+            let _ = name;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    fn panic(&self, name: &'static str) -> ! {
+        #[cfg(feature = "error-handler")]
+        error_handler::fail(name, FailureKind::Scope(ScopeError { kind: *self }));
+        #[cfg(not(feature = "error-handler"))]
+        panic!(
+            "{} (task-local `{}`, entered at {})",
+            self.message(),
+            name,
+            Location::caller()
+        )
     }
 }
 
@@ -756,6 +3839,20 @@ impl From<std::cell::BorrowMutError> for ScopeInnerErr {
     }
 }
 
+#[cfg(not(feature = "std"))]
+impl From<core::cell::BorrowMutError> for ScopeInnerErr {
+    fn from(_: core::cell::BorrowMutError) -> Self {
+        Self::BorrowError
+    }
+}
+
+#[cfg(all(feature = "std", loom))]
+impl From<loom::thread::AccessError> for ScopeInnerErr {
+    fn from(_: loom::thread::AccessError) -> Self {
+        Self::AccessError
+    }
+}
+
 #[cfg(feature = "std")]
 impl From<std::thread::AccessError> for ScopeInnerErr {
     fn from(_: std::thread::AccessError) -> Self {
@@ -763,5 +3860,27 @@ impl From<std::thread::AccessError> for ScopeInnerErr {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(feature = "custom-storage", not(loom)))]
+impl From<provider::NoTask> for ScopeInnerErr {
+    fn from(_: provider::NoTask) -> Self {
+        Self::AccessError
+    }
+}
+
+#[cfg(all(
+    feature = "std",
+    not(loom),
+    target_arch = "wasm32",
+    not(target_feature = "atomics"),
+    not(feature = "forbid-unsafe")
+))]
+impl From<core::convert::Infallible> for ScopeInnerErr {
+    fn from(never: core::convert::Infallible) -> Self {
+        match never {}
+    }
+}
+
+// The tests use the panicking accessors removed by `panic-free`, see
+// `tests/panic_free.rs` for that feature.
+#[cfg(all(test, not(loom), not(feature = "panic-free")))]
 mod tests;
@@ -0,0 +1,119 @@
+//! Keys reading a part of the value of another key.
+
+use core::fmt;
+
+use crate::{AccessError, LocalKey};
+
+impl<T: 'static> LocalKey<T> {
+    /// Returns a key reading the part of the value of this key selected by
+    /// `project`.
+    ///
+    /// The returned key has no scopes of its own: it reads through this key,
+    /// so it sees the value of its current scope. Being `const`, it can be
+    /// declared as a `static`, letting code depend on the narrow piece of a
+    /// larger context it needs instead of the whole context.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use task_local::MappedKey;
+    ///
+    /// struct RequestCtx {
+    ///     tenant: String,
+    ///     user_id: u64,
+    /// }
+    ///
+    /// task_local::task_local! {
+    ///     static CTX: RequestCtx;
+    /// }
+    ///
+    /// static TENANT: MappedKey<RequestCtx, str> = CTX.map(|ctx| &ctx.tenant);
+    ///
+    /// fn bucket() -> String {
+    ///     TENANT.with(|tenant| format!("{tenant}-uploads"))
+    /// }
+    ///
+    /// let ctx = RequestCtx {
+    ///     tenant: "acme".into(),
+    ///     user_id: 7,
+    /// };
+    /// assert_eq!(CTX.sync_scope(ctx, bucket), "acme-uploads");
+    /// ```
+    pub const fn map<U: ?Sized>(&'static self, project: fn(&T) -> &U) -> MappedKey<T, U> {
+        MappedKey { key: self, project }
+    }
+}
+
+/// A key reading a part of the value of another key.
+///
+/// Created by the method [`LocalKey::map`].
+pub struct MappedKey<T: 'static, U: ?Sized + 'static> {
+    key: &'static LocalKey<T>,
+    project: fn(&T) -> &U,
+}
+
+impl<T: 'static, U: ?Sized + 'static> MappedKey<T, U> {
+    /// Returns the key this key reads through.
+    pub const fn key(&self) -> &'static LocalKey<T> {
+        self.key
+    }
+
+    /// Accesses the part of the current task-local value and runs the
+    /// provided closure.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the underlying key doesn't have a value
+    /// set.
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&U) -> R,
+    {
+        let project = self.project;
+        self.key.with(|value| f(project(value)))
+    }
+
+    /// Accesses the part of the current task-local value and runs the
+    /// provided closure, returning the error of
+    /// [`LocalKey::try_with`] if the underlying key cannot be read.
+    pub fn try_with<F, R>(&self, f: F) -> Result<R, AccessError>
+    where
+        F: FnOnce(&U) -> R,
+    {
+        let project = self.project;
+        self.key.try_with(|value| f(project(value)))
+    }
+
+    /// Returns a copy of the part of the current task-local value.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the underlying key doesn't have a value
+    /// set.
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn get(&self) -> U
+    where
+        U: Clone,
+    {
+        self.with(U::clone)
+    }
+}
+
+impl<T: 'static, U: ?Sized + 'static> Clone for MappedKey<T, U> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: 'static, U: ?Sized + 'static> Copy for MappedKey<T, U> {}
+
+impl<T: 'static, U: ?Sized + 'static> fmt::Debug for MappedKey<T, U> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MappedKey")
+            .field("key", &self.key.name)
+            .finish_non_exhaustive()
+    }
+}
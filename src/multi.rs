@@ -0,0 +1,176 @@
+//! Access to several keys in one call.
+
+use core::fmt;
+#[cfg(all(not(feature = "panic-free"), not(feature = "error-handler")))]
+use core::panic::Location;
+
+use crate::{AccessError, LocalKey};
+
+/// A tuple of keys whose values can be accessed together with [`with`] and
+/// [`try_with`].
+///
+/// This is implemented for tuples of one to four `&'static LocalKey`s, and
+/// `F` is a closure taking a reference to the value of every key, in order.
+pub trait WithKeys<F, R>: Copy {
+    /// Runs `f` on the current values of the keys.
+    fn try_with(self, f: F) -> Result<R, KeyAccessError>;
+}
+
+/// Accesses the current values of several task-locals and runs the provided
+/// closure.
+///
+/// This is the same as nesting calls to [`LocalKey::with`], without the
+/// nested closures.
+///
+/// # Panics
+///
+/// This function will panic if one of the task-locals doesn't have a value
+/// set, naming the first such key.
+///
+/// # Examples
+///
+/// ```
+/// task_local::task_local! {
+///     static TENANT: &'static str;
+///     static REQUEST_ID: u64;
+/// }
+///
+/// TENANT.sync_scope("acme", || {
+///     REQUEST_ID.sync_scope(7u64, || {
+///         let line = task_local::with((&TENANT, &REQUEST_ID), |tenant, id| {
+///             format!("[{tenant}/{id}] started")
+///         });
+///         assert_eq!(line, "[acme/7] started");
+///     })
+/// });
+/// ```
+#[cfg(not(feature = "panic-free"))]
+#[track_caller]
+pub fn with<K, F, R>(keys: K, f: F) -> R
+where
+    K: WithKeys<F, R>,
+{
+    match keys.try_with(f) {
+        Ok(res) => res,
+        #[cfg(feature = "error-handler")]
+        Err(err) => crate::error_handler::fail(err.key, crate::FailureKind::Access(err.error)),
+        #[cfg(not(feature = "error-handler"))]
+        Err(err) => panic!("{} (accessed at {})", err, Location::caller()),
+    }
+}
+
+/// Accesses the current values of several task-locals and runs the provided
+/// closure.
+///
+/// Returns a [`KeyAccessError`] naming the first key that cannot be read, in
+/// which case `f` is not called. For a panicking variant, see [`with`].
+///
+/// # Examples
+///
+/// ```
+/// task_local::task_local! {
+///     static TENANT: &'static str;
+///     static REQUEST_ID: u64;
+/// }
+///
+/// let err = TENANT
+///     .sync_scope("acme", || task_local::try_with((&TENANT, &REQUEST_ID), |_, _| ()))
+///     .unwrap_err();
+/// assert_eq!(err.key(), "REQUEST_ID");
+/// assert_eq!(err.error(), task_local::AccessError::NotSet);
+/// ```
+pub fn try_with<K, F, R>(keys: K, f: F) -> Result<R, KeyAccessError>
+where
+    K: WithKeys<F, R>,
+{
+    keys.try_with(f)
+}
+
+/// Implements `WithKeys` for a tuple by accessing its keys from the first to
+/// the last. Every element is given as `Type key value`.
+macro_rules! impl_with_keys_for_tuple {
+    ($($T:ident $k:ident $v:ident),+) => {
+        impl<$($T: 'static,)+ F, R> WithKeys<F, R> for ($(&'static LocalKey<$T>,)+)
+        where
+            F: FnOnce($(&$T),+) -> R,
+        {
+            fn try_with(self, f: F) -> Result<R, KeyAccessError> {
+                let ($($k,)+) = self;
+                impl_with_keys_for_tuple!(@nest f, [$($k $v),+], [])
+            }
+        }
+    };
+
+    (@nest $f:ident, [$k:ident $v:ident $(, $ks:ident $vs:ident)*], [$($done:ident),*]) => {
+        $k.try_with(|$v| {
+            impl_with_keys_for_tuple!(@nest $f, [$($ks $vs),*], [$($done,)* $v])
+        })
+        .map_err(|error| KeyAccessError::new($k, error))
+        .and_then(|res| res)
+    };
+
+    (@nest $f:ident, [], [$($done:ident),*]) => {
+        Ok($f($($done),*))
+    };
+}
+
+impl_with_keys_for_tuple!(A a va);
+impl_with_keys_for_tuple!(A a va, B b vb);
+impl_with_keys_for_tuple!(A a va, B b vb, C c vc);
+impl_with_keys_for_tuple!(A a va, B b vb, C c vc, D d vd);
+
+/// An error returned by [`try_with`] when the value of one of the keys
+/// cannot be read.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct KeyAccessError {
+    key: &'static str,
+    module_path: &'static str,
+    error: AccessError,
+}
+
+impl KeyAccessError {
+    fn new<T: 'static>(key: &'static LocalKey<T>, error: AccessError) -> Self {
+        Self {
+            key: key.name,
+            module_path: key.module_path,
+            error,
+        }
+    }
+
+    /// Returns the name of the key that could not be read.
+    pub fn key(&self) -> &'static str {
+        self.key
+    }
+
+    /// Returns the module path of the key that could not be read.
+    pub fn module_path(&self) -> &'static str {
+        self.module_path
+    }
+
+    /// Returns why the key could not be read.
+    pub fn error(&self) -> AccessError {
+        self.error
+    }
+}
+
+impl fmt::Display for KeyAccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self.error {
+            AccessError::NotSet => "not set",
+            AccessError::Borrowed => "is being replaced",
+            AccessError::Poisoned => "poisoned by a panic in `with`",
+            AccessError::Gone => "refers to a value that was dropped",
+        };
+        write!(f, "task-local `{}` {}", self.key, reason)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for KeyAccessError {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "KeyAccessError({=str}, {})", self.key, self.error)
+    }
+}
+
+#[cfg(feature = "error-trait")]
+impl std::error::Error for KeyAccessError {}
@@ -0,0 +1,304 @@
+//! Namespaces grouping the keys of one component.
+//!
+//! Plugins, or any other components loaded into one host, declare their own
+//! task-locals, and nothing stops two of them from picking the same name. A
+//! [`KeyNamespace`] is a registry of the keys of one component, in which
+//! every name is taken at most once. The host snapshots the keys of a
+//! namespace into a [`Context`], to propagate them into the tasks that run
+//! the component, and formats them with [`KeyNamespace::dump`], without
+//! picking up the keys of any other component.
+//!
+//! Keys are registered explicitly, typically when the component is loaded,
+//! and stay registered for the rest of the program. The registry is a list
+//! whose entries are allocated once and never freed, so that reading it takes
+//! no lock.
+
+use alloc::boxed::Box;
+use core::fmt;
+use core::ptr;
+
+use crate::atomic::{AtomicPtr, Ordering};
+use crate::context::Context;
+use crate::{FmtValue, LocalKey};
+
+/// A key as seen by a namespace, whatever the type of its value.
+trait Member: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Returns a child of `context` holding the current value of the key, if
+    /// it has one.
+    fn capture(&'static self, context: &Context) -> Context;
+
+    /// Formats the current value of the key, if it has one, as an entry of
+    /// `map`.
+    fn dump(&'static self, map: &mut fmt::DebugMap<'_, '_>);
+}
+
+impl<T: Clone + Send + Sync + 'static> Member for LocalKey<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn capture(&'static self, context: &Context) -> Context {
+        context.with_current(self)
+    }
+
+    fn dump(&'static self, map: &mut fmt::DebugMap<'_, '_>) {
+        let _ = self.try_with(|value| {
+            map.entry(&self.name, &Value(value, self.fmt_value));
+        });
+    }
+}
+
+struct Value<'a, T>(&'a T, FmtValue<T>);
+
+impl<T> fmt::Debug for Value<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (self.1)(self.0, f)
+    }
+}
+
+struct Entry {
+    key: &'static dyn Member,
+    next: *const Entry,
+}
+
+/// A registry of the task-local keys of one component, such as a plugin.
+///
+/// Names are unique within a namespace: registering a second key under a
+/// name that is taken fails with [`NamespaceError`]. Every namespace is
+/// independent of the others, and a key may belong to several of them.
+///
+/// Requires the `context` feature. Not available with `forbid-unsafe`.
+///
+/// # Examples
+///
+/// ```
+/// use task_local::KeyNamespace;
+///
+/// mod billing {
+///     task_local::task_local! {
+///         pub static ACCOUNT: u64;
+///     }
+/// }
+///
+/// mod search {
+///     task_local::task_local! {
+///         pub static ACCOUNT: String;
+///     }
+/// }
+///
+/// static BILLING: KeyNamespace = KeyNamespace::new("billing");
+/// static SEARCH: KeyNamespace = KeyNamespace::new("search");
+///
+/// BILLING.register(&billing::ACCOUNT).unwrap();
+/// SEARCH.register(&search::ACCOUNT).unwrap();
+///
+/// // Only the keys of the billing plugin are propagated into its tasks.
+/// let snapshot = billing::ACCOUNT.sync_scope(7u64, || {
+///     search::ACCOUNT.sync_scope("acme".to_string(), || BILLING.snapshot())
+/// });
+/// snapshot.sync_scope(|| {
+///     assert_eq!(billing::ACCOUNT.get(), 7);
+///     assert!(search::ACCOUNT.try_with(|_| ()).is_err());
+///     assert_eq!(format!("{:?}", BILLING.dump()), r#"billing {"ACCOUNT": 7}"#);
+/// });
+/// ```
+pub struct KeyNamespace {
+    name: &'static str,
+    head: AtomicPtr<Entry>,
+}
+
+impl KeyNamespace {
+    /// Creates an empty namespace.
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            head: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Returns the name of the namespace.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Adds `key` to the namespace, under the name it was declared with.
+    ///
+    /// Registering a key that is already part of the namespace does nothing.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`NamespaceError`] if another key with the same name is
+    /// registered already.
+    pub fn register<T>(&self, key: &'static LocalKey<T>) -> Result<(), NamespaceError>
+    where
+        T: Clone + Send + Sync + 'static,
+    {
+        let mut entry = Box::new(Entry {
+            key,
+            next: ptr::null(),
+        });
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            // Only the entries added since the last attempt need checking, but
+            // registration is rare enough to check them all.
+            if let Some(taken) = self
+                .entries_from(head)
+                .find(|taken| taken.name() == key.name)
+            {
+                let taken: *const dyn Member = taken;
+                return if ptr::addr_eq(taken, key as *const LocalKey<T>) {
+                    Ok(())
+                } else {
+                    Err(NamespaceError {
+                        namespace: self.name,
+                        key: key.name,
+                    })
+                };
+            }
+
+            entry.next = head;
+            let entry_ptr = Box::into_raw(entry);
+            match self.head.compare_exchange_weak(
+                head,
+                entry_ptr,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(current) => {
+                    // Safety: The entry was not published, so this is still
+                    // the only pointer to it.
+                    entry = unsafe { Box::from_raw(entry_ptr) };
+                    head = current;
+                }
+            }
+        }
+    }
+
+    /// Returns an iterator over the names of the registered keys, the most
+    /// recently registered first.
+    pub fn keys(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.entries().map(|key| key.name())
+    }
+
+    /// Captures the current values of the registered keys into a new
+    /// [`Context`].
+    ///
+    /// Entering the context, with [`Context::scope`] or
+    /// [`Context::sync_scope`], sets the captured values again, in another
+    /// task for example. Keys without a value are left out.
+    pub fn snapshot(&self) -> Context {
+        self.entries()
+            .fold(Context::new(), |context, key| key.capture(&context))
+    }
+
+    /// Returns the registered keys that are set in the current task.
+    ///
+    /// The returned value implements `Debug`, printing the name of the
+    /// namespace followed by a map from the name of every key that is set to
+    /// its value.
+    pub fn dump(&self) -> NamespaceDump<'_> {
+        NamespaceDump { namespace: self }
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &'static dyn Member> + '_ {
+        self.entries_from(self.head.load(Ordering::Acquire))
+    }
+
+    fn entries_from(&self, head: *const Entry) -> impl Iterator<Item = &'static dyn Member> + '_ {
+        let mut next = head;
+        core::iter::from_fn(move || {
+            // Safety: Entries are leaked when they are published and never
+            // removed from the list, so every pointer in it stays valid.
+            let entry = unsafe { next.as_ref() }?;
+            next = entry.next;
+            Some(entry.key)
+        })
+    }
+}
+
+// Safety: The entries of the list are immutable once published, and only
+// point to keys, which are `Sync`.
+unsafe impl Send for Entry {}
+unsafe impl Sync for Entry {}
+
+impl fmt::Debug for KeyNamespace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyNamespace")
+            .field("name", &self.name)
+            .field("keys", &Keys(self))
+            .finish()
+    }
+}
+
+struct Keys<'a>(&'a KeyNamespace);
+
+impl fmt::Debug for Keys<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.0.keys()).finish()
+    }
+}
+
+/// The keys of a namespace that are set in the current task, returned by
+/// [`KeyNamespace::dump`].
+pub struct NamespaceDump<'a> {
+    namespace: &'a KeyNamespace,
+}
+
+impl fmt::Debug for NamespaceDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.namespace.name)?;
+        let mut map = f.debug_map();
+        for key in self.namespace.entries() {
+            key.dump(&mut map);
+        }
+        map.finish()
+    }
+}
+
+/// An error returned by [`KeyNamespace::register`] when the name of the key
+/// is taken by another key of the namespace.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NamespaceError {
+    namespace: &'static str,
+    key: &'static str,
+}
+
+impl NamespaceError {
+    /// Returns the name of the namespace.
+    pub fn namespace(&self) -> &'static str {
+        self.namespace
+    }
+
+    /// Returns the name that is taken.
+    pub fn key(&self) -> &'static str {
+        self.key
+    }
+}
+
+impl fmt::Display for NamespaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "namespace `{}` already has a task-local named `{}`",
+            self.namespace, self.key
+        )
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for NamespaceError {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(
+            f,
+            "namespace `{=str}` already has a task-local named `{=str}`",
+            self.namespace,
+            self.key
+        )
+    }
+}
+
+#[cfg(feature = "error-trait")]
+impl std::error::Error for NamespaceError {}
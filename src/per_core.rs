@@ -0,0 +1,83 @@
+//! Per-core storage for the no_std backend.
+//!
+//! Every no_std key keeps one copy of its storage per core and picks the copy
+//! of the core it is accessed from. Without the `per-core` feature there is
+//! only a single core and this compiles down to plain field access.
+//!
+//! With the `per-core` feature, the application has to tell the crate which
+//! core it is running on by registering a function with
+//! [`set_core_id_fn!`](crate::set_core_id_fn). Each core's executor then gets
+//! independent task-local state.
+
+/// The number of cores that can have independent task-local state.
+#[cfg(feature = "per-core")]
+pub const MAX_CORES: usize = 4;
+
+/// The number of cores that can have independent task-local state.
+#[cfg(not(feature = "per-core"))]
+pub const MAX_CORES: usize = 1;
+
+#[cfg(feature = "per-core")]
+extern "Rust" {
+    fn _task_local_current_core() -> usize;
+}
+
+/// Returns the index of the core the caller is running on, or `None` if the
+/// registered function returns an index of `MAX_CORES` or more.
+///
+/// # Panics
+///
+/// Panics if the index is out of range, unless the `panic-free` or
+/// `error-handler` feature is enabled. Then no scope can be entered on that
+/// core, which fails like when no task slot is left, and no value is set on
+/// it.
+#[cfg(feature = "per-core")]
+#[inline]
+pub(crate) fn current() -> Option<usize> {
+    // Safety: The symbol is defined by `set_core_id_fn!` with this exact
+    // signature.
+    let core = unsafe { _task_local_current_core() };
+    #[cfg(all(not(feature = "panic-free"), not(feature = "error-handler")))]
+    assert!(
+        core < MAX_CORES,
+        "core index {} returned by the `set_core_id_fn!` function is out of range",
+        core
+    );
+    (core < MAX_CORES).then_some(core)
+}
+
+/// Returns the index of the core the caller is running on.
+#[cfg(not(feature = "per-core"))]
+#[inline(always)]
+pub(crate) fn current() -> Option<usize> {
+    Some(0)
+}
+
+/// Registers the function that returns the index of the current core.
+///
+/// Required by the `per-core` feature. The function must return a value
+/// smaller than [`MAX_CORES`] and must be defined exactly once in the final
+/// binary. A larger value makes entering a scope panic, or with the
+/// `panic-free` or `error-handler` feature fail like when no task slot is
+/// left.
+///
+/// # Examples
+///
+/// ```ignore
+/// fn current_core() -> usize {
+///     // For example, read the SIO CPUID register on the RP2040.
+///     rp2040_hal::Sio::core() as usize
+/// }
+///
+/// task_local::set_core_id_fn!(current_core);
+/// ```
+#[cfg(feature = "per-core")]
+#[macro_export]
+macro_rules! set_core_id_fn {
+    ($f:path) => {
+        #[unsafe(no_mangle)]
+        fn _task_local_current_core() -> usize {
+            $f()
+        }
+    };
+}
@@ -0,0 +1,114 @@
+//! Poisoning of scopes by a panic in `with`.
+//!
+//! A key declared with `#[task_local(poison)]` poisons the innermost scope
+//! when a closure passed to `with` or `try_with` panics, like a `Mutex` whose
+//! guard is dropped during a panic. The panic may have left a value with
+//! interior mutability half-updated, so once the panic is caught, reading the
+//! key fails with [`AccessError::Poisoned`] until the scope exits. Entering a
+//! nested scope with a new value is not affected, and the enclosing scope sees
+//! its own value again afterwards.
+//!
+//! A closure that panics is detected by a guard dropped while it unwinds, so
+//! poisoning also works in no_std builds built with `panic = "unwind"`.
+
+use crate::sync::const_fn;
+use crate::value_cell::ValueCell;
+use crate::{AccessError, LocalKey};
+
+impl<T: 'static> LocalKey<T> {
+    const_fn! {
+        /// Makes the key poison its scopes, see `#[task_local(poison)]`.
+        #[doc(hidden)]
+        pub fn __poison(mut self) -> Self {
+            self.poison = true;
+            self
+        }
+    }
+
+    /// Returns whether the current scope of this key is poisoned, because a
+    /// closure passed to [`with`](Self::with) or [`try_with`](Self::try_with)
+    /// panicked inside it.
+    ///
+    /// Only keys declared with `#[task_local(poison)]` are ever poisoned.
+    /// Returns `false` outside of a scope.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::cell::RefCell;
+    /// use std::panic::{self, AssertUnwindSafe};
+    ///
+    /// task_local::task_local! {
+    ///     #[task_local(poison)]
+    ///     static LEDGER: RefCell<Vec<u64>>;
+    /// }
+    ///
+    /// LEDGER.sync_scope(RefCell::new(Vec::new()), || {
+    ///     let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+    ///         LEDGER.with(|ledger| {
+    ///             ledger.borrow_mut().push(1);
+    ///             panic!("second entry missing");
+    ///         })
+    ///     }));
+    ///     assert!(LEDGER.is_poisoned());
+    ///     assert_eq!(LEDGER.try_with(|_| ()), Err(task_local::AccessError::Poisoned));
+    /// });
+    /// assert!(!LEDGER.is_poisoned());
+    /// ```
+    pub fn is_poisoned(&'static self) -> bool {
+        #[cfg(feature = "std")]
+        return self.inner.try_with(ValueCell::is_poisoned).unwrap_or(false);
+        #[cfg(not(feature = "std"))]
+        return crate::exclusive(|| self.cell().is_some_and(ValueCell::is_poisoned));
+    }
+
+    /// Runs `f` on the value in `cell`, poisoning the scope if `f` panics and
+    /// this key poisons its scopes.
+    #[inline(always)]
+    pub(crate) fn access<F, R>(&'static self, cell: &ValueCell<T>, f: F) -> Result<R, AccessError>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        /// Poisons the scope unless forgotten once `f` returns.
+        struct PoisonOnUnwind<'a, T: 'static>(&'a ValueCell<T>);
+
+        impl<T: 'static> Drop for PoisonOnUnwind<'_, T> {
+            fn drop(&mut self) {
+                self.0.poison();
+            }
+        }
+
+        if cell.is_poisoned() {
+            return Err(AccessError::Poisoned);
+        }
+        let res = if self.poison {
+            let guard = PoisonOnUnwind(cell);
+            let res = cell.try_with(f);
+            core::mem::forget(guard);
+            res
+        } else {
+            cell.try_with(f)
+        };
+        match res {
+            Ok(Some(res)) => Ok(res),
+            Ok(None) => Err(AccessError::NotSet),
+            Err(_) => Err(AccessError::Borrowed),
+        }
+    }
+
+    /// Returns a copy of the value in `cell`.
+    #[inline(always)]
+    pub(crate) fn copy(&'static self, cell: &ValueCell<T>) -> Result<T, AccessError>
+    where
+        T: Copy,
+    {
+        if cell.is_poisoned() {
+            return Err(AccessError::Poisoned);
+        }
+        match cell.get_copied() {
+            Ok(Some(value)) => Ok(value),
+            Ok(None) => Err(AccessError::NotSet),
+            Err(_) => Err(AccessError::Borrowed),
+        }
+    }
+}
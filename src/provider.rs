@@ -0,0 +1,388 @@
+//! Pluggable storage for the std backend.
+//!
+//! By default, the std backend keeps the storage of every key in a
+//! `thread_local!`, so task-locals follow OS threads. OS kernels, simulators
+//! and executors with their own notion of the current task can instead, with
+//! the `custom-storage` feature, install a [`StorageProvider`] with
+//! [`set_provider`] at init. The provider gives the crate one pointer-sized
+//! slot per key in the current task, in which the crate keeps the storage of
+//! the key for that task, and is told when scopes are entered and exited.
+//!
+//! The crate comes with two providers: [`ThreadLocalProvider`] keeps the
+//! slots in a `thread_local!`, and [`FreeRtos`](crate::freertos::FreeRtos) in
+//! the thread-local storage pointers of FreeRTOS tasks. If no provider is
+//! installed before the first access to a key, the former is used, or the
+//! latter with the `freertos` feature.
+//!
+//! Without `custom-storage`, keys do not go through a provider. The std
+//! backend gives every key its own `thread_local!`, which finds the storage
+//! of the current thread without looking up a slot by key as a provider has
+//! to, and the no_std backends keep their storage in `static`s indexed by
+//! core, Embassy task or RTIC priority level, where no task is looked up.
+//!
+//! The storage of a key in a task is allocated on its first access in the
+//! task. Scopes are always exited before a task finishes, so only an empty
+//! storage is left behind when it does, which the provider frees by passing
+//! the slot to [`release`]. [`ThreadLocalProvider`] does so when its thread
+//! exits.
+//!
+//! # Examples
+//!
+//! A simulator running every simulated task on the same thread:
+//!
+//! ```
+//! use std::cell::{Cell, RefCell};
+//! use std::collections::HashMap;
+//! use task_local::provider::{self, StorageKey, StorageProvider};
+//!
+//! thread_local! {
+//!     static CURRENT: Cell<u32> = const { Cell::new(0) };
+//!     static SLOTS: RefCell<HashMap<(u32, StorageKey), Box<Cell<*mut ()>>>> =
+//!         RefCell::new(HashMap::new());
+//! }
+//!
+//! struct Simulator;
+//!
+//! // Safety: Every simulated task gets its own slots, which are never freed,
+//! // and only the thread running the simulation accesses them.
+//! unsafe impl StorageProvider for Simulator {
+//!     fn slot_for_current_task(&self, key: StorageKey) -> *mut *mut () {
+//!         let task = CURRENT.with(Cell::get);
+//!         SLOTS.with_borrow_mut(|slots| {
+//!             let slot = slots
+//!                 .entry((task, key))
+//!                 .or_insert_with(|| Box::new(Cell::new(std::ptr::null_mut())));
+//!             slot.as_ptr()
+//!         })
+//!     }
+//! }
+//!
+//! task_local::task_local! {
+//!     static NODE: &'static str;
+//! }
+//!
+//! static SIMULATOR: Simulator = Simulator;
+//! assert!(provider::set_provider(&SIMULATOR).is_ok());
+//!
+//! NODE.sync_scope("node-a", || {
+//!     CURRENT.with(|task| task.set(1));
+//!     assert!(NODE.try_get().is_err());
+//!     CURRENT.with(|task| task.set(0));
+//!     assert_eq!(NODE.get(), "node-a");
+//! });
+//! ```
+
+use core::cell::{Cell, RefCell};
+use core::fmt;
+use core::ptr;
+use std::boxed::Box;
+use std::sync::OnceLock;
+use std::vec::Vec;
+
+use crate::atomic::{AtomicUsize, Ordering};
+
+/// The provider installed with [`set_provider`], or the default one.
+static PROVIDER: OnceLock<&'static dyn StorageProvider> = OnceLock::new();
+
+/// The provider used if none is installed before the first access to a key.
+#[cfg(not(feature = "freertos"))]
+static DEFAULT: ThreadLocalProvider = ThreadLocalProvider;
+#[cfg(feature = "freertos")]
+static DEFAULT: crate::freertos::FreeRtos = crate::freertos::FreeRtos;
+
+/// Identifies a key to a [`StorageProvider`].
+///
+/// Every key has a distinct `StorageKey`, the same in every task. Keys are
+/// numbered from 0 in the order they are first accessed, so that a provider
+/// can keep the slots of a task in a table indexed by [`get`](Self::get).
+///
+/// Requires the `custom-storage` feature.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct StorageKey(usize);
+
+impl StorageKey {
+    /// Returns the number of the key.
+    pub fn get(self) -> usize {
+        self.0
+    }
+}
+
+/// Finds the storage of keys in the current task.
+///
+/// Requires the `custom-storage` feature.
+///
+/// # Safety
+///
+/// [`slot_for_current_task`](Self::slot_for_current_task) must return a
+/// pointer to a slot that is null until the crate first writes to it, and
+/// then keeps what was written. The slot of a key in a task must always be
+/// the same, different from the slots of other keys and other tasks, valid
+/// for as long as the task can access the key, and only ever accessed by
+/// code running in that task. Tasks must not run concurrently with
+/// themselves, and must not be switched in the middle of an access to a key,
+/// such as by an interrupt handler accessing keys.
+pub unsafe trait StorageProvider: Sync + 'static {
+    /// Returns the slot of `key` in the current task, or null if there is no
+    /// current task, for example before the scheduler is started.
+    fn slot_for_current_task(&self, key: StorageKey) -> *mut *mut ();
+
+    /// Called when a scope of `key` is entered in the current task, including
+    /// on every poll of a future in a scope.
+    fn enter(&self, key: StorageKey) {
+        let _ = key;
+    }
+
+    /// Called when a scope of `key` entered in the current task is exited,
+    /// including at the end of every poll of a future in a scope.
+    fn exit(&self, key: StorageKey) {
+        let _ = key;
+    }
+}
+
+/// Installs the provider of the storage of every key.
+///
+/// The provider can only be installed once, before any key is accessed. If a
+/// provider is already installed, or the default one was used, `provider` is
+/// returned as the error.
+///
+/// Requires the `custom-storage` feature.
+pub fn set_provider<P: StorageProvider>(provider: &'static P) -> Result<(), &'static P> {
+    let mut installed = false;
+    PROVIDER.get_or_init(|| {
+        installed = true;
+        provider
+    });
+    if installed {
+        Ok(())
+    } else {
+        Err(provider)
+    }
+}
+
+fn provider() -> &'static dyn StorageProvider {
+    *PROVIDER.get_or_init(|| &DEFAULT)
+}
+
+/// Frees the storage that the crate kept in a slot of a task that finished.
+///
+/// Providers call this with the content of every slot of a task, null or
+/// not, once the task can no longer access any key.
+///
+/// Requires the `custom-storage` feature.
+///
+/// # Safety
+///
+/// `storage` must be null or the content of a slot returned by
+/// [`StorageProvider::slot_for_current_task`], which is not accessed and not
+/// released again afterwards.
+pub unsafe fn release(storage: *mut ()) {
+    if !storage.is_null() {
+        // Safety: Non-null storage was written by `ProvidedStorage::try_with`
+        // and starts with its release function, see `Stored`.
+        unsafe {
+            let release = *storage.cast::<unsafe fn(*mut ())>();
+            release(storage);
+        }
+    }
+}
+
+/// A [`StorageProvider`] keeping the slots of every thread in a
+/// `thread_local!`, as the std backend does without `custom-storage`.
+///
+/// The storage of every key is released when the thread exits.
+///
+/// Requires the `custom-storage` feature.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadLocalProvider;
+
+/// A slot of a task, releasing its storage when dropped.
+struct Slot(Cell<*mut ()>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        // Safety: The slot is dropped once its task has finished.
+        unsafe { release(self.0.get()) }
+    }
+}
+
+/// The slots of the keys used in a task, indexed by their [`StorageKey`] and
+/// boxed so that they are not moved. Dropping them, once the task has
+/// finished, releases their storage.
+pub(crate) struct Slots(Vec<Option<Box<Slot>>>);
+
+impl Slots {
+    pub(crate) const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Returns the slot of `key`, creating it if needed.
+    pub(crate) fn get(&mut self, key: StorageKey) -> *mut *mut () {
+        let index = key.get();
+        if index >= self.0.len() {
+            self.0.resize_with(index + 1, || None);
+        }
+        let slot = self.0[index].get_or_insert_with(|| Box::new(Slot(Cell::new(ptr::null_mut()))));
+        slot.0.as_ptr()
+    }
+}
+
+std::thread_local! {
+    static SLOTS: RefCell<Slots> = const { RefCell::new(Slots::new()) };
+}
+
+// Safety: Every thread has its own slots, boxed so that they are not moved.
+// They are released when the thread exits, after which `try_with` fails.
+unsafe impl StorageProvider for ThreadLocalProvider {
+    fn slot_for_current_task(&self, key: StorageKey) -> *mut *mut () {
+        SLOTS
+            .try_with(|slots| slots.borrow_mut().get(key))
+            .unwrap_or(ptr::null_mut())
+    }
+}
+
+/// The storage of a key in a task, as written to its slot: `release` comes
+/// first, so that [`release`] can free it without knowing `S`.
+#[repr(C)]
+struct Stored<S> {
+    release: unsafe fn(*mut ()),
+    storage: S,
+}
+
+/// Frees a `Stored<S>`, see [`release`].
+unsafe fn release_stored<S>(stored: *mut ()) {
+    // Safety: `stored` was created by `ProvidedStorage::<S>::try_with`.
+    drop(unsafe { Box::from_raw(stored.cast::<Stored<S>>()) });
+}
+
+/// The storage of a std key with the `custom-storage` feature, one per task
+/// of the installed provider.
+#[doc(hidden)]
+pub struct ProvidedStorage<S: 'static> {
+    init: fn() -> S,
+    // The number of the key plus one, or 0 before its first access.
+    key: AtomicUsize,
+}
+
+/// The number of keys accessed so far.
+static KEYS: AtomicUsize = AtomicUsize::new(0);
+
+impl<S: 'static> ProvidedStorage<S> {
+    #[doc(hidden)]
+    pub const fn new(init: fn() -> S) -> Self {
+        Self {
+            init,
+            key: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the key, numbering it on its first access.
+    fn key(&'static self) -> StorageKey {
+        let key = match self.key.load(Ordering::Relaxed) {
+            0 => {
+                let next = KEYS.fetch_add(1, Ordering::Relaxed) + 1;
+                // A key accessed by two threads at once may skip a number.
+                match self
+                    .key
+                    .compare_exchange(0, next, Ordering::Relaxed, Ordering::Relaxed)
+                {
+                    Ok(_) => next,
+                    Err(key) => key,
+                }
+            }
+            key => key,
+        };
+        StorageKey(key - 1)
+    }
+
+    /// Runs `f` on the storage of the current task, like
+    /// `std::thread::LocalKey::with`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no current task.
+    #[track_caller]
+    pub(crate) fn with<R>(&'static self, f: impl FnOnce(&S) -> R) -> R {
+        match self.try_with(f) {
+            Ok(res) => res,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Runs `f` on the storage of the current task, like
+    /// `std::thread::LocalKey::try_with`, or fails if there is no current
+    /// task.
+    pub(crate) fn try_with<R>(&'static self, f: impl FnOnce(&S) -> R) -> Result<R, NoTask> {
+        let slot = provider().slot_for_current_task(self.key());
+        if slot.is_null() {
+            return Err(NoTask);
+        }
+        // Safety: The slot is only accessed by the current task, see
+        // `StorageProvider`, and holds null or a storage of this key created
+        // below, which is only shared, and only released once the task can
+        // no longer get here.
+        let storage = unsafe {
+            if (*slot).is_null() {
+                let stored = Stored {
+                    release: release_stored::<S>,
+                    storage: (self.init)(),
+                };
+                *slot = Box::into_raw(Box::new(stored)).cast();
+            }
+            &(*(*slot).cast::<Stored<S>>()).storage
+        };
+        Ok(f(storage))
+    }
+
+    /// Tells the provider that a scope of the key was entered.
+    pub(crate) fn entered(&'static self) {
+        provider().enter(self.key());
+    }
+
+    /// Tells the provider that a scope of the key was exited.
+    pub(crate) fn exited(&'static self) {
+        provider().exit(self.key());
+    }
+}
+
+/// The error of an access to a key outside of any task of the provider.
+#[derive(Debug)]
+pub(crate) struct NoTask;
+
+impl fmt::Display for NoTask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("task-local storage accessed outside of any task of the storage provider")
+    }
+}
+
+// With the `freertos` feature, the lib tests run every thread as a FreeRTOS
+// task deleted when the thread exits, see `freertos.rs`.
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::ProvidedStorage;
+
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Storage;
+
+    impl Drop for Storage {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    static STORAGE: ProvidedStorage<Storage> = ProvidedStorage::new(|| Storage);
+
+    #[test]
+    fn storage_released_when_task_finishes() {
+        std::thread::spawn(|| {
+            STORAGE.try_with(|_| ()).unwrap();
+            STORAGE.try_with(|_| ()).unwrap();
+            assert_eq!(DROPPED.load(Ordering::Relaxed), 0);
+        })
+        .join()
+        .unwrap();
+        assert_eq!(DROPPED.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,155 @@
+//! Mirroring of task-locals into Python `contextvars`.
+//!
+//! Applications embedding Python with PyO3 keep request context in
+//! task-locals on the Rust side and in `contextvars.ContextVar`s on the
+//! Python side. A [`ContextVarBridge`] maps keys to context variables: every
+//! key is registered under a name, for which the bridge creates a
+//! `ContextVar`, and [`ContextVarBridge::call`] sets every variable to the
+//! current value of its key while it calls into Python, resetting them when
+//! the call returns.
+//!
+//! The variables are set in the current Python context of the thread, with
+//! `ContextVar.set`, and reset with the returned tokens, so values that Python
+//! code sets in between are discarded too.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use pyo3::prelude::*;
+//! use pyo3::types::PyDict;
+//! use task_local::python::ContextVarBridge;
+//!
+//! task_local::task_local! {
+//!     static REQUEST_ID: u64;
+//! }
+//!
+//! Python::initialize();
+//! Python::attach(|py| -> PyResult<()> {
+//!     let mut bridge = ContextVarBridge::new();
+//!     bridge.register(py, &REQUEST_ID, "request_id")?;
+//!
+//!     // Make the variable available to the Python code.
+//!     let globals = PyDict::new(py);
+//!     globals.set_item("request_id", bridge.var(py, "request_id").unwrap())?;
+//!
+//!     let id: u64 = REQUEST_ID.sync_scope(7u64, || {
+//!         bridge.call(py, |py| py.eval(c"request_id.get()", Some(&globals), None)?.extract())
+//!     })??;
+//!     assert_eq!(id, 7);
+//!     Ok(())
+//! })
+//! .unwrap();
+//! ```
+
+use std::fmt;
+
+use pyo3::prelude::*;
+use pyo3::IntoPyObjectExt;
+
+use crate::LocalKey;
+
+/// Converts the current value of a key into a Python object, if it is set.
+type ToPython =
+    Box<dyn for<'py> Fn(Python<'py>) -> PyResult<Option<Bound<'py, PyAny>>> + Send + Sync>;
+
+/// A mapping from task-local keys to Python context variables.
+///
+/// Requires the `pyo3` feature.
+#[derive(Default)]
+pub struct ContextVarBridge {
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    name: String,
+    var: Py<PyAny>,
+    to_python: ToPython,
+}
+
+impl ContextVarBridge {
+    /// Creates a bridge without any key.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mirrors `key` into a new `ContextVar` named `name`.
+    ///
+    /// The value of the key is cloned and converted into a Python object on
+    /// every [`call`](Self::call) made while it is set.
+    pub fn register<T>(
+        &mut self,
+        py: Python<'_>,
+        key: &'static LocalKey<T>,
+        name: &str,
+    ) -> PyResult<()>
+    where
+        T: Clone + for<'py> IntoPyObject<'py> + 'static,
+    {
+        let var = py
+            .import("contextvars")?
+            .getattr("ContextVar")?
+            .call1((name,))?;
+        self.entries.push(Entry {
+            name: name.to_owned(),
+            var: var.unbind(),
+            to_python: Box::new(move |py| match key.try_with(Clone::clone) {
+                Ok(value) => value.into_bound_py_any(py).map(Some),
+                Err(_) => Ok(None),
+            }),
+        });
+        Ok(())
+    }
+
+    /// Returns the `ContextVar` registered under `name`, to be handed to the
+    /// Python code reading it.
+    pub fn var<'py>(&self, py: Python<'py>, name: &str) -> Option<Bound<'py, PyAny>> {
+        self.entries
+            .iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| entry.var.bind(py).clone())
+    }
+
+    /// Calls `f` with every context variable set to the current value of its
+    /// key, and resets the variables once `f` returns or panics.
+    ///
+    /// Variables whose key is not set are left as they are. Returns an error,
+    /// without calling `f`, if a value cannot be converted into a Python
+    /// object.
+    pub fn call<'py, F, R>(&self, py: Python<'py>, f: F) -> PyResult<R>
+    where
+        F: FnOnce(Python<'py>) -> R,
+    {
+        /// Resets the variables that were set, in reverse order.
+        struct Reset<'py> {
+            tokens: Vec<(Bound<'py, PyAny>, Bound<'py, PyAny>)>,
+        }
+
+        impl Drop for Reset<'_> {
+            fn drop(&mut self) {
+                for (var, token) in self.tokens.drain(..).rev() {
+                    // Resetting only fails for a token used in another
+                    // context, which Python code cannot get hold of.
+                    let _ = var.call_method1("reset", (token,));
+                }
+            }
+        }
+
+        let mut reset = Reset { tokens: Vec::new() };
+        for entry in &self.entries {
+            if let Some(value) = (entry.to_python)(py)? {
+                let var = entry.var.bind(py).clone();
+                let token = var.call_method1("set", (value,))?;
+                reset.tokens.push((var, token));
+            }
+        }
+        Ok(f(py))
+    }
+}
+
+impl fmt::Debug for ContextVarBridge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.entries.iter().map(|entry| &entry.name))
+            .finish()
+    }
+}
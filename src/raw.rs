@@ -0,0 +1,185 @@
+//! Hooks for executors that switch between tasks themselves.
+//!
+//! A scope sets the value of a key for the thread, or core, it runs on until
+//! it returns, and a `TaskLocalFuture` leaves its scope whenever it returns
+//! from `poll`. Executors that suspend a task without returning from it, such
+//! as kernels with their own context switch or job systems running fibers,
+//! have to hide the values of that task from the next one and bring them back
+//! when it resumes. [`exit_raw`] detaches the scopes of every key from the
+//! current thread into a [`RawContext`], and [`enter_raw`] attaches them
+//! again.
+//!
+//! The keys are found through the registry, which knows every key a scope was
+//! ever entered for. Nothing is copied: a `RawContext` only points to the
+//! values, which stay in the scopes of the suspended task.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::ptr::NonNull;
+
+use crate::registry;
+use crate::value_cell::ScopeState;
+use crate::{LocalKey, ValueCell};
+
+/// Detaches the scope of a key, returning its slot and its state.
+type DetachFn = fn(*const ()) -> Option<(NonNull<()>, ScopeState)>;
+
+/// Attaches a key to a slot returned by its `DetachFn`.
+type AttachFn = unsafe fn(*const (), NonNull<()>, ScopeState);
+
+/// Raw hooks of a key, embedded in its registry entry.
+pub(crate) struct Hooks {
+    detach: DetachFn,
+    attach: AttachFn,
+}
+
+impl Hooks {
+    pub(crate) const fn new<T: 'static>() -> Self {
+        Self {
+            detach: detach_key::<T>,
+            attach: attach_key::<T>,
+        }
+    }
+}
+
+fn detach_key<T: 'static>(key: *const ()) -> Option<(NonNull<()>, ScopeState)> {
+    // Safety: `key` was stored by `registry::register::<T>` from a
+    // `&'static LocalKey<T>`.
+    let key = unsafe { &*(key as *const LocalKey<T>) };
+    match key.with_current_cell(ValueCell::detach)? {
+        Ok(detached) => detached.map(|(slot, state)| (slot.cast(), state)),
+        Err(_) => panic!(
+            "cannot switch away from a task while task-local `{}` is borrowed",
+            key.name
+        ),
+    }
+}
+
+/// # Safety
+///
+/// `slot` must have been returned by `detach_key::<T>`, see [`enter_raw`].
+unsafe fn attach_key<T: 'static>(key: *const (), slot: NonNull<()>, state: ScopeState) {
+    // Safety: As in `detach_key`.
+    let key = unsafe { &*(key as *const LocalKey<T>) };
+    #[cfg(feature = "std")]
+    let mut state = state;
+    #[cfg(feature = "std")]
+    key.check_thread(&mut state);
+    // Safety: Guaranteed by the caller.
+    let attached = key.with_current_cell(|cell| unsafe { cell.attach(slot.cast(), state) });
+    if attached != Some(true) {
+        panic!(
+            "cannot switch to a task while task-local `{}` is set or borrowed",
+            key.name
+        );
+    }
+}
+
+struct Entry {
+    key: *const (),
+    hooks: &'static Hooks,
+    slot: NonNull<()>,
+    state: ScopeState,
+}
+
+/// The scopes of a suspended task, taken out of the keys by [`exit_raw`].
+///
+/// A `RawContext` only points to the values, which stay in the scopes of the
+/// task, so it is tied to the thread it was created on. Dropping it without
+/// passing it to [`enter_raw`] is allowed; the keys then stay unset until the
+/// task enters new scopes.
+///
+/// Requires the `raw-hooks` feature.
+pub struct RawContext {
+    entries: Vec<Entry>,
+}
+
+impl RawContext {
+    /// Returns `true` if no key was set when the context was taken.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl fmt::Debug for RawContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawContext")
+            .field("len", &self.entries.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Detaches the scopes of every key from the current thread, or core, before
+/// switching away from the running task.
+///
+/// Afterwards no key is set, as if no scope had been entered, until the
+/// scopes are attached again with [`enter_raw`] once the task resumes. Only
+/// keys that a scope was entered for at least once are known to the registry,
+/// which covers every key that can be set.
+///
+/// Requires the `raw-hooks` feature.
+///
+/// # Panics
+///
+/// Panics if a key is borrowed, for example when called from the closure
+/// passed to [`LocalKey::with`].
+///
+/// # Examples
+///
+/// ```
+/// task_local::task_local! {
+///     static TASK_ID: u32;
+/// }
+///
+/// TASK_ID.sync_scope(1u32, || {
+///     // The executor suspends task 1 and resumes task 2.
+///     let task_1 = task_local::exit_raw();
+///     assert!(TASK_ID.try_with(|_| ()).is_err());
+///     TASK_ID.sync_scope(2u32, || assert_eq!(TASK_ID.get(), 2));
+///
+///     // Task 2 is done, switch back to task 1.
+///     // Safety: Task 1 did not run in the meantime, and its scopes are
+///     // still entered.
+///     unsafe { task_local::enter_raw(task_1) };
+///     assert_eq!(TASK_ID.get(), 1);
+/// });
+/// ```
+pub fn exit_raw() -> RawContext {
+    let entries = registry::raw_hooks()
+        .filter_map(|(key, hooks)| {
+            let (slot, state) = (hooks.detach)(key)?;
+            Some(Entry {
+                key,
+                hooks,
+                slot,
+                state,
+            })
+        })
+        .collect();
+    RawContext { entries }
+}
+
+/// Attaches the scopes detached by [`exit_raw`] to the current thread, or
+/// core, when switching back to the task they belong to.
+///
+/// Requires the `raw-hooks` feature.
+///
+/// # Panics
+///
+/// Panics if a key of `context` is set or borrowed, for example because a
+/// task that ran in between was switched away from without calling
+/// [`exit_raw`].
+///
+/// # Safety
+///
+/// Every scope that was entered when `context` was taken must still be
+/// entered, and its value must not have moved: the task must not have run or
+/// been dropped since. In no_std builds with the `per-core` feature, `context`
+/// must be attached on the core it was taken on.
+pub unsafe fn enter_raw(context: RawContext) {
+    for entry in context.entries {
+        // Safety: The slot was returned by the detach hook of the same key,
+        // and is still valid as guaranteed by the caller.
+        unsafe { (entry.hooks.attach)(entry.key, entry.slot, entry.state) };
+    }
+}
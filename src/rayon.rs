@@ -0,0 +1,242 @@
+//! Task-locals in `rayon` parallel iterators.
+//!
+//! The closures of a parallel iterator run on the threads of the `rayon`
+//! pool, where the task-locals of the code that started the pipeline are not
+//! set. With the `rayon` feature, [`ParallelIteratorExt::scope_rayon`] takes
+//! a snapshot of a selection of keys, see [`Capture`], and sets it on the
+//! worker threads while they run the rest of the pipeline, so that CPU-bound
+//! fan-out keeps the context of the request for logging and metrics.
+//!
+//! The values are set once for every chunk of items a worker processes, and
+//! around the reductions that combine their results, so the cost does not
+//! grow with the number of items.
+//!
+//! # Examples
+//!
+//! ```
+//! use rayon::prelude::*;
+//! use task_local::rayon::ParallelIteratorExt;
+//!
+//! task_local::task_local! {
+//!     static REQUEST_ID: u64;
+//! }
+//!
+//! let lines: Vec<String> = REQUEST_ID.sync_scope(7u64, || {
+//!     (0..4)
+//!         .into_par_iter()
+//!         .scope_rayon(&REQUEST_ID)
+//!         .map(|i| format!("[{}] item {i}", REQUEST_ID.get()))
+//!         .collect()
+//! });
+//!
+//! assert_eq!(lines[3], "[7] item 3");
+//! ```
+
+use std::fmt;
+
+use ::rayon::iter::plumbing::{Consumer, Folder, Reducer, UnindexedConsumer};
+use ::rayon::iter::ParallelIterator;
+
+use crate::Capture;
+
+/// An extension trait for `rayon` parallel iterators.
+pub trait ParallelIteratorExt: ParallelIterator {
+    /// Captures the current values of `keys` and sets them on the worker
+    /// threads for the stages of the pipeline after this one.
+    ///
+    /// Closures of the stages before `scope_rayon` are not guaranteed to see
+    /// the values, as `rayon` may run them inside or outside of the scopes.
+    /// The returned iterator is unindexed, so adapters that need an indexed
+    /// iterator, such as `enumerate` or `zip`, go before it.
+    fn scope_rayon<C>(self, keys: C) -> ScopeRayon<Self, C>
+    where
+        C: Capture,
+    {
+        ScopeRayon {
+            snapshot: keys.capture(),
+            base: self,
+            keys,
+        }
+    }
+}
+
+impl<I: ParallelIterator> ParallelIteratorExt for I {}
+
+/// A parallel iterator that sets a snapshot of task-locals on the worker
+/// threads.
+///
+/// Created by the method [`ParallelIteratorExt::scope_rayon`]. Like the
+/// channel wrappers, the snapshot type `S` is a separate parameter that is
+/// left at its default.
+pub struct ScopeRayon<I, C, S = <C as Capture>::Snapshot> {
+    base: I,
+    keys: C,
+    snapshot: S,
+}
+
+impl<I, C, S> ScopeRayon<I, C, S> {
+    /// Returns the captured values.
+    pub fn snapshot(&self) -> &S {
+        &self.snapshot
+    }
+
+    /// Consumes the adapter, returning the wrapped iterator.
+    pub fn into_inner(self) -> I {
+        self.base
+    }
+}
+
+impl<I: fmt::Debug, C, S> fmt::Debug for ScopeRayon<I, C, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScopeRayon")
+            .field("base", &self.base)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<I, C, S> ParallelIterator for ScopeRayon<I, C, S>
+where
+    I: ParallelIterator,
+    C: Capture<Snapshot = S> + Send + Sync,
+    S: Clone + Send + Sync,
+{
+    type Item = I::Item;
+
+    fn drive_unindexed<Co>(self, consumer: Co) -> Co::Result
+    where
+        Co: UnindexedConsumer<Self::Item>,
+    {
+        let snapshot = self.snapshot;
+        self.base
+            .drive_unindexed(ScopeConsumer::new(consumer, self.keys, &snapshot))
+    }
+
+    fn opt_len(&self) -> Option<usize> {
+        self.base.opt_len()
+    }
+}
+
+/// Wraps the consumer of the rest of the pipeline, so that its folders and
+/// reducers run with the snapshot set.
+struct ScopeConsumer<'s, Co, C, S> {
+    base: Co,
+    keys: C,
+    snapshot: &'s S,
+}
+
+impl<'s, T, Co, C, S> Consumer<T> for ScopeConsumer<'s, Co, C, S>
+where
+    Co: Consumer<T>,
+    C: Capture<Snapshot = S> + Send,
+    S: Clone + Sync,
+{
+    type Folder = ScopeConsumer<'s, Co::Folder, C, S>;
+    type Reducer = ScopeConsumer<'s, Co::Reducer, C, S>;
+    type Result = Co::Result;
+
+    fn split_at(self, index: usize) -> (Self, Self, Self::Reducer) {
+        let (keys, snapshot) = (self.keys, self.snapshot);
+        let (left, right, reducer) = self.base.split_at(index);
+        (
+            ScopeConsumer::new(left, keys, snapshot),
+            ScopeConsumer::new(right, keys, snapshot),
+            ScopeConsumer::new(reducer, keys, snapshot),
+        )
+    }
+
+    fn into_folder(self) -> Self::Folder {
+        ScopeConsumer::new(self.base.into_folder(), self.keys, self.snapshot)
+    }
+
+    fn full(&self) -> bool {
+        self.base.full()
+    }
+}
+
+impl<T, Co, C, S> UnindexedConsumer<T> for ScopeConsumer<'_, Co, C, S>
+where
+    Co: UnindexedConsumer<T>,
+    C: Capture<Snapshot = S> + Send,
+    S: Clone + Sync,
+{
+    fn split_off_left(&self) -> Self {
+        ScopeConsumer::new(self.base.split_off_left(), self.keys, self.snapshot)
+    }
+
+    fn to_reducer(&self) -> Self::Reducer {
+        ScopeConsumer::new(self.base.to_reducer(), self.keys, self.snapshot)
+    }
+}
+
+impl<T, F, C, S> Folder<T> for ScopeConsumer<'_, F, C, S>
+where
+    F: Folder<T>,
+    C: Capture<Snapshot = S>,
+    S: Clone,
+{
+    type Result = F::Result;
+
+    fn consume(self, item: T) -> Self {
+        let Self {
+            base,
+            keys,
+            snapshot,
+        } = self;
+        let base = keys.sync_scope(snapshot.clone(), || base.consume(item));
+        Self {
+            base,
+            keys,
+            snapshot,
+        }
+    }
+
+    fn consume_iter<I>(self, iter: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let Self {
+            base,
+            keys,
+            snapshot,
+        } = self;
+        let base = keys.sync_scope(snapshot.clone(), || base.consume_iter(iter));
+        Self {
+            base,
+            keys,
+            snapshot,
+        }
+    }
+
+    fn complete(self) -> F::Result {
+        let base = self.base;
+        self.keys
+            .sync_scope(self.snapshot.clone(), || base.complete())
+    }
+
+    fn full(&self) -> bool {
+        self.base.full()
+    }
+}
+
+impl<R, Re, C, S> Reducer<R> for ScopeConsumer<'_, Re, C, S>
+where
+    Re: Reducer<R>,
+    C: Capture<Snapshot = S>,
+    S: Clone,
+{
+    fn reduce(self, left: R, right: R) -> R {
+        let base = self.base;
+        self.keys
+            .sync_scope(self.snapshot.clone(), || base.reduce(left, right))
+    }
+}
+
+impl<'s, B, C, S> ScopeConsumer<'s, B, C, S> {
+    fn new(base: B, keys: C, snapshot: &'s S) -> Self {
+        Self {
+            base,
+            keys,
+            snapshot,
+        }
+    }
+}
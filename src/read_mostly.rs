@@ -0,0 +1,194 @@
+//! Keys for values that are read very often and rarely changed.
+//!
+//! A [`ReadMostlyKey`] holds a global value in an `ArcSwap`, replaced with
+//! [`store`](ReadMostlyKey::store) when the configuration it holds is
+//! reloaded, for example, and overridden per task with
+//! [`scope`](ReadMostlyKey::scope). Every key counts the override scopes that
+//! are alive, so that while there are none, reading the value only loads the
+//! `ArcSwap` and does not touch the task-local storage at all.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use arc_swap::ArcSwapOption;
+use pin_project_lite::pin_project;
+
+use crate::{AccessError, LocalKey, TaskLocalFuture};
+
+/// Declares a new [`ReadMostlyKey`].
+///
+/// The key has no global value until one is stored with
+/// [`ReadMostlyKey::store`]. Requires the `read-mostly` feature.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// struct Config {
+///     timeout_ms: u64,
+/// }
+///
+/// task_local::read_mostly! {
+///     static CONFIG: Config;
+/// }
+///
+/// CONFIG.store(Arc::new(Config { timeout_ms: 500 }));
+/// assert_eq!(CONFIG.get_shared().timeout_ms, 500);
+/// ```
+#[macro_export]
+macro_rules! read_mostly {
+    () => {};
+
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty; $($rest:tt)*) => {
+        $crate::read_mostly!($(#[$attr])* $vis static $name: $t);
+        $crate::read_mostly!($($rest)*);
+    };
+
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty) => {
+        $(#[$attr])*
+        $vis static $name: $crate::ReadMostlyKey<$t> = {
+            $crate::task_local! {
+                static $name: $crate::__private::Arc<$t>;
+            }
+
+            $crate::ReadMostlyKey::__new(&$name)
+        };
+    };
+}
+
+/// A key for a value that is read very often and rarely changed.
+///
+/// This type is generated by the [`read_mostly!`](crate::read_mostly) macro.
+/// Values are shared as `Arc<T>`: the global value is replaced with
+/// [`store`](Self::store), and [`scope`](Self::scope) overrides it for a
+/// future like [`LocalKey::scope`].
+pub struct ReadMostlyKey<T: 'static> {
+    global: ArcSwapOption<T>,
+    overrides: &'static LocalKey<Arc<T>>,
+    scopes: AtomicUsize,
+}
+
+impl<T: 'static> ReadMostlyKey<T> {
+    #[doc(hidden)]
+    pub const fn __new(overrides: &'static LocalKey<Arc<T>>) -> Self {
+        Self {
+            global: ArcSwapOption::const_empty(),
+            overrides,
+            scopes: AtomicUsize::new(0),
+        }
+    }
+
+    /// Replaces the global value, seen wherever no override is set.
+    pub fn store(&'static self, value: Arc<T>) {
+        self.global.store(Some(value));
+    }
+
+    /// Returns the current value: the value of the innermost override scope,
+    /// or the global value outside of any.
+    ///
+    /// While no override scope of this key exists on any thread, this only
+    /// loads the global value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there is no override and no global value was stored.
+    #[track_caller]
+    pub fn get_shared(&'static self) -> Arc<T> {
+        match self.try_get_shared() {
+            Ok(value) => value,
+            Err(err) => panic!("read-mostly key `{}`: {}", self.overrides.name, err),
+        }
+    }
+
+    /// Returns the current value, or [`AccessError::NotSet`] if there is no
+    /// override and no global value was stored.
+    pub fn try_get_shared(&'static self) -> Result<Arc<T>, AccessError> {
+        // Override scopes count themselves on the thread they are created
+        // on, before they can be polled anywhere, so a task that is inside
+        // one always sees a non-zero count.
+        if self.scopes.load(Ordering::Relaxed) != 0 {
+            match self.overrides.try_with(Arc::clone) {
+                Err(AccessError::NotSet) => {}
+                res => return res,
+            }
+        }
+        self.global.load_full().ok_or(AccessError::NotSet)
+    }
+
+    /// Overrides the value for the future `f`.
+    pub fn scope<F>(&'static self, value: Arc<T>, f: F) -> ReadMostlyFuture<T, F>
+    where
+        F: Future,
+    {
+        ReadMostlyFuture {
+            _counted: Counted::new(self),
+            future: self.overrides.scope(value, f),
+        }
+    }
+
+    /// Overrides the value for the closure `f`.
+    #[track_caller]
+    pub fn sync_scope<F, R>(&'static self, value: Arc<T>, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let _counted = Counted::new(self);
+        self.overrides.sync_scope(value, f)
+    }
+}
+
+impl<T: 'static> fmt::Debug for ReadMostlyKey<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadMostlyKey")
+            .field("name", &self.overrides.name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Counts an override scope of a key while it is alive.
+struct Counted<T: 'static>(&'static ReadMostlyKey<T>);
+
+impl<T: 'static> Counted<T> {
+    fn new(key: &'static ReadMostlyKey<T>) -> Self {
+        key.scopes.fetch_add(1, Ordering::Relaxed);
+        Self(key)
+    }
+}
+
+impl<T: 'static> Drop for Counted<T> {
+    fn drop(&mut self) {
+        self.0.scopes.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pin_project! {
+    /// A future that overrides the value of a [`ReadMostlyKey`] during its
+    /// execution.
+    ///
+    /// Created by the function [`ReadMostlyKey::scope`].
+    pub struct ReadMostlyFuture<T: 'static, F> {
+        _counted: Counted<T>,
+        #[pin]
+        future: TaskLocalFuture<Arc<T>, F>,
+    }
+}
+
+impl<T: 'static, F: Future> Future for ReadMostlyFuture<T, F> {
+    type Output = F::Output;
+
+    #[track_caller]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        self.project().future.poll(cx)
+    }
+}
+
+impl<T: 'static, F> fmt::Debug for ReadMostlyFuture<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadMostlyFuture").finish_non_exhaustive()
+    }
+}
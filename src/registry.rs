@@ -0,0 +1,315 @@
+//! Registry of task-local keys, for debugging.
+//!
+//! With the `registry` feature, every key adds itself to a global intrusive
+//! list the first time a scope of it is entered. [`dump`] walks that list and
+//! formats the keys that are set in the current task, together with their
+//! values. Keys that were never entered cannot be set, so registering them
+//! lazily does not miss anything and needs neither allocation nor linker
+//! tricks.
+//!
+//! Every registered key also gets a [`KeyId`], a small number handed out in
+//! the order keys are registered, so that tools can keep per-key data in
+//! plain arrays instead of maps.
+
+use core::fmt;
+use core::ptr;
+
+use crate::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+#[cfg(any(feature = "leak-check", feature = "testing"))]
+use crate::ValueSource;
+use crate::{AnyLocalKey, LocalKey};
+
+/// Head of the list of registered keys.
+static HEAD: AtomicPtr<Node> = AtomicPtr::new(ptr::null_mut());
+
+/// The number of identifiers handed out so far.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// The value of `Node::id` before the key gets its identifier.
+const NO_ID: usize = usize::MAX;
+
+/// Formats the value of a key, if it is set, as an entry of `map`.
+type DumpFn = fn(*const (), &mut fmt::DebugMap<'_, '_>);
+
+/// Returns a key as a type-erased key.
+type AnyFn = fn(*const ()) -> &'static dyn AnyLocalKey;
+
+/// Returns the name and module path of a key if it is set.
+#[cfg(any(feature = "leak-check", feature = "testing"))]
+type SetFn = fn(*const ()) -> Option<(&'static str, &'static str)>;
+
+/// Registry entry embedded in every key.
+pub(crate) struct Node {
+    registered: AtomicBool,
+    next: AtomicPtr<Node>,
+    key: AtomicPtr<()>,
+    id: AtomicUsize,
+    dump: DumpFn,
+    any: AnyFn,
+    #[cfg(any(feature = "leak-check", feature = "testing"))]
+    is_set: SetFn,
+    #[cfg(feature = "raw-hooks")]
+    raw: crate::raw::Hooks,
+}
+
+impl Node {
+    pub(crate) const fn new<T: 'static>() -> Self {
+        Self {
+            registered: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+            key: AtomicPtr::new(ptr::null_mut()),
+            id: AtomicUsize::new(NO_ID),
+            dump: dump_key::<T>,
+            any: crate::any_key::erase::<T>,
+            #[cfg(any(feature = "leak-check", feature = "testing"))]
+            is_set: is_set::<T>,
+            #[cfg(feature = "raw-hooks")]
+            raw: crate::raw::Hooks::new::<T>(),
+        }
+    }
+}
+
+/// Adds `key` to the registry unless it is already registered.
+pub(crate) fn register<T: 'static>(key: &'static LocalKey<T>) {
+    let node = &key.node;
+    if node.registered.load(Ordering::Relaxed) || node.registered.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    assign_id(node);
+    node.key
+        .store(key as *const LocalKey<T> as *mut (), Ordering::Relaxed);
+    let node_ptr = node as *const Node as *mut Node;
+    let mut head = HEAD.load(Ordering::Relaxed);
+    loop {
+        node.next.store(head, Ordering::Relaxed);
+        match HEAD.compare_exchange_weak(head, node_ptr, Ordering::Release, Ordering::Relaxed) {
+            Ok(_) => break,
+            Err(current) => head = current,
+        }
+    }
+}
+
+/// Gives `node` the next identifier unless it already has one, and returns
+/// its identifier.
+///
+/// Called by the context registering the key, and by [`LocalKey::id`] in
+/// another context that gets there first. Only when both race is an
+/// identifier skipped, as the loser does not give its own back.
+fn assign_id(node: &Node) -> usize {
+    let id = node.id.load(Ordering::Relaxed);
+    if id != NO_ID {
+        return id;
+    }
+    let next = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    match node
+        .id
+        .compare_exchange(NO_ID, next, Ordering::Relaxed, Ordering::Relaxed)
+    {
+        Ok(_) => next,
+        Err(id) => id,
+    }
+}
+
+/// A small number identifying a registered key, returned by [`LocalKey::id`].
+///
+/// Identifiers are handed out from 0 in the order keys are registered, the
+/// first time a scope of them is entered or their identifier is asked for, and
+/// stay the same for the life of the process. They are below
+/// [`KeyId::count`], so per-key data such as captured values or counters can
+/// be kept in an array indexed by [`index`](Self::index).
+///
+/// Requires the `registry` feature.
+///
+/// # Examples
+///
+/// ```
+/// use task_local::KeyId;
+///
+/// task_local::task_local! {
+///     static REQUEST_ID: u64;
+///     static TENANT: &'static str;
+/// }
+///
+/// assert_ne!(REQUEST_ID.id(), TENANT.id());
+///
+/// let mut reads = vec![0; KeyId::count()];
+/// reads[TENANT.id().index()] += 1;
+/// assert_eq!(reads[TENANT.id().index()], 1);
+/// ```
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct KeyId(usize);
+
+impl KeyId {
+    /// Returns the number of the identifier.
+    pub const fn index(self) -> usize {
+        self.0
+    }
+
+    /// Returns the number of identifiers handed out so far, which every
+    /// identifier is below.
+    pub fn count() -> usize {
+        NEXT_ID.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for KeyId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+impl<T: 'static> LocalKey<T> {
+    /// Returns the identifier of this key, registering it if no scope of it
+    /// was entered yet.
+    ///
+    /// Requires the `registry` feature. See [`KeyId`].
+    pub fn id(&'static self) -> KeyId {
+        register(self);
+        KeyId(assign_id(&self.node))
+    }
+}
+
+fn dump_key<T: 'static>(key: *const (), map: &mut fmt::DebugMap<'_, '_>) {
+    // Safety: `key` was stored by `register::<T>` from a `&'static LocalKey<T>`.
+    let key = unsafe { &*(key as *const LocalKey<T>) };
+    let _ = key.try_with(|value| {
+        map.entry(
+            &format_args!("{}::{}", key.module_path, key.name),
+            &Value(value, key.fmt_value),
+        );
+    });
+}
+
+#[cfg(any(feature = "leak-check", feature = "testing"))]
+fn is_set<T: 'static>(key: *const ()) -> Option<(&'static str, &'static str)> {
+    // Safety: As in `dump_key`.
+    let key = unsafe { &*(key as *const LocalKey<T>) };
+    // A global or environment default is not set by a scope that could leak.
+    match key.try_with_source(|_| ()) {
+        Ok(((), ValueSource::Scope)) => Some((key.name, key.module_path)),
+        _ => None,
+    }
+}
+
+/// Returns the name and module path of every registered key that is set in
+/// the current task.
+#[cfg(any(feature = "leak-check", feature = "testing"))]
+pub(crate) fn set_keys() -> impl Iterator<Item = (&'static str, &'static str)> {
+    let mut node = HEAD.load(Ordering::Acquire);
+    core::iter::from_fn(move || loop {
+        // Safety: As in `Dump::fmt`.
+        let current = unsafe { node.as_ref() }?;
+        node = current.next.load(Ordering::Relaxed);
+        if let Some(key) = (current.is_set)(current.key.load(Ordering::Relaxed)) {
+            return Some(key);
+        }
+    })
+}
+
+/// Returns every registered key, as passed to the functions of its node, with
+/// its raw hooks.
+#[cfg(feature = "raw-hooks")]
+pub(crate) fn raw_hooks() -> impl Iterator<Item = (*const (), &'static crate::raw::Hooks)> {
+    let mut node = HEAD.load(Ordering::Acquire);
+    core::iter::from_fn(move || {
+        // Safety: As in `Dump::fmt`.
+        let current = unsafe { node.as_ref() }?;
+        node = current.next.load(Ordering::Relaxed);
+        Some((
+            current.key.load(Ordering::Relaxed) as *const (),
+            &current.raw,
+        ))
+    })
+}
+
+/// Returns every key known to the registry, set in the current task or not.
+///
+/// Keys are only known to the registry once a scope of them has been entered,
+/// and are returned from the most recently registered to the first.
+///
+/// Requires the `registry` feature.
+///
+/// # Examples
+///
+/// Capturing every key that is set, to set them again in another task:
+///
+/// ```
+/// use task_local::AnyLocalKey;
+///
+/// task_local::task_local! {
+///     static TENANT: &'static str;
+/// }
+///
+/// let captured: Vec<(&'static dyn AnyLocalKey, _)> = TENANT.sync_scope("acme", || {
+///     task_local::registered_keys()
+///         .filter_map(|key| Some((key, key.capture()?)))
+///         .collect()
+/// });
+///
+/// let mut restore = || assert_eq!(TENANT.get(), "acme");
+/// for (key, value) in captured {
+///     key.sync_restore(value, &mut restore).unwrap();
+/// }
+/// ```
+pub fn registered_keys() -> impl Iterator<Item = &'static dyn AnyLocalKey> {
+    let mut node = HEAD.load(Ordering::Acquire);
+    core::iter::from_fn(move || {
+        // Safety: As in `Dump::fmt`.
+        let current = unsafe { node.as_ref() }?;
+        node = current.next.load(Ordering::Relaxed);
+        Some((current.any)(current.key.load(Ordering::Relaxed)))
+    })
+}
+
+struct Value<'a, T>(&'a T, fn(&T, &mut fmt::Formatter<'_>) -> fmt::Result);
+
+impl<T> fmt::Debug for Value<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (self.1)(self.0, f)
+    }
+}
+
+/// Returns a snapshot of the task-local keys that are set in the current task.
+///
+/// The returned value implements `Debug`, printing a map from the path of
+/// every key that is currently set to its value. Values whose type does not
+/// implement `Debug` are printed as `<opaque>`. Keys are only known to the
+/// registry once a scope of them has been entered.
+///
+/// Requires the `registry` feature.
+///
+/// # Examples
+///
+/// ```
+/// task_local::task_local! {
+///     static DEVICE_ID: u32;
+/// }
+///
+/// DEVICE_ID.sync_scope(7u32, || {
+///     // Prints something like `{my_app::DEVICE_ID: 7}`.
+///     println!("{:?}", task_local::dump());
+/// });
+/// ```
+pub fn dump() -> Dump {
+    Dump { _private: () }
+}
+
+/// The keys that are set in the current task, returned by [`dump`].
+pub struct Dump {
+    _private: (),
+}
+
+impl fmt::Debug for Dump {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut map = f.debug_map();
+        let mut node = HEAD.load(Ordering::Acquire);
+        // Safety: Nodes live in `static` keys and are never removed from the
+        // list, so every pointer in it stays valid.
+        while let Some(current) = unsafe { node.as_ref() } {
+            (current.dump)(current.key.load(Ordering::Relaxed), &mut map);
+            node = current.next.load(Ordering::Relaxed);
+        }
+        map.finish()
+    }
+}
@@ -0,0 +1,135 @@
+//! RTIC v2 support for the no_std backend.
+//!
+//! RTIC runs every task at a fixed priority, and a task can only be preempted
+//! by tasks of a strictly higher priority. Tasks of the same priority share a
+//! single execution context (thread mode for `idle`, a dispatcher interrupt
+//! for async software tasks, or the bound interrupt for hardware tasks) and
+//! run cooperatively within it.
+//!
+//! With the `rtic` feature, every key keeps one slot per execution context
+//! that is currently inside a scope of the key. On Cortex-M the context is
+//! identified by the active exception number read from `IPSR`, so tasks of
+//! different priorities never see or disturb each other's values, while
+//! async tasks of the same priority get the usual swap-on-poll behavior.
+//!
+//! On other architectures, register a function returning a number that is
+//! unique per priority level with [`set_context_id_fn!`](crate::set_context_id_fn).
+//!
+//! # Examples
+//!
+//! `scope` and `sync_scope` work the same in `idle`, hardware tasks and async
+//! software tasks:
+//!
+//! ```ignore
+//! task_local::task_local! {
+//!     static REQUEST_ID: u32;
+//! }
+//!
+//! #[rtic::app(device = rp2040_hal::pac, dispatchers = [TIMER_IRQ_1])]
+//! mod app {
+//!     use super::REQUEST_ID;
+//!
+//!     #[idle]
+//!     fn idle(_: idle::Context) -> ! {
+//!         REQUEST_ID.sync_scope(0u32, || loop {
+//!             assert_eq!(REQUEST_ID.get(), 0);
+//!         })
+//!     }
+//!
+//!     #[task(binds = UART0_IRQ, priority = 3)]
+//!     fn uart(_: uart::Context) {
+//!         // Preempts `idle` and `worker` without disturbing their values.
+//!         REQUEST_ID.sync_scope(7u32, || assert_eq!(REQUEST_ID.get(), 7));
+//!     }
+//!
+//!     #[task(priority = 1)]
+//!     async fn worker(_: worker::Context) {
+//!         REQUEST_ID
+//!             .scope(42u32, async {
+//!                 Mono::delay(10.millis()).await;
+//!                 assert_eq!(REQUEST_ID.get(), 42);
+//!             })
+//!             .await;
+//!     }
+//! }
+//! ```
+
+/// Returns the identity of the current execution context.
+///
+/// The value is never zero: thread mode maps to `1` and exception `n` to
+/// `n + 1`.
+#[cfg(target_arch = "arm")]
+#[inline]
+pub(crate) fn current_context() -> Option<usize> {
+    let ipsr: u32;
+    // Safety: Reading IPSR has no side effects and is allowed at any
+    // privilege level.
+    unsafe {
+        core::arch::asm!("mrs {}, IPSR", out(reg) ipsr, options(nomem, nostack, preserves_flags));
+    }
+    Some((ipsr & 0x1FF) as usize + 1)
+}
+
+#[cfg(not(target_arch = "arm"))]
+extern "Rust" {
+    fn _task_local_current_context() -> usize;
+}
+
+/// Returns the identity of the current execution context, or `None` if the
+/// function registered with `set_context_id_fn!` returns `usize::MAX`, which
+/// has no identity left.
+///
+/// The value is never zero, which marks free slots.
+///
+/// # Panics
+///
+/// Panics if the registered function returns `usize::MAX`, unless the
+/// `panic-free` or `error-handler` feature is enabled. Then the context gets
+/// no slot, which fails like when no task slot is left.
+#[cfg(not(target_arch = "arm"))]
+#[inline]
+pub(crate) fn current_context() -> Option<usize> {
+    // Safety: The symbol is defined by `set_context_id_fn!` with this exact
+    // signature.
+    let context = unsafe { _task_local_current_context() }.checked_add(1);
+    #[cfg(all(not(feature = "panic-free"), not(feature = "error-handler")))]
+    assert!(
+        context.is_some(),
+        "the function registered with `set_context_id_fn!` returned `usize::MAX`"
+    );
+    context
+}
+
+/// Registers the function that identifies the current RTIC execution context.
+///
+/// Only needed with the `rtic` feature on architectures other than Cortex-M.
+/// The function must return the same value for all tasks of one priority
+/// level, and different values for different priority levels. It must be
+/// defined exactly once in the final binary.
+///
+/// Any value but `usize::MAX` can be returned, which is kept to tell free
+/// slots apart. If the function returns `usize::MAX`, entering a scope
+/// panics, or with the `panic-free` feature fails with a [`ScopeError`], and
+/// with the `error-handler` feature reports that error to the handler.
+///
+/// [`ScopeError`]: crate::ScopeError
+///
+/// # Examples
+///
+/// ```ignore
+/// fn current_priority() -> usize {
+///     // For example, read the current threshold of the interrupt controller.
+///     read_threshold() as usize
+/// }
+///
+/// task_local::set_context_id_fn!(current_priority);
+/// ```
+#[macro_export]
+macro_rules! set_context_id_fn {
+    ($f:path) => {
+        #[unsafe(no_mangle)]
+        fn _task_local_current_context() -> usize {
+            $f()
+        }
+    };
+}
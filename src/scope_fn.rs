@@ -0,0 +1,92 @@
+//! Scopes around every future returned by a handler function.
+//!
+//! Frameworks that are given handler functions rather than futures call the
+//! handler once per request, so there is no single future to scope. These
+//! wrappers return a new handler that scopes the future of every call.
+
+use core::future::Future;
+
+use crate::{LocalKey, TaskLocalFuture};
+
+impl<T: 'static> LocalKey<T> {
+    /// Wraps `handler` so that the future of every call runs with a clone of
+    /// `value` as the task-local value.
+    ///
+    /// Only the returned futures are scoped; the handler itself is called
+    /// outside of the scope. To compute the value from the request, see
+    /// [`scope_fn_with`](Self::scope_fn_with).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// task_local::task_local! {
+    ///     static SERVICE: &'static str;
+    /// }
+    ///
+    /// let mut handler = SERVICE.scope_fn("users", |id: u32| async move {
+    ///     format!("{}/{id}", SERVICE.get())
+    /// });
+    ///
+    /// assert_eq!(handler(1).await, "users/1");
+    /// assert_eq!(handler(2).await, "users/2");
+    /// # }
+    /// ```
+    pub fn scope_fn<H, Req, Fut>(
+        &'static self,
+        value: impl Into<T>,
+        handler: H,
+    ) -> impl FnMut(Req) -> TaskLocalFuture<T, Fut>
+    where
+        T: Clone,
+        H: FnMut(Req) -> Fut,
+        Fut: Future,
+    {
+        let value = value.into();
+        self.scope_fn_with(move |_: &Req| value.clone(), handler)
+    }
+
+    /// Wraps `handler` so that the future of every call runs with the value
+    /// returned by `make_value` for the request as the task-local value.
+    ///
+    /// `make_value` is called with the request before it is passed to the
+    /// handler. Neither is called inside the scope.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// task_local::task_local! {
+    ///     static REQUEST_ID: u64;
+    /// }
+    ///
+    /// struct Request {
+    ///     id: u64,
+    ///     path: &'static str,
+    /// }
+    ///
+    /// let mut handler = REQUEST_ID.scope_fn_with(
+    ///     |req: &Request| req.id,
+    ///     |req: Request| async move { format!("[{}] {}", REQUEST_ID.get(), req.path) },
+    /// );
+    ///
+    /// let line = handler(Request { id: 7, path: "/health" }).await;
+    /// assert_eq!(line, "[7] /health");
+    /// # }
+    /// ```
+    pub fn scope_fn_with<V, H, Req, Fut>(
+        &'static self,
+        mut make_value: V,
+        mut handler: H,
+    ) -> impl FnMut(Req) -> TaskLocalFuture<T, Fut>
+    where
+        V: FnMut(&Req) -> T,
+        H: FnMut(Req) -> Fut,
+        Fut: Future,
+    {
+        move |req| {
+            let value = make_value(&req);
+            self.scope(value, handler(req))
+        }
+    }
+}
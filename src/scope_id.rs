@@ -0,0 +1,86 @@
+//! Ids telling apart the scopes of a key.
+//!
+//! Logs of a key whose scopes are entered over and over with the same value,
+//! like the scopes of the requests of one user, cannot tell which exit
+//! belongs to which entry. With the `scope-ids` feature, every scope gets a
+//! [`ScopeId`] when it is created, by `sync_scope`, `scope` or a clone of a
+//! `TaskLocalFuture`, which is returned by [`current_scope_id`] inside the
+//! scope and logged with its entry and exit by `trace-scopes` and
+//! `instrument`.
+//!
+//! The id travels with the value, like the poisoned flag: a `TaskLocalFuture`
+//! keeps its id across polls, and the enclosing scope finds its own id again
+//! when a nested one exits.
+
+use core::fmt;
+
+use crate::atomic::{AtomicUsize, Ordering};
+use crate::LocalKey;
+
+/// The number of the next id returned by [`ScopeId::next`].
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// The id of a scope, unique among the scopes of every key.
+///
+/// Ids increase in the order the scopes were created, and only wrap around
+/// after `usize::MAX` scopes. Formatted as `#` followed by the number.
+///
+/// Requires the `scope-ids` feature.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ScopeId(u64);
+
+impl ScopeId {
+    /// Returns a new id, greater than every id returned before.
+    pub(crate) fn next() -> Self {
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed) as u64)
+    }
+
+    /// Returns the number of the id.
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for ScopeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for ScopeId {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "#{=u64}", self.0)
+    }
+}
+
+/// Returns the id of the innermost scope of `key`, or `None` outside of any
+/// scope of it.
+///
+/// Requires the `scope-ids` feature.
+///
+/// # Examples
+///
+/// ```
+/// use task_local::current_scope_id;
+///
+/// task_local::task_local! {
+///     static USER: &'static str;
+/// }
+///
+/// assert_eq!(current_scope_id(&USER), None);
+/// let first = USER.sync_scope("ferris", || current_scope_id(&USER).unwrap());
+/// let second = USER.sync_scope("ferris", || {
+///     let outer = current_scope_id(&USER);
+///     USER.sync_scope("ferris", || assert_ne!(current_scope_id(&USER), outer));
+///     assert_eq!(current_scope_id(&USER), outer);
+///     outer.unwrap()
+/// });
+/// assert!(first < second);
+/// ```
+pub fn current_scope_id<T: 'static>(key: &'static LocalKey<T>) -> Option<ScopeId> {
+    #[cfg(feature = "std")]
+    return key.inner.try_with(|cell| cell.state().id).ok().flatten();
+    #[cfg(not(feature = "std"))]
+    return crate::exclusive(|| key.cell().and_then(|cell| cell.state().id));
+}
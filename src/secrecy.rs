@@ -0,0 +1,42 @@
+//! Redacting secrets in the diagnostics of the crate.
+//!
+//! Keys holding a [`SecretBox`], such as a `SecretString`, keep their value
+//! out of everything the crate formats on its own: `dump`,
+//! `KeyNamespace::dump`, [`LocalKey::debug`](crate::LocalKey::debug), the
+//! `Debug` output of a `Handle`, the values logged with `trace-scope-values`
+//! and the values reported to an `Instrument` all show `[REDACTED]` instead.
+//!
+//! The other integrations refuse secrets at compile time rather than format
+//! them: `SecretBox` implements neither `Display`, needed by
+//! [`LocalKey::display`](crate::LocalKey::display) and the Sentry bridge, nor
+//! `tracing::Value`, needed by `tracing::record`. The `Debug` output of a
+//! `Snapshot` is that of its values, which `SecretBox` redacts itself, and a
+//! `Context` only lists the names of its keys.
+//!
+//! Reading the value still takes an explicit `expose_secret`:
+//!
+//! ```
+//! use secrecy::{ExposeSecret, SecretString};
+//!
+//! task_local::task_local! {
+//!     static API_TOKEN: SecretString;
+//! }
+//!
+//! API_TOKEN.sync_scope("hunter2", || {
+//!     assert_eq!(format!("{:?}", API_TOKEN.debug()), "[REDACTED]");
+//!     assert_eq!(API_TOKEN.with(|token| token.expose_secret().len()), 7);
+//! });
+//! ```
+
+use core::fmt;
+
+use ::secrecy::zeroize::Zeroize;
+use ::secrecy::SecretBox;
+
+use crate::__private::{DebugProbe, ViaRedacted};
+
+impl<S: Zeroize + ?Sized> ViaRedacted<SecretBox<S>> for &&DebugProbe<SecretBox<S>> {
+    fn fmt_value(&self, _: &SecretBox<S>, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
@@ -0,0 +1,151 @@
+//! Propagation of task-locals into Sentry events.
+//!
+//! Services keep the user, the request id and similar context in task-locals,
+//! while Sentry reads it from the scope of the current `Hub`. A
+//! [`SentryBridge`] maps keys to parts of an event: every key is registered as
+//! a tag, as the user or with a function of its own, and the bridge adds an
+//! event processor to a Sentry scope that reads the current values of the keys
+//! whenever an event is captured. Values are read on the thread capturing the
+//! event, so an event captured in a task carries the values of that task, and
+//! keys that are not set are left out.
+//!
+//! [`SentryBridge::install`] adds the processor to the scope of the current
+//! hub. [`SentryBridge::bind`] instead runs a future with a hub of its own, a
+//! child of the current hub with the processor added, so that scope changes
+//! made by the task stay in the task.
+//!
+//! # Examples
+//!
+//! ```
+//! use task_local::sentry::SentryBridge;
+//!
+//! task_local::task_local! {
+//!     static REQUEST_ID: u64;
+//!     static USER_ID: u64;
+//! }
+//!
+//! let mut bridge = SentryBridge::new();
+//! bridge.tag("request_id", &REQUEST_ID);
+//! bridge.user(&USER_ID, |id| sentry_core::User {
+//!     id: Some(id.to_string()),
+//!     ..Default::default()
+//! });
+//!
+//! # futures::executor::block_on(async {
+//! REQUEST_ID
+//!     .scope(7u64, bridge.bind(async {
+//!         // Captured with the tag `request_id: 7`.
+//!         sentry_core::capture_message("request failed", sentry_core::Level::Error);
+//!     }))
+//!     .await;
+//! # });
+//! ```
+
+use std::fmt;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::string::ToString;
+use std::sync::Arc;
+use std::vec::Vec;
+
+use sentry_core::protocol::Event;
+use sentry_core::{Hub, Scope, SentryFuture, SentryFutureExt, User};
+
+use crate::LocalKey;
+
+/// Adds the current value of a key to an event, if it is set.
+type ApplyFn = Arc<dyn Fn(&mut Event<'static>) + Send + Sync>;
+
+/// A mapping from task-local keys to the parts of Sentry events.
+///
+/// Requires the `sentry` feature.
+#[derive(Clone, Default)]
+pub struct SentryBridge {
+    entries: Vec<ApplyFn>,
+}
+
+impl SentryBridge {
+    /// Creates a bridge without any key.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the value of `key`, formatted with `Display`, to every event as
+    /// the tag `name`.
+    ///
+    /// The tag replaces a tag of the same name set on the scope.
+    pub fn tag<T>(&mut self, name: &str, key: &'static LocalKey<T>) -> &mut Self
+    where
+        T: fmt::Display + 'static,
+    {
+        let name = name.to_string();
+        self.with(key, move |value, event| {
+            event.tags.insert(name.clone(), value.to_string());
+        })
+    }
+
+    /// Sets the user of every event to the user returned by `user` for the
+    /// value of `key`, replacing the user set on the scope.
+    pub fn user<T, F>(&mut self, key: &'static LocalKey<T>, user: F) -> &mut Self
+    where
+        T: 'static,
+        F: Fn(&T) -> User + Send + Sync + 'static,
+    {
+        self.with(key, move |value, event| event.user = Some(user(value)))
+    }
+
+    /// Calls `f` with the value of `key` and every event, to add the value to
+    /// any part of the event.
+    pub fn with<T, F>(&mut self, key: &'static LocalKey<T>, f: F) -> &mut Self
+    where
+        T: 'static,
+        F: Fn(&T, &mut Event<'static>) + Send + Sync + 'static,
+    {
+        self.entries.push(Arc::new(move |event| {
+            let _ = key.try_with(|value| f(value, event));
+        }));
+        self
+    }
+
+    /// Adds the event processor of this bridge to the scope of the current
+    /// hub, so that every event captured through the hub carries the values
+    /// of the keys.
+    pub fn install(&self) {
+        Hub::current().configure_scope(|scope| self.apply_to_scope(scope));
+    }
+
+    /// Runs `future` with a hub of its own, a child of the current hub whose
+    /// scope has the event processor of this bridge added.
+    ///
+    /// The hub is created when this method is called, so it should be called
+    /// where the task is spawned.
+    pub fn bind<F>(&self, future: F) -> SentryFuture<F>
+    where
+        F: Future,
+    {
+        let hub = Hub::new_from_top(Hub::current());
+        hub.configure_scope(|scope| self.apply_to_scope(scope));
+        future.bind_hub(hub)
+    }
+
+    /// Adds the event processor of this bridge to `scope`.
+    pub fn apply_to_scope(&self, scope: &mut Scope) {
+        // The processor only reads the values of the keys and never leaves
+        // them half-updated, so it is safe to call after a panic.
+        let entries = AssertUnwindSafe(self.entries.clone());
+        scope.add_event_processor(move |mut event| {
+            for apply in entries.iter() {
+                apply(&mut event);
+            }
+            Some(event)
+        });
+    }
+}
+
+impl fmt::Debug for SentryBridge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SentryBridge")
+            .field("keys", &self.entries.len())
+            .finish()
+    }
+}
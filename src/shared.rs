@@ -0,0 +1,143 @@
+//! Conveniences for task-locals holding an `Arc`.
+
+use alloc::sync::Arc;
+use core::future::Future;
+
+use crate::{LocalKey, TaskLocalFuture};
+
+impl<T: ?Sized + 'static> LocalKey<Arc<T>> {
+    /// Returns a new reference to the shared task-local value.
+    ///
+    /// This is the same as [`get`](Self::get), but makes explicit that only
+    /// the reference count is incremented: the value itself is never cloned,
+    /// whatever its type.
+    ///
+    /// Requires the `alloc` feature.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the task local doesn't have a value set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// struct Context {
+    ///     user: String,
+    /// }
+    ///
+    /// task_local::task_local! {
+    ///     static CONTEXT: Arc<Context>;
+    /// }
+    ///
+    /// let context = Arc::new(Context { user: "ferris".into() });
+    /// CONTEXT.sync_scope(context.clone(), || {
+    ///     assert!(Arc::ptr_eq(&CONTEXT.get_shared(), &context));
+    /// });
+    /// ```
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn get_shared(&'static self) -> Arc<T> {
+        self.with(Arc::clone)
+    }
+
+    /// Sets the current shared value as the task-local value for the future
+    /// `f`.
+    ///
+    /// This propagates the context of the current scope to a future that
+    /// runs outside of it, such as a spawned task, by sharing the same `Arc`
+    /// instead of cloning the value. Both scopes see the same value; values
+    /// set with [`set`](Self::set) in one of them are not seen by the other.
+    ///
+    /// Requires the `alloc` feature.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the task local doesn't have a value set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// use std::sync::Arc;
+    ///
+    /// task_local::task_local! {
+    ///     static CONFIG: Arc<str>;
+    /// }
+    ///
+    /// CONFIG
+    ///     .scope("production", async {
+    ///         let task = tokio::spawn(CONFIG.scope_shared(async { CONFIG.get_shared() }));
+    ///         assert_eq!(&*task.await.unwrap(), "production");
+    ///     })
+    ///     .await;
+    /// # }
+    /// ```
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn scope_shared<F>(&'static self, f: F) -> TaskLocalFuture<Arc<T>, F>
+    where
+        F: Future,
+    {
+        self.scope(self.get_shared(), f)
+    }
+
+    /// Sets `value` as the task-local value for the future `f`.
+    ///
+    /// This is the same as [`scope`](Self::scope), but `value` is an `Arc`
+    /// that coerces to the type of the key, so `Arc::new(SystemClock)` can be
+    /// passed for an `Arc<dyn Clock>` key.
+    ///
+    /// Requires the `alloc` feature.
+    #[track_caller]
+    pub fn scope_arc<F>(&'static self, value: Arc<T>, f: F) -> TaskLocalFuture<Arc<T>, F>
+    where
+        F: Future,
+    {
+        self.scope(value, f)
+    }
+
+    /// Sets `value` as the task-local value for the closure `f`.
+    ///
+    /// This is the same as [`sync_scope`](Self::sync_scope), but `value` is an
+    /// `Arc` that coerces to the type of the key.
+    ///
+    /// Requires the `alloc` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// trait Clock: Send + Sync {
+    ///     fn now(&self) -> u64;
+    /// }
+    ///
+    /// struct FixedClock(u64);
+    ///
+    /// impl Clock for FixedClock {
+    ///     fn now(&self) -> u64 {
+    ///         self.0
+    ///     }
+    /// }
+    ///
+    /// task_local::task_local! {
+    ///     static CLOCK: Arc<dyn Clock>;
+    /// }
+    ///
+    /// CLOCK.sync_scope_arc(Arc::new(FixedClock(42)), || {
+    ///     // Only the reference count is incremented.
+    ///     let clock: Arc<dyn Clock> = CLOCK.get_shared();
+    ///     assert_eq!(clock.now(), 42);
+    /// });
+    /// ```
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn sync_scope_arc<F, R>(&'static self, value: Arc<T>, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        self.sync_scope(value, f)
+    }
+}
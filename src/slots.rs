@@ -0,0 +1,187 @@
+//! Per-context slot tables for the no_std backend.
+//!
+//! Instead of a single slot per key, the `embassy` and `rtic` features give
+//! every key a small table of slots, one per execution context that is
+//! currently inside a scope of the key. A context is an Embassy task or an
+//! RTIC priority level; contexts that can preempt each other therefore never
+//! share a slot.
+//!
+//! The table is a `static` declared by `task_local!` next to the key, with
+//! [`TASK_SLOTS`] slots per core unless the key sets its own size with
+//! `#[task_local(tasks = N)]`.
+
+use crate::per_core::{self, MAX_CORES};
+use crate::sync::{const_fn, AtomicUsize, Ordering};
+use crate::value_cell::ValueCell;
+use crate::ScopeInnerErr;
+
+#[cfg(feature = "embassy")]
+use crate::embassy::current_task;
+#[cfg(feature = "rtic")]
+use crate::rtic::current_context as current_task;
+
+/// The number of contexts that can be inside a scope of the same key at the
+/// same time, unless the key sets it with `#[task_local(tasks = N)]`.
+///
+/// Slots are only claimed while a context is being polled or running a
+/// `sync_scope`, so this bounds the nesting depth of preempting
+/// contexts rather than the total number of tasks.
+pub const TASK_SLOTS: usize = 8;
+
+/// Marks a slot that is not claimed by any context. Context identities are
+/// never zero.
+const FREE: usize = 0;
+
+/// A slot of the table of a key, declared by `task_local!`.
+#[doc(hidden)]
+pub struct TaskSlot<T: 'static> {
+    task: AtomicUsize,
+    value: ValueCell<T>,
+}
+
+// Safety: Like the key itself, a slot behaves like a mutex around the value:
+// it is only accessed by the context that claimed it, see `LocalKey`.
+#[cfg(not(feature = "forbid-unsafe"))]
+unsafe impl<T: Send + 'static> Sync for TaskSlot<T> {}
+
+impl<T: 'static> TaskSlot<T> {
+    const_fn! {
+        #[allow(clippy::new_without_default)]
+        pub fn new() -> Self {
+            Self {
+                task: AtomicUsize::new(FREE),
+                value: ValueCell::new(),
+            }
+        }
+    }
+}
+
+/// The per-context slot table of a single key, with an equal share of the
+/// slots for every core.
+pub(crate) struct TaskSlots<T: 'static> {
+    slots: &'static [TaskSlot<T>],
+}
+
+impl<T: 'static> TaskSlots<T> {
+    pub(crate) const fn new(slots: &'static [TaskSlot<T>]) -> Self {
+        Self { slots }
+    }
+
+    /// Returns the slots of the core the caller is running on, none if the
+    /// core is out of range.
+    fn of_core(&self) -> &'static [TaskSlot<T>] {
+        let len = self.slots.len() / MAX_CORES;
+        match per_core::current() {
+            Some(core) => &self.slots[core * len..][..len],
+            None => &[],
+        }
+    }
+
+    /// Returns the slot of the current context, if it has one.
+    pub(crate) fn current(&self) -> Option<&ValueCell<T>> {
+        self.slot_of(current_task()?)
+    }
+
+    /// Returns the slot of `task`, if it has one.
+    fn slot_of(&self, task: usize) -> Option<&ValueCell<T>> {
+        self.of_core()
+            .iter()
+            .find(|slot| slot.task.load(Ordering::Acquire) == task)
+            .map(|slot| &slot.value)
+    }
+
+    /// Returns the slot of the current context, claiming a free one if needed.
+    ///
+    /// The returned index must be passed to [`release`](Self::release) once
+    /// the scope is left.
+    pub(crate) fn enter(&self) -> Result<(&ValueCell<T>, Option<usize>), ScopeInnerErr> {
+        self.enter_as(current_task().ok_or(ScopeInnerErr::NoTaskSlot)?)
+    }
+
+    fn enter_as(&self, task: usize) -> Result<(&ValueCell<T>, Option<usize>), ScopeInnerErr> {
+        if let Some(cell) = self.slot_of(task) {
+            return Ok((cell, None));
+        }
+
+        for (index, slot) in self.of_core().iter().enumerate() {
+            if slot
+                .task
+                .compare_exchange(FREE, task, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                return Ok((&slot.value, Some(index)));
+            }
+        }
+
+        Err(ScopeInnerErr::NoTaskSlot)
+    }
+
+    /// Gives a slot claimed by [`enter`](Self::enter) back to the table.
+    pub(crate) fn release(&self, claimed: Option<usize>) {
+        if let Some(slot) = claimed.and_then(|index| self.of_core().get(index)) {
+            slot.task.store(FREE, Ordering::Release);
+        }
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+
+    extern crate std;
+
+    use loom::sync::Arc;
+    use loom::thread;
+    use std::boxed::Box;
+
+    fn slots() -> TaskSlots<u32> {
+        TaskSlots::new(Box::leak(Box::new(
+            core::array::from_fn::<_, TASK_SLOTS, _>(|_| TaskSlot::new()),
+        )))
+    }
+
+    // Contexts racing for the last free slot never end up sharing it.
+    #[test]
+    fn claims_are_exclusive() {
+        loom::model(|| {
+            let slots = Arc::new(slots());
+            for task in 1..TASK_SLOTS {
+                assert!(slots.enter_as(100 + task).is_ok());
+            }
+
+            let handles = [1, 2].map(|task| {
+                let slots = slots.clone();
+                thread::spawn(move || slots.enter_as(task).is_ok())
+            });
+            let claimed = handles.map(|handle| handle.join().unwrap());
+
+            assert_eq!(claimed.iter().filter(|&&claimed| claimed).count(), 1);
+        });
+    }
+
+    // A released slot can be claimed by another context, which then owns it
+    // alone.
+    #[test]
+    fn released_slots_are_reused() {
+        loom::model(|| {
+            let slots = Arc::new(slots());
+            for task in 1..TASK_SLOTS {
+                assert!(slots.enter_as(100 + task).is_ok());
+            }
+
+            let releasing = {
+                let slots = slots.clone();
+                thread::spawn(move || {
+                    if let Ok((_, claimed)) = slots.enter_as(1) {
+                        slots.release(claimed);
+                    }
+                })
+            };
+            let entered = slots.enter_as(2).is_ok();
+            releasing.join().unwrap();
+
+            assert!(slots.slot_of(1).is_none());
+            assert_eq!(slots.slot_of(2).is_some(), entered);
+        });
+    }
+}
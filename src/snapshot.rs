@@ -0,0 +1,157 @@
+//! Snapshots of task-locals for blocking on futures.
+//!
+//! Code that drops into `futures::executor::block_on` or a hand-written
+//! executor polls the blocked-on future outside of the task that created it,
+//! possibly on another thread, where the task-locals of the caller are not
+//! set. A [`Snapshot`] captures the values of a selection of keys, see
+//! [`Capture`], so that they can be set again around that future, and
+//! [`block_on_with_context`] does both in one call.
+//!
+//! Every scope of a snapshot moves its own values into the keys, so a
+//! snapshot propagated into many tasks is cloned, values included, once per
+//! task. With the `context` feature, a `context::Context` is a snapshot whose
+//! values are shared between its clones and copied on write instead.
+
+use core::fmt;
+use core::future::Future;
+
+use crate::Capture;
+
+/// The values of a selection of task-local keys, together with the keys they
+/// belong to.
+///
+/// Created by [`current`] or [`Snapshot::new`]. Like the channel wrappers,
+/// the snapshot type `S` is a separate parameter that is left at its default.
+#[derive(Clone)]
+pub struct Snapshot<C, S = <C as Capture>::Snapshot> {
+    keys: C,
+    values: S,
+}
+
+/// Captures the current values of `keys`.
+///
+/// Keys without a value are left unset when the snapshot is applied.
+///
+/// # Examples
+///
+/// ```
+/// task_local::task_local! {
+///     static REQUEST_ID: u64;
+/// }
+///
+/// let snapshot = REQUEST_ID.sync_scope(7u64, || task_local::current(&REQUEST_ID));
+/// assert_eq!(snapshot.sync_scope(|| REQUEST_ID.get()), 7);
+/// ```
+pub fn current<C: Capture>(keys: C) -> Snapshot<C> {
+    Snapshot::new(keys, keys.capture())
+}
+
+impl<C: Capture> Snapshot<C> {
+    /// Creates a snapshot from values captured earlier with
+    /// [`Capture::capture`].
+    pub fn new(keys: C, values: C::Snapshot) -> Self {
+        Self { keys, values }
+    }
+
+    /// Sets the captured values as the task-local values for the future `f`.
+    pub fn scope<F>(self, f: F) -> C::Scope<F>
+    where
+        F: Future,
+    {
+        self.keys.scope(self.values, f)
+    }
+
+    /// Sets the captured values as the task-local values for the closure
+    /// `f`.
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn sync_scope<F, R>(self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        self.keys.sync_scope(self.values, f)
+    }
+}
+
+impl<C, S> Snapshot<C, S> {
+    /// Returns the captured values.
+    pub fn values(&self) -> &S {
+        &self.values
+    }
+
+    /// Consumes the snapshot, returning the keys and the captured values.
+    pub fn into_parts(self) -> (C, S) {
+        (self.keys, self.values)
+    }
+}
+
+impl<C, S: fmt::Debug> fmt::Debug for Snapshot<C, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Snapshot")
+            .field("values", &self.values)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Runs `future` to completion on the current thread, with the values of
+/// `snapshot` as its task-locals.
+///
+/// The thread is parked while the future is pending. This is the same as
+/// `futures::executor::block_on(snapshot.scope(future))`, without the
+/// dependency; other executors can be given [`Snapshot::scope`] directly.
+///
+/// # Examples
+///
+/// ```
+/// task_local::task_local! {
+///     static REQUEST_ID: u64;
+/// }
+///
+/// let id = REQUEST_ID.sync_scope(7u64, || {
+///     let snapshot = task_local::current(&REQUEST_ID);
+///     std::thread::spawn(move || {
+///         task_local::block_on_with_context(snapshot, async { REQUEST_ID.get() })
+///     })
+///     .join()
+///     .unwrap()
+/// });
+/// assert_eq!(id, 7);
+/// ```
+#[cfg(feature = "std")]
+pub fn block_on_with_context<C, F>(snapshot: Snapshot<C>, future: F) -> F::Output
+where
+    C: Capture,
+    F: Future,
+{
+    block_on(snapshot.scope(future))
+}
+
+#[cfg(feature = "std")]
+fn block_on<F: Future>(future: F) -> F::Output {
+    use std::pin::pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Usage counters of task-local keys.
+//!
+//! With the `stats` feature, every key counts its scopes, the accesses to its
+//! value and the borrow conflicts it runs into, which [`LocalKey::stats`]
+//! returns as a [`Stats`]. The counters are shared by all threads and tasks
+//! using the key and are updated with relaxed atomic operations, so they cost
+//! an uncontended atomic increment per event.
+//!
+//! With the `metrics` feature, [`LocalKey::record_metrics`] reports the
+//! counters of a key to the `metrics` recorder installed by the application.
+
+use crate::sync::{const_fn, AtomicUsize, Ordering};
+use crate::LocalKey;
+
+/// Counters embedded in every key.
+pub(crate) struct Counters {
+    active_scopes: AtomicUsize,
+    scopes: AtomicUsize,
+    accesses: AtomicUsize,
+    borrow_conflicts: AtomicUsize,
+}
+
+impl Counters {
+    const_fn! {
+        pub(crate) fn new() -> Self {
+            Self {
+                active_scopes: AtomicUsize::new(0),
+                scopes: AtomicUsize::new(0),
+                accesses: AtomicUsize::new(0),
+                borrow_conflicts: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    pub(crate) fn scope_entered(&self) {
+        self.active_scopes.fetch_add(1, Ordering::Relaxed);
+        self.scopes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn scope_exited(&self) {
+        self.active_scopes.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn access(&self) {
+        self.accesses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn borrow_conflict(&self) {
+        self.borrow_conflicts.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The usage counters of a key, returned by [`LocalKey::stats`].
+///
+/// Every counter covers all threads and tasks using the key since the
+/// program started. Counters wrap around on overflow.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct Stats {
+    /// The number of scopes of the key that are currently entered.
+    ///
+    /// A `sync_scope` is active while its closure runs. The scope of a
+    /// `TaskLocalFuture` is active from its first poll until it completes, is
+    /// cancelled or is dropped, including while the future is suspended.
+    pub active_scopes: usize,
+    /// The number of scopes of the key that were entered.
+    pub scopes: usize,
+    /// The number of times the value was read, through `with`, `try_with`,
+    /// `get`, `get_copied` and the functions built on them.
+    /// `with_unchecked` is not counted.
+    pub accesses: usize,
+    /// The number of accesses and scopes that failed because the storage of
+    /// the key was borrowed, see [`AccessError::Borrowed`](crate::AccessError::Borrowed).
+    pub borrow_conflicts: usize,
+}
+
+impl<T: 'static> LocalKey<T> {
+    /// Returns the usage counters of this key.
+    ///
+    /// Requires the `stats` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// task_local::task_local! {
+    ///     static REQUEST_ID: u64;
+    /// }
+    ///
+    /// REQUEST_ID.sync_scope(7u64, || {
+    ///     let stats = REQUEST_ID.stats();
+    ///     assert_eq!(stats.active_scopes, 1);
+    ///
+    ///     REQUEST_ID.get();
+    ///     assert_eq!(REQUEST_ID.stats().accesses, stats.accesses + 1);
+    /// });
+    /// assert_eq!(REQUEST_ID.stats().active_scopes, 0);
+    /// ```
+    pub fn stats(&'static self) -> Stats {
+        let counters = &self.stats;
+        Stats {
+            active_scopes: counters.active_scopes.load(Ordering::Relaxed),
+            scopes: counters.scopes.load(Ordering::Relaxed),
+            accesses: counters.accesses.load(Ordering::Relaxed),
+            borrow_conflicts: counters.borrow_conflicts.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reports the usage counters of this key to the installed `metrics`
+    /// recorder.
+    ///
+    /// The counters are reported as the gauge `task_local_active_scopes` and
+    /// the counters `task_local_scopes_total`, `task_local_accesses_total` and
+    /// `task_local_borrow_conflicts_total`, labelled with the `key` name and
+    /// the `module` it was declared in. Call it periodically, for example
+    /// before the recorder is scraped.
+    ///
+    /// Requires the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn record_metrics(&'static self) {
+        let stats = self.stats();
+        let labels = [("key", self.name), ("module", self.module_path)];
+        metrics::gauge!("task_local_active_scopes", &labels).set(stats.active_scopes as f64);
+        metrics::counter!("task_local_scopes_total", &labels).absolute(stats.scopes as u64);
+        metrics::counter!("task_local_accesses_total", &labels).absolute(stats.accesses as u64);
+        metrics::counter!("task_local_borrow_conflicts_total", &labels)
+            .absolute(stats.borrow_conflicts as u64);
+    }
+}
@@ -0,0 +1,104 @@
+//! A trait abstracting over task-local keys.
+
+use core::future::Future;
+
+use crate::{AccessError, LocalKey, TaskLocalFuture};
+
+/// Common interface of task-local keys.
+///
+/// This is implemented by [`LocalKey`] for every backend, so library code can
+/// be written against "some task-local key" without depending on which
+/// backend is enabled or on the concrete key type.
+///
+/// # Examples
+///
+/// ```
+/// use task_local::TaskLocalStorage;
+///
+/// task_local::task_local! {
+///     static REQUEST_ID: u64;
+/// }
+///
+/// fn log<K>(key: &'static K, message: &str)
+/// where
+///     K: TaskLocalStorage<Value = u64>,
+/// {
+///     match key.try_with(|id| *id) {
+///         Ok(id) => println!("[{id}] {message}"),
+///         Err(_) => println!("[-] {message}"),
+///     }
+/// }
+///
+/// REQUEST_ID.sync_scope(7u64, || log(&REQUEST_ID, "handling request"));
+/// ```
+pub trait TaskLocalStorage: 'static {
+    /// The type of the value stored by the key.
+    type Value: 'static;
+
+    /// The future returned by [`scope`](Self::scope).
+    type Scope<F: Future>: Future<Output = F::Output>;
+
+    /// Sets `value` as the task-local value for the future `f`.
+    fn scope<F>(&'static self, value: Self::Value, f: F) -> Self::Scope<F>
+    where
+        F: Future;
+
+    /// Sets `value` as the task-local value for the closure `f`.
+    #[cfg(not(feature = "panic-free"))]
+    fn sync_scope<F, R>(&'static self, value: Self::Value, f: F) -> R
+    where
+        F: FnOnce() -> R;
+
+    /// Accesses the current task-local value and runs the provided closure.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the task-local doesn't have a value set.
+    #[cfg(not(feature = "panic-free"))]
+    fn with<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&Self::Value) -> R;
+
+    /// Accesses the current task-local value and runs the provided closure,
+    /// returning an [`AccessError`] if the value is not set.
+    fn try_with<F, R>(&'static self, f: F) -> Result<R, AccessError>
+    where
+        F: FnOnce(&Self::Value) -> R;
+}
+
+impl<T: 'static> TaskLocalStorage for LocalKey<T> {
+    type Value = T;
+    type Scope<F: Future> = TaskLocalFuture<T, F>;
+
+    fn scope<F>(&'static self, value: T, f: F) -> TaskLocalFuture<T, F>
+    where
+        F: Future,
+    {
+        LocalKey::scope(self, value, f)
+    }
+
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    fn sync_scope<F, R>(&'static self, value: T, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        LocalKey::sync_scope(self, value, f)
+    }
+
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    fn with<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        LocalKey::with(self, f)
+    }
+
+    fn try_with<F, R>(&'static self, f: F) -> Result<R, AccessError>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        LocalKey::try_with(self, f)
+    }
+}
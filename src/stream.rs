@@ -0,0 +1,114 @@
+//! Scoping the items of a stream.
+
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use futures_core::Stream;
+use pin_project_lite::pin_project;
+
+use crate::{LocalKey, TaskLocalFuture};
+
+impl<T: 'static> LocalKey<T> {
+    /// Scopes every future produced by `stream` with its own task-local value.
+    ///
+    /// For each future yielded by `stream`, `f` is called to compute the value
+    /// for that future, and the future is wrapped in a [`TaskLocalFuture`] as if
+    /// by [`scope`](Self::scope). Since every future carries its own value, the
+    /// returned stream can be run with combinators that poll several items
+    /// concurrently, such as `buffered`.
+    ///
+    /// Requires the `stream` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// use futures::stream::{self, StreamExt};
+    ///
+    /// task_local::task_local! {
+    ///     static REQUEST_ID: u32;
+    /// }
+    ///
+    /// async fn handle(message: &str) -> String {
+    ///     format!("[{}] {}", REQUEST_ID.get(), message)
+    /// }
+    ///
+    /// let mut next_id = 0;
+    /// let responses: Vec<String> = REQUEST_ID
+    ///     .scope_each(stream::iter(["a", "b"]).map(handle), |_| {
+    ///         next_id += 1;
+    ///         next_id
+    ///     })
+    ///     .buffered(2)
+    ///     .collect()
+    ///     .await;
+    ///
+    /// assert_eq!(responses, ["[1] a", "[2] b"]);
+    /// # }
+    /// ```
+    pub fn scope_each<S, F>(&'static self, stream: S, f: F) -> ScopeEach<T, S, F>
+    where
+        S: Stream,
+        S::Item: Future,
+        F: FnMut(&S::Item) -> T,
+    {
+        ScopeEach {
+            local: self,
+            stream,
+            f,
+        }
+    }
+}
+
+pin_project! {
+    /// A stream that scopes every future yielded by the stream `S` with its own
+    /// task-local value.
+    ///
+    /// Created by the function [`LocalKey::scope_each`].
+    #[must_use = "streams do nothing unless polled"]
+    pub struct ScopeEach<T, S, F>
+    where
+        T: 'static,
+    {
+        local: &'static LocalKey<T>,
+        #[pin]
+        stream: S,
+        f: F,
+    }
+}
+
+impl<T, S, F> Stream for ScopeEach<T, S, F>
+where
+    T: 'static,
+    S: Stream,
+    S::Item: Future,
+    F: FnMut(&S::Item) -> T,
+{
+    type Item = TaskLocalFuture<T, S::Item>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let local = *this.local;
+        let f = this.f;
+        this.stream.poll_next(cx).map(|item| {
+            item.map(|future| {
+                let value = f(&future);
+                local.scope(value, future)
+            })
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.stream.size_hint()
+    }
+}
+
+impl<T: 'static, S, F> fmt::Debug for ScopeEach<T, S, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScopeEach")
+            .field("key", &self.local.name)
+            .finish_non_exhaustive()
+    }
+}
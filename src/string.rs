@@ -0,0 +1,146 @@
+//! Accessors for keys holding strings and other smart pointers.
+//!
+//! [`get`](LocalKey::get) clones the whole value, which allocates for a
+//! `String` read on every log line. These accessors lend the borrowed form of
+//! the value instead, a `&str` for any key whose value is a string of some
+//! kind, or the target of any `Deref` value.
+
+#[cfg(all(feature = "alloc", target_has_atomic = "ptr"))]
+use alloc::sync::Arc;
+use core::ops::Deref;
+
+use crate::{AccessError, LocalKey};
+
+impl<T: AsRef<str> + 'static> LocalKey<T> {
+    /// Accesses the task-local value as a `&str`, without cloning it.
+    ///
+    /// This works for keys holding a `String`, a `&'static str`, a
+    /// `Cow<'static, str>`, an `Arc<str>` or a `Box<str>`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the task local doesn't have a value set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// task_local::task_local! {
+    ///     static MESSAGE: String;
+    /// }
+    ///
+    /// MESSAGE.sync_scope("request handled".to_string(), || {
+    ///     assert_eq!(MESSAGE.with_str(str::len), 15);
+    /// });
+    /// ```
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn with_str<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&str) -> R,
+    {
+        self.with(|value| f(value.as_ref()))
+    }
+
+    /// Accesses the task-local value as a `&str`, or returns an
+    /// [`AccessError`] if it cannot be read.
+    ///
+    /// This is the non-panicking variant of [`with_str`](Self::with_str).
+    #[cfg_attr(feature = "audit", track_caller)]
+    pub fn try_with_str<F, R>(&'static self, f: F) -> Result<R, AccessError>
+    where
+        F: FnOnce(&str) -> R,
+    {
+        self.try_with(|value| f(value.as_ref()))
+    }
+}
+
+impl<T: Deref + 'static> LocalKey<T> {
+    /// Accesses the target of the task-local value, like
+    /// [`Option::as_deref`].
+    ///
+    /// This lends a `&str` for a `String`, a `&[u8]` for a `Vec<u8>` or the
+    /// inner value of a `Box` or an `Arc`.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the task local doesn't have a value set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// task_local::task_local! {
+    ///     static PAYLOAD: Vec<u8>;
+    /// }
+    ///
+    /// PAYLOAD.sync_scope(vec![1, 2, 3], || {
+    ///     assert_eq!(PAYLOAD.with_deref(|bytes: &[u8]| bytes.first().copied()), Some(1));
+    /// });
+    /// ```
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn with_deref<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T::Target) -> R,
+    {
+        self.with(|value| f(value))
+    }
+
+    /// Accesses the target of the task-local value, or returns an
+    /// [`AccessError`] if it cannot be read.
+    ///
+    /// This is the non-panicking variant of [`with_deref`](Self::with_deref).
+    #[cfg_attr(feature = "audit", track_caller)]
+    pub fn try_with_deref<F, R>(&'static self, f: F) -> Result<R, AccessError>
+    where
+        F: FnOnce(&T::Target) -> R,
+    {
+        self.try_with(|value| f(value))
+    }
+}
+
+#[cfg(all(feature = "alloc", target_has_atomic = "ptr"))]
+impl LocalKey<Arc<str>> {
+    /// Returns a new reference to the task-local string, without copying it.
+    ///
+    /// Only the reference count is incremented, so string context kept in an
+    /// `Arc<str>` can be handed to a logger or another task without
+    /// allocating.
+    ///
+    /// Requires the `alloc` feature.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the task local doesn't have a value set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// task_local::task_local! {
+    ///     static TENANT: Arc<str>;
+    /// }
+    ///
+    /// let tenant: Arc<str> = Arc::from("acme");
+    /// TENANT.sync_scope(tenant.clone(), || {
+    ///     assert!(Arc::ptr_eq(&TENANT.get_arc_str(), &tenant));
+    /// });
+    /// ```
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn get_arc_str(&'static self) -> Arc<str> {
+        self.with(Arc::clone)
+    }
+
+    /// Returns a new reference to the task-local string, or an
+    /// [`AccessError`] if it cannot be read.
+    ///
+    /// This is the non-panicking variant of
+    /// [`get_arc_str`](Self::get_arc_str).
+    ///
+    /// Requires the `alloc` feature.
+    #[cfg_attr(feature = "audit", track_caller)]
+    pub fn try_get_arc_str(&'static self) -> Result<Arc<str>, AccessError> {
+        self.try_with(Arc::clone)
+    }
+}
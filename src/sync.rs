@@ -0,0 +1,108 @@
+//! Synchronization primitives used by keys, switched to their `loom`
+//! counterparts under `cfg(loom)`.
+//!
+//! Routing the atomics and mutexes of a key through this module lets its
+//! scopes, slot tables and change notifications be model-checked, both by the
+//! loom tests of this crate and by downstream crates using task-locals inside
+//! their own loom models. Loom types cannot be created in constant contexts,
+//! so under `cfg(loom)` the constructors declared with [`const_fn!`] are plain
+//! functions and `task_local!` initializes its keys lazily.
+//!
+//! State kept in standalone `static`s, namely the registry and the current
+//! Embassy task, keeps using the crate's regular atomics.
+
+#[cfg(not(loom))]
+pub(crate) use crate::atomic::{AtomicUsize, Ordering};
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(all(feature = "std", loom))]
+pub(crate) use loom::sync::Mutex;
+#[cfg(all(feature = "std", not(loom)))]
+pub(crate) use std::sync::Mutex;
+
+/// The thread-local holding the storage of a std key.
+#[cfg(all(
+    feature = "std",
+    not(loom),
+    not(feature = "custom-storage"),
+    not(all(
+        target_arch = "wasm32",
+        not(target_feature = "atomics"),
+        not(feature = "forbid-unsafe")
+    ))
+))]
+pub(crate) type ThreadLocal<T> = std::thread::LocalKey<T>;
+#[cfg(all(feature = "custom-storage", not(loom)))]
+pub(crate) type ThreadLocal<T> = crate::provider::ProvidedStorage<T>;
+#[cfg(all(
+    feature = "std",
+    not(loom),
+    target_arch = "wasm32",
+    not(target_feature = "atomics"),
+    not(feature = "forbid-unsafe")
+))]
+pub(crate) type ThreadLocal<T> = single_thread::SingleThread<T>;
+#[cfg(all(feature = "std", loom))]
+pub(crate) type ThreadLocal<T> = &'static loom::thread::LocalKey<T>;
+
+/// The storage of a std key on WebAssembly without the `atomics` target
+/// feature, such as `wasm32-unknown-unknown`.
+///
+/// Such a module cannot share its memory with another thread, so the storage
+/// is kept directly in the key instead of behind a `thread_local!`, skipping
+/// its lazy initialization and destruction checks on every access. The mutex
+/// and atomics of the key already compile to plain memory accesses on these
+/// targets. Under `forbid-unsafe`, keys keep using `thread_local!`.
+#[cfg(all(
+    feature = "std",
+    not(loom),
+    target_arch = "wasm32",
+    not(target_feature = "atomics"),
+    not(feature = "forbid-unsafe")
+))]
+pub(crate) mod single_thread {
+    use core::convert::Infallible;
+
+    #[doc(hidden)]
+    pub struct SingleThread<T>(T);
+
+    // Safety: Without the `atomics` target feature there is only ever a
+    // single thread accessing the memory of the module.
+    unsafe impl<T> Sync for SingleThread<T> {}
+
+    impl<T> SingleThread<T> {
+        #[doc(hidden)]
+        pub const fn new(value: T) -> Self {
+            Self(value)
+        }
+
+        /// Runs `f` on the storage, like `std::thread::LocalKey::with`.
+        #[inline(always)]
+        pub(crate) fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+            f(&self.0)
+        }
+
+        /// Runs `f` on the storage, like `std::thread::LocalKey::try_with`.
+        /// The storage is never destroyed, so this cannot fail.
+        #[inline(always)]
+        pub(crate) fn try_with<R>(&self, f: impl FnOnce(&T) -> R) -> Result<R, Infallible> {
+            Ok(f(&self.0))
+        }
+    }
+}
+
+/// Declares a `const fn`, or a plain `fn` under `cfg(loom)`.
+macro_rules! const_fn {
+    ($(#[$attr:meta])* $vis:vis fn $($rest:tt)*) => {
+        #[cfg(not(loom))]
+        $(#[$attr])*
+        $vis const fn $($rest)*
+
+        #[cfg(loom)]
+        $(#[$attr])*
+        $vis fn $($rest)*
+    };
+}
+
+pub(crate) use const_fn;
@@ -0,0 +1,145 @@
+//! Structured concurrency with inherited task-locals.
+//!
+//! A [`TaskScope`] spawns child tasks that inherit the inheritable task-locals
+//! of the task spawning them, like [`spawn`](crate::spawn), and ties their
+//! lifetime to the scope: the children are awaited together with
+//! [`TaskScope::join_all`], and the ones still running when the scope is
+//! dropped are cancelled. This is the pattern of spawning a group of workers
+//! for a request, each carrying the trace of the request, without leaving any
+//! of them running once the request is done.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::Future;
+use std::panic;
+
+use tokio::task::{AbortHandle, JoinError, JoinSet};
+
+use crate::Inherited;
+
+/// A group of child tasks inheriting the task-locals of their parent, which
+/// are cancelled when the group is dropped.
+///
+/// Every task spawned with [`spawn`](Self::spawn) runs with the values the
+/// keys declared with `#[task_local(inherit)]` have where it is spawned.
+/// Tasks run on the current Tokio runtime, concurrently with the parent, and
+/// are aborted when the `TaskScope` is dropped, for example because the
+/// parent returns early or is cancelled itself.
+///
+/// Requires the `inherit` and `tokio-interop` features.
+///
+/// # Examples
+///
+/// ```
+/// # async fn dox() {
+/// use task_local::TaskScope;
+///
+/// task_local::task_local! {
+///     #[task_local(inherit)]
+///     static TRACE_ID: u64;
+/// }
+///
+/// let results = TRACE_ID
+///     .scope(7u64, async {
+///         let mut scope = TaskScope::new();
+///         for shard in 0..3u64 {
+///             scope.spawn(async move { (shard, TRACE_ID.get()) });
+///         }
+///         scope.join_all().await
+///     })
+///     .await;
+/// assert_eq!(results.len(), 3);
+/// assert!(results.iter().all(|&(_, trace)| trace == 7));
+/// # }
+/// ```
+pub struct TaskScope<T> {
+    tasks: JoinSet<T>,
+}
+
+impl<T: Send + 'static> TaskScope<T> {
+    /// Creates a scope without any task.
+    pub fn new() -> Self {
+        Self {
+            tasks: JoinSet::new(),
+        }
+    }
+
+    /// Spawns `future` as a child task, with the inheritable task-locals of
+    /// the current task.
+    ///
+    /// The values are captured when `spawn` is called, and the task gets a
+    /// new [`TaskId`](crate::keys::TaskId). The returned handle can cancel
+    /// the task on its own.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a Tokio runtime, like `tokio::spawn`.
+    #[track_caller]
+    pub fn spawn<F>(&mut self, future: F) -> AbortHandle
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        self.tasks.spawn(Inherited::capture().scope(future))
+    }
+
+    /// Waits for the next child task to complete and returns its output, or
+    /// `None` if no task is left.
+    ///
+    /// Returns an error for a task that panicked or was cancelled.
+    pub async fn join_next(&mut self) -> Option<Result<T, JoinError>> {
+        self.tasks.join_next().await
+    }
+
+    /// Waits for every child task to complete and returns their outputs, in
+    /// the order they completed.
+    ///
+    /// If a task panics, the other tasks are cancelled and the panic is
+    /// resumed in the current task.
+    pub async fn join_all(mut self) -> Vec<T> {
+        let mut outputs = Vec::with_capacity(self.tasks.len());
+        while let Some(res) = self.tasks.join_next().await {
+            match res {
+                Ok(output) => outputs.push(output),
+                Err(err) if err.is_panic() => {
+                    self.tasks.abort_all();
+                    panic::resume_unwind(err.into_panic());
+                }
+                // Only tasks cancelled through their `AbortHandle` end here.
+                Err(_) => {}
+            }
+        }
+        outputs
+    }
+
+    /// Cancels every child task.
+    ///
+    /// The tasks stop at their next `.await`; [`join_next`](Self::join_next)
+    /// still returns them, as cancelled.
+    pub fn abort_all(&mut self) {
+        self.tasks.abort_all();
+    }
+
+    /// Returns the number of child tasks that were not joined yet.
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Returns `true` if no child task is left to join.
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+}
+
+impl<T: Send + 'static> Default for TaskScope<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for TaskScope<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskScope")
+            .field("len", &self.tasks.len())
+            .finish()
+    }
+}
@@ -0,0 +1,199 @@
+//! Assertions and a harness for tests of code using task-locals.
+//!
+//! With the `testing` feature, [`assert_set!`] and [`assert_unset!`] check
+//! whether a key is set by a scope in the current task, [`with_locals!`]
+//! runs a block in the scopes of several keys at once, and [`run`] runs a
+//! test and fails it if any key is still set when it returns, found through
+//! the registry of keys.
+//!
+//! A key only counts as set inside one of its scopes: a global or
+//! environment default does not make it set, as it cannot be left behind by
+//! a test.
+//!
+//! # Examples
+//!
+//! ```
+//! use task_local::{assert_set, assert_unset, with_locals};
+//!
+//! task_local::task_local! {
+//!     static REQUEST_ID: u64;
+//!     static USER: &'static str;
+//! }
+//!
+//! task_local::testing::run(|| {
+//!     with_locals! {
+//!         REQUEST_ID = 7u64;
+//!         USER = "ferris";
+//!         {
+//!             assert_set!(REQUEST_ID, 7);
+//!             assert_set!(USER);
+//!         }
+//!     }
+//!     assert_unset!(REQUEST_ID);
+//! });
+//! ```
+
+use std::fmt;
+use std::vec::Vec;
+
+use crate::{AccessError, LocalKey, ValueSource};
+
+/// Returns whether `key` is set by a scope in the current task.
+fn is_set<T: 'static>(key: &'static LocalKey<T>) -> bool {
+    match key.try_with_source(|_| ()) {
+        Ok(((), source)) => source == ValueSource::Scope,
+        Err(AccessError::NotSet) => false,
+        // The key is in a scope, whose value cannot be read right now.
+        Err(_) => true,
+    }
+}
+
+/// Runs `f`, then panics if any key is still set in the current task.
+///
+/// Only the keys known to the registry are checked, which are the keys a
+/// scope was ever entered for. `f` is run on the current thread, so an async
+/// test calls its executor, such as `block_on`, inside `f`.
+///
+/// Requires the `testing` feature.
+///
+/// # Panics
+///
+/// Panics with the list of the keys left set.
+#[track_caller]
+pub fn run<R>(f: impl FnOnce() -> R) -> R {
+    let res = f();
+    assert_no_keys_set();
+    res
+}
+
+/// Panics if any key known to the registry is set in the current task.
+///
+/// Requires the `testing` feature.
+#[track_caller]
+pub fn assert_no_keys_set() {
+    let set: Vec<_> = crate::registry::set_keys()
+        .map(|(key, module_path)| std::format!("  `{module_path}::{key}`"))
+        .collect();
+    if !set.is_empty() {
+        panic!("task-local keys left set:\n{}", set.join("\n"));
+    }
+}
+
+#[doc(hidden)]
+#[track_caller]
+pub fn __assert_set<T: 'static>(key: &'static LocalKey<T>) {
+    if !is_set(key) {
+        panic!(
+            "assertion failed: `{}::{}` is not set",
+            key.module_path, key.name
+        );
+    }
+}
+
+#[doc(hidden)]
+#[track_caller]
+pub fn __assert_set_to<T, U>(key: &'static LocalKey<T>, expected: &U)
+where
+    T: PartialEq<U> + fmt::Debug + 'static,
+    U: fmt::Debug + ?Sized,
+{
+    __assert_set(key);
+    // The panic is raised out of `with`, so that it points at the caller.
+    let mismatch = key.with(|value| (value != expected).then(|| std::format!("{value:?}")));
+    if let Some(value) = mismatch {
+        panic!(
+            "assertion failed: `{}::{}` is set to {}, expected {:?}",
+            key.module_path, key.name, value, expected
+        );
+    }
+}
+
+#[doc(hidden)]
+#[track_caller]
+pub fn __assert_unset<T: 'static>(key: &'static LocalKey<T>) {
+    if is_set(key) {
+        panic!(
+            "assertion failed: `{}::{}` is set",
+            key.module_path, key.name
+        );
+    }
+}
+
+/// Asserts that a key is set by a scope in the current task, and optionally
+/// that its value equals the given one.
+///
+/// Requires the `testing` feature.
+///
+/// # Examples
+///
+/// ```
+/// task_local::task_local! {
+///     static REQUEST_ID: u64;
+/// }
+///
+/// REQUEST_ID.sync_scope(7u64, || {
+///     task_local::assert_set!(REQUEST_ID);
+///     task_local::assert_set!(REQUEST_ID, 7);
+/// });
+/// ```
+#[macro_export]
+macro_rules! assert_set {
+    ($key:expr $(,)?) => {
+        $crate::testing::__assert_set(&$key)
+    };
+    ($key:expr, $expected:expr $(,)?) => {
+        $crate::testing::__assert_set_to(&$key, &$expected)
+    };
+}
+
+/// Asserts that a key is not set by a scope in the current task.
+///
+/// Requires the `testing` feature.
+///
+/// # Examples
+///
+/// ```
+/// task_local::task_local! {
+///     static REQUEST_ID: u64;
+/// }
+///
+/// task_local::assert_unset!(REQUEST_ID);
+/// ```
+#[macro_export]
+macro_rules! assert_unset {
+    ($key:expr $(,)?) => {
+        $crate::testing::__assert_unset(&$key)
+    };
+}
+
+/// Runs a block in the scopes of several keys, entered in the order they are
+/// listed, and returns its result.
+///
+/// Each key is followed by `=`, its value and `;`, and the block comes last.
+///
+/// Requires the `testing` feature.
+///
+/// # Examples
+///
+/// ```
+/// task_local::task_local! {
+///     static REQUEST_ID: u64;
+///     static USER: &'static str;
+/// }
+///
+/// let len = task_local::with_locals! {
+///     REQUEST_ID = 7u64;
+///     USER = "ferris";
+///     { USER.get().len() as u64 + REQUEST_ID.get() }
+/// };
+/// assert_eq!(len, 13);
+/// ```
+#[macro_export]
+macro_rules! with_locals {
+    ($body:block) => {
+        $body
+    };
+    ($key:path = $value:expr; $($rest:tt)+) => {
+        $key.sync_scope($value, || $crate::with_locals!($($rest)+))
+    };
+}
@@ -1,8 +1,9 @@
 //! Test that the library works in both std and no_std modes
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
-    use crate::task_local;
+    use crate::AccessError;
 
     task_local! {
         static TEST_VALUE: u32;
@@ -11,7 +12,7 @@ mod tests {
 
     #[test]
     fn test_sync_scope() {
-        TEST_VALUE.sync_scope(42, || {
+        TEST_VALUE.sync_scope(42u32, || {
             assert_eq!(TEST_VALUE.get(), 42);
         });
 
@@ -22,10 +23,10 @@ mod tests {
 
     #[test]
     fn test_nested_scopes() {
-        TEST_VALUE.sync_scope(1, || {
+        TEST_VALUE.sync_scope(1u32, || {
             assert_eq!(TEST_VALUE.get(), 1);
             
-            TEST_VALUE.sync_scope(2, || {
+            TEST_VALUE.sync_scope(2u32, || {
                 assert_eq!(TEST_VALUE.get(), 2);
             });
             
@@ -36,13 +37,245 @@ mod tests {
     #[test]
     fn test_try_with_error() {
         let result = TEST_VALUE.try_with(|_| ());
-        assert!(result.is_err());
+        assert_eq!(result, Err(AccessError::NotSet));
+    }
+
+    // Keys only store a pointer to the value of the current scope, so the RAM they
+    // use does not depend on the size of the value.
+    #[cfg(not(feature = "forbid-unsafe"))]
+    #[test]
+    fn test_storage_size_independent_of_value() {
+        use crate::value_cell::ValueCell;
+        use crate::LocalKey;
+        use core::mem::size_of;
+
+        assert_eq!(size_of::<LocalKey<[u8; 1024]>>(), size_of::<LocalKey<u8>>());
+        assert_eq!(
+            size_of::<ValueCell<[u8; 1024]>>(),
+            size_of::<ValueCell<u8>>()
+        );
+    }
+
+    // Simulates an interrupt that preempts a scope in the middle of swapping the
+    // value in.
+    #[cfg(not(any(
+        feature = "std",
+        feature = "embassy",
+        feature = "rtic",
+        feature = "forbid-unsafe"
+    )))]
+    #[test]
+    fn test_try_with_borrowed() {
+        TEST_VALUE.sync_scope(1u32, || {
+            let _swapping = TEST_VALUE.inner[0].ptr.borrow_mut();
+            assert_eq!(TEST_VALUE.try_with(|_| ()), Err(AccessError::Borrowed));
+        });
+    }
+
+    // An interrupt handler reads the value of the code it preempted, even in the
+    // middle of `with`, without changing the borrow state of the key.
+    #[cfg(not(any(feature = "std", feature = "forbid-unsafe")))]
+    #[test]
+    fn test_peek_from_isr() {
+        assert_eq!(TEST_VALUE.peek_from_isr(), Err(AccessError::NotSet));
+        TEST_VALUE.sync_scope(7u32, || {
+            TEST_VALUE.with(|value| {
+                assert_eq!(TEST_VALUE.peek_from_isr(), Ok(7));
+                assert_eq!(*value, 7);
+            });
+            TEST_VALUE.set(8);
+            assert_eq!(TEST_VALUE.peek_from_isr(), Ok(8));
+        });
+    }
+
+    #[cfg(not(any(
+        feature = "std",
+        feature = "embassy",
+        feature = "rtic",
+        feature = "forbid-unsafe"
+    )))]
+    #[test]
+    fn test_peek_from_isr_borrowed() {
+        TEST_VALUE.sync_scope(1u32, || {
+            let swapping = TEST_VALUE.inner[0].ptr.borrow_mut();
+            assert_eq!(TEST_VALUE.peek_from_isr(), Err(AccessError::Borrowed));
+            drop(swapping);
+            assert_eq!(TEST_VALUE.peek_from_isr(), Ok(1));
+        });
+    }
+
+    // With `critical-section` and a single cell shared by every core, a handler
+    // on another core, here another thread, reads the value while a task replaces
+    // it with `set`, and sees either value in full.
+    #[cfg(all(
+        feature = "critical-section",
+        not(any(
+            feature = "std",
+            feature = "per-core",
+            feature = "embassy",
+            feature = "rtic",
+            feature = "forbid-unsafe"
+        ))
+    ))]
+    #[test]
+    fn test_peek_from_isr_during_set() {
+        extern crate std;
+
+        use core::sync::atomic::{AtomicBool, Ordering};
+
+        task_local! {
+            static PAIR: (u64, u64);
+        }
+
+        let done = AtomicBool::new(false);
+        PAIR.sync_scope((0, 0), || {
+            std::thread::scope(|scope| {
+                scope.spawn(|| {
+                    // Stops the writer even if an assertion fails.
+                    struct Done<'a>(&'a AtomicBool);
+                    impl Drop for Done<'_> {
+                        fn drop(&mut self) {
+                            self.0.store(true, Ordering::Relaxed);
+                        }
+                    }
+                    let _done = Done(&done);
+
+                    for _ in 0..10_000 {
+                        let (first, second) = PAIR.peek_from_isr().unwrap();
+                        assert_eq!(first, second);
+                    }
+                });
+                let mut i = 0;
+                while !done.load(Ordering::Relaxed) {
+                    i += 1;
+                    PAIR.set((i, i));
+                }
+            });
+        });
+    }
+
+    // A handler on another core, here another thread, sharing the buffers with a
+    // task that keeps publishing always loads a whole value.
+    #[cfg(not(any(feature = "std", feature = "per-core", feature = "forbid-unsafe")))]
+    #[test]
+    fn test_double_buffered_load_during_publish() {
+        extern crate std;
+
+        use core::sync::atomic::{AtomicBool, Ordering};
+
+        use crate::DoubleBuffered;
+
+        static VALUES: DoubleBuffered<[u64; 16]> = DoubleBuffered::new();
+
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                // Stops the writer even if an assertion fails.
+                struct Done<'a>(&'a AtomicBool);
+                impl Drop for Done<'_> {
+                    fn drop(&mut self) {
+                        self.0.store(true, Ordering::Relaxed);
+                    }
+                }
+                let _done = Done(&done);
+
+                for _ in 0..100_000 {
+                    if let Some(values) = VALUES.load() {
+                        assert!(values.iter().all(|value| *value == values[0]));
+                    }
+                }
+            });
+            let mut i = 0;
+            while !done.load(Ordering::Relaxed) {
+                i += 1;
+                VALUES.set([i; 16]);
+            }
+        });
+    }
+
+    // Every scope publishes its value and publishes the previous one again when
+    // it is left, also between the polls of a future.
+    #[cfg(not(any(feature = "std", feature = "forbid-unsafe")))]
+    #[test]
+    fn test_double_buffered() {
+        use crate::DoubleBuffered;
+
+        static GAIN: DoubleBuffered<u32> = DoubleBuffered::new();
+
+        assert_eq!(GAIN.load(), None);
+        GAIN.sync_scope(1u32, || {
+            assert_eq!(GAIN.load(), Some(1));
+            GAIN.sync_scope(2u32, || {
+                assert_eq!(GAIN.set(3), Some(2));
+                assert_eq!(GAIN.load(), Some(3));
+            });
+            assert_eq!(GAIN.load(), Some(1));
+        });
+        assert_eq!(GAIN.load(), None);
+
+        futures::executor::block_on(async {
+            let scoped = GAIN.scope(4u32, async {
+                assert_eq!(GAIN.load(), Some(4));
+                GAIN.set(5);
+                futures::pending!();
+                assert_eq!(GAIN.load(), Some(5));
+            });
+            futures::pin_mut!(scoped);
+
+            assert!(futures::poll!(scoped.as_mut()).is_pending());
+            assert_eq!(GAIN.load(), None);
+            scoped.await;
+        });
+    }
+
+    // Claimed keys are distinct and independent, until the set is exhausted.
+    #[cfg(all(
+        not(feature = "std"),
+        not(all(feature = "forbid-unsafe", any(feature = "embassy", feature = "rtic")))
+    ))]
+    #[test]
+    fn test_local_key_set() {
+        crate::local_key_set! {
+            static PLUGINS: [u32; 2];
+        }
+
+        assert_eq!((PLUGINS.claimed(), PLUGINS.capacity()), (0, 2));
+        let first = PLUGINS.claim().unwrap();
+        let second = PLUGINS.claim().unwrap();
+        assert!(!core::ptr::eq(first, second));
+        assert_eq!(PLUGINS.claim().unwrap_err().capacity(), 2);
+        assert_eq!(PLUGINS.claimed(), 2);
+
+        first.sync_scope(1u32, || {
+            assert_eq!(second.try_with(|v| *v), Err(AccessError::NotSet));
+            second.sync_scope(2u32, || {
+                assert_eq!((first.get(), second.get()), (1, 2));
+            });
+        });
+    }
+
+    #[test]
+    fn test_well_known_keys() {
+        use crate::keys::{self, Priority, TaskId};
+
+        assert_eq!(keys::task_id(), None);
+        keys::TASK_NAME.sync_scope("worker", || {
+            keys::PRIORITY.sync_scope(Priority::new(3), || {
+                assert_eq!(keys::task_name(), Some("worker"));
+                assert_eq!(keys::priority().map(Priority::get), Some(3));
+                assert_eq!(keys::task_id(), None);
+                assert_eq!(keys::component(), None);
+            });
+            keys::TASK_ID.sync_scope(TaskId::new(7), || {
+                assert_eq!(keys::task_id(), Some(TaskId::new(7)));
+            });
+        });
     }
 
     #[cfg(feature = "std")]
     #[tokio::test]
     async fn test_async_scope() {
-        TEST_VALUE.scope(100, async {
+        TEST_VALUE.scope(100u32, async {
             assert_eq!(TEST_VALUE.get(), 100);
         }).await;
     }
@@ -50,14 +283,493 @@ mod tests {
     #[cfg(feature = "std")]
     #[tokio::test]
     async fn test_nested_async_scopes() {
-        TEST_VALUE.scope(1, async {
+        TEST_VALUE.scope(1u32, async {
             assert_eq!(TEST_VALUE.get(), 1);
             
-            TEST_VALUE.scope(2, async {
+            TEST_VALUE.scope(2u32, async {
                 assert_eq!(TEST_VALUE.get(), 2);
             }).await;
             
             assert_eq!(TEST_VALUE.get(), 1);
         }).await;
     }
+
+    #[test]
+    fn test_nested_scopes_interleaved_futures() {
+        task_local! {
+            static TEST_VALUE: u32;
+        }
+
+        futures::executor::block_on(async {
+            let outer = TEST_VALUE.scope(1u32, async {
+                let inner = TEST_VALUE.scope(2u32, async {
+                    futures::pending!();
+                    assert_eq!(TEST_VALUE.get(), 2);
+                });
+                futures::pin_mut!(inner);
+
+                assert!(futures::poll!(inner.as_mut()).is_pending());
+                assert_eq!(TEST_VALUE.get(), 1);
+
+                inner.await;
+                assert_eq!(TEST_VALUE.get(), 1);
+            });
+            outer.await;
+        });
+    }
+
+    #[test]
+    fn test_block_on_scoped() {
+        use crate::embedded::block_on_scoped;
+
+        task_local! {
+            static STAGE: &'static str;
+        }
+
+        let stage = block_on_scoped((&STAGE, "boot"), async {
+            core::future::ready(()).await;
+            STAGE.get()
+        });
+        assert_eq!(stage, "boot");
+        assert!(STAGE.try_with(|_| ()).is_err());
+    }
+
+    // With `error-handler`, the failure goes to the handler instead.
+    #[cfg(not(feature = "error-handler"))]
+    #[test]
+    #[should_panic(expected = "while the task-local storage is borrowed")]
+    fn test_scope_inside_with_panics() {
+        task_local! {
+            static TEST_STRING: &'static str;
+        }
+
+        TEST_STRING.sync_scope("outer", || {
+            TEST_STRING.with(|_| TEST_STRING.sync_scope("inner", || {}));
+        });
+    }
+
+    // A deterministic executor for the swap-on-poll logic of no_std builds, where
+    // every task using a key shares its storage. With the `embassy` backend the
+    // tasks share the slot of the no-op waker they are polled with.
+    mod interleave {
+        extern crate std;
+
+        use core::future::Future;
+        use core::pin::Pin;
+        use core::task::{Context, Poll};
+        use std::boxed::Box;
+        use std::vec::Vec;
+
+        /// Polls several tasks on the current thread, switching between them only
+        /// at their yield points.
+        ///
+        /// The next task to poll is picked among the unfinished ones by a
+        /// generator seeded with `seed`, so that the same seed always gives the
+        /// same interleaving, and a failing one can be replayed.
+        struct Interleave<'a> {
+            tasks: Vec<Pin<Box<dyn Future<Output = ()> + 'a>>>,
+            seed: u64,
+        }
+
+        impl<'a> Interleave<'a> {
+            fn new(seed: u64) -> Self {
+                Self {
+                    tasks: Vec::new(),
+                    // xorshift never leaves zero, so the state is made odd,
+                    // differently for every seed below 2^63.
+                    seed: seed.wrapping_mul(2).wrapping_add(1),
+                }
+            }
+
+            fn spawn(mut self, task: impl Future<Output = ()> + 'a) -> Self {
+                self.tasks.push(Box::pin(task));
+                self
+            }
+
+            fn next_index(&mut self, len: usize) -> usize {
+                self.seed ^= self.seed << 13;
+                self.seed ^= self.seed >> 7;
+                self.seed ^= self.seed << 17;
+                (self.seed % len as u64) as usize
+            }
+
+            /// Runs every task to completion, and returns the indices of the tasks
+            /// in the order they were polled.
+            ///
+            /// Between two polls, no task is inside its scopes, so `check` is
+            /// called to assert that nothing leaked out of them.
+            fn run(mut self, check: impl Fn()) -> Vec<usize> {
+                let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+                let mut running: Vec<_> = (0..self.tasks.len()).collect();
+                let mut order = Vec::new();
+                while !running.is_empty() {
+                    let next = self.next_index(running.len());
+                    let index = running[next];
+                    order.push(index);
+                    if self.tasks[index].as_mut().poll(&mut cx).is_ready() {
+                        running.remove(next);
+                    }
+                    check();
+                }
+                order
+            }
+        }
+
+        /// Returns `Pending` once, giving the other tasks a turn.
+        async fn yield_now() {
+            let mut yielded = false;
+            core::future::poll_fn(|_| {
+                if core::mem::replace(&mut yielded, true) {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            })
+            .await
+        }
+
+        task_local! {
+            static TASK: u32;
+            static DEPTH: u32;
+        }
+
+        fn assert_unset() {
+            assert!(TASK.try_with(|_| ()).is_err());
+            assert!(DEPTH.try_with(|_| ()).is_err());
+        }
+
+        #[test]
+        fn test_tasks_see_their_own_values() {
+            for seed in 0..64 {
+                let mut interleave = Interleave::new(seed);
+                for task in 0..4u32 {
+                    interleave = interleave.spawn(TASK.scope(task, async move {
+                        for _ in 0..4 {
+                            assert_eq!(TASK.get(), task);
+                            yield_now().await;
+                        }
+                    }));
+                }
+                assert_eq!(interleave.run(assert_unset).len(), 4 * 5);
+            }
+        }
+
+        #[test]
+        fn test_set_and_nested_scopes_across_tasks() {
+            for seed in 0..64 {
+                let nested = TASK.scope(1u32, async {
+                    yield_now().await;
+                    DEPTH.scope(1u32, async {
+                        yield_now().await;
+                        TASK.scope(2u32, async {
+                            yield_now().await;
+                            assert_eq!((TASK.get(), DEPTH.get()), (2, 1));
+                        })
+                        .await;
+                        assert_eq!((TASK.get(), DEPTH.get()), (1, 1));
+                    })
+                    .await;
+                    yield_now().await;
+                    assert_eq!(TASK.get(), 1);
+                    assert!(DEPTH.try_with(|_| ()).is_err());
+                });
+                let set = TASK.scope(10u32, async {
+                    for value in 11..14u32 {
+                        assert_eq!(TASK.set(value), value - 1);
+                        yield_now().await;
+                        assert_eq!(TASK.get(), value);
+                    }
+                });
+                let unset = async {
+                    for _ in 0..3 {
+                        assert_unset();
+                        yield_now().await;
+                    }
+                };
+                Interleave::new(seed)
+                    .spawn(nested)
+                    .spawn(set)
+                    .spawn(unset)
+                    .run(assert_unset);
+            }
+        }
+
+        #[test]
+        fn test_interleaving_is_reproducible() {
+            fn order(seed: u64) -> Vec<usize> {
+                let task = |value: u32| {
+                    TASK.scope(value, async move {
+                        for _ in 0..3 {
+                            yield_now().await;
+                        }
+                    })
+                };
+                Interleave::new(seed)
+                    .spawn(task(0))
+                    .spawn(task(1))
+                    .spawn(task(2))
+                    .run(assert_unset)
+            }
+
+            assert_eq!(order(7), order(7));
+            let orders: Vec<_> = (0..8).map(order).collect();
+            assert!(orders.iter().any(|other| *other != orders[0]));
+        }
+    }
+
+    #[cfg(feature = "per-core")]
+    mod per_core {
+        extern crate std;
+
+        use std::cell::Cell;
+
+        std::thread_local! {
+            static CORE: Cell<usize> = const { Cell::new(0) };
+        }
+
+        fn current_core() -> usize {
+            CORE.with(Cell::get)
+        }
+
+        crate::set_core_id_fn!(current_core);
+
+        task_local! {
+            static CORE_VALUE: u32;
+        }
+
+        #[test]
+        fn test_cores_are_independent() {
+            CORE_VALUE.sync_scope(0u32, || {
+                std::thread::spawn(|| {
+                    CORE.with(|core| core.set(1));
+                    assert!(CORE_VALUE.try_with(|_| ()).is_err());
+
+                    CORE_VALUE.sync_scope(1u32, || {
+                        assert_eq!(CORE_VALUE.get(), 1);
+                    });
+                })
+                .join()
+                .unwrap();
+
+                assert_eq!(CORE_VALUE.get(), 0);
+            });
+        }
+
+        #[cfg(not(feature = "error-handler"))]
+        #[test]
+        #[should_panic(expected = "out of range")]
+        fn test_core_out_of_range() {
+            CORE.with(|core| core.set(crate::MAX_CORES));
+            CORE_VALUE.sync_scope(1u32, || ());
+        }
+
+        // With an error handler the core gets no storage instead of panicking.
+        #[cfg(feature = "error-handler")]
+        #[test]
+        fn test_core_out_of_range() {
+            CORE.with(|core| core.set(crate::MAX_CORES));
+            assert!(CORE_VALUE.try_sync_scope(1u32, || ()).is_err());
+            assert_eq!(CORE_VALUE.try_with(|_| ()), Err(crate::AccessError::NotSet));
+        }
+    }
+
+    #[cfg(feature = "freertos")]
+    mod freertos {
+        extern crate std;
+
+        use core::ffi::{c_int, c_void};
+
+        use crate::freertos::TLS_INDEX;
+
+        // Defined by the mock in `freertos.rs`, every thread being a task.
+        extern "C" {
+            fn xTaskGetCurrentTaskHandle() -> *mut c_void;
+            fn pvTaskGetThreadLocalStoragePointer(task: *mut c_void, index: c_int) -> *mut c_void;
+        }
+
+        fn block() -> *mut c_void {
+            // Safety: The mock functions have no preconditions.
+            unsafe { pvTaskGetThreadLocalStoragePointer(xTaskGetCurrentTaskHandle(), TLS_INDEX) }
+        }
+
+        task_local! {
+            static TASK_VALUE: u32;
+        }
+
+        #[test]
+        fn test_storage_follows_the_task() {
+            TASK_VALUE.sync_scope(1u32, || {
+                std::thread::spawn(|| {
+                    assert!(block().is_null());
+                    assert!(TASK_VALUE.try_with(|_| ()).is_err());
+                    assert!(!block().is_null());
+
+                    TASK_VALUE.sync_scope(2u32, || assert_eq!(TASK_VALUE.get(), 2));
+                })
+                .join()
+                .unwrap();
+
+                assert_eq!(TASK_VALUE.get(), 1);
+            });
+        }
+    }
+
+    #[cfg(feature = "rtic")]
+    mod rtic {
+        extern crate std;
+
+        use std::cell::Cell;
+
+        std::thread_local! {
+            static PRIORITY: Cell<usize> = const { Cell::new(0) };
+        }
+
+        fn current_priority() -> usize {
+            PRIORITY.with(Cell::get)
+        }
+
+        crate::set_context_id_fn!(current_priority);
+
+        /// Runs `f` as if it preempted the current task at `priority`.
+        fn preempt<R>(priority: usize, f: impl FnOnce() -> R) -> R {
+            let prev = PRIORITY.with(|p| p.replace(priority));
+            let res = f();
+            PRIORITY.with(|p| p.set(prev));
+            res
+        }
+
+        task_local! {
+            static REQUEST_ID: u32;
+        }
+
+        #[cfg(not(feature = "error-handler"))]
+        #[test]
+        #[should_panic(expected = "returned `usize::MAX`")]
+        fn test_context_id_out_of_range() {
+            preempt(usize::MAX, || REQUEST_ID.sync_scope(1u32, || ()));
+        }
+
+        // With an error handler the context gets no slot instead of panicking.
+        #[cfg(feature = "error-handler")]
+        #[test]
+        fn test_context_id_out_of_range() {
+            preempt(usize::MAX, || {
+                assert!(REQUEST_ID.try_sync_scope(1u32, || ()).is_err());
+                assert_eq!(REQUEST_ID.try_with(|_| ()), Err(crate::AccessError::NotSet));
+            });
+        }
+
+        #[test]
+        fn test_preemption_inside_with() {
+            REQUEST_ID.sync_scope(1u32, || {
+                REQUEST_ID.with(|id| {
+                    preempt(3, || {
+                        assert!(REQUEST_ID.try_with(|_| ()).is_err());
+                        REQUEST_ID.sync_scope(3u32, || assert_eq!(REQUEST_ID.get(), 3));
+                    });
+                    assert_eq!(*id, 1);
+                });
+            });
+        }
+
+        task_local! {
+            #[task_local(tasks = 2)]
+            static SESSION: u32;
+        }
+
+        #[test]
+        fn test_slots_option() {
+            SESSION.sync_scope(1u32, || {
+                preempt(3, || {
+                    SESSION.sync_scope(3u32, || {
+                        // Both slots are claimed by the contexts inside a scope.
+                        preempt(5, || assert!(SESSION.try_sync_scope(5u32, || ()).is_err()));
+                    });
+                    // The slot of the returned context is free again.
+                    preempt(5, || SESSION.sync_scope(5u32, || assert_eq!(SESSION.get(), 5)));
+                });
+            });
+        }
+    }
+
+    #[cfg(feature = "error-handler")]
+    mod error_handler {
+        extern crate std;
+
+        use crate::{Failure, FailureKind};
+
+        fn handle(failure: &Failure) -> ! {
+            assert_eq!(failure.key(), "NUMBER");
+            assert_eq!(failure.location().file(), file!());
+            std::panic!("handled: {:?}", failure.kind())
+        }
+
+        crate::set_error_handler!(handle);
+
+        task_local! {
+            static NUMBER: u32;
+        }
+
+        #[test]
+        #[should_panic(expected = "handled: Access(NotSet)")]
+        fn test_access_failure_is_handled() {
+            NUMBER.get();
+        }
+
+        #[test]
+        #[should_panic(expected = "handled: Set")]
+        fn test_set_failure_is_handled() {
+            NUMBER.set(1);
+        }
+
+        #[test]
+        #[should_panic(expected = "handled: Scope(ScopeError)")]
+        fn test_scope_failure_is_handled() {
+            NUMBER.sync_scope(1u32, || NUMBER.with(|_| NUMBER.sync_scope(2u32, || ())));
+        }
+
+        #[test]
+        fn test_kind_display() {
+            let kind = FailureKind::Access(crate::AccessError::NotSet);
+            assert_eq!(std::format!("{}", kind), "task-local value not set");
+        }
+    }
+
+    #[cfg(feature = "audit")]
+    mod audit {
+        use core::sync::atomic::{AtomicU32, Ordering};
+
+        use crate::{Access, AccessError};
+
+        // Successful reads are counted in the low half, failed ones in the high.
+        static READS: AtomicU32 = AtomicU32::new(0);
+
+        fn record(access: &Access) {
+            assert_eq!(access.key(), "SECRET");
+            assert_eq!(access.location().file(), file!());
+            let read = match access.result() {
+                Ok(()) => 1,
+                Err(AccessError::NotSet) => 1 << 16,
+                Err(err) => panic!("unexpected {:?}", err),
+            };
+            READS.fetch_add(read, Ordering::SeqCst);
+        }
+
+        crate::set_audit_hook!(record);
+
+        task_local! {
+            #[task_local(audited)]
+            static SECRET: u32;
+        }
+
+        #[test]
+        fn test_reads_are_audited() {
+            assert!(SECRET.try_get_copied().is_err());
+            SECRET.sync_scope(1u32, || {
+                assert_eq!(SECRET.get(), 1);
+                assert_eq!(SECRET.get_copied(), 1);
+            });
+            assert_eq!(READS.load(Ordering::SeqCst), (1 << 16) | 2);
+        }
+    }
 }
@@ -0,0 +1,140 @@
+//! Tokens proving that a scope is entered.
+//!
+//! `get` and `with` panic when called outside of a scope of their key, which
+//! only shows when the code runs. [`LocalKey::sync_scope_token`] passes the
+//! closure it runs an [`InScope`] token instead, which cannot outlive the
+//! closure, and accessors taking the token can then only be called where the
+//! key is set. Code written against the token asks for one from its caller,
+//! so forgetting to enter the scope is a compile error.
+//!
+//! Tokens only exist for `sync_scope`: the closure's borrow cannot be carried
+//! into a future polled later.
+
+use core::fmt;
+use core::marker::PhantomData;
+use core::ptr;
+
+use crate::LocalKey;
+
+/// A token proving that a scope of a key is entered, passed to the closure
+/// of [`LocalKey::sync_scope_token`].
+///
+/// The token names its key and is `Copy`, but neither `Send` nor `Sync`, and
+/// cannot escape the closure. Inside a nested scope of the same key,
+/// accessors read the value of the innermost scope.
+pub struct InScope<'a, T: 'static> {
+    key: &'static LocalKey<T>,
+    // Ties the token to the closure and to the current thread.
+    _scope: PhantomData<(&'a (), *const ())>,
+}
+
+impl<T: 'static> InScope<'_, T> {
+    /// Returns the key whose scope is entered.
+    pub fn key(self) -> &'static LocalKey<T> {
+        self.key
+    }
+}
+
+impl<T: 'static> Clone for InScope<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: 'static> Copy for InScope<'_, T> {}
+
+impl<T: 'static> fmt::Debug for InScope<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InScope")
+            .field("key", &self.key.name)
+            .finish()
+    }
+}
+
+impl<T: 'static> LocalKey<T> {
+    /// Sets a value `T` as the task-local value for the closure `f`, and
+    /// passes `f` a token proving that the scope is entered.
+    ///
+    /// The token is accepted by [`with_token`](Self::with_token) and
+    /// [`get_with`](Self::get_with), which cannot be called outside of the
+    /// scope. This is otherwise the same as [`sync_scope`](Self::sync_scope).
+    ///
+    /// # Panics
+    ///
+    /// As [`sync_scope`](Self::sync_scope).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use task_local::InScope;
+    ///
+    /// task_local::task_local! {
+    ///     static REQUEST_ID: u64;
+    /// }
+    ///
+    /// // Can only be called by code inside a scope of `REQUEST_ID`.
+    /// fn log(scope: InScope<'_, u64>, message: &str) -> String {
+    ///     format!("[{}] {message}", REQUEST_ID.get_with(scope))
+    /// }
+    ///
+    /// let line = REQUEST_ID.sync_scope_token(7u64, |scope| log(scope, "started"));
+    /// assert_eq!(line, "[7] started");
+    /// ```
+    ///
+    /// The token cannot be kept for after the scope:
+    ///
+    /// ```compile_fail
+    /// task_local::task_local! {
+    ///     static REQUEST_ID: u64;
+    /// }
+    ///
+    /// let scope = REQUEST_ID.sync_scope_token(7u64, |scope| scope);
+    /// REQUEST_ID.get_with(scope);
+    /// ```
+    #[track_caller]
+    pub fn sync_scope_token<F, R>(&'static self, value: impl Into<T>, f: F) -> R
+    where
+        F: for<'a> FnOnce(InScope<'a, T>) -> R,
+    {
+        self.sync_scope(value, || {
+            f(InScope {
+                key: self,
+                _scope: PhantomData,
+            })
+        })
+    }
+
+    /// Accesses the task-local value of the scope proven by `token`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `token` is the token of another key, or for the same
+    /// reasons as [`with`](Self::with) inside a scope, such as a poisoned
+    /// scope.
+    #[track_caller]
+    pub fn with_token<F, R>(&'static self, token: InScope<'_, T>, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        assert!(
+            ptr::eq(self, token.key),
+            "token of task-local `{}` used to access `{}`",
+            token.key.name,
+            self.name
+        );
+        self.with(f)
+    }
+
+    /// Returns a copy of the task-local value of the scope proven by `token`.
+    ///
+    /// # Panics
+    ///
+    /// As [`with_token`](Self::with_token).
+    #[track_caller]
+    pub fn get_with(&'static self, token: InScope<'_, T>) -> T
+    where
+        T: Clone,
+    {
+        self.with_token(token, T::clone)
+    }
+}
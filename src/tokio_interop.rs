@@ -0,0 +1,73 @@
+//! Interoperability with `tokio::task_local!` keys.
+//!
+//! With the `tokio-interop` feature, [`tokio::task::LocalKey`] implements
+//! [`TaskLocalStorage`], so keys declared by dependencies with
+//! `tokio::task_local!` can be read and re-established by the same generic
+//! code that handles this crate's keys.
+//!
+//! # Examples
+//!
+//! ```
+//! use task_local::TaskLocalStorage;
+//!
+//! tokio::task_local! {
+//!     static TOKIO_KEY: u32;
+//! }
+//!
+//! task_local::task_local! {
+//!     static OUR_KEY: u32;
+//! }
+//!
+//! fn current<K: TaskLocalStorage<Value = u32>>(key: &'static K) -> Option<u32> {
+//!     key.try_with(|v| *v).ok()
+//! }
+//!
+//! TOKIO_KEY.sync_scope(1, || {
+//!     OUR_KEY.sync_scope(2u32, || {
+//!         assert_eq!(current(&TOKIO_KEY), Some(1));
+//!         assert_eq!(current(&OUR_KEY), Some(2));
+//!     })
+//! });
+//! ```
+
+use std::future::Future;
+
+use tokio::task::futures::TaskLocalFuture;
+use tokio::task::LocalKey;
+
+use crate::{AccessError, TaskLocalStorage};
+
+impl<T: 'static> TaskLocalStorage for LocalKey<T> {
+    type Value = T;
+    type Scope<F: Future> = TaskLocalFuture<T, F>;
+
+    fn scope<F>(&'static self, value: T, f: F) -> TaskLocalFuture<T, F>
+    where
+        F: Future,
+    {
+        LocalKey::scope(self, value, f)
+    }
+
+    #[track_caller]
+    fn sync_scope<F, R>(&'static self, value: T, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        LocalKey::sync_scope(self, value, f)
+    }
+
+    #[track_caller]
+    fn with<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        LocalKey::with(self, f)
+    }
+
+    fn try_with<F, R>(&'static self, f: F) -> Result<R, AccessError>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        LocalKey::try_with(self, f).map_err(|_| AccessError::NotSet)
+    }
+}
@@ -0,0 +1,419 @@
+//! Task-local scopes for `tower` services.
+//!
+//! With the `tower` feature, two layers establish task-locals around the
+//! futures of a service stack:
+//!
+//! - [`ScopeLayer`] scopes a value computed from every request around the
+//!   response future of that request.
+//! - [`ConnectionScopeLayer`] wraps a make-service, the service called once
+//!   per connection to create the service handling the requests of that
+//!   connection. It computes a value from the connection target, such as the
+//!   peer address or TLS identity, and scopes a clone of it around the
+//!   response future of every request on that connection.
+//!
+//! Both can be combined: request-level scopes are entered inside the
+//! connection-level ones, so a request sees the values of both, and a key
+//! scoped at both levels shows the request-level value.
+//!
+//! Only the futures are scoped. `poll_ready` and `call` on the wrapped
+//! services run outside of the scope, except that the future creating the
+//! service of a connection already sees the connection-level value.
+//!
+//! # Examples
+//!
+//! ```
+//! # async fn dox() {
+//! use std::convert::Infallible;
+//! use std::future::{ready, Future, Ready};
+//! use std::net::SocketAddr;
+//! use std::pin::Pin;
+//! use std::task::{Context, Poll};
+//!
+//! use task_local::tower::{ConnectionScopeLayer, Scope, ScopeLayer};
+//! use tower_layer::Layer;
+//! use tower_service::Service;
+//!
+//! task_local::task_local! {
+//!     static PEER: SocketAddr;
+//!     static REQUEST_ID: u64;
+//! }
+//!
+//! /// Answers requests with the peer and request id it sees.
+//! struct Echo;
+//!
+//! impl Service<u64> for Echo {
+//!     type Response = String;
+//!     type Error = Infallible;
+//!     type Future = Pin<Box<dyn Future<Output = Result<String, Infallible>>>>;
+//!
+//!     fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+//!         Poll::Ready(Ok(()))
+//!     }
+//!
+//!     fn call(&mut self, _: u64) -> Self::Future {
+//!         Box::pin(async { Ok(format!("{} #{}", PEER.get(), REQUEST_ID.get())) })
+//!     }
+//! }
+//!
+//! fn request_id(id: &u64) -> u64 {
+//!     *id
+//! }
+//!
+//! /// Creates the service of every connection.
+//! struct MakeEcho;
+//!
+//! impl Service<SocketAddr> for MakeEcho {
+//!     type Response = Scope<Echo, u64, fn(&u64) -> u64>;
+//!     type Error = Infallible;
+//!     type Future = Ready<Result<Self::Response, Infallible>>;
+//!
+//!     fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+//!         Poll::Ready(Ok(()))
+//!     }
+//!
+//!     fn call(&mut self, _: SocketAddr) -> Self::Future {
+//!         let layer = ScopeLayer::new(&REQUEST_ID, request_id as fn(&u64) -> u64);
+//!         ready(Ok(layer.layer(Echo)))
+//!     }
+//! }
+//!
+//! let layer = ConnectionScopeLayer::new(&PEER, |peer: &SocketAddr| *peer);
+//! let mut make = layer.layer(MakeEcho);
+//!
+//! let mut conn = make.call("127.0.0.1:4000".parse().unwrap()).await.unwrap();
+//! assert_eq!(conn.call(1).await.unwrap(), "127.0.0.1:4000 #1");
+//! assert_eq!(conn.call(2).await.unwrap(), "127.0.0.1:4000 #2");
+//! # }
+//! ```
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use pin_project_lite::pin_project;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{LocalKey, TaskLocalFuture};
+
+/// A layer scoping a value computed from every request around its response
+/// future.
+///
+/// See the [module documentation](self).
+pub struct ScopeLayer<T: 'static, V> {
+    key: &'static LocalKey<T>,
+    make_value: V,
+}
+
+impl<T: 'static, V> ScopeLayer<T, V> {
+    /// Creates a layer scoping `key` with the value returned by `make_value`
+    /// for every request.
+    pub fn new(key: &'static LocalKey<T>, make_value: V) -> Self {
+        Self { key, make_value }
+    }
+}
+
+impl<T: 'static, V: Clone> Clone for ScopeLayer<T, V> {
+    fn clone(&self) -> Self {
+        Self {
+            key: self.key,
+            make_value: self.make_value.clone(),
+        }
+    }
+}
+
+impl<T: 'static, V> fmt::Debug for ScopeLayer<T, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScopeLayer")
+            .field("key", &self.key.name())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, T: 'static, V: Clone> Layer<S> for ScopeLayer<T, V> {
+    type Service = Scope<S, T, V>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Scope {
+            inner,
+            key: self.key,
+            make_value: self.make_value.clone(),
+        }
+    }
+}
+
+/// A service scoping a value computed from every request around its response
+/// future.
+///
+/// Created by [`ScopeLayer`].
+pub struct Scope<S, T: 'static, V> {
+    inner: S,
+    key: &'static LocalKey<T>,
+    make_value: V,
+}
+
+impl<S, T: 'static, V> Scope<S, T, V> {
+    /// Returns a reference to the wrapped service.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Consumes this service, returning the wrapped service.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Clone, T: 'static, V: Clone> Clone for Scope<S, T, V> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            key: self.key,
+            make_value: self.make_value.clone(),
+        }
+    }
+}
+
+impl<S: fmt::Debug, T: 'static, V> fmt::Debug for Scope<S, T, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Scope")
+            .field("inner", &self.inner)
+            .field("key", &self.key.name())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, T, V, Req> Service<Req> for Scope<S, T, V>
+where
+    S: Service<Req>,
+    T: 'static,
+    V: FnMut(&Req) -> T,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = TaskLocalFuture<T, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        let value = (self.make_value)(&req);
+        self.key.scope(value, self.inner.call(req))
+    }
+}
+
+/// A layer wrapping a make-service, scoping a value computed from the
+/// connection target around the response future of every request on the
+/// connection.
+///
+/// See the [module documentation](self).
+pub struct ConnectionScopeLayer<T: 'static, V> {
+    key: &'static LocalKey<T>,
+    make_value: V,
+}
+
+impl<T: 'static, V> ConnectionScopeLayer<T, V> {
+    /// Creates a layer scoping `key` with the value returned by `make_value`
+    /// for every connection target.
+    pub fn new(key: &'static LocalKey<T>, make_value: V) -> Self {
+        Self { key, make_value }
+    }
+}
+
+impl<T: 'static, V: Clone> Clone for ConnectionScopeLayer<T, V> {
+    fn clone(&self) -> Self {
+        Self {
+            key: self.key,
+            make_value: self.make_value.clone(),
+        }
+    }
+}
+
+impl<T: 'static, V> fmt::Debug for ConnectionScopeLayer<T, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionScopeLayer")
+            .field("key", &self.key.name())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<M, T: 'static, V: Clone> Layer<M> for ConnectionScopeLayer<T, V> {
+    type Service = MakeConnectionScope<M, T, V>;
+
+    fn layer(&self, inner: M) -> Self::Service {
+        MakeConnectionScope {
+            inner,
+            key: self.key,
+            make_value: self.make_value.clone(),
+        }
+    }
+}
+
+/// A make-service creating a [`ConnectionScope`] for every connection.
+///
+/// Created by [`ConnectionScopeLayer`].
+pub struct MakeConnectionScope<M, T: 'static, V> {
+    inner: M,
+    key: &'static LocalKey<T>,
+    make_value: V,
+}
+
+impl<M, T: 'static, V> MakeConnectionScope<M, T, V> {
+    /// Returns a reference to the wrapped make-service.
+    pub fn get_ref(&self) -> &M {
+        &self.inner
+    }
+
+    /// Consumes this service, returning the wrapped make-service.
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+}
+
+impl<M: Clone, T: 'static, V: Clone> Clone for MakeConnectionScope<M, T, V> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            key: self.key,
+            make_value: self.make_value.clone(),
+        }
+    }
+}
+
+impl<M: fmt::Debug, T: 'static, V> fmt::Debug for MakeConnectionScope<M, T, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MakeConnectionScope")
+            .field("inner", &self.inner)
+            .field("key", &self.key.name())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<M, T, V, Target> Service<Target> for MakeConnectionScope<M, T, V>
+where
+    M: Service<Target>,
+    T: 'static,
+    V: FnMut(&Target) -> T,
+{
+    type Response = ConnectionScope<M::Response, T>;
+    type Error = M::Error;
+    type Future = MakeConnectionScopeFuture<M::Future, T>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, target: Target) -> Self::Future {
+        let value = (self.make_value)(&target);
+        MakeConnectionScopeFuture {
+            inner: self.key.scope(value, self.inner.call(target)),
+        }
+    }
+}
+
+pin_project! {
+    /// The future creating a [`ConnectionScope`].
+    ///
+    /// The connection-level value is already set while the wrapped service is
+    /// created.
+    pub struct MakeConnectionScopeFuture<F, T>
+    where
+        T: 'static,
+    {
+        #[pin]
+        inner: TaskLocalFuture<T, F>,
+    }
+}
+
+impl<F, S, E, T> Future for MakeConnectionScopeFuture<F, T>
+where
+    F: Future<Output = Result<S, E>>,
+    T: 'static,
+{
+    type Output = Result<ConnectionScope<S, T>, E>;
+
+    #[track_caller]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.project().inner;
+        let res = match inner.as_mut().poll(cx) {
+            Poll::Ready(res) => res,
+            Poll::Pending => return Poll::Pending,
+        };
+        let key = inner.local;
+        // The value is only taken here, after the future completed.
+        let value = inner.take_value().expect("connection value taken");
+        Poll::Ready(res.map(|inner| ConnectionScope { inner, key, value }))
+    }
+}
+
+impl<F, T: 'static> fmt::Debug for MakeConnectionScopeFuture<F, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.inner, f)
+    }
+}
+
+/// A service scoping a clone of the value of its connection around the
+/// response future of every request.
+///
+/// Created by [`MakeConnectionScope`].
+pub struct ConnectionScope<S, T: 'static> {
+    inner: S,
+    key: &'static LocalKey<T>,
+    value: T,
+}
+
+impl<S, T: 'static> ConnectionScope<S, T> {
+    /// Returns the value of the connection.
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    /// Returns a reference to the wrapped service.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Consumes this service, returning the wrapped service.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Clone, T: Clone + 'static> Clone for ConnectionScope<S, T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            key: self.key,
+            value: self.value.clone(),
+        }
+    }
+}
+
+impl<S: fmt::Debug, T: 'static> fmt::Debug for ConnectionScope<S, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConnectionScope")
+            .field("inner", &self.inner)
+            .field("key", &self.key.name())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, T, Req> Service<Req> for ConnectionScope<S, T>
+where
+    S: Service<Req>,
+    T: Clone + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = TaskLocalFuture<T, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        self.key.scope(self.value.clone(), self.inner.call(req))
+    }
+}
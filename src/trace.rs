@@ -0,0 +1,119 @@
+//! Logging of scopes being entered and exited.
+//!
+//! With the `trace-scopes` feature, every scope of every key emits a
+//! `TRACE` event when it is entered and when it is exited: through `tracing`,
+//! under the target `task_local`, in std builds, and through `defmt` in
+//! no_std builds. A future entered in a scope is logged once, on its first
+//! poll, and exited once, when it completes, is cancelled or is dropped, so
+//! a scope that is entered but never exited points to a leaked future.
+//!
+//! With `trace-scope-values`, entering a scope also logs the value, formatted
+//! with `Debug` if its type implements it and as `<opaque>` otherwise. With
+//! `scope-ids`, both events carry the id of the scope.
+
+use crate::value_cell::ScopeState;
+use crate::LocalKey;
+
+#[cfg(feature = "trace-scope-values")]
+use core::fmt;
+
+/// Logs that a scope of `key` was entered, with its value unless it was
+/// taken.
+pub(crate) fn enter<T: 'static>(key: &'static LocalKey<T>, state: ScopeState) {
+    #[cfg(all(feature = "std", feature = "trace-scope-values"))]
+    let logged = key.try_with(|value| {
+        tracing::trace!(
+            target: "task_local",
+            key = key.name,
+            module = key.module_path,
+            scope = scope_id(state),
+            value = ?Value(value, key.fmt_value),
+            "task-local scope entered"
+        )
+    });
+    #[cfg(all(not(feature = "std"), feature = "trace-scope-values"))]
+    let logged = key.try_with(|value| {
+        defmt::trace!(
+            "task-local `{=str}` scope{} entered with {}",
+            key.name,
+            ScopeSuffix(state),
+            defmt::Debug2Format(&Value(value, key.fmt_value))
+        )
+    });
+    #[cfg(feature = "trace-scope-values")]
+    if logged.is_ok() {
+        return;
+    }
+
+    #[cfg(feature = "std")]
+    tracing::trace!(
+        target: "task_local",
+        key = key.name,
+        module = key.module_path,
+        scope = scope_id(state),
+        "task-local scope entered"
+    );
+    #[cfg(not(feature = "std"))]
+    defmt::trace!(
+        "task-local `{=str}` scope{} entered",
+        key.name,
+        ScopeSuffix(state)
+    );
+}
+
+/// Logs that a scope of `key` was exited.
+pub(crate) fn exit<T: 'static>(key: &'static LocalKey<T>, state: ScopeState) {
+    #[cfg(feature = "std")]
+    tracing::trace!(
+        target: "task_local",
+        key = key.name,
+        module = key.module_path,
+        scope = scope_id(state),
+        "task-local scope exited"
+    );
+
+    #[cfg(not(feature = "std"))]
+    defmt::trace!(
+        "task-local `{=str}` scope{} exited",
+        key.name,
+        ScopeSuffix(state)
+    );
+}
+
+/// Returns the number of the id of the scope, recorded as the `scope` field
+/// of the events, which is left empty without `scope-ids`.
+#[cfg(feature = "std")]
+#[cfg_attr(not(feature = "scope-ids"), allow(unused_variables))]
+fn scope_id(state: ScopeState) -> Option<u64> {
+    #[cfg(feature = "scope-ids")]
+    return state.id.map(|id| id.get());
+    #[cfg(not(feature = "scope-ids"))]
+    return None;
+}
+
+/// Formats the id of the scope after the word "scope", if there is one.
+#[cfg(not(feature = "std"))]
+#[cfg_attr(not(feature = "scope-ids"), allow(dead_code))]
+struct ScopeSuffix(ScopeState);
+
+#[cfg(not(feature = "std"))]
+impl defmt::Format for ScopeSuffix {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        #[cfg(feature = "scope-ids")]
+        if let Some(id) = self.0.id {
+            defmt::write!(f, " {}", id);
+        }
+        #[cfg(not(feature = "scope-ids"))]
+        let _ = f;
+    }
+}
+
+#[cfg(feature = "trace-scope-values")]
+struct Value<'a, T>(&'a T, crate::FmtValue<T>);
+
+#[cfg(feature = "trace-scope-values")]
+impl<T> fmt::Debug for Value<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (self.1)(self.0, f)
+    }
+}
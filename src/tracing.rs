@@ -0,0 +1,88 @@
+//! Recording task-local values on `tracing` spans.
+//!
+//! [`record`] copies the current values of a selection of keys into fields of
+//! the current span, which covers attaching the context of a task to a span
+//! when it is created, without a subscriber layer reading the keys on every
+//! event. A key is recorded into the field named after it in lowercase, so
+//! `REQUEST_ID` is recorded as `request_id`. As with `Span::record`, the
+//! fields must be declared when the span is created, with
+//! `tracing::field::Empty` as placeholder.
+//!
+//! # Examples
+//!
+//! ```
+//! use tracing::field::Empty;
+//!
+//! task_local::task_local! {
+//!     static REQUEST_ID: u64;
+//!     static TENANT: &'static str;
+//! }
+//!
+//! REQUEST_ID.sync_scope(7u64, || {
+//!     TENANT.sync_scope("acme", || {
+//!         let span = tracing::info_span!("handle", request_id = Empty, tenant = Empty);
+//!         let _entered = span.enter();
+//!         task_local::tracing::record(&[&REQUEST_ID, &TENANT]);
+//!     })
+//! });
+//! ```
+
+use ::tracing::{Span, Value};
+
+use crate::LocalKey;
+
+/// A key whose value can be recorded as a field of a span.
+///
+/// This is implemented for every [`LocalKey`] whose value implements
+/// `tracing::Value`, such as integers, strings and the `Debug` and `Display`
+/// wrappers of `tracing::field`.
+///
+/// Requires the `tracing` feature.
+pub trait RecordKey: Sync {
+    /// Records the current value of the key into `span`, if the key is set
+    /// and the span has a field named after the key.
+    fn record_into(&'static self, span: &Span);
+}
+
+impl<T: Value + 'static> RecordKey for LocalKey<T> {
+    fn record_into(&'static self, span: &Span) {
+        let Some(field) = field_name(span, self.name) else {
+            return;
+        };
+        let _ = self.try_with(|value| {
+            span.record(field, value);
+        });
+    }
+}
+
+/// Returns the name of the field of `span` that a key named `key` is recorded
+/// into: the name of the key in lowercase, or as written.
+fn field_name(span: &Span, key: &'static str) -> Option<&'static str> {
+    let fields = span.metadata()?.fields();
+    let field = fields
+        .field(key.to_ascii_lowercase().as_str())
+        .or_else(|| fields.field(key))?;
+    Some(field.name())
+}
+
+/// Records the current values of `keys` as fields of the current span.
+///
+/// Keys that are not set, and keys without a field of the same name in the
+/// span, are skipped. See the [module documentation](self) for how fields are
+/// named.
+///
+/// Requires the `tracing` feature.
+pub fn record(keys: &[&'static dyn RecordKey]) {
+    record_into_span(&Span::current(), keys);
+}
+
+/// Records the current values of `keys` as fields of `span`.
+///
+/// This is the same as [`record`], for a span that is not entered.
+///
+/// Requires the `tracing` feature.
+pub fn record_into_span(span: &Span, keys: &[&'static dyn RecordKey]) {
+    for key in keys {
+        key.record_into(span);
+    }
+}
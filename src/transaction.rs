@@ -0,0 +1,214 @@
+//! Scoped futures that commit or roll back their value.
+//!
+//! A database transaction per request is the typical case: the transaction
+//! is the value of the scope, the handler runs inside it, and the
+//! transaction is committed if the handler succeeds and rolled back if it
+//! fails or is given up on. [`LocalKey::scope_transaction`] does the
+//! bookkeeping once instead of in every handler.
+
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use pin_project_lite::pin_project;
+
+use crate::{FinishTaskLocalFuture, LocalKey};
+
+impl<T: 'static> LocalKey<T> {
+    /// Sets a value `T` as the task-local value for the fallible future `f`,
+    /// then passes the value to `commit` if `f` succeeds and to `rollback`
+    /// if it fails.
+    ///
+    /// The returned future resolves to the output of `f` once the future
+    /// returned by `commit` or `rollback` completes, or to the error of the
+    /// commit if it fails. The callbacks receive the value as it was left by
+    /// `f`, and run after the scope is left.
+    ///
+    /// To give up on `f`, call [`TransactionFuture::cancel`], which returns
+    /// the rollback future to await. A `TransactionFuture` dropped before
+    /// completion runs neither callback, and the value is dropped as usual.
+    ///
+    /// # Panics
+    ///
+    /// Polling the returned future panics if it is polled inside a call to
+    /// [`with`](Self::with) or [`try_with`](Self::try_with) on the same key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// # struct Transaction;
+    /// # impl Transaction {
+    /// #     async fn commit(self) -> Result<(), &'static str> { Ok(()) }
+    /// #     async fn rollback(self) {}
+    /// # }
+    /// task_local::task_local! {
+    ///     static TRANSACTION: Transaction;
+    /// }
+    ///
+    /// async fn handle() -> Result<u32, &'static str> {
+    ///     // Queries run in `TRANSACTION`.
+    ///     Ok(201)
+    /// }
+    ///
+    /// let status = TRANSACTION
+    ///     .scope_transaction(Transaction, handle(), Transaction::commit, Transaction::rollback)
+    ///     .await;
+    /// assert_eq!(status, Ok(201));
+    /// # }
+    /// ```
+    pub fn scope_transaction<F, O, E, C, R, CFut, RFut>(
+        &'static self,
+        value: impl Into<T>,
+        f: F,
+        commit: C,
+        rollback: R,
+    ) -> TransactionFuture<T, F, C, R, CFut, RFut>
+    where
+        F: Future<Output = Result<O, E>>,
+        C: FnOnce(T) -> CFut,
+        CFut: Future<Output = Result<(), E>>,
+        R: FnOnce(T) -> RFut,
+        RFut: Future<Output = ()>,
+    {
+        TransactionFuture {
+            inner: self.scope(value, f).finish(),
+            callbacks: Some((commit, rollback)),
+            output: None,
+            settle: Settle::Running,
+        }
+    }
+}
+
+pin_project! {
+    /// A future that sets a value `T` of a task local for the fallible future
+    /// `F` during its execution, and then commits or rolls back the value.
+    ///
+    /// Created by the function [`LocalKey::scope_transaction`].
+    pub struct TransactionFuture<T, F, C, R, CFut, RFut>
+    where
+        T: 'static,
+        F: Future,
+    {
+        #[pin]
+        inner: FinishTaskLocalFuture<T, F>,
+        callbacks: Option<(C, R)>,
+        output: Option<F::Output>,
+        #[pin]
+        settle: Settle<CFut, RFut>,
+    }
+}
+
+pin_project! {
+    #[project = SettleProj]
+    enum Settle<CFut, RFut> {
+        Running,
+        Committing {
+            #[pin]
+            future: CFut,
+        },
+        RollingBack {
+            #[pin]
+            future: RFut,
+        },
+    }
+}
+
+impl<T, F, O, E, C, R, CFut, RFut> TransactionFuture<T, F, C, R, CFut, RFut>
+where
+    T: 'static,
+    F: Future<Output = Result<O, E>>,
+    C: FnOnce(T) -> CFut,
+    R: FnOnce(T) -> RFut,
+{
+    /// Cancels the wrapped future, returning the future rolling back the
+    /// value.
+    ///
+    /// The wrapped future is dropped as by
+    /// [`TaskLocalFuture::cancel`](crate::TaskLocalFuture::cancel), and this
+    /// future must not be polled again. Returns `None` if the wrapped future
+    /// completed already, in which case the value is being committed or
+    /// rolled back by this future.
+    pub fn cancel(self: Pin<&mut Self>) -> Option<RFut> {
+        let this = self.project();
+        if !matches!(*this.settle, Settle::Running) {
+            return None;
+        }
+        let value = this.inner.cancel()?;
+        let (_, rollback) = this.callbacks.take()?;
+        Some(rollback(value))
+    }
+}
+
+impl<T, F, O, E, C, R, CFut, RFut> Future for TransactionFuture<T, F, C, R, CFut, RFut>
+where
+    T: 'static,
+    F: Future<Output = Result<O, E>>,
+    C: FnOnce(T) -> CFut,
+    CFut: Future<Output = Result<(), E>>,
+    R: FnOnce(T) -> RFut,
+    RFut: Future<Output = ()>,
+{
+    type Output = Result<O, E>;
+
+    #[track_caller]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<O, E>> {
+        let mut this = self.project();
+        if let Settle::Running = *this.settle {
+            let (output, value) = match this.inner.poll(cx) {
+                Poll::Ready(done) => done,
+                Poll::Pending => return Poll::Pending,
+            };
+            // The callbacks are only taken here and by `cancel`, after which
+            // the future is not polled again.
+            #[cfg(not(feature = "panic-free"))]
+            let (commit, rollback) = this.callbacks.take().expect("transaction settled");
+            #[cfg(feature = "panic-free")]
+            let Some((commit, rollback)) = this.callbacks.take() else {
+                return Poll::Pending;
+            };
+            this.settle.set(match output {
+                Ok(_) => Settle::Committing {
+                    future: commit(value),
+                },
+                Err(_) => Settle::RollingBack {
+                    future: rollback(value),
+                },
+            });
+            *this.output = Some(output);
+        }
+        let committed = match this.settle.project() {
+            SettleProj::Running => return Poll::Pending,
+            SettleProj::Committing { future } => match future.poll(cx) {
+                Poll::Ready(committed) => committed,
+                Poll::Pending => return Poll::Pending,
+            },
+            SettleProj::RollingBack { future } => match future.poll(cx) {
+                Poll::Ready(()) => Ok(()),
+                Poll::Pending => return Poll::Pending,
+            },
+        };
+        match (committed, this.output.take()) {
+            (Err(err), _) => Poll::Ready(Err(err)),
+            (Ok(()), Some(output)) => Poll::Ready(output),
+            (Ok(()), None) => Poll::Pending,
+        }
+    }
+}
+
+impl<T: 'static, F: Future, C, R, CFut, RFut> fmt::Debug
+    for TransactionFuture<T, F, C, R, CFut, RFut>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stage = match self.settle {
+            Settle::Running => "running",
+            Settle::Committing { .. } => "committing",
+            Settle::RollingBack { .. } => "rolling back",
+        };
+        f.debug_struct("TransactionFuture")
+            .field("inner", &self.inner)
+            .field("stage", &stage)
+            .finish_non_exhaustive()
+    }
+}
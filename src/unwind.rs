@@ -0,0 +1,163 @@
+//! Scopes that catch panics.
+//!
+//! Leaving a scope never depends on the closure or future returning: the
+//! previous value of the key is restored by a drop guard, so it is also
+//! restored while a panic unwinds out of the scope. The helpers here stop the
+//! unwinding at the scope boundary and return the panic payload instead.
+
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe, UnwindSafe};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use pin_project_lite::pin_project;
+
+use crate::{LocalKey, TaskLocalFuture};
+
+impl<T: 'static> LocalKey<T> {
+    /// Sets a value `T` as the task-local value for the closure `f`, catching
+    /// a panic in `f`.
+    ///
+    /// This is [`sync_scope`](Self::sync_scope) wrapped in
+    /// [`catch_unwind`](std::panic::catch_unwind). If `f` panics, the value of
+    /// the scope is dropped, the value of the enclosing scope is restored and
+    /// the panic payload is returned.
+    ///
+    /// Requires the `std` feature.
+    ///
+    /// # Panics
+    ///
+    /// This method panics, without calling `f`, if called inside a call to
+    /// [`with`](Self::with) or [`try_with`](Self::try_with) on the same key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// task_local::task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// NUMBER.sync_scope(1u32, || {
+    ///     let res = NUMBER.sync_scope_catch_unwind(2u32, || panic!("boom"));
+    ///     assert!(res.is_err());
+    ///     assert_eq!(NUMBER.get(), 1);
+    /// });
+    /// ```
+    #[track_caller]
+    pub fn sync_scope_catch_unwind<F, R>(
+        &'static self,
+        value: impl Into<T>,
+        f: F,
+    ) -> Result<R, Box<dyn Any + Send>>
+    where
+        F: FnOnce() -> R + UnwindSafe,
+    {
+        // Only the panics of `f` are caught, not a failure to enter the scope.
+        self.sync_scope(value, || panic::catch_unwind(f))
+    }
+
+    /// Sets a value `T` as the task-local value for the future `f`, catching
+    /// a panic while `f` is polled.
+    ///
+    /// The returned future resolves to the payload of the panic instead of
+    /// propagating it. The future is dropped when it panics, but the value of
+    /// the scope is kept and can still be retrieved with
+    /// [`CatchUnwindTaskLocalFuture::take_value`].
+    ///
+    /// Requires the `std` feature.
+    ///
+    /// # Panics
+    ///
+    /// Polling the returned future panics if it is polled inside a call to
+    /// [`with`](Self::with) or [`try_with`](Self::try_with) on the same key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # async fn dox() {
+    /// task_local::task_local! {
+    ///     static NUMBER: u32;
+    /// }
+    ///
+    /// let mut fut = Box::pin(NUMBER.scope_catch_unwind(1u32, async { panic!("boom") }));
+    /// assert!(fut.as_mut().await.is_err());
+    /// assert_eq!(fut.as_mut().take_value(), Some(1));
+    /// # }
+    /// ```
+    pub fn scope_catch_unwind<F>(
+        &'static self,
+        value: impl Into<T>,
+        f: F,
+    ) -> CatchUnwindTaskLocalFuture<T, F>
+    where
+        F: Future + UnwindSafe,
+    {
+        CatchUnwindTaskLocalFuture {
+            inner: self.scope(value, f),
+        }
+    }
+}
+
+pin_project! {
+    /// A future that sets a value `T` of a task local for the future `F` during
+    /// its execution, resolving to the payload of a panic of `F` instead of
+    /// propagating it.
+    ///
+    /// Created by the function [`LocalKey::scope_catch_unwind`].
+    pub struct CatchUnwindTaskLocalFuture<T, F>
+    where
+        T: 'static,
+    {
+        #[pin]
+        inner: TaskLocalFuture<T, F>,
+    }
+}
+
+impl<T, F> CatchUnwindTaskLocalFuture<T, F>
+where
+    T: 'static,
+{
+    /// Returns the value stored in the task local by this future.
+    ///
+    /// See [`TaskLocalFuture::take_value`]. The value is still available after
+    /// the future panicked.
+    pub fn take_value(self: Pin<&mut Self>) -> Option<T> {
+        self.project().inner.take_value()
+    }
+
+    /// Consumes this future, returning the wrapped future.
+    ///
+    /// See [`TaskLocalFuture::into_inner`].
+    pub fn into_inner(self) -> Option<F> {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: 'static, F: Future + UnwindSafe> Future for CatchUnwindTaskLocalFuture<T, F> {
+    type Output = Result<F::Output, Box<dyn Any + Send>>;
+
+    #[track_caller]
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.project().inner;
+        let local = inner.local;
+        if inner.future.is_none() {
+            panic!("`CatchUnwindTaskLocalFuture` polled after completion");
+        }
+
+        // Only the panics of the future are caught, not a failure to enter the
+        // scope.
+        match panic::catch_unwind(AssertUnwindSafe(|| inner.as_mut().poll_scope(cx))) {
+            Ok(Ok(res)) => res.map(Ok),
+            Ok(Err(err)) => err.panic(local.name),
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    }
+}
+
+impl<T: 'static, F> fmt::Debug for CatchUnwindTaskLocalFuture<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.inner, f)
+    }
+}
@@ -0,0 +1,446 @@
+//! The storage cell of a task-local key.
+//!
+//! The value of a scope never moves into the key. It stays in the slot owned
+//! by the scope (the `TaskLocalFuture`, or the stack frame of `sync_scope`),
+//! and entering the scope only stores a pointer to that slot in the cell. This
+//! keeps entering and leaving a scope, which happens on every poll, O(1)
+//! regardless of the size of the value.
+//!
+//! Zero-sized values need no special casing: nothing of the value is ever
+//! copied, so a marker key like `static IN_TRANSACTION: ()` costs what every
+//! other key costs when entering and leaving a scope: storing a pointer and
+//! swapping the state of the scope, see below. `benches/poll.rs` compares
+//! this to polling the same future outside of any scope.
+//!
+//! The cell itself holds no value, so the static RAM of a key (one cell, or
+//! one per core, priority level or task slot) does not depend on `T`. The slot
+//! is an `Option<T>` rather than a `MaybeUninit<T>` with a presence flag: it
+//! needs a flag anyway, because `replace_value` can set a slot whose value was
+//! taken, and `Option` stores that flag in a niche of `T` where there is one.
+//!
+//! With the `forbid-unsafe` feature the cell instead owns the value of the
+//! innermost scope, and entering or leaving a scope swaps it with the slot.
+//! This needs no `unsafe` code, at the cost of moving the value on every poll.
+//!
+//! Next to the value, the cell holds the [`ScopeState`] of the innermost
+//! scope: whether it is poisoned, see `poison.rs`, the version seen by its
+//! watchers, see `watch.rs`, and with the `scope-ids` feature the id of the
+//! scope, see `scope_id.rs`. Like the value, the state
+//! belongs to the scope: entering a scope swaps in its state and leaving it
+//! hands the state back, so that a `TaskLocalFuture` stays poisoned across
+//! polls and keeps its id.
+
+#[cfg(feature = "scope-ids")]
+use crate::ScopeId;
+
+#[cfg(not(feature = "forbid-unsafe"))]
+pub(crate) use pointer::Entered;
+#[cfg(not(feature = "forbid-unsafe"))]
+pub use pointer::ValueCell;
+#[cfg(feature = "forbid-unsafe")]
+pub(crate) use swap::Entered;
+#[cfg(feature = "forbid-unsafe")]
+pub use swap::ValueCell;
+
+/// The state of a scope that is swapped in and out of a cell together with
+/// its value.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ScopeState {
+    /// Whether the scope is poisoned.
+    pub(crate) poisoned: bool,
+    /// The version of the value seen by the watchers of the scope, 0 until
+    /// the scope is first watched.
+    pub(crate) version: usize,
+    /// The id of the scope, `None` outside of any scope.
+    #[cfg(feature = "scope-ids")]
+    pub(crate) id: Option<ScopeId>,
+    /// The thread the scope was first entered on, recorded for values that
+    /// are not `Send`, see `affinity.rs`.
+    #[cfg(all(feature = "std", debug_assertions, not(loom)))]
+    pub(crate) thread: Option<std::thread::ThreadId>,
+}
+
+impl ScopeState {
+    /// The state of a cell outside of any scope.
+    pub(crate) const OUTSIDE: Self = Self {
+        poisoned: false,
+        version: 0,
+        #[cfg(feature = "scope-ids")]
+        id: None,
+        #[cfg(all(feature = "std", debug_assertions, not(loom)))]
+        thread: None,
+    };
+
+    /// Returns the state of a new scope, with a new id.
+    pub(crate) fn new() -> Self {
+        Self {
+            poisoned: false,
+            version: 0,
+            #[cfg(feature = "scope-ids")]
+            id: Some(ScopeId::next()),
+            #[cfg(all(feature = "std", debug_assertions, not(loom)))]
+            thread: None,
+        }
+    }
+
+    /// Returns the state of a new scope copied from this one, poisoned if
+    /// this one is.
+    pub(crate) fn fork(self) -> Self {
+        let mut state = Self::new();
+        state.poisoned = self.poisoned;
+        state
+    }
+}
+
+#[cfg(not(feature = "forbid-unsafe"))]
+mod pointer {
+    use core::cell::{BorrowError, BorrowMutError, Cell, RefCell};
+    use core::marker::PhantomData;
+    use core::mem;
+    use core::ptr::NonNull;
+
+    use super::ScopeState;
+
+    /// Pointer to the slot of the scope that is currently entered, if any.
+    type SlotPtr<T> = Option<NonNull<Option<T>>>;
+
+    /// The slot of a detached scope and its state.
+    #[cfg(feature = "raw-hooks")]
+    type Detached<T> = (NonNull<Option<T>>, ScopeState);
+
+    /// Holds a pointer to the value of the innermost entered scope.
+    #[doc(hidden)]
+    pub struct ValueCell<T: 'static> {
+        pub(crate) ptr: RefCell<SlotPtr<T>>,
+        state: Cell<ScopeState>,
+    }
+
+    /// A scope entered with [`ValueCell::enter`], which must be passed to
+    /// [`ValueCell::exit`] to leave it.
+    pub(crate) struct Entered<'a, T: 'static> {
+        prev: SlotPtr<T>,
+        prev_state: ScopeState,
+        state: &'a mut ScopeState,
+        _slot: PhantomData<&'a mut Option<T>>,
+    }
+
+    impl<T: 'static> ValueCell<T> {
+        #[doc(hidden)]
+        pub const fn new() -> Self {
+            Self {
+                ptr: RefCell::new(None),
+                state: Cell::new(ScopeState::OUTSIDE),
+            }
+        }
+
+        /// Makes `slot` the value of the cell, and `state` the state of its
+        /// scope.
+        ///
+        /// # Safety
+        ///
+        /// [`exit`](Self::exit) must be called with the returned `Entered` before
+        /// it is dropped, so that the cell does not keep pointing to `slot`.
+        pub(crate) unsafe fn enter<'a>(
+            &self,
+            slot: &'a mut Option<T>,
+            state: &'a mut ScopeState,
+        ) -> Result<Entered<'a, T>, BorrowMutError> {
+            let mut ptr = self.ptr.try_borrow_mut()?;
+            Ok(Entered {
+                prev: ptr.replace(NonNull::from(slot)),
+                prev_state: self.state.replace(*state),
+                state,
+                _slot: PhantomData,
+            })
+        }
+
+        /// Restores the slot that was current before the matching `enter`,
+        /// handing back the state of the scope.
+        pub(crate) fn exit(&self, entered: &mut Entered<'_, T>) {
+            // This should not panic: the cell is only mutably borrowed while a
+            // pointer is replaced, and user-code never gets access to the borrow
+            // guards.
+            *self.ptr.borrow_mut() = entered.prev;
+            *entered.state = self.state.replace(entered.prev_state);
+        }
+
+        /// Returns the state of the current scope.
+        #[cfg(feature = "scope-ids")]
+        pub(crate) fn state(&self) -> ScopeState {
+            self.state.get()
+        }
+
+        /// Returns whether the current scope is poisoned.
+        pub(crate) fn is_poisoned(&self) -> bool {
+            self.state.get().poisoned
+        }
+
+        /// Poisons the current scope until it is exited.
+        pub(crate) fn poison(&self) {
+            let mut state = self.state.get();
+            state.poisoned = true;
+            self.state.set(state);
+        }
+
+        /// Runs `f` on the state of the current scope.
+        pub(crate) fn update_state<R>(&self, f: impl FnOnce(&mut ScopeState) -> R) -> R {
+            let mut state = self.state.get();
+            let res = f(&mut state);
+            self.state.set(state);
+            res
+        }
+
+        /// Runs `f` on the current value, returning `None` if there is none.
+        pub(crate) fn try_with<F, R>(&self, f: F) -> Result<Option<R>, BorrowError>
+        where
+            F: FnOnce(&T) -> R,
+        {
+            let ptr = self.ptr.try_borrow()?;
+            // Safety: The slot stays valid and is not accessed elsewhere while it
+            // is entered, and it cannot be mutated through the cell while the
+            // shared borrow is held.
+            let value = ptr.map(|slot| unsafe { slot.as_ref() });
+            Ok(value.and_then(Option::as_ref).map(f))
+        }
+
+        /// Returns a copy of the current value, or `None` if there is none.
+        ///
+        /// Unlike `try_with`, this only checks that the cell is not mutably
+        /// borrowed instead of taking a shared borrow for the duration of a
+        /// closure.
+        #[inline(always)]
+        pub(crate) fn get_copied(&self) -> Result<Option<T>, BorrowError>
+        where
+            T: Copy,
+        {
+            // Safety: The pointer is copied out before anything else can borrow
+            // the cell, and the slot it points to is valid as in `try_with`.
+            let ptr = unsafe { *self.ptr.try_borrow_unguarded()? };
+            Ok(ptr.and_then(|slot| unsafe { *slot.as_ptr() }))
+        }
+
+        /// Runs `f` on the current value without checking that there is one or
+        /// that the cell is not borrowed.
+        ///
+        /// # Safety
+        ///
+        /// A scope must be entered and its value must be present, and the cell
+        /// must not be mutably borrowed or the value replaced while `f` runs.
+        #[inline(always)]
+        pub(crate) unsafe fn with_unchecked<F, R>(&self, f: F) -> R
+        where
+            F: FnOnce(&T) -> R,
+        {
+            let slot = (*self.ptr.as_ptr()).unwrap_unchecked();
+            f((*slot.as_ptr()).as_ref().unwrap_unchecked())
+        }
+
+        /// Replaces the current value, returning the previous one.
+        ///
+        /// Returns `None`, dropping `value`, if there is no current value or the
+        /// cell is borrowed.
+        pub(crate) fn replace(&self, value: T) -> Option<T> {
+            let ptr = self.ptr.try_borrow_mut().ok()?;
+            // Safety: As in `try_with`; the mutable borrow of the cell guarantees
+            // that no reference to the value handed out by `try_with` is alive.
+            let slot = unsafe { &mut *ptr.as_ref()?.as_ptr() };
+            slot.as_mut().map(|slot| mem::replace(slot, value))
+        }
+
+        /// Takes the current value out of the slot, leaving the scope entered
+        /// without a value.
+        ///
+        /// Returns `None` if there is no current value or the cell is borrowed.
+        pub(crate) fn take(&self) -> Option<T> {
+            let ptr = self.ptr.try_borrow_mut().ok()?;
+            // Safety: As in `replace`.
+            let slot = unsafe { &mut *ptr.as_ref()?.as_ptr() };
+            slot.take()
+        }
+
+        /// Detaches the cell from the scope that is currently entered, if any,
+        /// returning its slot and its state, see `raw.rs`.
+        #[cfg(feature = "raw-hooks")]
+        pub(crate) fn detach(&self) -> Result<Option<Detached<T>>, BorrowMutError> {
+            let slot = self.ptr.try_borrow_mut()?.take();
+            Ok(slot.map(|slot| (slot, self.state.replace(ScopeState::OUTSIDE))))
+        }
+
+        /// Attaches the cell to a slot returned by [`detach`](Self::detach).
+        ///
+        /// Returns `false`, leaving the cell unchanged, if a scope is entered
+        /// or the cell is borrowed.
+        ///
+        /// # Safety
+        ///
+        /// The scope that owns `slot` must still be entered, and the slot must
+        /// not have moved since it was detached.
+        #[cfg(feature = "raw-hooks")]
+        pub(crate) unsafe fn attach(&self, slot: NonNull<Option<T>>, state: ScopeState) -> bool {
+            match self.ptr.try_borrow_mut() {
+                Ok(mut ptr) if ptr.is_none() => {
+                    *ptr = Some(slot);
+                    self.state.set(state);
+                    true
+                }
+                _ => false,
+            }
+        }
+    }
+}
+
+#[cfg(feature = "forbid-unsafe")]
+mod swap {
+    use core::cell::{BorrowError, BorrowMutError, Cell, RefCell};
+    use core::mem;
+
+    use super::ScopeState;
+
+    /// Holds the value of the innermost entered scope.
+    #[doc(hidden)]
+    pub struct ValueCell<T: 'static> {
+        #[cfg(feature = "std")]
+        value: RefCell<Option<T>>,
+        // A `RefCell` is not `Sync`, which the no_std keys need to be usable
+        // from a `static`. Every access already happens inside a critical
+        // section, see `crate::exclusive`.
+        #[cfg(not(feature = "std"))]
+        value: critical_section::Mutex<RefCell<Option<T>>>,
+        #[cfg(feature = "std")]
+        state: Cell<ScopeState>,
+        #[cfg(not(feature = "std"))]
+        state: critical_section::Mutex<Cell<ScopeState>>,
+    }
+
+    /// A scope entered with [`ValueCell::enter`], which must be passed to
+    /// [`ValueCell::exit`] to leave it.
+    ///
+    /// Holds the value of the enclosing scope, and its state, while the
+    /// scope is entered.
+    pub(crate) struct Entered<'a, T: 'static> {
+        slot: &'a mut Option<T>,
+        state: &'a mut ScopeState,
+    }
+
+    impl<T: 'static> ValueCell<T> {
+        #[doc(hidden)]
+        pub const fn new() -> Self {
+            Self {
+                #[cfg(feature = "std")]
+                value: RefCell::new(None),
+                #[cfg(not(feature = "std"))]
+                value: critical_section::Mutex::new(RefCell::new(None)),
+                #[cfg(feature = "std")]
+                state: Cell::new(ScopeState::OUTSIDE),
+                #[cfg(not(feature = "std"))]
+                state: critical_section::Mutex::new(Cell::new(ScopeState::OUTSIDE)),
+            }
+        }
+
+        fn with_cell<R>(&self, f: impl FnOnce(&RefCell<Option<T>>) -> R) -> R {
+            #[cfg(feature = "std")]
+            return f(&self.value);
+            #[cfg(not(feature = "std"))]
+            return critical_section::with(|cs| f(self.value.borrow(cs)));
+        }
+
+        fn with_state<R>(&self, f: impl FnOnce(&Cell<ScopeState>) -> R) -> R {
+            #[cfg(feature = "std")]
+            return f(&self.state);
+            #[cfg(not(feature = "std"))]
+            return critical_section::with(|cs| f(self.state.borrow(cs)));
+        }
+
+        /// Moves the value in `slot` into the cell, keeping the value of the
+        /// enclosing scope in `slot` until [`exit`](Self::exit) is called.
+        /// Likewise for the state of the scope.
+        pub(crate) fn enter<'a>(
+            &self,
+            slot: &'a mut Option<T>,
+            state: &'a mut ScopeState,
+        ) -> Result<Entered<'a, T>, BorrowMutError> {
+            self.with_cell(|cell| {
+                mem::swap(&mut *cell.try_borrow_mut()?, slot);
+                Ok(())
+            })?;
+            self.with_state(|cell| *state = cell.replace(*state));
+            Ok(Entered { slot, state })
+        }
+
+        /// Moves the value back into its slot and restores the value of the
+        /// enclosing scope.
+        pub(crate) fn exit(&self, entered: &mut Entered<'_, T>) {
+            // This should not panic: the cell is only mutably borrowed while
+            // a value is swapped or replaced, and user-code never gets access
+            // to the borrow guards.
+            self.with_cell(|cell| mem::swap(&mut *cell.borrow_mut(), entered.slot));
+            self.with_state(|cell| *entered.state = cell.replace(*entered.state));
+        }
+
+        /// Returns the state of the current scope.
+        #[cfg(feature = "scope-ids")]
+        pub(crate) fn state(&self) -> ScopeState {
+            self.with_state(Cell::get)
+        }
+
+        /// Returns whether the current scope is poisoned.
+        pub(crate) fn is_poisoned(&self) -> bool {
+            self.with_state(Cell::get).poisoned
+        }
+
+        /// Poisons the current scope until it is exited.
+        pub(crate) fn poison(&self) {
+            self.with_state(|cell| {
+                let mut state = cell.get();
+                state.poisoned = true;
+                cell.set(state);
+            });
+        }
+
+        /// Runs `f` on the state of the current scope.
+        pub(crate) fn update_state<R>(&self, f: impl FnOnce(&mut ScopeState) -> R) -> R {
+            self.with_state(|cell| {
+                let mut state = cell.get();
+                let res = f(&mut state);
+                cell.set(state);
+                res
+            })
+        }
+
+        /// Runs `f` on the current value, returning `None` if there is none.
+        pub(crate) fn try_with<F, R>(&self, f: F) -> Result<Option<R>, BorrowError>
+        where
+            F: FnOnce(&T) -> R,
+        {
+            self.with_cell(|cell| Ok(cell.try_borrow()?.as_ref().map(f)))
+        }
+
+        /// Returns a copy of the current value, or `None` if there is none.
+        #[inline(always)]
+        pub(crate) fn get_copied(&self) -> Result<Option<T>, BorrowError>
+        where
+            T: Copy,
+        {
+            self.with_cell(|cell| Ok(*cell.try_borrow()?))
+        }
+
+        /// Replaces the current value, returning the previous one.
+        ///
+        /// Returns `None`, dropping `value`, if there is no current value or
+        /// the cell is borrowed.
+        pub(crate) fn replace(&self, value: T) -> Option<T> {
+            self.with_cell(|cell| {
+                let mut current = cell.try_borrow_mut().ok()?;
+                current.as_mut().map(|current| mem::replace(current, value))
+            })
+        }
+
+        /// Takes the current value out of the cell, leaving the scope entered
+        /// without a value.
+        ///
+        /// Returns `None` if there is no current value or the cell is borrowed.
+        pub(crate) fn take(&self) -> Option<T> {
+            self.with_cell(|cell| cell.try_borrow_mut().ok()?.take())
+        }
+    }
+}
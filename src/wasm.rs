@@ -0,0 +1,47 @@
+//! Task-locals in `wasm-bindgen-futures` tasks.
+//!
+//! On `wasm32-unknown-unknown`, keys keep their storage in the key itself
+//! rather than in a `thread_local!`, since the module cannot share its memory
+//! with another thread. Scopes work unchanged in futures passed to
+//! `wasm_bindgen_futures::spawn_local`, but like any spawned task, those
+//! futures start without the task-locals of the code that spawned them.
+//!
+//! With the `wasm-bindgen-futures` feature, [`spawn_local`] captures a
+//! selection of keys, see [`Capture`], and sets it around the spawned future.
+//!
+//! # Examples
+//!
+//! ```ignore
+//! task_local::task_local! {
+//!     static REQUEST_ID: u64;
+//! }
+//!
+//! REQUEST_ID.sync_scope(7u64, || {
+//!     task_local::wasm::spawn_local(&REQUEST_ID, async {
+//!         web_sys::console::log_1(&format!("request {}", REQUEST_ID.get()).into());
+//!     });
+//! });
+//! ```
+
+use std::future::Future;
+
+use crate::keys::{TaskId, TASK_ID};
+use crate::Capture;
+
+/// Spawns `future` on the current thread with
+/// `wasm_bindgen_futures::spawn_local`, with the current values of `keys` as
+/// its task-locals.
+///
+/// The values are captured when `spawn_local` is called and set for the whole
+/// lifetime of the spawned future. Keys without a value are left unset. The
+/// future also gets a new [`TaskId`] as the value of
+/// [`TASK_ID`](crate::keys::TASK_ID).
+pub fn spawn_local<C, F>(keys: C, future: F)
+where
+    C: Capture,
+    F: Future<Output = ()> + 'static,
+    C::Scope<F>: 'static,
+{
+    let scoped = crate::current(keys).scope(future);
+    wasm_bindgen_futures::spawn_local(TASK_ID.scope(TaskId::next(), scoped));
+}
@@ -0,0 +1,353 @@
+//! Change notifications for task-local values.
+//!
+//! Changes are tracked per scope, and with it per task: the `ScopeState` of
+//! a scope carries the version of its value, which [`LocalKey::set`] and
+//! `TaskLocalFuture::replace_value` bump. A [`Watch`] remembers the version it
+//! has seen in the scope of the current task it is used in, so that it sees a
+//! change when the value of that scope is replaced, and when it is used in
+//! another scope than before: after a scope was entered or exited around it.
+//!
+//! A scope gets a version the first time it is watched, from a counter of the
+//! key. Until then its version is 0 and replacing its value records nothing,
+//! so that entering, leaving and setting keys that are not watched costs no
+//! more than before.
+
+use core::fmt;
+use core::future::Future;
+use core::mem;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+#[cfg(feature = "std")]
+use crate::sync::Mutex;
+use crate::sync::{const_fn, AtomicUsize, Ordering};
+use crate::value_cell::ScopeState;
+use crate::LocalKey;
+
+#[cfg(all(not(feature = "std"), feature = "forbid-unsafe"))]
+use core::cell::RefCell;
+#[cfg(all(not(feature = "std"), not(feature = "forbid-unsafe")))]
+use core::cell::UnsafeCell;
+#[cfg(feature = "std")]
+use std::sync::PoisonError;
+
+/// The wakers of the pending watchers, with the version each has seen.
+#[cfg(feature = "alloc")]
+type Wakers = alloc::vec::Vec<(usize, Waker)>;
+#[cfg(feature = "alloc")]
+const NO_WAKERS: Wakers = alloc::vec::Vec::new();
+#[cfg(not(feature = "alloc"))]
+type Wakers = Option<(usize, Waker)>;
+#[cfg(not(feature = "alloc"))]
+const NO_WAKERS: Wakers = None;
+
+/// Per-key version counter and wakers shared by all watchers of the key.
+#[cfg(feature = "std")]
+pub(crate) struct WatchState {
+    versions: AtomicUsize,
+    wakers: Mutex<Wakers>,
+}
+
+#[cfg(feature = "std")]
+impl WatchState {
+    const_fn! {
+        pub(crate) fn new() -> Self {
+            Self {
+                versions: AtomicUsize::new(0),
+                wakers: Mutex::new(NO_WAKERS),
+            }
+        }
+    }
+
+    /// Runs `f` with exclusive access to the wakers.
+    fn with_wakers<R>(&self, f: impl FnOnce(&mut Wakers) -> R) -> R {
+        f(&mut self.wakers.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+/// Per-key version counter and wakers for no_std environments.
+///
+/// With the `alloc` feature all pending watchers of a scope are woken, like in
+/// the std version. Otherwise only a single waker is kept: the most recently
+/// registered watcher is the one that gets woken.
+#[cfg(not(feature = "std"))]
+pub(crate) struct WatchState {
+    versions: AtomicUsize,
+    #[cfg(not(feature = "forbid-unsafe"))]
+    wakers: UnsafeCell<Wakers>,
+    #[cfg(feature = "forbid-unsafe")]
+    wakers: critical_section::Mutex<RefCell<Wakers>>,
+}
+
+#[cfg(not(feature = "std"))]
+impl WatchState {
+    const_fn! {
+        pub(crate) fn new() -> Self {
+            Self {
+                versions: AtomicUsize::new(0),
+                #[cfg(not(feature = "forbid-unsafe"))]
+                wakers: UnsafeCell::new(NO_WAKERS),
+                #[cfg(feature = "forbid-unsafe")]
+                wakers: critical_section::Mutex::new(RefCell::new(NO_WAKERS)),
+            }
+        }
+    }
+
+    /// Runs `f` with exclusive access to the wakers.
+    fn with_wakers<R>(&self, f: impl FnOnce(&mut Wakers) -> R) -> R {
+        // Safety: Access to the wakers is exclusive, see `crate::exclusive`.
+        #[cfg(not(feature = "forbid-unsafe"))]
+        return crate::exclusive(|| f(unsafe { &mut *self.wakers.get() }));
+        #[cfg(feature = "forbid-unsafe")]
+        return critical_section::with(|cs| f(&mut self.wakers.borrow_ref_mut(cs)));
+    }
+}
+
+impl WatchState {
+    /// Returns a new version, never 0.
+    fn next_version(&self) -> usize {
+        match self
+            .versions
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_add(1)
+        {
+            0 => self.next_version(),
+            version => version,
+        }
+    }
+
+    /// Returns the version of the scope with state `state`, giving it one if
+    /// it has never been watched.
+    fn watched(&self, state: &mut ScopeState) -> usize {
+        if state.version == 0 {
+            state.version = self.next_version();
+        }
+        state.version
+    }
+
+    /// Gives the scope with state `state` a new version if it is watched,
+    /// returning the version its watchers have seen.
+    pub(crate) fn bump(&self, state: &mut ScopeState) -> Option<usize> {
+        (state.version != 0).then(|| mem::replace(&mut state.version, self.next_version()))
+    }
+
+    /// Wakes the pending watchers that have seen version `seen`.
+    pub(crate) fn wake(&self, seen: usize) {
+        #[cfg(feature = "alloc")]
+        {
+            let (woken, pending): (Wakers, Wakers) = self
+                .with_wakers(mem::take)
+                .into_iter()
+                .partition(|(version, _)| *version == seen);
+            if !pending.is_empty() {
+                self.with_wakers(|wakers| wakers.extend(pending));
+            }
+            woken.into_iter().for_each(|(_, waker)| waker.wake());
+        }
+        #[cfg(not(feature = "alloc"))]
+        if let Some((_, waker)) =
+            self.with_wakers(|wakers| wakers.take_if(|(version, _)| *version == seen))
+        {
+            waker.wake();
+        }
+    }
+
+    /// Registers `waker` to be woken when version `seen` changes.
+    fn register(&self, seen: usize, waker: &Waker) {
+        self.with_wakers(|wakers| {
+            #[cfg(feature = "alloc")]
+            if !wakers.iter().any(|(v, w)| *v == seen && w.will_wake(waker)) {
+                wakers.push((seen, waker.clone()));
+            }
+            #[cfg(not(feature = "alloc"))]
+            match wakers {
+                Some((v, w)) if *v == seen && w.will_wake(waker) => {}
+                _ => *wakers = Some((seen, waker.clone())),
+            }
+        });
+    }
+
+    /// Removes `waker`, registered for version `seen`.
+    fn unregister(&self, seen: usize, waker: &Waker) {
+        let matches = |(v, w): &(usize, Waker)| *v == seen && w.will_wake(waker);
+        // Dropped outside of `with_wakers`, like woken wakers.
+        #[cfg(feature = "alloc")]
+        let _removed = self.with_wakers(|wakers| {
+            let index = wakers.iter().position(matches)?;
+            Some(wakers.swap_remove(index))
+        });
+        #[cfg(not(feature = "alloc"))]
+        let _removed = self.with_wakers(|wakers| wakers.take_if(|entry| matches(entry)));
+    }
+}
+
+impl<T: 'static> LocalKey<T> {
+    /// Records that the value of the current scope was replaced.
+    pub(crate) fn value_changed(&'static self) {
+        let watch = &self.watch;
+        // Wakes outside of `exclusive`, which the cell is accessed in.
+        let seen = self.with_current_cell(|cell| cell.update_state(|state| watch.bump(state)));
+        if let Some(Some(seen)) = seen {
+            watch.wake(seen);
+        }
+    }
+}
+
+/// A receiver that is notified whenever the value of a task-local changes.
+///
+/// Created by [`LocalKey::watch`]. A watch follows the value of the key that
+/// the task it is used in sees: a change is recorded when the value of the
+/// current scope is replaced with [`LocalKey::set`] or
+/// [`TaskLocalFuture::replace_value`](crate::TaskLocalFuture::replace_value),
+/// and when the watch is used in another scope than before, because a scope
+/// was entered or exited around it. Scopes of other tasks are not seen.
+///
+/// # Examples
+///
+/// ```
+/// # async fn dox() {
+/// task_local::task_local! {
+///     static MODE: u32;
+/// }
+///
+/// MODE.scope(1u32, async {
+///     let mut watch = MODE.watch();
+///     assert!(!watch.has_changed());
+///
+///     MODE.set(2);
+///     assert!(watch.has_changed());
+///     watch.mark_unchanged();
+///
+///     MODE.scope(3u32, async {
+///         assert!(watch.has_changed());
+///     })
+///     .await;
+///     assert!(!watch.has_changed());
+/// })
+/// .await;
+/// # }
+/// ```
+pub struct Watch<T: 'static> {
+    local: &'static LocalKey<T>,
+    seen: usize,
+}
+
+impl<T: 'static> Watch<T> {
+    pub(crate) fn new(local: &'static LocalKey<T>) -> Self {
+        let mut watch = Self { local, seen: 0 };
+        watch.mark_unchanged();
+        watch
+    }
+
+    /// Returns the version of the current scope, or the version seen if the
+    /// key cannot be accessed.
+    fn version(&self) -> usize {
+        self.local
+            .with_current_cell(|cell| cell.update_state(|state| state.version))
+            .unwrap_or(self.seen)
+    }
+
+    /// Returns `true` if the value has changed since it was last seen.
+    pub fn has_changed(&self) -> bool {
+        self.version() != self.seen
+    }
+
+    /// Marks the current state as seen.
+    pub fn mark_unchanged(&mut self) {
+        let watch = &self.local.watch;
+        if let Some(version) = self
+            .local
+            .with_current_cell(|cell| cell.update_state(|state| watch.watched(state)))
+        {
+            self.seen = version;
+        }
+    }
+
+    /// Waits until the value changes, then marks it as seen.
+    ///
+    /// Resolves immediately if a change has happened since the last time the
+    /// state was marked as seen.
+    pub fn changed(&mut self) -> Changed<'_, T> {
+        Changed {
+            watch: self,
+            registered: None,
+        }
+    }
+}
+
+impl<T: 'static> fmt::Debug for Watch<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watch").field("seen", &self.seen).finish()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<T: 'static> defmt::Format for Watch<T> {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "Watch {{ seen: {} }}", self.seen)
+    }
+}
+
+/// Future returned by [`Watch::changed`].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Changed<'a, T: 'static> {
+    watch: &'a mut Watch<T>,
+    // The waker registered by the last poll and the version it waits on,
+    // removed again when the future is dropped.
+    registered: Option<(usize, Waker)>,
+}
+
+impl<T: 'static> Future for Changed<'_, T> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        let watch = &mut *this.watch;
+
+        if watch.has_changed() {
+            watch.mark_unchanged();
+            return Poll::Ready(());
+        }
+
+        let state = &watch.local.watch;
+        match &this.registered {
+            Some((seen, waker)) if *seen == watch.seen && waker.will_wake(cx.waker()) => {}
+            _ => {
+                if let Some((seen, waker)) = this.registered.take() {
+                    state.unregister(seen, &waker);
+                }
+                state.register(watch.seen, cx.waker());
+                this.registered = Some((watch.seen, cx.waker().clone()));
+            }
+        }
+
+        // Re-check to avoid missing a change that raced with registration.
+        if watch.has_changed() {
+            watch.mark_unchanged();
+            return Poll::Ready(());
+        }
+
+        Poll::Pending
+    }
+}
+
+impl<T: 'static> Drop for Changed<'_, T> {
+    fn drop(&mut self) {
+        if let Some((seen, waker)) = self.registered.take() {
+            self.watch.local.watch.unregister(seen, &waker);
+        }
+    }
+}
+
+impl<T: 'static> fmt::Debug for Changed<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Changed").finish()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<T: 'static> defmt::Format for Changed<'_, T> {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "Changed")
+    }
+}
@@ -0,0 +1,113 @@
+//! Conveniences for task-locals holding a weak reference.
+//!
+//! A `LocalKey<Weak<T>>` scopes a handle to a value owned elsewhere, such as
+//! a connection in a pool, without keeping the value alive for as long as the
+//! scope. The value is upgraded on every access, which fails with
+//! [`AccessError::Gone`] once it was dropped.
+
+use alloc::sync::{Arc, Weak};
+use core::future::Future;
+
+use crate::{AccessError, LocalKey, TaskLocalFuture};
+
+impl<T: ?Sized + 'static> LocalKey<Weak<T>> {
+    /// Accesses the value the task-local refers to and runs the provided
+    /// closure on it.
+    ///
+    /// The weak reference is upgraded for the duration of the call, so the
+    /// value is not dropped while `f` runs.
+    ///
+    /// Requires the `alloc` feature.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if the task local doesn't have a value set,
+    /// or if the value it refers to was dropped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::{Arc, Weak};
+    ///
+    /// struct Connection {
+    ///     id: u32,
+    /// }
+    ///
+    /// task_local::task_local! {
+    ///     static CONNECTION: Weak<Connection>;
+    /// }
+    ///
+    /// let connection = Arc::new(Connection { id: 7 });
+    /// CONNECTION.sync_scope_weak(&connection, || {
+    ///     assert_eq!(CONNECTION.with_upgraded(|connection| connection.id), 7);
+    /// });
+    /// ```
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn with_upgraded<F, R>(&'static self, f: F) -> R
+    where
+        F: FnOnce(&T) -> R,
+    {
+        match self.try_with_upgraded(f) {
+            Ok(res) => res,
+            Err(err) => self.access_panic(err),
+        }
+    }
+
+    /// Accesses the value the task-local refers to and runs the provided
+    /// closure on it, returning [`AccessError::Gone`] if the value was dropped
+    /// and the other errors of [`try_with`](Self::try_with).
+    ///
+    /// Requires the `alloc` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::{Arc, Weak};
+    /// use task_local::AccessError;
+    ///
+    /// task_local::task_local! {
+    ///     static CONNECTION: Weak<u32>;
+    /// }
+    ///
+    /// let connection = Arc::new(7);
+    /// CONNECTION.sync_scope(Arc::downgrade(&connection), move || {
+    ///     // The pool closes the connection while the scope is still running.
+    ///     drop(connection);
+    ///     assert_eq!(CONNECTION.try_with_upgraded(|_| ()), Err(AccessError::Gone));
+    /// });
+    /// ```
+    pub fn try_with_upgraded<F, R>(&'static self, f: F) -> Result<R, AccessError>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        // The key is not borrowed while `f` runs, which may then access it.
+        let value = self.try_with(Weak::upgrade)?.ok_or(AccessError::Gone)?;
+        Ok(f(&value))
+    }
+
+    /// Sets a weak reference to `value` as the task-local value for the
+    /// future `f`.
+    ///
+    /// Requires the `alloc` feature.
+    #[track_caller]
+    pub fn scope_weak<F>(&'static self, value: &Arc<T>, f: F) -> TaskLocalFuture<Weak<T>, F>
+    where
+        F: Future,
+    {
+        self.scope(Arc::downgrade(value), f)
+    }
+
+    /// Sets a weak reference to `value` as the task-local value for the
+    /// closure `f`.
+    ///
+    /// Requires the `alloc` feature.
+    #[cfg(not(feature = "panic-free"))]
+    #[track_caller]
+    pub fn sync_scope_weak<F, R>(&'static self, value: &Arc<T>, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        self.sync_scope(Arc::downgrade(value), f)
+    }
+}
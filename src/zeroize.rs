@@ -0,0 +1,78 @@
+//! Wiping the values of keys declared with `#[task_local(zeroize)]`.
+//!
+//! Secrets such as short-lived credentials should not linger in memory once
+//! the scope holding them is over. A key declared with
+//! `#[task_local(zeroize)]` wipes the value of a scope with [`Zeroize`] right
+//! before it is dropped: when a `sync_scope` returns or unwinds, and when a
+//! `TaskLocalFuture` is dropped, whether it completed or was cancelled.
+//!
+//! Values handed back to the caller, by `set`, `TaskLocalFuture::take_value`
+//! or `TaskLocalFuture::into_inner`, are the caller's to wipe. Copies made
+//! when a value is moved, such as into `scope`, are out of reach, as with any
+//! use of `zeroize`; with the `forbid-unsafe` feature, entering and leaving a
+//! no_std scope moves the value too.
+
+use core::ops::{Deref, DerefMut};
+
+use ::zeroize::Zeroize;
+
+use crate::LocalKey;
+
+fn zeroize_value<T: Zeroize>(value: &mut T) {
+    value.zeroize();
+}
+
+impl<T: 'static> LocalKey<T> {
+    crate::sync::const_fn! {
+        /// Makes the key wipe its values, see `#[task_local(zeroize)]`.
+        #[doc(hidden)]
+        pub fn __zeroize(mut self) -> Self
+        where
+            T: Zeroize,
+        {
+            self.zeroize = Some(zeroize_value::<T>);
+            self
+        }
+    }
+
+    /// Wipes the value in `slot`, if there is one and the key was declared
+    /// with `#[task_local(zeroize)]`.
+    pub(crate) fn wipe(&self, slot: &mut Option<T>) {
+        if let (Some(zeroize), Some(value)) = (self.zeroize, slot) {
+            zeroize(value);
+        }
+    }
+}
+
+/// The value of a `sync_scope`, wiped when it is dropped, also while a panic
+/// unwinds out of the scope.
+pub(crate) struct Wiped<T: 'static> {
+    key: &'static LocalKey<T>,
+    slot: Option<T>,
+}
+
+impl<T: 'static> Wiped<T> {
+    pub(crate) fn new(key: &'static LocalKey<T>, slot: Option<T>) -> Self {
+        Self { key, slot }
+    }
+}
+
+impl<T: 'static> Deref for Wiped<T> {
+    type Target = Option<T>;
+
+    fn deref(&self) -> &Option<T> {
+        &self.slot
+    }
+}
+
+impl<T: 'static> DerefMut for Wiped<T> {
+    fn deref_mut(&mut self) -> &mut Option<T> {
+        &mut self.slot
+    }
+}
+
+impl<T: 'static> Drop for Wiped<T> {
+    fn drop(&mut self) {
+        self.key.wipe(&mut self.slot);
+    }
+}
@@ -0,0 +1,36 @@
+//! Model-checked tests of the std backend.
+//!
+//! Run with `RUSTFLAGS="--cfg loom" cargo test --release --test loom`.
+
+#![cfg(loom)]
+
+use loom::thread;
+use task_local::task_local;
+
+task_local! {
+    static NUMBER: u32;
+}
+
+#[test]
+fn test_scopes_isolated_between_threads() {
+    loom::model(|| {
+        let other = thread::spawn(|| NUMBER.sync_scope(2u32, || NUMBER.get()));
+        assert_eq!(NUMBER.sync_scope(1u32, || NUMBER.get()), 1);
+        assert_eq!(other.join().unwrap(), 2);
+        assert!(NUMBER.try_with(|_| ()).is_err());
+    });
+}
+
+#[test]
+fn test_watch_ignores_other_threads() {
+    loom::model(|| {
+        NUMBER.sync_scope(1u32, || {
+            let mut watch = NUMBER.watch();
+            let other = thread::spawn(|| NUMBER.sync_scope(2u32, || NUMBER.set(3)));
+            NUMBER.set(4);
+            loom::future::block_on(watch.changed());
+            other.join().unwrap();
+            assert!(!watch.has_changed());
+        });
+    });
+}
@@ -0,0 +1,61 @@
+//! Tests of the `panic-free` feature.
+//!
+//! Run with `cargo test --no-default-features --features panic-free --test panic_free`.
+
+#![cfg(feature = "panic-free")]
+
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::task::{waker, ArcWake};
+use task_local::{task_local, AccessError};
+
+task_local! {
+    static NUMBER: u32;
+}
+
+struct Flag(AtomicBool);
+
+impl ArcWake for Flag {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_fallible_accessors() {
+    assert_eq!(NUMBER.try_get(), Err(AccessError::NotSet));
+    assert_eq!(NUMBER.try_set(1), None);
+
+    let res = NUMBER.try_sync_scope(1u32, || {
+        assert_eq!(NUMBER.try_get_copied(), Ok(1));
+        assert_eq!(NUMBER.try_set(2), Some(1));
+        NUMBER.try_get()
+    });
+    assert_eq!(res.unwrap(), Ok(2));
+
+    let nested = NUMBER.try_sync_scope(1u32, || NUMBER.try_with(|_| NUMBER.try_sync_scope(2u32, || ())));
+    assert!(nested.unwrap().unwrap().is_err());
+}
+
+// A scope that cannot be entered is retried on the next poll, and a completed
+// future stays pending.
+#[test]
+fn test_future_retries_and_fuses() {
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = waker(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+
+    let mut fut = pin!(NUMBER.scope(2u32, async { NUMBER.try_get_copied() }));
+    let conflict = NUMBER.try_sync_scope(1u32, || {
+        NUMBER.try_with(|_| fut.as_mut().poll(&mut cx).is_pending())
+    });
+    assert_eq!(conflict.unwrap(), Ok(true));
+    assert!(flag.0.load(Ordering::SeqCst));
+
+    assert_eq!(fut.as_mut().poll(&mut cx), Poll::Ready(Ok(2)));
+    assert_eq!(fut.as_mut().poll(&mut cx), Poll::Pending);
+}
@@ -0,0 +1,114 @@
+//! Tests of a custom `StorageProvider`, installed once for the whole process.
+//!
+//! Run with `cargo test --features custom-storage --test provider`.
+
+#![cfg(feature = "custom-storage")]
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+
+use futures::task::noop_waker_ref;
+use task_local::provider::{self, StorageKey, StorageProvider, ThreadLocalProvider};
+use task_local::task_local;
+
+/// The storage slot of each key in each simulated task.
+type Slots = HashMap<(u32, StorageKey), Box<Cell<*mut ()>>>;
+
+thread_local! {
+    static CURRENT: Cell<u32> = const { Cell::new(0) };
+    static SLOTS: RefCell<Slots> = RefCell::new(HashMap::new());
+}
+
+/// Runs simulated tasks on the current thread, switched with `run_as`.
+struct Simulator {
+    entered: AtomicUsize,
+    exited: AtomicUsize,
+}
+
+// Safety: Every simulated task of every thread gets its own slots, which
+// are only released once the tasks have finished.
+unsafe impl StorageProvider for Simulator {
+    fn slot_for_current_task(&self, key: StorageKey) -> *mut *mut () {
+        let task = CURRENT.with(Cell::get);
+        SLOTS.with_borrow_mut(|slots| {
+            let slot = slots
+                .entry((task, key))
+                .or_insert_with(|| Box::new(Cell::new(std::ptr::null_mut())));
+            slot.as_ptr()
+        })
+    }
+
+    fn enter(&self, _: StorageKey) {
+        self.entered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn exit(&self, _: StorageKey) {
+        self.exited.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+static SIMULATOR: Simulator = Simulator {
+    entered: AtomicUsize::new(0),
+    exited: AtomicUsize::new(0),
+};
+
+fn run_as<R>(task: u32, f: impl FnOnce() -> R) -> R {
+    let prev = CURRENT.with(|current| current.replace(task));
+    let res = f();
+    CURRENT.with(|current| current.set(prev));
+    res
+}
+
+task_local! {
+    static NODE: &'static str;
+}
+
+#[test]
+fn test_custom_provider() {
+    provider::set_provider(&SIMULATOR).unwrap_or_else(|_| panic!("already installed"));
+    assert!(provider::set_provider(&ThreadLocalProvider).is_err());
+
+    // Scopes of two simulated tasks on the same thread, polled in turn.
+    let mut first = pin!(NODE.scope("a", async {
+        futures::pending!();
+        NODE.get()
+    }));
+    let mut second = pin!(NODE.scope("b", async {
+        futures::pending!();
+        NODE.get()
+    }));
+    let mut cx = Context::from_waker(noop_waker_ref());
+    assert!(run_as(1, || first.as_mut().poll(&mut cx)).is_pending());
+    // The first task is suspended outside of its scope...
+    assert!(run_as(1, || NODE.try_get()).is_err());
+    // ...and the second one only sees its own scope.
+    NODE.sync_scope("main", || {
+        assert!(run_as(2, || second.as_mut().poll(&mut cx)).is_pending());
+        assert_eq!(
+            run_as(2, || second.as_mut().poll(&mut cx)),
+            Poll::Ready("b")
+        );
+        assert_eq!(NODE.get(), "main");
+    });
+    assert_eq!(run_as(1, || first.as_mut().poll(&mut cx)), Poll::Ready("a"));
+
+    // One scope entered by `sync_scope`, and one per poll.
+    assert_eq!(SIMULATOR.entered.load(Ordering::Relaxed), 5);
+    assert_eq!(SIMULATOR.exited.load(Ordering::Relaxed), 5);
+
+    // The simulated tasks have finished.
+    SLOTS.with_borrow_mut(|slots| {
+        for (_, slot) in slots.drain() {
+            // Safety: No task accesses its slots anymore.
+            unsafe { provider::release(slot.get()) };
+        }
+    });
+    assert_eq!(
+        run_as(1, || NODE.try_get()),
+        Err(task_local::AccessError::NotSet)
+    );
+}
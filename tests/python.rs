@@ -0,0 +1,59 @@
+//! Tests of the bridge to Python `contextvars`.
+//!
+//! Run with `cargo test --features pyo3 --test python`, which needs a Python
+//! interpreter to link against.
+
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use task_local::python::ContextVarBridge;
+use task_local::task_local;
+
+#[test]
+fn test_python_context_vars() {
+    task_local! {
+        static REQUEST_ID: u64;
+        static TENANT: String;
+    }
+
+    Python::initialize();
+    Python::attach(|py| {
+        let mut bridge = ContextVarBridge::new();
+        bridge.register(py, &REQUEST_ID, "request_id").unwrap();
+        bridge.register(py, &TENANT, "tenant").unwrap();
+        assert_eq!(format!("{bridge:?}"), r#"["request_id", "tenant"]"#);
+
+        let globals = PyDict::new(py);
+        globals
+            .set_item("request_id", bridge.var(py, "request_id").unwrap())
+            .unwrap();
+        globals
+            .set_item("tenant", bridge.var(py, "tenant").unwrap())
+            .unwrap();
+        let read = |py: Python<'_>| -> (Option<u64>, Option<String>) {
+            py.eval(
+                c"(request_id.get(None), tenant.get(None))",
+                Some(&globals),
+                None,
+            )
+            .unwrap()
+            .extract()
+            .unwrap()
+        };
+
+        let seen = REQUEST_ID.sync_scope(7u64, || {
+            TENANT.sync_scope("acme", || bridge.call(py, read).unwrap())
+        });
+        assert_eq!(seen, (Some(7), Some("acme".into())));
+
+        // Variables are reset on return, and keys without a value are skipped
+        assert_eq!(read(py), (None, None));
+        let seen = REQUEST_ID.sync_scope(8u64, || bridge.call(py, read).unwrap());
+        assert_eq!(seen, (Some(8), None));
+
+        let panic = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            REQUEST_ID.sync_scope(9u64, || bridge.call(py, |_| panic!("python call failed")))
+        }));
+        assert!(panic.is_err());
+        assert_eq!(read(py), (None, None));
+    });
+}
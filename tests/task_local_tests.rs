@@ -1,3 +1,6 @@
+// Keys are created lazily inside `loom::model` under `cfg(loom)`; see `tests/loom.rs`.
+#![cfg(not(loom))]
+
 use task_local::task_local;
 
 task_local! {
@@ -9,18 +12,18 @@ task_local! {
 async fn test_basic_functionality() {
     // Test basic scope functionality
     NUMBER
-        .scope(42, async {
+        .scope(42u32, async {
             assert_eq!(NUMBER.get(), 42);
         })
         .await;
 
     // Test nested scopes
     NUMBER
-        .scope(1, async {
+        .scope(1u32, async {
             assert_eq!(NUMBER.get(), 1);
 
             NUMBER
-                .scope(2, async {
+                .scope(2u32, async {
                     assert_eq!(NUMBER.get(), 2);
                 })
                 .await;
@@ -35,7 +38,7 @@ async fn test_basic_functionality() {
 async fn test_multiple_task_locals() {
     // Test using multiple task locals together
     NUMBER
-        .scope(42, async {
+        .scope(42u32, async {
             MESSAGE
                 .scope("Hello".to_string(), async {
                     assert_eq!(NUMBER.get(), 42);
@@ -46,6 +49,23 @@ async fn test_multiple_task_locals() {
         .await;
 }
 
+#[tokio::test]
+async fn test_scope_into() {
+    task_local! {
+        static NAME: std::sync::Arc<str>;
+    }
+
+    // Values are converted into the type of the key
+    MESSAGE
+        .scope("hello", async {
+            assert_eq!(MESSAGE.get(), "hello");
+            MESSAGE.sync_scope('!', || assert_eq!(MESSAGE.get(), "!"));
+        })
+        .await;
+    NAME.sync_scope("acme", || assert_eq!(&*NAME.get(), "acme"));
+    NUMBER.sync_scope(7u8, || assert_eq!(NUMBER.get(), 7));
+}
+
 #[tokio::test]
 async fn test_across_await_points() {
     async fn inner_function() {
@@ -53,7 +73,7 @@ async fn test_across_await_points() {
     }
 
     NUMBER
-        .scope(99, async {
+        .scope(99u32, async {
             assert_eq!(NUMBER.get(), 99);
             inner_function().await;
             assert_eq!(NUMBER.get(), 99);
@@ -63,7 +83,7 @@ async fn test_across_await_points() {
 
 #[tokio::test]
 async fn test_take_value() {
-    let fut = NUMBER.scope(42, async {
+    let fut = NUMBER.scope(42u32, async {
         // Do some work
         NUMBER.get()
     });
@@ -86,15 +106,15 @@ async fn test_take_value() {
 #[test]
 fn test_sync_scope() {
     // Test synchronous scope
-    NUMBER.sync_scope(42, || {
+    NUMBER.sync_scope(42u32, || {
         assert_eq!(NUMBER.get(), 42);
     });
 
     // Test nested synchronous scopes
-    NUMBER.sync_scope(1, || {
+    NUMBER.sync_scope(1u32, || {
         assert_eq!(NUMBER.get(), 1);
 
-        NUMBER.sync_scope(2, || {
+        NUMBER.sync_scope(2u32, || {
             assert_eq!(NUMBER.get(), 2);
         });
 
@@ -102,3 +122,2935 @@ fn test_sync_scope() {
         assert_eq!(NUMBER.get(), 1);
     });
 }
+
+#[test]
+fn test_set() {
+    NUMBER.sync_scope(1u32, || {
+        assert_eq!(NUMBER.set(2), 1);
+        assert_eq!(NUMBER.get(), 2);
+    });
+
+    // Setting outside of a scope is not allowed
+    let result = std::panic::catch_unwind(|| NUMBER.set(3));
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_key_metadata() {
+    assert_eq!(NUMBER.name(), "NUMBER");
+    assert_eq!(NUMBER.module_path(), "task_local_tests");
+    assert_eq!(
+        format!("{:?}", NUMBER),
+        r#"LocalKey { name: "NUMBER", .. }"#
+    );
+}
+
+#[tokio::test]
+async fn test_replace_value() {
+    let mut fut = Box::pin(NUMBER.scope(1u32, async {
+        let first = NUMBER.get();
+        tokio::task::yield_now().await;
+        (first, NUMBER.get())
+    }));
+
+    assert!(futures::poll!(fut.as_mut()).is_pending());
+
+    assert_eq!(fut.as_mut().replace_value(2), Some(1));
+    assert_eq!(fut.await, (1, 2));
+}
+
+#[tokio::test]
+async fn test_future_accessors() {
+    let mut fut = Box::pin(NUMBER.scope(1u32, std::future::ready(5)));
+    assert!(fut.get_ref().is_some());
+    assert!(fut.as_mut().get_pin_mut().is_some());
+    assert_eq!(fut.as_mut().await, 5);
+    assert!(fut.get_ref().is_none());
+
+    let mut fut = NUMBER.scope(1u32, async { NUMBER.get() });
+    assert!(fut.get_mut().is_some());
+    let inner = fut.into_inner().unwrap();
+    assert_eq!(NUMBER.scope(2u32, inner).await, 2);
+}
+
+#[tokio::test]
+async fn test_future_clone() {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    // Pending on its first poll, then returns the value of `NUMBER`
+    #[derive(Clone)]
+    struct Request {
+        polled: bool,
+    }
+
+    impl Future for Request {
+        type Output = u32;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<u32> {
+            if std::mem::replace(&mut self.polled, true) {
+                Poll::Ready(NUMBER.get())
+            } else {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    let fut = NUMBER.scope(1u32, Request { polled: false });
+    let retry = fut.clone();
+    assert_eq!(fut.await, 1);
+    assert_eq!(retry.await, 1);
+
+    // The clone of a polled future copies its state and its current value
+    let mut fut = Box::pin(NUMBER.scope(1u32, Request { polled: false }));
+    assert!(futures::poll!(fut.as_mut()).is_pending());
+    fut.as_mut().replace_value(2);
+    let clone = fut.clone();
+    assert_eq!(clone.get_ref().map(|request| request.polled), Some(true));
+    assert_eq!(fut.await, 2);
+    assert_eq!(clone.await, 2);
+}
+
+#[tokio::test]
+async fn test_and_scope() {
+    let fut = NUMBER
+        .scope(1u32, async { (NUMBER.get(), MESSAGE.get()) })
+        .and_scope(&MESSAGE, "hello".to_string());
+    assert_eq!(fut.await, (1, "hello".to_string()));
+
+    // The first scope is the innermost one
+    let fut = NUMBER
+        .scope(1u32, async { NUMBER.get() })
+        .and_scope(&NUMBER, 2u32);
+    assert_eq!(fut.await, 1);
+}
+
+#[test]
+fn test_future_debug() {
+    struct Opaque;
+
+    task_local! {
+        static HANDLE: Opaque;
+    }
+
+    let fut = NUMBER.scope(1u32, async {});
+    assert_eq!(
+        format!("{:?}", fut),
+        r#"TaskLocalFuture { key: "NUMBER", value: 1 }"#
+    );
+
+    let fut = HANDLE.scope(Opaque, async {});
+    assert_eq!(
+        format!("{:?}", fut),
+        r#"TaskLocalFuture { key: "HANDLE", value: <opaque> }"#
+    );
+}
+
+#[test]
+#[should_panic(expected = "task-local `MESSAGE` not set (accessed at tests/task_local_tests.rs:")]
+fn test_panic_message() {
+    MESSAGE.with(|_| ());
+}
+
+#[tokio::test]
+async fn test_marker_key() {
+    task_local! {
+        static IN_TRANSACTION: ();
+    }
+
+    assert!(IN_TRANSACTION.try_with(|_| ()).is_err());
+    IN_TRANSACTION
+        .scope((), async {
+            tokio::task::yield_now().await;
+            assert!(IN_TRANSACTION.try_with(|_| ()).is_ok());
+        })
+        .await;
+    assert!(IN_TRANSACTION.try_with(|_| ()).is_err());
+
+    let mut fut =
+        Box::pin(IN_TRANSACTION.scope((), async { IN_TRANSACTION.try_with(|_| ()).is_ok() }));
+    assert_eq!(fut.as_mut().take_value(), Some(()));
+    assert!(!fut.await);
+}
+
+#[tokio::test]
+async fn test_get_copied() {
+    NUMBER
+        .scope(1u32, async {
+            assert_eq!(NUMBER.get_copied(), 1);
+            NUMBER.sync_scope(2u32, || assert_eq!(NUMBER.get_copied(), 2));
+            tokio::task::yield_now().await;
+            NUMBER.set(3);
+            assert_eq!(NUMBER.get_copied(), 3);
+        })
+        .await;
+}
+
+#[test]
+#[should_panic(expected = "task-local `NUMBER` not set")]
+fn test_get_copied_not_set() {
+    NUMBER.get_copied();
+}
+
+#[cfg(not(feature = "forbid-unsafe"))]
+#[tokio::test]
+async fn test_with_unchecked() {
+    MESSAGE
+        .scope("outer".to_string(), async {
+            tokio::task::yield_now().await;
+            // Safety: We are inside a scope of `MESSAGE`.
+            let len = unsafe { MESSAGE.with_unchecked(|message| message.len()) };
+            assert_eq!(len, 5);
+            MESSAGE.sync_scope("inner".to_string() + "!", || {
+                // Safety: As above, for the inner scope.
+                let message = unsafe { MESSAGE.with_unchecked(Clone::clone) };
+                assert_eq!(message, "inner!");
+            });
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn test_scope_dyn() {
+    let fut = std::pin::pin!(async {
+        tokio::task::yield_now().await;
+        NUMBER.get()
+    });
+    assert_eq!(NUMBER.scope_dyn(1u32, fut).await, 1);
+}
+
+#[cfg(feature = "alloc")]
+#[tokio::test]
+async fn test_scope_boxed() {
+    let fut = NUMBER.scope_boxed(2u32, async {
+        tokio::task::yield_now().await;
+        NUMBER.get()
+    });
+    assert_eq!(tokio::spawn(fut).await.unwrap(), 2);
+}
+
+#[tokio::test]
+async fn test_get_handle() {
+    let handle = NUMBER.sync_scope(1u32, || {
+        let handle = NUMBER.get_handle();
+        // The handle is a snapshot and does not follow later changes.
+        NUMBER.set(2);
+        handle
+    });
+    assert_eq!(*handle, 1);
+    assert_eq!(
+        format!("{:?}", handle),
+        r#"Handle { key: "NUMBER", value: 1 }"#
+    );
+
+    let task = tokio::spawn(handle.clone().scope(async { NUMBER.get() }));
+    assert_eq!(task.await.unwrap(), 1);
+    assert_eq!(handle.sync_scope(|| NUMBER.get()), 1);
+}
+
+#[tokio::test]
+async fn test_scope_with() {
+    let (seen, through_key) = NUMBER
+        .scope_with(1u32, |number| async move {
+            assert_eq!((*number.get(), NUMBER.get()), (1, 1));
+            tokio::task::yield_now().await;
+            NUMBER.set(2);
+            (*number, NUMBER.get())
+        })
+        .await;
+    // The handle keeps the value the scope was entered with.
+    assert_eq!((seen, through_key), (1, 2));
+}
+
+#[cfg(feature = "alloc")]
+#[tokio::test]
+async fn test_shared() {
+    use std::sync::Arc;
+
+    task_local! {
+        static CONTEXT: Arc<Vec<u8>>;
+    }
+
+    let context = Arc::new(vec![1, 2, 3]);
+    let inner = CONTEXT
+        .scope(context.clone(), async {
+            assert!(Arc::ptr_eq(&CONTEXT.get_shared(), &context));
+            tokio::spawn(CONTEXT.scope_shared(async { CONTEXT.get_shared() }))
+                .await
+                .unwrap()
+        })
+        .await;
+    assert!(Arc::ptr_eq(&inner, &context));
+}
+
+#[test]
+fn test_string_accessors() {
+    use std::borrow::Cow;
+    use std::sync::Arc;
+    use task_local::AccessError;
+
+    task_local! {
+        static MESSAGE: String;
+        static LABEL: Cow<'static, str>;
+        static TENANT: Arc<str>;
+        static PAYLOAD: Box<[u8]>;
+    }
+
+    MESSAGE.sync_scope("handled".to_string(), || {
+        assert_eq!(MESSAGE.with_str(|message| message.to_uppercase()), "HANDLED");
+        assert_eq!(MESSAGE.with_deref(str::len), 7);
+    });
+    assert_eq!(MESSAGE.try_with_str(str::len), Err(AccessError::NotSet));
+
+    LABEL.sync_scope(Cow::Borrowed("static"), || {
+        assert_eq!(LABEL.try_with_str(|label| label == "static"), Ok(true));
+    });
+
+    let tenant: Arc<str> = Arc::from("acme");
+    TENANT.sync_scope(tenant.clone(), || {
+        assert!(Arc::ptr_eq(&TENANT.get_arc_str(), &tenant));
+        assert_eq!(TENANT.with_str(str::len), 4);
+    });
+    assert_eq!(Arc::strong_count(&tenant), 1);
+    assert_eq!(TENANT.try_get_arc_str(), Err(AccessError::NotSet));
+
+    PAYLOAD.sync_scope(vec![1, 2, 3].into_boxed_slice(), || {
+        assert_eq!(PAYLOAD.try_with_deref(|bytes| bytes.iter().sum::<u8>()), Ok(6));
+    });
+}
+
+#[cfg(feature = "alloc")]
+#[tokio::test]
+async fn test_weak_keys() {
+    use std::sync::{Arc, Weak};
+    use task_local::AccessError;
+
+    task_local! {
+        static CONNECTION: Weak<String>;
+    }
+
+    let connection = Arc::new("db-1".to_string());
+    let fut = CONNECTION.scope_weak(&connection, async {
+        let before = CONNECTION.with_upgraded(|name| name.clone());
+        tokio::task::yield_now().await;
+        (before, CONNECTION.try_with_upgraded(|name| name.clone()))
+    });
+    // The scope does not keep the value alive
+    assert_eq!(Arc::strong_count(&connection), 1);
+    assert_eq!(fut.await, ("db-1".to_string(), Ok("db-1".to_string())));
+
+    let weak = Arc::downgrade(&connection);
+    drop(connection);
+    CONNECTION.sync_scope(weak, || {
+        assert_eq!(CONNECTION.try_with_upgraded(|_| ()), Err(AccessError::Gone));
+        let panic = std::panic::catch_unwind(|| CONNECTION.with_upgraded(|_| ())).unwrap_err();
+        let message = panic.downcast_ref::<String>().unwrap();
+        assert!(message.starts_with("task-local `CONNECTION` refers to a value that was dropped"));
+    });
+    assert_eq!(
+        CONNECTION.try_with_upgraded(|_| ()),
+        Err(AccessError::NotSet)
+    );
+}
+
+#[cfg(feature = "alloc")]
+#[tokio::test]
+async fn test_trait_object_keys() {
+    use std::sync::Arc;
+    use task_local::AccessError;
+
+    trait Clock: Send + Sync {
+        fn now(&self) -> u64;
+    }
+
+    struct FixedClock(u64);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> u64 {
+            self.0
+        }
+    }
+
+    task_local! {
+        static CLOCK: Box<dyn Clock>;
+        static SHARED_CLOCK: Arc<dyn Clock>;
+    }
+
+    let seen = CLOCK
+        .scope_box(Box::new(FixedClock(1)), async {
+            tokio::task::yield_now().await;
+            CLOCK.with_dyn(|clock| clock.now())
+        })
+        .await;
+    assert_eq!(seen, 1);
+    assert_eq!(
+        CLOCK.sync_scope_box(Box::new(FixedClock(2)), || CLOCK
+            .try_with_dyn(|clock| clock.now())),
+        Ok(2)
+    );
+    assert_eq!(
+        CLOCK.try_with_dyn(|clock| clock.now()),
+        Err(AccessError::NotSet)
+    );
+
+    let clock: Arc<dyn Clock> = Arc::new(FixedClock(3));
+    let task = SHARED_CLOCK
+        .scope_arc(Arc::new(FixedClock(3)), async {
+            tokio::spawn(SHARED_CLOCK.scope_shared(async { SHARED_CLOCK.get_shared().now() }))
+                .await
+                .unwrap()
+        })
+        .await;
+    assert_eq!(task, clock.now());
+    SHARED_CLOCK.sync_scope_arc(clock.clone(), || {
+        assert!(Arc::ptr_eq(&SHARED_CLOCK.get_shared(), &clock));
+    });
+}
+
+// The no_std backend requires values to be `Send`.
+#[cfg(feature = "std")]
+#[test]
+fn test_not_send_value() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    task_local! {
+        static CACHE: Rc<RefCell<Vec<u32>>>;
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let local = tokio::task::LocalSet::new();
+    let cache = Rc::new(RefCell::new(Vec::new()));
+
+    local.block_on(&runtime, async {
+        let first = CACHE.scope(cache.clone(), async {
+            CACHE.with(|cache| cache.borrow_mut().push(1));
+            tokio::task::yield_now().await;
+            CACHE.with(|cache| cache.borrow_mut().push(3));
+        });
+        let second = CACHE.scope(cache.clone(), async {
+            CACHE.with(|cache| cache.borrow_mut().push(2));
+        });
+        let first = tokio::task::spawn_local(first);
+        let second = tokio::task::spawn_local(second);
+        first.await.unwrap();
+        second.await.unwrap();
+    });
+
+    assert_eq!(*cache.borrow(), [1, 2, 3]);
+}
+
+// Only debug builds check the thread of values that are not `Send`.
+#[cfg(all(feature = "std", debug_assertions))]
+#[test]
+fn test_not_send_value_other_thread() {
+    use std::future::Future;
+    use std::rc::Rc;
+    use std::task::{Context, Poll};
+
+    task_local! {
+        static CACHE: Rc<u32>;
+    }
+
+    struct AssertSend<F>(F);
+    // Safety: The `Rc` in the future is never cloned, so moving it to another
+    // thread does not share its count between threads.
+    unsafe impl<F> Send for AssertSend<F> {}
+
+    let waker = futures::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = Box::pin(CACHE.scope(Rc::new(1), async {
+        let mut yielded = false;
+        std::future::poll_fn(|cx| {
+            if std::mem::replace(&mut yielded, true) {
+                return Poll::Ready(());
+            }
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+        .await;
+    }));
+    assert!(fut.as_mut().poll(&mut cx).is_pending());
+
+    let fut = AssertSend(fut);
+    let err = std::thread::spawn(move || {
+        let mut fut = fut;
+        let waker = futures::task::noop_waker();
+        let _ = fut.0.as_mut().poll(&mut Context::from_waker(&waker));
+    })
+    .join()
+    .unwrap_err();
+    let message = err.downcast_ref::<String>().unwrap();
+    assert!(message.contains("task-local `CACHE`"), "{message}");
+}
+
+#[test]
+fn test_try_sync_scope() {
+    let result = NUMBER.try_sync_scope(1u32, || NUMBER.get());
+    assert_eq!(result, Ok(1));
+
+    let result = MESSAGE.try_sync_scope("hello", || MESSAGE.with(Clone::clone));
+    assert_eq!(result.as_deref(), Ok("hello"));
+
+    // Entering a scope while the value is borrowed fails instead of panicking
+    NUMBER.sync_scope(1u32, || {
+        NUMBER.with(|_| {
+            let result = NUMBER.try_sync_scope(2u32, || unreachable!());
+            assert!(result.is_err());
+        });
+        assert_eq!(NUMBER.get(), 1);
+    });
+}
+
+#[tokio::test]
+async fn test_try_scope() {
+    let result = NUMBER.try_scope(1u32, async { NUMBER.get() }).await;
+    assert_eq!(result, Ok(1));
+
+    let result = MESSAGE.try_scope("hello", async { MESSAGE.with(Clone::clone) }).await;
+    assert_eq!(result.as_deref(), Ok("hello"));
+
+    let mut fut = Box::pin(NUMBER.try_scope(2u32, async { unreachable!() }));
+    let result = NUMBER.sync_scope(1u32, || {
+        NUMBER.with(|_| {
+            let waker = futures::task::noop_waker();
+            let mut cx = std::task::Context::from_waker(&waker);
+            std::future::Future::poll(fut.as_mut(), &mut cx)
+        })
+    });
+    assert!(matches!(result, std::task::Poll::Ready(Err(_))));
+    assert_eq!(fut.as_mut().take_value(), Some(2));
+}
+
+#[tokio::test]
+async fn test_scope_async_fn() {
+    let suffix = String::from("!");
+    let result = MESSAGE
+        .scope_async_fn("hello".to_string(), async move || {
+            tokio::task::yield_now().await;
+            MESSAGE.with(|message| format!("{message}{suffix}"))
+        })
+        .await;
+    assert_eq!(result, "hello!");
+
+    let mut fut = Box::pin(NUMBER.scope_async_fn(1u32, async || NUMBER.set(2)));
+    fut.as_mut().await;
+    assert_eq!(fut.as_mut().take_value(), Some(2));
+}
+
+#[tokio::test]
+async fn test_scope_async_fn_mut() {
+    let mut total = 0;
+    let mut handler = async || {
+        tokio::task::yield_now().await;
+        total += NUMBER.get();
+    };
+
+    for n in 1..=3u32 {
+        NUMBER.scope_async_fn_mut(n, &mut handler).await;
+    }
+    assert_eq!(total, 6);
+}
+
+#[tokio::test]
+async fn test_scope_fn() {
+    let mut handler = MESSAGE.scope_fn("svc".to_string(), |n: u32| {
+        // The handler is called outside of the scope
+        assert!(MESSAGE.try_with(|_| ()).is_err());
+        async move {
+            tokio::task::yield_now().await;
+            MESSAGE.with(|message| format!("{message}:{n}"))
+        }
+    });
+
+    let (a, b) = tokio::join!(handler(1), handler(2));
+    assert_eq!((a.as_str(), b.as_str()), ("svc:1", "svc:2"));
+}
+
+#[tokio::test]
+async fn test_scope_fn_with() {
+    let mut calls = 0;
+    let handles = {
+        let mut handler = NUMBER.scope_fn_with(
+            |req: &u32| req * 10,
+            |req: u32| {
+                calls += 1;
+                async move {
+                    tokio::task::yield_now().await;
+                    NUMBER.get() + req
+                }
+            },
+        );
+        [1, 2, 3].map(|req| tokio::spawn(handler(req)))
+    };
+
+    let mut results = Vec::new();
+    for handle in handles {
+        results.push(handle.await.unwrap());
+    }
+    assert_eq!(results, [11, 22, 33]);
+    assert_eq!(calls, 3);
+}
+
+#[test]
+fn test_cancel() {
+    use std::future::{pending, Future};
+    use std::task::Context;
+
+    task_local! {
+        static STATE: u32;
+    }
+
+    struct Flush;
+
+    impl Drop for Flush {
+        fn drop(&mut self) {
+            STATE.set(STATE.get() + 10);
+        }
+    }
+
+    let waker = futures::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+
+    let mut fut = Box::pin(STATE.scope(1u32, async {
+        let _flush = Flush;
+        STATE.set(2);
+        pending::<()>().await;
+    }));
+    assert!(fut.as_mut().poll(&mut cx).is_pending());
+
+    // The future is dropped inside the scope before the value is returned
+    assert_eq!(fut.as_mut().cancel(), Some(12));
+    assert!(fut.as_mut().get_pin_mut().is_none());
+    assert_eq!(fut.as_mut().cancel(), None);
+
+    let mut fut = Box::pin(STATE.scope(3u32, async {}));
+    assert_eq!(fut.as_mut().cancel(), Some(3));
+}
+
+#[tokio::test]
+async fn test_finish() {
+    use task_local::DropPolicy;
+
+    task_local! {
+        static STATE: u32;
+    }
+
+    let result = STATE
+        .scope(1u32, async {
+            STATE.set(2);
+            "done"
+        })
+        .finish()
+        .await;
+    assert_eq!(result, ("done", 2));
+
+    let result = STATE
+        .scope(3u32, async { STATE.get() })
+        .drop_policy(DropPolicy::InsideScope)
+        .finish()
+        .await;
+    assert_eq!(result, (3, 3));
+}
+
+#[tokio::test]
+async fn test_scope_with_finalizer() {
+    task_local! {
+        static BUFFER: Vec<u32>;
+    }
+
+    let flushed = BUFFER
+        .scope_with_finalizer(
+            vec![1],
+            async {
+                BUFFER.set(vec![1, 2]);
+                "done"
+            },
+            |buffer, output| async move {
+                // The finalizer runs outside of the scope
+                assert!(BUFFER.try_with(|_| ()).is_err());
+                tokio::task::yield_now().await;
+                (output, buffer)
+            },
+        )
+        .await;
+    assert_eq!(flushed, (Some("done"), vec![1, 2]));
+
+    // A cancelled future still runs the finalizer, without an output
+    let mut fut = Box::pin(BUFFER.scope_with_finalizer(
+        vec![3],
+        std::future::pending::<()>(),
+        |buffer, output| async move { (output, buffer) },
+    ));
+    assert!(futures::poll!(fut.as_mut()).is_pending());
+    fut.as_mut().cancel();
+    fut.as_mut().cancel();
+    assert_eq!(fut.await, (None, vec![3]));
+}
+
+#[tokio::test]
+async fn test_scope_transaction() {
+    use std::sync::{Arc, Mutex};
+
+    task_local! {
+        static TRANSACTION: Vec<&'static str>;
+    }
+
+    type Log = Arc<Mutex<Vec<(&'static str, Vec<&'static str>)>>>;
+
+    let log = Log::default();
+    let commit = |log: Log| {
+        move |queries: Vec<&'static str>| async move {
+            assert!(TRANSACTION.try_with(|_| ()).is_err());
+            if queries.contains(&"conflict") {
+                return Err("serialization failure");
+            }
+            log.lock().unwrap().push(("commit", queries));
+            Ok(())
+        }
+    };
+    let rollback = |log: Log| {
+        move |queries| async move { log.lock().unwrap().push(("rollback", queries)) }
+    };
+
+    let result = TRANSACTION
+        .scope_transaction(
+            vec![],
+            async {
+                TRANSACTION.set(vec!["insert"]);
+                Ok::<_, &str>(1)
+            },
+            commit(log.clone()),
+            rollback(log.clone()),
+        )
+        .await;
+    assert_eq!(result, Ok(1));
+
+    let result = TRANSACTION
+        .scope_transaction(
+            vec!["update"],
+            async { Err::<(), _>("not found") },
+            commit(log.clone()),
+            rollback(log.clone()),
+        )
+        .await;
+    assert_eq!(result, Err("not found"));
+
+    // A failed commit fails the transaction, without a rollback
+    let result = TRANSACTION
+        .scope_transaction(
+            vec!["conflict"],
+            async { Ok(()) },
+            commit(log.clone()),
+            rollback(log.clone()),
+        )
+        .await;
+    assert_eq!(result, Err("serialization failure"));
+
+    let mut fut = Box::pin(TRANSACTION.scope_transaction(
+        vec!["delete"],
+        std::future::pending::<Result<(), &str>>(),
+        commit(log.clone()),
+        rollback(log.clone()),
+    ));
+    assert!(futures::poll!(fut.as_mut()).is_pending());
+    fut.as_mut().cancel().unwrap().await;
+    assert!(fut.as_mut().cancel().is_none());
+
+    assert_eq!(
+        *log.lock().unwrap(),
+        [
+            ("commit", vec!["insert"]),
+            ("rollback", vec!["update"]),
+            ("rollback", vec!["delete"]),
+        ]
+    );
+}
+
+#[test]
+fn test_sync_scope_token() {
+    use task_local::InScope;
+
+    task_local! {
+        static REQUEST_ID: u64;
+        static ATTEMPT: u64;
+    }
+
+    fn describe(scope: InScope<'_, u64>) -> String {
+        REQUEST_ID.with_token(scope, |id| format!("request {id}"))
+    }
+
+    let described = REQUEST_ID.sync_scope_token(1u64, |outer| {
+        assert_eq!(outer.key().get(), 1);
+        REQUEST_ID.sync_scope_token(2u64, |inner| {
+            assert_eq!(REQUEST_ID.get_with(inner), 2);
+            // The innermost scope is read with any token of the key.
+            assert_eq!(REQUEST_ID.get_with(outer), 2);
+        });
+        describe(outer)
+    });
+    assert_eq!(described, "request 1");
+
+    let panic = std::panic::catch_unwind(|| {
+        REQUEST_ID.sync_scope_token(1u64, |scope| {
+            ATTEMPT.sync_scope(2u64, || ATTEMPT.get_with(scope))
+        })
+    })
+    .unwrap_err();
+    assert_eq!(
+        panic.downcast_ref::<String>().unwrap(),
+        "token of task-local `REQUEST_ID` used to access `ATTEMPT`"
+    );
+}
+
+#[test]
+fn test_sync_scope_unwind() {
+    use std::panic::{self, AssertUnwindSafe};
+
+    task_local! {
+        static DEPTH: u32;
+    }
+
+    DEPTH.sync_scope(1u32, || {
+        let result = panic::catch_unwind(|| DEPTH.sync_scope(2u32, || panic!("boom")));
+        assert!(result.is_err());
+        assert_eq!(DEPTH.get(), 1);
+
+        let result = panic::catch_unwind(|| DEPTH.with(|_| panic!("boom")));
+        assert!(result.is_err());
+
+        // Neither panic left the storage borrowed
+        DEPTH.sync_scope(3u32, || assert_eq!(DEPTH.get(), 3));
+        DEPTH.set(4);
+        assert_eq!(DEPTH.get(), 4);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            DEPTH.sync_scope(5u32, || DEPTH.sync_scope(6u32, || panic!("boom")))
+        }));
+        assert!(result.is_err());
+        assert_eq!(DEPTH.get(), 4);
+    });
+    assert!(DEPTH.try_with(|_| ()).is_err());
+}
+
+#[test]
+fn test_scope_unwind() {
+    use std::future::Future;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::task::{Context, Poll};
+
+    task_local! {
+        static DEPTH: u32;
+    }
+
+    let waker = futures::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = Box::pin(DEPTH.scope(2u32, async {
+        assert_eq!(DEPTH.get(), 2);
+        panic!("boom")
+    }));
+
+    DEPTH.sync_scope(1u32, || {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| fut.as_mut().poll(&mut cx)));
+        assert!(result.is_err());
+        assert_eq!(DEPTH.get(), 1);
+    });
+    assert!(DEPTH.try_with(|_| ()).is_err());
+
+    // The future was dropped, but the value is kept
+    assert!(fut.as_mut().get_pin_mut().is_none());
+    assert_eq!(fut.as_mut().take_value(), Some(2));
+
+    let mut fut = Box::pin(DEPTH.scope(3u32, async { DEPTH.get() }));
+    assert_eq!(fut.as_mut().poll(&mut cx), Poll::Ready(3));
+}
+
+#[test]
+fn test_sync_scope_drop_policy() {
+    use std::panic;
+    use std::sync::Mutex;
+    use task_local::DropPolicy;
+
+    task_local! {
+        static SPAN: Span;
+    }
+
+    static SEEN: Mutex<Vec<(&str, Option<&str>)>> = Mutex::new(Vec::new());
+
+    struct Span(&'static str);
+
+    impl Drop for Span {
+        fn drop(&mut self) {
+            let current = SPAN.try_with(|span| span.0).ok();
+            SEEN.lock().unwrap().push((self.0, current));
+        }
+    }
+
+    SPAN.sync_scope(Span("outer"), || {
+        SPAN.sync_scope(Span("outside"), || {});
+        SPAN.sync_scope_with_drop_policy(Span("inside"), DropPolicy::InsideScope, || {});
+        let result = panic::catch_unwind(|| {
+            SPAN.sync_scope_with_drop_policy(Span("unwind"), DropPolicy::InsideScope, || {
+                panic!("boom")
+            })
+        });
+        assert!(result.is_err());
+    });
+
+    assert_eq!(
+        *SEEN.lock().unwrap(),
+        [
+            ("outside", Some("outer")),
+            ("inside", None),
+            ("unwind", None),
+            ("outer", None),
+        ]
+    );
+}
+
+#[test]
+fn test_scope_drop_policy() {
+    use std::future::{pending, Future};
+    use std::sync::Mutex;
+    use std::task::Context;
+    use task_local::DropPolicy;
+
+    task_local! {
+        static SPAN: Span;
+    }
+
+    static SEEN: Mutex<Vec<(&str, Option<&str>)>> = Mutex::new(Vec::new());
+
+    struct Span(&'static str);
+
+    impl Drop for Span {
+        fn drop(&mut self) {
+            let current = SPAN.try_with(|span| span.0).ok();
+            SEEN.lock().unwrap().push((self.0, current));
+        }
+    }
+
+    let waker = futures::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+
+    SPAN.sync_scope(Span("outer"), || {
+        let mut fut = Box::pin(SPAN.scope(Span("outside"), async {}));
+        assert!(fut.as_mut().poll(&mut cx).is_ready());
+        assert!(SEEN.lock().unwrap().is_empty());
+        drop(fut);
+
+        let mut fut = Box::pin(
+            SPAN.scope(Span("completed"), async {})
+                .drop_policy(DropPolicy::InsideScope),
+        );
+        assert!(fut.as_mut().poll(&mut cx).is_ready());
+        assert_eq!(SEEN.lock().unwrap().len(), 2);
+        assert_eq!(fut.as_mut().take_value().map(|span| span.0), None);
+        drop(fut);
+
+        let mut fut = Box::pin(
+            SPAN.scope(Span("cancelled"), pending::<()>())
+                .drop_policy(DropPolicy::InsideScope),
+        );
+        assert!(fut.as_mut().poll(&mut cx).is_pending());
+        drop(fut);
+    });
+
+    assert_eq!(
+        *SEEN.lock().unwrap(),
+        [
+            ("outside", Some("outer")),
+            ("completed", None),
+            ("cancelled", None),
+            ("outer", None),
+        ]
+    );
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn test_sync_scope_catch_unwind() {
+    let result = NUMBER.sync_scope_catch_unwind(1u32, || NUMBER.get());
+    assert!(matches!(result, Ok(1)));
+
+    NUMBER.sync_scope(1u32, || {
+        let payload = NUMBER
+            .sync_scope_catch_unwind(2u32, || panic!("boom"))
+            .unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom"));
+        assert_eq!(NUMBER.get(), 1);
+    });
+}
+
+#[cfg(feature = "std")]
+#[tokio::test]
+async fn test_scope_catch_unwind() {
+    let result = NUMBER.scope_catch_unwind(1u32, async { NUMBER.get() }).await;
+    assert!(matches!(result, Ok(1)));
+
+    let mut fut = Box::pin(NUMBER.scope_catch_unwind(2u32, async { panic!("boom") }));
+    let result = NUMBER
+        .scope(1u32, async {
+            let result = fut.as_mut().await;
+            assert_eq!(NUMBER.get(), 1);
+            result
+        })
+        .await;
+    let payload = result.unwrap_err();
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom"));
+    assert_eq!(fut.as_mut().take_value(), Some(2));
+}
+
+#[tokio::test]
+async fn test_watch() {
+    task_local! {
+        static MODE: u32;
+    }
+
+    MODE.scope(1u32, async {
+        let mut watch = MODE.watch();
+        assert!(!watch.has_changed());
+
+        let (seen, ()) = futures::join!(
+            async {
+                watch.changed().await;
+                MODE.get()
+            },
+            async {
+                tokio::task::yield_now().await;
+                MODE.set(2);
+            },
+        );
+        assert_eq!(seen, 2);
+        assert!(!watch.has_changed());
+
+        // Entering a scope changes the value the watch sees, exiting it
+        // changes it back
+        MODE.sync_scope(3u32, || assert!(watch.has_changed()));
+        assert!(!watch.has_changed());
+
+        // Scopes of other tasks are not seen
+        tokio::spawn(MODE.scope(4u32, async {
+            MODE.set(5);
+        }))
+        .await
+        .unwrap();
+        assert!(!watch.has_changed());
+
+        let mut fut = Box::pin(MODE.scope(6u32, async {}));
+        fut.as_mut().replace_value(7);
+        fut.await;
+        assert!(!watch.has_changed());
+
+        MODE.set(8);
+        assert!(watch.has_changed());
+        watch.mark_unchanged();
+        assert!(!watch.has_changed());
+    })
+    .await;
+}
+
+mod shadowed_std {
+    // `task_local!` must not rely on `std` being in scope at the call site.
+    #[allow(dead_code)]
+    mod std {}
+
+    task_local::task_local! {
+        static SHADOWED: u32;
+    }
+
+    #[test]
+    fn test_macro_without_std_in_scope() {
+        SHADOWED.sync_scope(7u32, || {
+            assert_eq!(SHADOWED.get(), 7);
+        });
+    }
+}
+
+#[tokio::test]
+async fn test_generic_storage() {
+    use task_local::TaskLocalStorage;
+
+    task_local! {
+        static FACTOR: u32;
+    }
+
+    async fn double<K>(key: &'static K) -> u32
+    where
+        K: TaskLocalStorage<Value = u32>,
+    {
+        key.scope(21, async { key.with(|v| v * 2) }).await
+    }
+
+    assert_eq!(double(&FACTOR).await, 42);
+    assert!(TaskLocalStorage::try_with(&FACTOR, |_| ()).is_err());
+}
+
+#[cfg(feature = "registry")]
+#[test]
+fn test_dump() {
+    struct Opaque;
+
+    task_local! {
+        static DEVICE_ID: u32;
+        static HANDLE: Opaque;
+    }
+
+    assert_eq!(format!("{:?}", task_local::dump()), "{}");
+
+    DEVICE_ID.sync_scope(7u32, || {
+        HANDLE.sync_scope(Opaque, || {
+            let dump = format!("{:?}", task_local::dump());
+            assert!(dump.contains("task_local_tests::DEVICE_ID: 7"));
+            assert!(dump.contains("task_local_tests::HANDLE: <opaque>"));
+        });
+
+        let dump = format!("{:?}", task_local::dump());
+        assert!(dump.contains("DEVICE_ID"));
+        assert!(!dump.contains("HANDLE"));
+    });
+}
+
+#[tokio::test]
+async fn test_any_local_key() {
+    use std::any::TypeId;
+    use std::rc::Rc;
+    use task_local::AnyLocalKey;
+
+    task_local! {
+        static USER: String;
+        static SHARED: Rc<u32>;
+    }
+
+    let user: &'static dyn AnyLocalKey = &USER;
+    let shared: &'static dyn AnyLocalKey = &SHARED;
+    assert_eq!(user.name(), "USER");
+    assert_eq!(user.module_path(), "task_local_tests");
+    assert_eq!(user.value_type_id(), TypeId::of::<String>());
+    assert_eq!(shared.value_type_name(), "alloc::rc::Rc<u32>");
+    assert!(!user.is_set());
+    assert!(user.capture().is_none());
+
+    let value = USER.sync_scope("alice".to_string(), || {
+        assert!(user.is_set());
+        user.capture().unwrap()
+    });
+    // `Rc` is not `Send`, so its values cannot be captured.
+    SHARED.sync_scope(Rc::new(1), || {
+        assert!(shared.is_set());
+        assert!(shared.capture().is_none());
+    });
+
+    let value = shared.sync_restore(value, &mut || unreachable!()).unwrap_err();
+    let scope = user
+        .scope_any(value, Box::pin(async { assert_eq!(USER.get(), "alice") }))
+        .unwrap();
+    scope.await;
+    assert!(!user.is_set());
+}
+
+#[cfg(feature = "registry")]
+#[test]
+fn test_registered_keys() {
+    use task_local::AnyLocalKey;
+
+    task_local! {
+        static REGION: &'static str;
+    }
+
+    // Other tests declare keys with the same name.
+    let is_region = |key: &&dyn AnyLocalKey| std::ptr::addr_eq(*key, &REGION);
+
+    assert!(!task_local::registered_keys().any(|key| is_region(&key)));
+    let captured: Vec<_> = REGION.sync_scope("eu", || {
+        task_local::registered_keys()
+            .filter(|key| key.module_path() == "task_local_tests")
+            .filter_map(|key| Some((key, key.capture()?)))
+            .collect()
+    });
+    let region = task_local::registered_keys().find(is_region).unwrap();
+    assert!(!region.is_set());
+
+    let mut seen = None;
+    for (key, value) in captured {
+        if is_region(&key) {
+            key.sync_restore(value, &mut || seen = Some(REGION.get()))
+                .unwrap();
+        }
+    }
+    assert_eq!(seen, Some("eu"));
+}
+
+#[cfg(feature = "registry")]
+#[test]
+fn test_key_ids() {
+    use task_local::{AnyLocalKey, KeyId};
+
+    task_local! {
+        static FIRST: u8;
+        static SECOND: u8;
+    }
+
+    let first = FIRST.id();
+    assert_eq!(FIRST.sync_scope(1, || FIRST.id()), first);
+    assert_ne!(SECOND.id(), first);
+    assert!(SECOND.id().index() < KeyId::count());
+    assert_eq!(AnyLocalKey::id(&SECOND), SECOND.id());
+    assert_eq!(SECOND.id().to_string(), format!("#{}", SECOND.id().index()));
+
+    // Asking for the identifier registers the key.
+    let registered: Vec<_> = task_local::registered_keys().map(|key| key.id()).collect();
+    assert!(registered.contains(&first));
+}
+
+#[cfg(feature = "stream")]
+#[tokio::test]
+async fn test_scope_each() {
+    use futures::stream::{self, StreamExt};
+
+    task_local! {
+        static ITEM: u32;
+    }
+
+    // Items are processed concurrently, each with its own value.
+    let mut next = 0;
+    let mut values: Vec<(u32, u32)> = ITEM
+        .scope_each(
+            stream::iter(1..=3).map(|i| async move {
+                tokio::task::yield_now().await;
+                (i, ITEM.get())
+            }),
+            |_| {
+                next += 10;
+                next
+            },
+        )
+        .buffer_unordered(3)
+        .collect()
+        .await;
+    values.sort();
+    assert_eq!(values, [(1, 10), (2, 20), (3, 30)]);
+    assert!(ITEM.try_with(|_| ()).is_err());
+}
+
+#[cfg(feature = "tower")]
+#[tokio::test]
+async fn test_tower_scopes() {
+    use std::convert::Infallible;
+    use std::future::{poll_fn, Future};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use task_local::tower::{ConnectionScopeLayer, Scope, ScopeLayer};
+    use task_local::LocalKey;
+    use tower_layer::Layer;
+    use tower_service::Service;
+
+    task_local! {
+        static PEER: &'static str;
+        static USER: &'static str;
+    }
+
+    type Seen = (Option<&'static str>, Option<&'static str>);
+    type BoxFuture<T> = Pin<Box<dyn Future<Output = Result<T, Infallible>>>>;
+
+    /// Answers with the peer and user it sees.
+    #[derive(Clone)]
+    struct Whoami;
+
+    impl Service<&'static str> for Whoami {
+        type Response = Seen;
+        type Error = Infallible;
+        type Future = BoxFuture<Seen>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: &'static str) -> Self::Future {
+            Box::pin(async {
+                tokio::task::yield_now().await;
+                Ok((PEER.try_with(|p| *p).ok(), USER.try_with(|u| *u).ok()))
+            })
+        }
+    }
+
+    fn request(req: &&'static str) -> &'static str {
+        req
+    }
+
+    type Handler = Scope<Whoami, &'static str, fn(&&'static str) -> &'static str>;
+
+    /// Creates a `Whoami` scoping `key` with every request.
+    struct MakeWhoami(&'static LocalKey<&'static str>);
+
+    impl Service<&'static str> for MakeWhoami {
+        type Response = Handler;
+        type Error = Infallible;
+        type Future = BoxFuture<Handler>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: &'static str) -> Self::Future {
+            let key = self.0;
+            Box::pin(async move {
+                // The service of a connection is created inside its scope
+                assert_eq!(PEER.get(), "10.0.0.1");
+                Ok(ScopeLayer::new(key, request as fn(&_) -> _).layer(Whoami))
+            })
+        }
+    }
+
+    let connections = ConnectionScopeLayer::new(&PEER, |peer: &&'static str| *peer);
+
+    // Request-level scopes of another key see the connection-level value
+    let mut make = connections.layer(MakeWhoami(&USER));
+    poll_fn(|cx| make.poll_ready(cx)).await.unwrap();
+    let mut conn = make.call("10.0.0.1").await.unwrap();
+    assert_eq!(*conn.value(), "10.0.0.1");
+    let mut other = conn.clone();
+    let (a, b) = tokio::join!(conn.call("alice"), other.call("bob"));
+    assert_eq!(a.unwrap(), (Some("10.0.0.1"), Some("alice")));
+    assert_eq!(b.unwrap(), (Some("10.0.0.1"), Some("bob")));
+
+    // Request-level scopes of the same key shadow the connection-level value
+    let mut make = connections.layer(MakeWhoami(&PEER));
+    let mut conn = make.call("10.0.0.1").await.unwrap();
+    assert_eq!(conn.call("proxy").await.unwrap(), (Some("proxy"), None));
+    assert_eq!(conn.call("direct").await.unwrap(), (Some("direct"), None));
+}
+
+#[cfg(feature = "async-graphql")]
+#[tokio::test]
+async fn test_async_graphql_scopes() {
+    use async_graphql::{value, EmptyMutation, EmptySubscription, Object, Request, Schema};
+    use task_local::async_graphql::{ResolverScopeExtension, ScopeExtension};
+
+    task_local! {
+        static TENANT: String;
+        static SECTION: String;
+    }
+
+    struct Tenant(&'static str);
+
+    struct Section;
+
+    #[Object]
+    impl Section {
+        async fn name(&self) -> String {
+            tokio::task::yield_now().await;
+            SECTION.get()
+        }
+
+        async fn tenant(&self) -> String {
+            TENANT.get()
+        }
+    }
+
+    struct Query;
+
+    #[Object]
+    impl Query {
+        async fn tenant(&self) -> Option<String> {
+            TENANT.try_with(Clone::clone).ok()
+        }
+
+        async fn section(&self) -> Section {
+            Section
+        }
+    }
+
+    let schema = Schema::build(Query, EmptyMutation, EmptySubscription)
+        .extension(ScopeExtension::new(&TENANT, |ctx| {
+            ctx.data_opt::<Tenant>().map(|tenant| tenant.0.to_string())
+        }))
+        .extension(ResolverScopeExtension::new(&SECTION, |_, info| {
+            (info.parent_type == "Query").then(|| info.alias.unwrap_or(info.name).to_string())
+        }))
+        .finish();
+
+    // Sibling fields are resolved concurrently, each in its own scope
+    let request = Request::new("{ tenant a: section { name tenant } b: section { name } }")
+        .data(Tenant("acme"));
+    let response = schema.execute(request).await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    assert_eq!(
+        response.data,
+        value!({
+            "tenant": "acme",
+            "a": { "name": "a", "tenant": "acme" },
+            "b": { "name": "b" },
+        })
+    );
+
+    // Without a value, the request runs without a scope
+    let response = schema.execute("{ tenant }").await;
+    assert_eq!(response.data.to_string(), "{tenant: null}");
+}
+
+#[cfg(feature = "tokio-channel")]
+#[tokio::test]
+async fn test_tokio_context_channel() {
+    use task_local::channel::tokio::channel;
+    use tokio::sync::mpsc::error::TrySendError;
+
+    task_local! {
+        static TRACE: u64;
+        static TENANT: &'static str;
+    }
+
+    let (tx, mut rx) = channel::<u32, _>((&TRACE, &TENANT), 2);
+
+    let producer = tokio::spawn(TRACE.scope(7u64, async move {
+        TENANT.scope("acme", tx.send(1)).await.unwrap();
+        // Keys without a value are left unset on the receiving side
+        tx.send(2).await.unwrap();
+        tx
+    }));
+    let tx = producer.await.unwrap();
+
+    let first = rx.recv().await.unwrap();
+    assert_eq!(*first.message(), 1);
+    let seen = first
+        .scope(|message| async move { (message, TRACE.get(), TENANT.get()) })
+        .await;
+    assert_eq!(seen, (1, 7, "acme"));
+
+    let second = rx.recv().await.unwrap();
+    let seen = second.sync_scope(|message| (message, TRACE.get(), TENANT.try_with(|t| *t).ok()));
+    assert_eq!(seen, (2, 7, None));
+
+    // The values are only set while the message is handled
+    assert!(TRACE.try_with(|_| ()).is_err());
+
+    // Errors give the message back
+    tx.try_send(3).unwrap();
+    tx.try_send(4).unwrap();
+    assert!(matches!(tx.try_send(5), Err(TrySendError::Full(5))));
+    assert_eq!(rx.try_recv().unwrap().into_parts(), (3, (None, None)));
+    drop(rx);
+    assert_eq!(tx.send(6).await.unwrap_err().0, 6);
+}
+
+#[cfg(feature = "embassy-sync")]
+#[tokio::test]
+async fn test_embassy_context_channel() {
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embassy_sync::channel::TrySendError;
+    use task_local::channel::embassy_sync::ContextChannel;
+
+    task_local! {
+        static TRACE: u64;
+    }
+
+    let jobs = ContextChannel::<NoopRawMutex, &str, _, 1>::new(&TRACE);
+    let (sender, receiver) = (jobs.sender(), jobs.receiver());
+
+    TRACE.scope(9u64, sender.send("job")).await;
+    assert!(matches!(
+        sender.try_send("full"),
+        Err(TrySendError::Full("full"))
+    ));
+
+    let seen = receiver
+        .receive()
+        .await
+        .scope(|job| async move { (job, TRACE.get()) })
+        .await;
+    assert_eq!(seen, ("job", 9));
+
+    jobs.try_send("unscoped").unwrap();
+    let envelope = jobs.try_receive().unwrap();
+    assert_eq!(envelope.snapshot(), &None);
+    assert_eq!(envelope.into_message(), "unscoped");
+    assert!(jobs.is_empty());
+}
+
+#[cfg(feature = "inherit")]
+#[tokio::test]
+async fn test_inherited() {
+    use task_local::Inherited;
+
+    task_local! {
+        /// Inherited by spawned tasks.
+        #[task_local(inherit)]
+        #[allow(unused)]
+        static TRACE: u64;
+
+        #[task_local(inherit)]
+        static TENANT: String;
+
+        static SCRATCH: Vec<u8>;
+    }
+
+    assert!(Inherited::capture().is_empty());
+
+    let inherited = TRACE
+        .scope(1u64, async {
+            SCRATCH
+                .scope(vec![0; 16], async { Inherited::capture() })
+                .await
+        })
+        .await;
+    assert_eq!(format!("{inherited:?}"), r#"["TRACE"]"#);
+
+    // Only the keys set when capturing are inherited, and they are only set
+    // while the scoped future runs
+    let seen = TENANT
+        .scope("acme".to_string(), async {
+            inherited
+                .scope(async {
+                    (
+                        TRACE.get(),
+                        TENANT.try_with(Clone::clone).ok(),
+                        SCRATCH.try_with(|_| ()).is_ok(),
+                    )
+                })
+                .await
+        })
+        .await;
+    assert_eq!(seen, (1, Some("acme".to_string()), false));
+    assert!(TRACE.try_with(|_| ()).is_err());
+
+    // Values set inside the scope are captured instead of the outer ones
+    let inherited = TENANT
+        .scope("outer".to_string(), async {
+            TENANT.set("inner".to_string());
+            Inherited::capture()
+        })
+        .await;
+    let tenant = inherited.scope(async { TENANT.get() }).await;
+    assert_eq!(tenant, "inner");
+}
+
+#[cfg(all(feature = "inherit", feature = "tokio-interop"))]
+#[tokio::test]
+#[allow(clippy::async_yields_async)] // The scopes return the `JoinHandle`
+async fn test_spawn_inherits() {
+    task_local! {
+        #[task_local(inherit)]
+        static TRACE: u64;
+
+        static USER: &'static str;
+    }
+
+    let handle = TRACE
+        .scope(7u64, async {
+            USER.scope("ferris", async {
+                task_local::spawn(async {
+                    tokio::task::yield_now().await;
+                    (TRACE.get(), USER.try_with(|user| *user).ok())
+                })
+            })
+            .await
+        })
+        .await;
+    assert_eq!(handle.await.unwrap(), (7, None));
+
+    // Without a value, the task is spawned unscoped
+    let handle = task_local::spawn(async { TRACE.try_with(|_| ()).is_ok() });
+    assert!(!handle.await.unwrap());
+}
+
+#[cfg(all(feature = "inherit", feature = "tokio-interop"))]
+#[tokio::test]
+async fn test_task_scope() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use task_local::TaskScope;
+
+    task_local! {
+        #[task_local(inherit)]
+        static TRACE: u64;
+
+        static USER: &'static str;
+    }
+
+    let mut outputs = TRACE
+        .scope(7u64, async {
+            USER.scope("ferris", async {
+                let mut scope = TaskScope::new();
+                for shard in 0..3u64 {
+                    scope.spawn(async move {
+                        tokio::task::yield_now().await;
+                        (shard, TRACE.get(), USER.try_with(|user| *user).ok())
+                    });
+                }
+                assert_eq!(scope.len(), 3);
+                scope.join_all().await
+            })
+            .await
+        })
+        .await;
+    outputs.sort();
+    assert_eq!(outputs, [(0, 7, None), (1, 7, None), (2, 7, None)]);
+
+    // Dropping the scope cancels the tasks still running
+    let finished = Arc::new(AtomicBool::new(false));
+    let mut scope = TaskScope::new();
+    let flag = finished.clone();
+    scope.spawn(async move {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        flag.store(true, Ordering::Relaxed);
+    });
+    drop(scope);
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert!(!finished.load(Ordering::Relaxed));
+
+    // A panicking task cancels the others and resumes the panic
+    let mut scope = TaskScope::new();
+    scope.spawn(async { panic!("child failed") });
+    scope.spawn(std::future::pending());
+    let joined = tokio::spawn(scope.join_all()).await.unwrap_err();
+    assert_eq!(
+        *joined.into_panic().downcast::<&str>().unwrap(),
+        "child failed"
+    );
+}
+
+#[cfg(feature = "tokio-interop")]
+#[tokio::test]
+async fn test_scoped_join_set() {
+    use task_local::ScopedJoinSet;
+
+    task_local! {
+        static REQUEST_ID: u64;
+        static TENANT: &'static str;
+    }
+
+    let mut set = ScopedJoinSet::new((&REQUEST_ID, &TENANT));
+    REQUEST_ID
+        .scope(7u64, async {
+            set.spawn(async {
+                tokio::task::yield_now().await;
+                (REQUEST_ID.get(), TENANT.try_with(|tenant| *tenant).ok())
+            });
+            TENANT.sync_scope("acme", || {
+                set.spawn_blocking(|| (REQUEST_ID.get(), Some(TENANT.get())));
+            });
+        })
+        .await;
+    // Tasks spawned outside of any scope see no value
+    set.spawn(async { (REQUEST_ID.try_with(|id| *id).unwrap_or(0), None) });
+    assert_eq!(set.len(), 3);
+
+    let mut outputs = Vec::new();
+    while let Some(output) = set.join_next().await {
+        outputs.push(output.unwrap());
+    }
+    outputs.sort();
+    assert_eq!(outputs, [(0, None), (7, None), (7, Some("acme"))]);
+    assert!(set.is_empty());
+}
+
+#[cfg(all(feature = "inherit", feature = "tokio-interop"))]
+#[tokio::test]
+async fn test_spawned_task_ids() {
+    use task_local::keys::{self, TaskId};
+    use task_local::{Inherited, ScopedJoinSet, TaskScope};
+
+    task_local! {
+        static UNUSED: u8;
+    }
+
+    let parent = TaskId::new(0);
+    let ids = keys::TASK_ID
+        .scope(parent, async {
+            let spawned = task_local::spawn(async { keys::task_id() });
+            let mut scope = TaskScope::new();
+            scope.spawn(async { keys::task_id() });
+            let mut set = ScopedJoinSet::new(&UNUSED);
+            set.spawn(async { keys::task_id() });
+            set.spawn_blocking(keys::task_id);
+            let inherited = Inherited::capture();
+            let expected = inherited.task_id();
+            let scoped = inherited.scope(async { keys::task_id() }).await;
+            assert_eq!(scoped, Some(expected));
+            // The spawning task keeps its own identifier
+            assert_eq!(keys::task_id(), Some(parent));
+
+            let mut ids = vec![scoped, spawned.await.unwrap()];
+            ids.extend(scope.join_all().await);
+            while let Some(id) = set.join_next().await {
+                ids.push(id.unwrap());
+            }
+            ids
+        })
+        .await;
+
+    // Every task gets a new identifier
+    let mut ids: Vec<TaskId> = ids.into_iter().map(Option::unwrap).collect();
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), 5);
+    assert!(!ids.contains(&parent));
+}
+
+#[test]
+fn test_block_on_scoped() {
+    use task_local::embedded::block_on_scoped;
+
+    task_local! {
+        static DEVICE_ID: u32;
+        static STAGE: &'static str;
+    }
+
+    // The future is polled again without being woken
+    let mut polls = 0;
+    let output = block_on_scoped(((&DEVICE_ID, 7), (&STAGE, "init")), async {
+        std::future::poll_fn(|_| {
+            polls += 1;
+            if polls < 3 {
+                std::task::Poll::Pending
+            } else {
+                std::task::Poll::Ready(())
+            }
+        })
+        .await;
+        (DEVICE_ID.get(), STAGE.get())
+    });
+    assert_eq!(output, (7, "init"));
+    assert_eq!(polls, 3);
+
+    assert_eq!(
+        block_on_scoped((&DEVICE_ID, 1), async { DEVICE_ID.get() }),
+        1
+    );
+    assert!(block_on_scoped((), async { DEVICE_ID.try_with(|_| ()) }).is_err());
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_scope_rayon() {
+    use rayon::prelude::*;
+    use task_local::rayon::ParallelIteratorExt;
+
+    task_local! {
+        static REQUEST_ID: u64;
+        static TENANT: &'static str;
+    }
+
+    let (after, sum) = REQUEST_ID.sync_scope(7u64, || {
+        TENANT.sync_scope("acme", || {
+            let after = (0..1000)
+                .into_par_iter()
+                .scope_rayon((&REQUEST_ID, &TENANT))
+                .map(|i| (i, REQUEST_ID.get(), TENANT.get()))
+                .collect::<Vec<_>>();
+
+            // Reductions combining the results of several workers are scoped too
+            let sum = (0..1000u64)
+                .into_par_iter()
+                .scope_rayon(&REQUEST_ID)
+                .map(|i| i * REQUEST_ID.get())
+                .reduce(|| 0, |a, b| a + b + REQUEST_ID.get() - 7);
+
+            (after, sum)
+        })
+    });
+
+    assert_eq!(after.len(), 1000);
+    assert!(after
+        .iter()
+        .enumerate()
+        .all(|(i, seen)| *seen == (i, 7, "acme")));
+    assert_eq!(sum, 7 * 999 * 1000 / 2);
+
+    // Keys without a value are left unset
+    let unset = (0..10)
+        .into_par_iter()
+        .scope_rayon(&REQUEST_ID)
+        .all(|_| REQUEST_ID.try_with(|_| ()).is_err());
+    assert!(unset);
+}
+
+#[test]
+fn test_block_on_with_context() {
+    task_local! {
+        static REQUEST_ID: u64;
+        static TENANT: &'static str;
+    }
+
+    let snapshot = REQUEST_ID.sync_scope(7u64, || {
+        TENANT.sync_scope("acme", || task_local::current((&REQUEST_ID, &TENANT)))
+    });
+    assert_eq!(snapshot.values(), &(Some(7), Some("acme")));
+
+    // The blocked-on future sees the values across await points
+    let seen = task_local::block_on_with_context(snapshot.clone(), async {
+        let id = REQUEST_ID.get();
+        tokio::task::yield_now().await;
+        (id, TENANT.get())
+    });
+    assert_eq!(seen, (7, "acme"));
+
+    // Another thread, as with a blocking pool
+    let seen = std::thread::spawn(move || {
+        task_local::block_on_with_context(snapshot, async { (REQUEST_ID.get(), TENANT.get()) })
+    })
+    .join()
+    .unwrap();
+    assert_eq!(seen, (7, "acme"));
+
+    // Keys without a value are left unset
+    let snapshot = task_local::current(&REQUEST_ID);
+    let unset =
+        task_local::block_on_with_context(snapshot, async { REQUEST_ID.try_with(|_| ()).is_err() });
+    assert!(unset);
+}
+
+#[tokio::test]
+async fn test_display_and_debug() {
+    struct Opaque;
+
+    task_local! {
+        static REQUEST_ID: u64;
+        static TENANT: &'static str;
+        static OPAQUE: Opaque;
+    }
+
+    // The value is read when formatting, not when the adapter is created
+    let id = REQUEST_ID.display();
+    assert_eq!(format!("[{id}]"), "[<unset>]");
+    let line = REQUEST_ID
+        .scope(7u64, async {
+            tokio::task::yield_now().await;
+            format!(
+                "[{id}] [{:>3}] [{:?}]",
+                REQUEST_ID.display(),
+                REQUEST_ID.debug()
+            )
+        })
+        .await;
+    assert_eq!(line, "[7] [  7] [7]");
+
+    TENANT.sync_scope("acme", || {
+        assert_eq!(
+            format!("{} {:?}", TENANT.display(), TENANT.debug()),
+            "acme \"acme\""
+        );
+    });
+    assert_eq!(format!("{:?}", TENANT.debug()), "<unset>");
+    assert_eq!(format!("{:>9}", TENANT.display()), "  <unset>");
+
+    OPAQUE.sync_scope(Opaque, || {
+        assert_eq!(format!("{:?}", OPAQUE.debug()), "<opaque>");
+    });
+}
+
+#[cfg(feature = "trace-scopes")]
+#[tokio::test]
+async fn test_trace_scopes() {
+    use std::fmt::Write as _;
+    use std::sync::{Arc, Mutex};
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// Records every event as its fields, `name=value` separated by spaces.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            metadata.target() == "task_local"
+        }
+
+        fn new_span(&self, _: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            struct Fields(String);
+
+            impl Visit for Fields {
+                fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                    if !self.0.is_empty() {
+                        self.0.push(' ');
+                    }
+                    let _ = write!(self.0, "{}={:?}", field.name(), value);
+                }
+            }
+
+            let mut fields = Fields(String::new());
+            event.record(&mut fields);
+            self.0.lock().unwrap().push(fields.0);
+        }
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    task_local! {
+        static REQUEST_ID: u64;
+    }
+
+    let recorder = Recorder::default();
+    let _guard = tracing::subscriber::set_default(recorder.clone());
+
+    REQUEST_ID.sync_scope(1u64, || ());
+
+    // A future is entered on its first poll and exited once, however often it
+    // is polled
+    REQUEST_ID
+        .scope(2u64, async {
+            tokio::task::yield_now().await;
+            tokio::task::yield_now().await;
+        })
+        .await;
+
+    // Dropping a future that was polled exits its scope
+    let mut pending = Box::pin(REQUEST_ID.scope(3u64, std::future::pending::<()>()));
+    let _ = futures::poll!(pending.as_mut());
+    drop(pending);
+
+    // A future that is never polled is never entered
+    drop(REQUEST_ID.scope(4u64, async {}));
+
+    let value = |value: u64| {
+        if cfg!(feature = "trace-scope-values") {
+            format!(" value={value}")
+        } else {
+            String::new()
+        }
+    };
+    let entered = |n| {
+        format!(
+            "message=task-local scope entered key=\"REQUEST_ID\" module=\"task_local_tests\"{}",
+            value(n)
+        )
+    };
+    let exited = "message=task-local scope exited key=\"REQUEST_ID\" module=\"task_local_tests\"";
+
+    // With `scope-ids`, the exit of a scope has the id of its entry
+    let mut events = recorder.0.lock().unwrap().clone();
+    if cfg!(feature = "scope-ids") {
+        let ids: Vec<u64> = events
+            .iter_mut()
+            .map(|event| {
+                let start = event.find(" scope=").unwrap();
+                let len = event[start + 7..]
+                    .find(' ')
+                    .unwrap_or(event.len() - start - 7);
+                let id = event[start + 7..start + 7 + len].parse().unwrap();
+                event.replace_range(start..start + 7 + len, "");
+                id
+            })
+            .collect();
+        assert!(ids.chunks(2).all(|pair| pair[0] == pair[1]));
+        assert!(ids[0] < ids[2] && ids[2] < ids[4]);
+    }
+    assert_eq!(
+        events,
+        [
+            entered(1),
+            exited.to_owned(),
+            entered(2),
+            exited.to_owned(),
+            entered(3),
+            exited.to_owned(),
+        ]
+    );
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_tracing_record() {
+    use std::fmt::Write as _;
+    use std::sync::{Arc, Mutex};
+
+    use tracing::field::{Empty, Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// Records the fields recorded into spans, as `name=value`.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Visit for Recorder {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            let mut fields = self.0.lock().unwrap();
+            fields.push(String::new());
+            let _ = write!(fields.last_mut().unwrap(), "{}={:?}", field.name(), value);
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _: &Id, values: &Record<'_>) {
+            values.record(&mut self.clone());
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, _: &Event<'_>) {}
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    task_local! {
+        static REQUEST_ID: u64;
+        static TENANT: &'static str;
+        static USER: &'static str;
+    }
+
+    let recorder = Recorder::default();
+    let _guard = tracing::subscriber::set_default(recorder.clone());
+
+    REQUEST_ID.sync_scope(7u64, || {
+        TENANT.sync_scope("acme", || {
+            let span = tracing::info_span!("handle", request_id = Empty, TENANT = Empty);
+            // Unset keys and keys without a field are skipped
+            task_local::tracing::record_into_span(&span, &[&REQUEST_ID, &TENANT, &USER]);
+            USER.sync_scope("ferris", || {
+                task_local::tracing::record_into_span(&span, &[&USER])
+            });
+        })
+    });
+
+    assert_eq!(
+        *recorder.0.lock().unwrap(),
+        ["request_id=7", "TENANT=\"acme\""]
+    );
+}
+
+#[cfg(feature = "sentry")]
+#[test]
+fn test_sentry_bridge() {
+    use sentry_core::{Level, User};
+    use task_local::sentry::SentryBridge;
+
+    task_local! {
+        static REQUEST_ID: u64;
+        static USER_ID: u64;
+    }
+
+    let mut bridge = SentryBridge::new();
+    bridge
+        .tag("request_id", &REQUEST_ID)
+        .user(&USER_ID, |id| User {
+            id: Some(id.to_string()),
+            ..Default::default()
+        });
+
+    let events = sentry_core::test::with_captured_events(|| {
+        bridge.install();
+        REQUEST_ID.sync_scope(7u64, || {
+            USER_ID.sync_scope(42u64, || {
+                sentry_core::capture_message("in scope", Level::Error);
+            })
+        });
+        // Keys that are not set are left out
+        sentry_core::capture_message("outside", Level::Error);
+
+        // A bound future captures through a hub of its own
+        let future = bridge.bind(async {
+            sentry_core::configure_scope(|scope| scope.set_tag("task", "child"));
+            sentry_core::capture_message("bound", Level::Error);
+        });
+        futures::executor::block_on(REQUEST_ID.scope(8u64, future));
+        sentry_core::capture_message("after", Level::Error);
+    });
+
+    assert_eq!(events.len(), 4);
+    assert_eq!(events[0].tags["request_id"], "7");
+    assert_eq!(events[0].user.as_ref().unwrap().id.as_deref(), Some("42"));
+    assert!(!events[1].tags.contains_key("request_id"));
+    assert!(events[1].user.is_none());
+    assert_eq!(events[2].tags["request_id"], "8");
+    assert_eq!(events[2].tags["task"], "child");
+    assert!(!events[3].tags.contains_key("task"));
+}
+
+#[cfg(feature = "stats")]
+#[tokio::test]
+async fn test_stats() {
+    task_local! {
+        static REQUEST_ID: u64;
+    }
+
+    assert_eq!(REQUEST_ID.stats(), task_local::Stats::default());
+
+    REQUEST_ID.sync_scope(1u64, || {
+        assert_eq!(REQUEST_ID.stats().active_scopes, 1);
+        REQUEST_ID.get();
+        REQUEST_ID.get_copied();
+        // Entering a scope while the value is borrowed is a conflict
+        REQUEST_ID.with(|_| assert!(REQUEST_ID.try_sync_scope(2u64, || ()).is_err()));
+    });
+
+    // A suspended future keeps its scope active
+    let mut pending = Box::pin(REQUEST_ID.scope(3u64, async {
+        REQUEST_ID.get();
+        std::future::pending::<()>().await;
+    }));
+    let _ = futures::poll!(pending.as_mut());
+    let _ = futures::poll!(pending.as_mut());
+    assert_eq!(REQUEST_ID.stats().active_scopes, 1);
+    drop(pending);
+
+    // Accesses outside of any scope are counted too
+    assert!(REQUEST_ID.try_with(|_| ()).is_err());
+
+    let stats = REQUEST_ID.stats();
+    assert_eq!(stats.active_scopes, 0);
+    assert_eq!(stats.scopes, 2);
+    assert_eq!(stats.accesses, 5);
+    assert_eq!(stats.borrow_conflicts, 1);
+}
+
+#[cfg(feature = "leak-check")]
+#[tokio::test]
+async fn test_leak_check() {
+    task_local! {
+        static REQUEST_ID: u64;
+    }
+
+    let check = task_local::LeakCheck::new();
+    REQUEST_ID.scope(1u64, async {}).await;
+    drop(REQUEST_ID.scope(2u64, async {}));
+    check.assert_no_leaks();
+
+    let check = task_local::LeakCheck::new();
+    let line = line!() + 1;
+    let leaked = REQUEST_ID.scope(3u64, async {});
+    std::mem::forget(leaked);
+
+    // Futures created on other threads are not reported
+    std::thread::spawn(|| std::mem::forget(REQUEST_ID.scope(4u64, async {})))
+        .join()
+        .unwrap();
+
+    let leaks = check.leaks();
+    assert_eq!(leaks.len(), 1);
+    assert_eq!(leaks[0].key(), "REQUEST_ID");
+    let location = leaks[0].location().unwrap();
+    assert_eq!((location.file(), location.line()), (file!(), line));
+
+    // Keys left set are reported without a location
+    let check = task_local::LeakCheck::new();
+    let leaks = REQUEST_ID.sync_scope(5u64, || check.leaks());
+    assert_eq!(leaks.len(), 1);
+    assert_eq!(leaks[0].location(), None);
+    assert_eq!(
+        leaks[0].to_string(),
+        "`task_local_tests::REQUEST_ID` is still set"
+    );
+
+    let panic = std::panic::catch_unwind(|| {
+        let check = task_local::LeakCheck::new();
+        std::mem::forget(REQUEST_ID.scope(6u64, async {}));
+        check.assert_no_leaks();
+    })
+    .unwrap_err();
+    let message = panic.downcast_ref::<String>().unwrap();
+    assert!(message.starts_with("task-local scopes leaked:\n  scope of `task_local_tests::REQUEST_ID` created at tests/task_local_tests.rs:"));
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn test_testing_helpers() {
+    use task_local::{assert_set, assert_unset, testing, with_locals};
+
+    task_local! {
+        static REQUEST_ID: u64;
+        static USER: &'static str;
+    }
+
+    fn message(panic: Box<dyn std::any::Any + Send>) -> String {
+        panic.downcast::<String>().map(|message| *message).unwrap()
+    }
+
+    let sum = testing::run(|| {
+        assert_unset!(REQUEST_ID);
+        with_locals! {
+            REQUEST_ID = 7u64;
+            USER = "ferris";
+            {
+                assert_set!(REQUEST_ID, 7);
+                assert_set!(USER, "ferris");
+                REQUEST_ID.get() + USER.get().len() as u64
+            }
+        }
+    });
+    assert_eq!(sum, 13);
+
+    let panic = std::panic::catch_unwind(|| assert_set!(USER)).unwrap_err();
+    assert_eq!(
+        message(panic),
+        "assertion failed: `task_local_tests::USER` is not set"
+    );
+    let panic = std::panic::catch_unwind(|| {
+        REQUEST_ID.sync_scope(1u64, || assert_set!(REQUEST_ID, 2))
+    })
+    .unwrap_err();
+    assert_eq!(
+        message(panic),
+        "assertion failed: `task_local_tests::REQUEST_ID` is set to 1, expected 2"
+    );
+
+    // A key left set when the test returns fails it.
+    let panic = std::panic::catch_unwind(|| {
+        REQUEST_ID.sync_scope(1u64, || testing::run(|| assert_unset!(USER)))
+    })
+    .unwrap_err();
+    assert_eq!(
+        message(panic),
+        "task-local keys left set:\n  `task_local_tests::REQUEST_ID`"
+    );
+}
+
+#[cfg(feature = "instrument")]
+#[tokio::test]
+async fn test_instrument() {
+    use task_local::instrument::{self, TaskRegistry};
+
+    static TASKS: TaskRegistry = TaskRegistry::new();
+
+    task_local! {
+        static REQUEST_ID: u64;
+        static TENANT: &'static str;
+    }
+
+    instrument::set_instrument(&TASKS).unwrap();
+    assert!(instrument::set_instrument(TaskRegistry::new()).is_err());
+
+    let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+    let task = tokio::spawn(REQUEST_ID.scope(7u64, async {
+        TENANT.scope("acme", async { rx.await.unwrap() }).await;
+        let scopes = TASKS.scopes(tokio::task::id());
+        assert_eq!(scopes.len(), 1);
+        assert_eq!(scopes[0].to_string(), "REQUEST_ID = 7");
+        #[cfg(feature = "scope-ids")]
+        assert_eq!(
+            scopes[0].scope_id(),
+            task_local::current_scope_id(&REQUEST_ID)
+        );
+    }));
+    tokio::task::yield_now().await;
+
+    let scopes = TASKS.scopes(task.id());
+    let scopes: Vec<_> = scopes.iter().map(|scope| scope.to_string()).collect();
+    assert_eq!(scopes, ["REQUEST_ID = 7", "TENANT = \"acme\""]);
+    assert!(TASKS.tasks().iter().any(|(id, _)| *id == task.id()));
+
+    // Scopes outside of a Tokio task are not kept
+    REQUEST_ID.sync_scope(808u64, || {
+        let tasks = TASKS.tasks();
+        let mut scopes = tasks.iter().flat_map(|(_, scopes)| scopes);
+        assert!(scopes.all(|scope| scope.value() != Some("808")));
+    });
+
+    tx.send(()).unwrap();
+    let id = task.id();
+    task.await.unwrap();
+    assert!(TASKS.scopes(id).is_empty());
+}
+
+#[tokio::test]
+async fn test_poison() {
+    use std::cell::Cell;
+    use std::panic::{self, AssertUnwindSafe};
+    use task_local::AccessError;
+
+    task_local! {
+        #[task_local(poison)]
+        static BALANCE: Cell<i64>;
+
+        static PLAIN: Cell<i64>;
+    }
+
+    fn panic_in_with(key: &'static task_local::LocalKey<Cell<i64>>) {
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            key.with(|balance| {
+                balance.set(balance.get() - 10);
+                panic!("credit missing");
+            })
+        }));
+        assert!(res.is_err());
+    }
+
+    BALANCE.sync_scope(Cell::new(100), || {
+        panic_in_with(&BALANCE);
+        assert!(BALANCE.is_poisoned());
+        assert_eq!(BALANCE.try_with(|_| ()), Err(AccessError::Poisoned));
+
+        // A nested scope starts unpoisoned and does not clear the outer one
+        BALANCE.sync_scope(Cell::new(5), || {
+            assert!(!BALANCE.is_poisoned());
+            assert_eq!(BALANCE.with(Cell::get), 5);
+        });
+        assert_eq!(BALANCE.try_with(|_| ()), Err(AccessError::Poisoned));
+
+        let panic = panic::catch_unwind(|| BALANCE.with(|_| ())).unwrap_err();
+        let message = panic.downcast_ref::<String>().unwrap();
+        assert!(message.starts_with("task-local `BALANCE` poisoned by a panic in `with`"));
+    });
+    assert!(!BALANCE.is_poisoned());
+    assert_eq!(
+        BALANCE.sync_scope(Cell::new(1), || BALANCE.with(Cell::get)),
+        1
+    );
+
+    // A future stays poisoned across polls
+    BALANCE
+        .scope(Cell::new(100), async {
+            panic_in_with(&BALANCE);
+            tokio::task::yield_now().await;
+            assert_eq!(BALANCE.try_with(|_| ()), Err(AccessError::Poisoned));
+        })
+        .await;
+
+    // Keys are not poisoned unless declared with `#[task_local(poison)]`
+    PLAIN.sync_scope(Cell::new(100), || {
+        panic_in_with(&PLAIN);
+        assert!(!PLAIN.is_poisoned());
+        assert_eq!(PLAIN.with(Cell::get), 90);
+    });
+}
+
+#[cfg(feature = "scope-ids")]
+#[tokio::test]
+async fn test_scope_ids() {
+    use task_local::current_scope_id;
+
+    task_local! {
+        static USER: &'static str;
+    }
+
+    assert_eq!(current_scope_id(&USER), None);
+
+    // A future keeps its id across polls, and nested scopes have their own
+    let first = USER
+        .scope("ferris", async {
+            let id = current_scope_id(&USER).unwrap();
+            tokio::task::yield_now().await;
+            assert_eq!(current_scope_id(&USER), Some(id));
+            let nested = USER
+                .scope("ferris", async { current_scope_id(&USER).unwrap() })
+                .await;
+            assert!(nested > id);
+            assert_eq!(current_scope_id(&USER), Some(id));
+            id
+        })
+        .await;
+
+    // A clone is a new scope
+    #[derive(Clone)]
+    struct CurrentId;
+
+    impl std::future::Future for CurrentId {
+        type Output = task_local::ScopeId;
+
+        fn poll(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<task_local::ScopeId> {
+            std::task::Poll::Ready(current_scope_id(&USER).unwrap())
+        }
+    }
+
+    let fut = USER.scope("ferris", CurrentId);
+    let retry = fut.clone();
+    let (second, third) = (fut.await, retry.await);
+    assert!(first < second && second < third);
+
+    let id = USER.sync_scope("ferris", || current_scope_id(&USER).unwrap());
+    assert!(id > third);
+    assert_eq!(id.to_string(), format!("#{}", id.get()));
+}
+
+#[cfg(feature = "zeroize")]
+#[tokio::test]
+async fn test_zeroize() {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use task_local::DropPolicy;
+    use zeroize::Zeroize;
+
+    // Records the value it held when it was dropped.
+    static DROPPED: AtomicU64 = AtomicU64::new(u64::MAX);
+
+    struct Token(u64);
+
+    impl Zeroize for Token {
+        fn zeroize(&mut self) {
+            self.0 = 0;
+        }
+    }
+
+    impl Drop for Token {
+        fn drop(&mut self) {
+            DROPPED.store(self.0, Ordering::SeqCst);
+        }
+    }
+
+    task_local! {
+        #[task_local(zeroize)]
+        static TOKEN: Token;
+    }
+
+    TOKEN.sync_scope(Token(1), || assert_eq!(TOKEN.with(|t| t.0), 1));
+    assert_eq!(DROPPED.swap(u64::MAX, Ordering::SeqCst), 0);
+
+    TOKEN.sync_scope_with_drop_policy(Token(2), DropPolicy::InsideScope, || {});
+    assert_eq!(DROPPED.swap(u64::MAX, Ordering::SeqCst), 0);
+
+    // A panic unwinding out of the scope wipes the value too
+    let res = std::panic::catch_unwind(|| TOKEN.sync_scope(Token(3), || panic!("boom")));
+    assert!(res.is_err());
+    assert_eq!(DROPPED.swap(u64::MAX, Ordering::SeqCst), 0);
+
+    TOKEN.scope(Token(4), tokio::task::yield_now()).await;
+    assert_eq!(DROPPED.swap(u64::MAX, Ordering::SeqCst), 0);
+
+    // A cancelled future wipes its value when dropped
+    let cancelled = TOKEN.scope(Token(5), std::future::pending::<()>());
+    let res = tokio::time::timeout(std::time::Duration::from_millis(1), cancelled).await;
+    assert!(res.is_err());
+    assert_eq!(DROPPED.swap(u64::MAX, Ordering::SeqCst), 0);
+
+    // A value handed back to the caller is not wiped
+    let replaced = TOKEN.sync_scope(Token(6), || TOKEN.set(Token(7)));
+    assert_eq!(DROPPED.swap(u64::MAX, Ordering::SeqCst), 0);
+    drop(replaced);
+    assert_eq!(DROPPED.swap(u64::MAX, Ordering::SeqCst), 6);
+}
+
+#[cfg(feature = "context")]
+#[tokio::test]
+async fn test_context() {
+    use task_local::context::Context;
+
+    task_local! {
+        static TENANT: &'static str;
+        static REQUEST_ID: u64;
+        static DEPTH: u32;
+    }
+
+    let base = Context::new()
+        .with_value(&TENANT, "acme")
+        .with_value(&DEPTH, 0);
+    assert_eq!(base.get(&TENANT), Some(&"acme"));
+    assert_eq!(base.get(&REQUEST_ID), None);
+    assert!(Context::current().is_empty());
+
+    base.clone()
+        .scope(async {
+            assert_eq!(TENANT.get(), "acme");
+            assert_eq!(DEPTH.get_copied(), 0);
+            assert!(REQUEST_ID.try_with(|_| ()).is_err());
+
+            // A child overrides a key and shares the others with its parent
+            let child = Context::current()
+                .with_value(&REQUEST_ID, 7)
+                .with_value(&DEPTH, 1);
+            assert_eq!(format!("{child:?}"), r#"["DEPTH", "REQUEST_ID", "TENANT"]"#);
+            child
+                .scope(async {
+                    tokio::task::yield_now().await;
+                    assert_eq!(TENANT.get(), "acme");
+                    assert_eq!(REQUEST_ID.get(), 7);
+                    assert_eq!(DEPTH.get(), 1);
+
+                    // Scopes of the keys themselves take precedence
+                    DEPTH.sync_scope(5u32, || assert_eq!(DEPTH.get(), 5));
+                })
+                .await;
+
+            assert_eq!(DEPTH.get(), 0);
+            assert!(REQUEST_ID.try_with(|_| ()).is_err());
+        })
+        .await;
+
+    let depth = Context::new()
+        .with_value(&DEPTH, 3)
+        .sync_scope(|| DEPTH.get());
+    assert_eq!(depth, 3);
+    assert!(TENANT.try_with(|_| ()).is_err());
+}
+
+#[cfg(feature = "context")]
+#[tokio::test]
+async fn test_context_copy_on_write() {
+    use task_local::context::Context;
+
+    task_local! {
+        static TAGS: Vec<&'static str>;
+        static UNSET: u32;
+    }
+
+    let base = TAGS.sync_scope(vec!["api"], || {
+        Context::new().with_current(&TAGS).with_current(&UNSET)
+    });
+    assert_eq!(format!("{base:?}"), r#"["TAGS"]"#);
+
+    let tasks: Vec<_> = (0..100)
+        .map(|_| tokio::spawn(base.clone().scope(async { TAGS.with(Vec::len) })))
+        .collect();
+    for task in tasks {
+        assert_eq!(task.await.unwrap(), 1);
+    }
+
+    // Writing a shared value copies it into an override
+    let mut admin = base.clone();
+    admin.make_mut(&TAGS).unwrap().push("admin");
+    assert_eq!(admin.get(&TAGS).unwrap(), &["api", "admin"]);
+    assert_eq!(base.get(&TAGS).unwrap(), &["api"]);
+
+    // The override is then modified in place
+    let before: *const Vec<&str> = admin.get(&TAGS).unwrap();
+    admin.make_mut(&TAGS).unwrap().push("root");
+    assert!(std::ptr::eq(before, admin.get(&TAGS).unwrap()));
+    assert!(admin.make_mut(&UNSET).is_none());
+    assert_eq!(admin.sync_scope(|| TAGS.get()), ["api", "admin", "root"]);
+}
+
+#[cfg(feature = "audit")]
+static AUDIT_TRAIL: std::sync::Mutex<Vec<(&str, u32, bool)>> = std::sync::Mutex::new(Vec::new());
+
+#[cfg(feature = "audit")]
+fn audit(access: &task_local::Access) {
+    let entry = (access.key(), access.location().line(), access.result().is_ok());
+    AUDIT_TRAIL.lock().unwrap().push(entry);
+}
+
+#[cfg(feature = "audit")]
+task_local::set_audit_hook!(audit);
+
+#[cfg(feature = "audit")]
+#[test]
+fn test_audited() {
+    task_local! {
+        #[task_local(audited)]
+        static CREDENTIAL: String;
+        static PLAIN: u32;
+    }
+
+    let line = line!();
+    assert!(CREDENTIAL.try_get().is_err());
+    CREDENTIAL.sync_scope("secret", || {
+        PLAIN.sync_scope(1u32, || {
+            assert_eq!(CREDENTIAL.with(String::len), 6);
+            assert_eq!(CREDENTIAL.get(), "secret");
+            assert_eq!(PLAIN.get(), 1);
+        })
+    });
+
+    // Reads of keys that are not audited are not reported
+    let trail = std::mem::take(&mut *AUDIT_TRAIL.lock().unwrap());
+    assert_eq!(
+        trail,
+        [
+            ("CREDENTIAL", line + 1, false),
+            ("CREDENTIAL", line + 4, true),
+            ("CREDENTIAL", line + 5, true),
+        ]
+    );
+}
+
+#[cfg(feature = "secrecy")]
+#[test]
+fn test_secrecy_redacted() {
+    use secrecy::{ExposeSecret, SecretString};
+
+    task_local! {
+        static API_TOKEN: SecretString;
+    }
+
+    API_TOKEN.sync_scope("hunter2", || {
+        assert_eq!(format!("{:?}", API_TOKEN.debug()), "[REDACTED]");
+        #[cfg(feature = "registry")]
+        assert!(!format!("{:?}", task_local::dump()).contains("hunter2"));
+        let snapshot = task_local::current(&API_TOKEN);
+        assert!(!format!("{snapshot:?}").contains("hunter2"));
+        assert_eq!(API_TOKEN.with(|token| token.expose_secret().to_owned()), "hunter2");
+    });
+}
+
+#[cfg(all(feature = "context", not(feature = "forbid-unsafe")))]
+#[tokio::test]
+async fn test_key_namespace() {
+    use task_local::KeyNamespace;
+
+    mod plugin_a {
+        task_local::task_local! {
+            pub static USER: u64;
+            pub static LOCALE: &'static str;
+        }
+    }
+
+    mod plugin_b {
+        task_local::task_local! {
+            pub static USER: String;
+        }
+    }
+
+    static A: KeyNamespace = KeyNamespace::new("plugin_a");
+    static B: KeyNamespace = KeyNamespace::new("plugin_b");
+
+    A.register(&plugin_a::USER).unwrap();
+    A.register(&plugin_a::LOCALE).unwrap();
+    A.register(&plugin_a::USER).unwrap();
+    B.register(&plugin_b::USER).unwrap();
+
+    // Names only collide within a namespace
+    let err = A.register(&plugin_b::USER).unwrap_err();
+    assert_eq!((err.namespace(), err.key()), ("plugin_a", "USER"));
+    assert_eq!(A.keys().collect::<Vec<_>>(), ["LOCALE", "USER"]);
+
+    let snapshot = plugin_a::USER.sync_scope(7u64, || {
+        plugin_b::USER.sync_scope("bob".to_string(), || {
+            assert_eq!(format!("{:?}", B.dump()), r#"plugin_b {"USER": "bob"}"#);
+            A.snapshot()
+        })
+    });
+    assert_eq!(format!("{snapshot:?}"), r#"["USER"]"#);
+
+    tokio::spawn(snapshot.scope(async {
+        tokio::task::yield_now().await;
+        assert_eq!(plugin_a::USER.get(), 7);
+        assert!(plugin_a::LOCALE.try_with(|_| ()).is_err());
+        assert!(plugin_b::USER.try_with(|_| ()).is_err());
+        assert_eq!(format!("{:?}", A.dump()), r#"plugin_a {"USER": 7}"#);
+    }))
+    .await
+    .unwrap();
+}
+
+#[cfg(feature = "read-mostly")]
+#[tokio::test]
+async fn test_read_mostly() {
+    use std::sync::Arc;
+    use task_local::AccessError;
+
+    task_local::read_mostly! {
+        static LIMIT: u32;
+    }
+
+    assert_eq!(LIMIT.try_get_shared(), Err(AccessError::NotSet));
+    LIMIT.store(Arc::new(10));
+    assert_eq!(*LIMIT.get_shared(), 10);
+
+    LIMIT.sync_scope(Arc::new(20), || {
+        assert_eq!(*LIMIT.get_shared(), 20);
+        // Other threads keep seeing the global value
+        let other = std::thread::spawn(|| *LIMIT.get_shared()).join().unwrap();
+        assert_eq!(other, 10);
+    });
+
+    let fut = LIMIT.scope(Arc::new(30), async {
+        tokio::task::yield_now().await;
+        *LIMIT.get_shared()
+    });
+    assert_eq!(*LIMIT.get_shared(), 10);
+    assert_eq!(fut.await, 30);
+
+    // Stored values are seen everywhere outside of overrides
+    LIMIT.store(Arc::new(40));
+    let task = tokio::spawn(async { *LIMIT.get_shared() });
+    assert_eq!(task.await.unwrap(), 40);
+}
+
+#[tokio::test]
+async fn test_mapped_key() {
+    use task_local::{AccessError, MappedKey};
+
+    struct RequestCtx {
+        tenant: String,
+        user_id: u64,
+    }
+
+    task_local! {
+        static CTX: RequestCtx;
+    }
+
+    static TENANT: MappedKey<RequestCtx, str> = CTX.map(|ctx| &ctx.tenant);
+    static USER_ID: MappedKey<RequestCtx, u64> = CTX.map(|ctx| &ctx.user_id);
+
+    assert_eq!(TENANT.try_with(str::len), Err(AccessError::NotSet));
+    assert!(std::ptr::eq(USER_ID.key(), &CTX));
+
+    let ctx = |tenant: &str, user_id| RequestCtx {
+        tenant: tenant.into(),
+        user_id,
+    };
+    CTX.scope(ctx("acme", 7), async {
+        tokio::task::yield_now().await;
+        assert_eq!(TENANT.with(str::to_owned), "acme");
+        assert_eq!(USER_ID.get(), 7);
+
+        CTX.scope(ctx("globex", 8), async {
+            assert_eq!(TENANT.with(str::to_owned), "globex");
+            assert_eq!(USER_ID.get(), 8);
+        })
+        .await;
+        assert_eq!(USER_ID.get(), 7);
+    })
+    .await;
+}
+
+#[test]
+fn test_with_several_keys() {
+    use task_local::{AccessError, KeyAccessError};
+
+    task_local! {
+        static TENANT: &'static str;
+        static REQUEST_ID: u64;
+        static DEPTH: u32;
+        static TAGS: Vec<&'static str>;
+    }
+
+    TENANT.sync_scope("acme", || {
+        REQUEST_ID.sync_scope(7u64, || {
+            let line = task_local::with((&TENANT, &REQUEST_ID), |tenant, id| {
+                format!("[{tenant}/{id}]")
+            });
+            assert_eq!(line, "[acme/7]");
+            assert_eq!(task_local::with((&REQUEST_ID,), |id| *id), 7);
+
+            // The first key without a value is named
+            let err: KeyAccessError =
+                task_local::try_with((&TENANT, &DEPTH, &REQUEST_ID, &TAGS), |_, _, _, _| ())
+                    .unwrap_err();
+            assert_eq!(err.key(), "DEPTH");
+            assert_eq!(err.error(), AccessError::NotSet);
+            assert_eq!(err.to_string(), "task-local `DEPTH` not set");
+
+            DEPTH.sync_scope(1u32, || {
+                TAGS.sync_scope(vec!["api"], || {
+                    let all = task_local::with(
+                        (&TENANT, &DEPTH, &REQUEST_ID, &TAGS),
+                        |tenant, depth, id, tags| format!("{tenant} {depth} {id} {tags:?}"),
+                    );
+                    assert_eq!(all, r#"acme 1 7 ["api"]"#);
+                })
+            });
+        })
+    });
+
+    let panic =
+        std::panic::catch_unwind(|| task_local::with((&TENANT, &TAGS), |_, _| ())).unwrap_err();
+    let message = panic.downcast_ref::<String>().unwrap();
+    assert!(message.starts_with("task-local `TENANT` not set (accessed at "));
+}
+
+#[test]
+fn test_c_export() {
+    #[repr(C)]
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct LogConfig {
+        level: u8,
+        verbose: bool,
+    }
+
+    task_local! {
+        #[task_local(c_export)]
+        static DEVICE_ID: u32;
+
+        #[task_local(poison, c_export)]
+        static LOG_CONFIG: LogConfig;
+
+        #[task_local(c_export = "test_c_export_device_id")]
+        static OTHER_DEVICE_ID: u32;
+    }
+
+    extern "C" {
+        fn task_local_get_DEVICE_ID(out: *mut u32) -> bool;
+        fn task_local_get_LOG_CONFIG(out: *mut LogConfig) -> bool;
+        fn test_c_export_device_id(out: *mut u32) -> bool;
+    }
+
+    // A C driver reading the context of the task that calls it
+    fn driver() -> (Option<u32>, Option<LogConfig>) {
+        let mut id = 0;
+        let mut config = LogConfig {
+            level: 0,
+            verbose: false,
+        };
+        unsafe {
+            (
+                task_local_get_DEVICE_ID(&mut id).then_some(id),
+                task_local_get_LOG_CONFIG(&mut config).then_some(config),
+            )
+        }
+    }
+
+    assert_eq!(driver(), (None, None));
+    let config = LogConfig {
+        level: 3,
+        verbose: true,
+    };
+    let seen = DEVICE_ID.sync_scope(42u32, || LOG_CONFIG.sync_scope(config, driver));
+    assert_eq!(seen, (Some(42), Some(config)));
+    assert!(DEVICE_ID.sync_scope(42u32, || !unsafe {
+        task_local_get_DEVICE_ID(std::ptr::null_mut())
+    }));
+
+    let mut id = 0;
+    assert!(!unsafe { test_c_export_device_id(&mut id) });
+    let seen = OTHER_DEVICE_ID.sync_scope(7u32, || unsafe { test_c_export_device_id(&mut id) });
+    assert!(seen);
+    assert_eq!(id, 7);
+}
+
+#[test]
+fn test_env_default() {
+    use task_local::AccessError;
+
+    fn parse_flag(value: &str) -> Result<bool, ()> {
+        match value {
+            "on" => Ok(true),
+            "off" => Ok(false),
+            _ => Err(()),
+        }
+    }
+
+    task_local! {
+        #[task_local(env = "TASK_LOCAL_TEST_LOG_LEVEL", parse = FromStr)]
+        static LOG_LEVEL: u8;
+
+        #[task_local(env = "TASK_LOCAL_TEST_ENDPOINT")]
+        static ENDPOINT: String;
+
+        #[task_local(poison, env = "TASK_LOCAL_TEST_VERBOSE", parse = parse_flag)]
+        static VERBOSE: bool;
+
+        #[task_local(env = "TASK_LOCAL_TEST_INVALID")]
+        static INVALID: u32;
+
+        #[task_local(env = "TASK_LOCAL_TEST_MISSING")]
+        static MISSING: u32;
+    }
+
+    std::env::set_var("TASK_LOCAL_TEST_LOG_LEVEL", "3");
+    std::env::set_var("TASK_LOCAL_TEST_ENDPOINT", "https://example.com");
+    std::env::set_var("TASK_LOCAL_TEST_VERBOSE", "on");
+    std::env::set_var("TASK_LOCAL_TEST_INVALID", "three");
+
+    assert_eq!(LOG_LEVEL.get(), 3);
+    assert_eq!(LOG_LEVEL.sync_scope(1u8, || LOG_LEVEL.get()), 1);
+    assert_eq!(ENDPOINT.with(String::len), 19);
+    assert!(VERBOSE.get());
+    assert_eq!(INVALID.try_with(|_| ()), Err(AccessError::NotSet));
+    assert_eq!(MISSING.try_with(|_| ()), Err(AccessError::NotSet));
+
+    // The variable is only parsed once
+    std::env::set_var("TASK_LOCAL_TEST_LOG_LEVEL", "5");
+    assert_eq!(LOG_LEVEL.get(), 3);
+}
+
+#[test]
+fn test_global_default() {
+    task_local! {
+        static REGION: &'static str;
+
+        #[task_local(env = "TASK_LOCAL_TEST_GLOBAL_PORT")]
+        static PORT: u16;
+    }
+
+    assert!(REGION.try_with(|_| ()).is_err());
+    REGION.set_global_default("eu-west-1").unwrap();
+    assert_eq!(REGION.set_global_default("ap-south-1"), Err("ap-south-1"));
+
+    assert_eq!(REGION.get(), "eu-west-1");
+    assert_eq!(REGION.sync_scope("us-east-1", || REGION.get()), "us-east-1");
+    let region = std::thread::spawn(|| REGION.with(|region| region.len()));
+    assert_eq!(region.join().unwrap(), 9);
+
+    // The global default takes precedence over the environment
+    std::env::set_var("TASK_LOCAL_TEST_GLOBAL_PORT", "80");
+    PORT.set_global_default(8080).unwrap();
+    assert_eq!(PORT.get(), 8080);
+}
+
+#[test]
+fn test_value_source() {
+    use task_local::{AccessError, ValueSource};
+
+    task_local! {
+        static TENANT: u32;
+
+        static TIMEOUT: u64;
+
+        #[task_local(env = "TASK_LOCAL_TEST_SOURCE_RETRIES")]
+        static RETRIES: u8;
+    }
+
+    assert_eq!(TENANT.try_with_source(|_| ()), Err(AccessError::NotSet));
+    TENANT.set_global_default(1).unwrap();
+    assert_eq!(TENANT.get_or_global(), (1, ValueSource::Global));
+    assert_eq!(
+        TENANT.sync_scope(7u32, || TENANT.get_or_global()),
+        (7, ValueSource::Scope)
+    );
+
+    TIMEOUT.sync_scope(30u64, || {
+        assert_eq!(
+            TIMEOUT.try_with_source(|timeout| timeout * 2),
+            Ok((60, ValueSource::Scope))
+        );
+    });
+
+    std::env::set_var("TASK_LOCAL_TEST_SOURCE_RETRIES", "3");
+    assert_eq!(RETRIES.get_or_global(), (3, ValueSource::Env));
+}
+
+#[cfg(feature = "raw-hooks")]
+#[test]
+fn test_raw_hooks() {
+    task_local! {
+        static TASK_ID: u32;
+        static PRIORITY: u8;
+    }
+
+    PRIORITY.sync_scope(0u8, || {});
+    let idle = task_local::exit_raw();
+    assert!(idle.is_empty());
+
+    TASK_ID.sync_scope(1u32, || {
+        PRIORITY.sync_scope(3u8, || {
+            let task_1 = task_local::exit_raw();
+            assert!(!task_1.is_empty());
+            assert!(TASK_ID.try_with(|_| ()).is_err());
+            assert!(PRIORITY.try_with(|_| ()).is_err());
+
+            TASK_ID.sync_scope(2u32, || {
+                let task_2 = task_local::exit_raw();
+                // Safety: Task 1 did not run since it was switched away from.
+                unsafe { task_local::enter_raw(task_1) };
+                assert_eq!((TASK_ID.get(), PRIORITY.get()), (1, 3));
+
+                let task_1 = task_local::exit_raw();
+                // Safety: As above, for task 2.
+                unsafe { task_local::enter_raw(task_2) };
+                assert_eq!(TASK_ID.get(), 2);
+                assert!(PRIORITY.try_with(|_| ()).is_err());
+
+                // Task 2 leaves its scope while task 1 is suspended.
+                drop(task_1);
+            });
+        });
+    });
+    assert!(TASK_ID.try_with(|_| ()).is_err());
+}
@@ -0,0 +1,94 @@
+//! Tests of the single-threaded WebAssembly backend.
+//!
+//! Run with `cargo test --target wasm32-unknown-unknown --features wasm-bindgen-futures
+//! --test wasm`, with `wasm-bindgen-test-runner` as the runner of the target.
+
+#![cfg(target_arch = "wasm32")]
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use futures::channel::oneshot;
+use task_local::task_local;
+use wasm_bindgen_test::wasm_bindgen_test;
+
+task_local! {
+    static NUMBER: u32;
+    static CACHE: Rc<RefCell<Vec<u32>>>;
+}
+
+/// Yields to the JavaScript event loop once.
+async fn yield_now() {
+    let (tx, rx) = oneshot::channel();
+    wasm_bindgen_futures::spawn_local(async move {
+        let _ = tx.send(());
+    });
+    rx.await.unwrap();
+}
+
+#[wasm_bindgen_test]
+fn test_sync_scope() {
+    let res = NUMBER.sync_scope(1u32, || {
+        let inner = NUMBER.sync_scope(2u32, || NUMBER.get());
+        (NUMBER.get(), inner)
+    });
+    assert_eq!(res, (1, 2));
+    assert!(NUMBER.try_with(|_| ()).is_err());
+}
+
+#[wasm_bindgen_test]
+async fn test_scope_in_spawn_local() {
+    // Two tasks interleaving at their await points keep their own values
+    let (tx1, rx1) = oneshot::channel();
+    let (tx2, rx2) = oneshot::channel();
+    for (number, tx) in [(1u32, tx1), (2, tx2)] {
+        wasm_bindgen_futures::spawn_local(NUMBER.scope(number, async move {
+            let before = NUMBER.get();
+            yield_now().await;
+            let _ = tx.send((before, NUMBER.get()));
+        }));
+    }
+    assert_eq!(rx1.await.unwrap(), (1, 1));
+    assert_eq!(rx2.await.unwrap(), (2, 2));
+
+    // Spawned futures do not see the values of the spawning task
+    let (tx, rx) = oneshot::channel();
+    NUMBER.sync_scope(3u32, || {
+        wasm_bindgen_futures::spawn_local(async move {
+            let _ = tx.send(NUMBER.try_with(|_| ()).is_ok());
+        })
+    });
+    assert!(!rx.await.unwrap());
+}
+
+#[wasm_bindgen_test]
+async fn test_values_not_send() {
+    let cache = Rc::new(RefCell::new(Vec::new()));
+    CACHE
+        .scope(cache.clone(), async {
+            yield_now().await;
+            CACHE.with(|cache| cache.borrow_mut().push(1));
+        })
+        .await;
+    assert_eq!(*cache.borrow(), [1]);
+}
+
+#[cfg(feature = "wasm-bindgen-futures")]
+#[wasm_bindgen_test]
+async fn test_spawn_local_carries_values() {
+    let (tx, rx) = oneshot::channel();
+    NUMBER.sync_scope(7u32, || {
+        task_local::wasm::spawn_local((&NUMBER,), async move {
+            yield_now().await;
+            let _ = tx.send(NUMBER.get());
+        })
+    });
+    assert_eq!(rx.await.unwrap(), 7);
+
+    // Keys without a value are left unset
+    let (tx, rx) = oneshot::channel();
+    task_local::wasm::spawn_local(&NUMBER, async move {
+        let _ = tx.send(NUMBER.try_with(|_| ()).is_ok());
+    });
+    assert!(!rx.await.unwrap());
+}